@@ -0,0 +1,439 @@
+use std::{
+    fmt::{self, Write},
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io, mem,
+    path::{Path, PathBuf},
+    str::{self, FromStr},
+    time::SystemTime,
+};
+
+use mpd::Song;
+
+use crate::{
+    events::Frames,
+    running_text::{RunningText, TextFrame},
+    utils::{cache_dir, Base64},
+};
+
+const EXTENSIONS: [&str; 4] = ["jpg", "png", "webp", "img"];
+
+/// Album art images stored under the cache directory, the least recently used ones are evicted
+/// once the total size goes over the limit.
+#[derive(Debug)]
+pub struct ArtCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl ArtCache {
+    pub fn new(max_size: u64) -> Self {
+        Self::with_dir(cache_dir().join("art"), max_size)
+    }
+    pub fn with_dir(dir: PathBuf, max_size: u64) -> Self {
+        Self { dir, max_size }
+    }
+    /// Changes when the song file is replaced, so stale art is never served.
+    pub fn key(song: &Song) -> String {
+        let mut hasher = DefaultHasher::new();
+        song.file.hash(&mut hasher);
+        song.last_mod.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+    pub fn lookup(&self, key: &str) -> Option<PathBuf> {
+        let path = EXTENSIONS
+            .iter()
+            .map(|ext| self.dir.join(key).with_extension(ext))
+            .find(|path| path.is_file())?;
+        // the modification time doubles as the last access time
+        if let Err(e) = fs::File::open(&path).and_then(|f| f.set_modified(SystemTime::now())) {
+            log::debug!("Failed touching {}: {e}", path.display());
+        }
+        Some(path)
+    }
+    pub fn store(&self, key: &str, data: &[u8]) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(key).with_extension(extension(data));
+        // readers never see a half-written image
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        self.evict(&path)?;
+        Ok(path)
+    }
+    fn evict(&self, keep: &Path) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                entries.push((meta.modified()?, meta.len(), entry.path()));
+            }
+        }
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            log::debug!("Evicting {} from the art cache", path.display());
+            fs::remove_file(&path)?;
+            size -= len;
+        }
+        Ok(())
+    }
+}
+
+/// An sRGB color, shown as `#rrggbb` unless another [`ColorFormat`] is asked for.
+#[cfg(feature = "art-color")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub [u8; 3]);
+
+/// How `{artColor}` is written: `#rrggbb` for Pango and polybar, `rrggbb` for CSS class names,
+/// `r;g;b` for ANSI true color escapes.
+#[cfg(feature = "art-color")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorFormat {
+    #[default]
+    Hash,
+    Hex,
+    Rgb,
+}
+
+#[cfg(feature = "art-color")]
+impl FromStr for ColorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(ColorFormat::Hex),
+            "rgb" => Ok(ColorFormat::Rgb),
+            _ => anyhow::bail!("unknown color format '{s}', expected hex or rgb"),
+        }
+    }
+}
+
+#[cfg(feature = "art-color")]
+impl ColorFormat {
+    /// What follows the placeholder name, empty for the default.
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorFormat::Hash => "",
+            ColorFormat::Hex => "hex",
+            ColorFormat::Rgb => "rgb",
+        }
+    }
+    pub fn write(self, Color([r, g, b]): Color, f: &mut impl Write) -> fmt::Result {
+        match self {
+            ColorFormat::Hash => write!(f, "#{r:02x}{g:02x}{b:02x}"),
+            ColorFormat::Hex => write!(f, "{r:02x}{g:02x}{b:02x}"),
+            ColorFormat::Rgb => write!(f, "{r};{g};{b}"),
+        }
+    }
+}
+
+/// Dominant color of a cover, `None` if it can not be decoded (only JPEG and PNG are).
+#[cfg(feature = "art-color")]
+pub fn dominant_color(path: &Path) -> Option<Color> {
+    let image = image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(image::ImageError::IoError)
+        .and_then(|reader| reader.decode());
+    match image {
+        // a thumbnail has the same dominant color and takes a fraction of the time
+        Ok(image) => dominant_color_of(&image.thumbnail(64, 64).into_rgba8()),
+        Err(e) => {
+            log::debug!("Failed decoding {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// The most common color with channels quantized to 4 bits. Saturated pixels count up to 8
+/// times as much, so a colorful accent wins over a plain white or black background.
+#[cfg(feature = "art-color")]
+fn dominant_color_of(image: &image::RgbaImage) -> Option<Color> {
+    let bucket =
+        |[r, g, b, _]: [u8; 4]| (r as usize >> 4) << 8 | (g as usize >> 4) << 4 | b as usize >> 4;
+    let opaque = || image.pixels().map(|p| p.0).filter(|[.., a]| *a >= 128);
+    let mut weights = vec![0u32; 1 << 12];
+    for pixel @ [r, g, b, _] in opaque() {
+        let chroma = r.max(g).max(b) - r.min(g).min(b);
+        weights[bucket(pixel)] += 1 + u32::from(chroma / 32);
+    }
+    let (best, _) = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| **w > 0)
+        .max_by_key(|(_, w)| **w)?;
+    // the average of the bucket is closer to the real color than its corner
+    let (mut sum, mut count) = ([0u32; 3], 0);
+    for pixel in opaque().filter(|p| bucket(*p) == best) {
+        for (s, c) in sum.iter_mut().zip(pixel) {
+            *s += u32::from(c);
+        }
+        count += 1;
+    }
+    Some(Color(sum.map(|s| (s / count) as u8)))
+}
+
+fn extension(data: &[u8]) -> &'static str {
+    match data {
+        [0xff, 0xd8, 0xff, ..] => "jpg",
+        [0x89, b'P', b'N', b'G', ..] => "png",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "webp",
+        _ => "img",
+    }
+}
+
+/// Terminal graphics protocols that can show an image inline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageProtocol {
+    Kitty,
+    Iterm,
+}
+
+impl FromStr for ImageProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kitty" => Ok(ImageProtocol::Kitty),
+            "iterm" => Ok(ImageProtocol::Iterm),
+            _ => anyhow::bail!("unknown image protocol '{s}', expected kitty or iterm"),
+        }
+    }
+}
+
+impl ImageProtocol {
+    /// Cells the image takes at the start of the line, the text goes after them.
+    pub const WIDTH: usize = 2;
+    /// Lets the image be replaced or deleted without touching images of other programs.
+    const KITTY_ID: u32 = 0x6d67;
+
+    /// Writes an escape sequence showing the image at the cursor without moving it.
+    pub fn write_image(self, data: &[u8], out: &mut String) -> fmt::Result {
+        match self {
+            ImageProtocol::Kitty => {
+                let encoded = Base64(data).to_string();
+                // kitty takes at most 4096 bytes of payload per escape sequence
+                let mut chunks = encoded.as_bytes().chunks(4096).peekable();
+                let mut first = true;
+                while let Some(chunk) = chunks.next() {
+                    match mem::take(&mut first) {
+                        true => write!(
+                            out,
+                            "\x1b_Ga=T,f=100,i={},c={},r=1,C=1,q=2,",
+                            Self::KITTY_ID,
+                            Self::WIDTH
+                        )?,
+                        false => out.push_str("\x1b_G"),
+                    }
+                    // base64 is ASCII, any chunk of it is valid UTF-8
+                    let chunk = str::from_utf8(chunk).unwrap();
+                    let more = u8::from(chunks.peek().is_some());
+                    write!(out, "m={more};{chunk}\x1b\\")?;
+                }
+                Ok(())
+            }
+            ImageProtocol::Iterm => write!(
+                out,
+                "\x1b7\x1b]1337;File=inline=1;width={};height=1;preserveAspectRatio=1:{}\x07\x1b8",
+                Self::WIDTH,
+                Base64(data)
+            ),
+        }
+    }
+    /// Writes an escape sequence removing the image shown last.
+    pub fn clear_image(self, out: &mut String) -> fmt::Result {
+        match self {
+            ImageProtocol::Kitty => write!(out, "\x1b_Ga=d,d=I,i={},q=2\x1b\\", Self::KITTY_ID),
+            // iTerm2 images are text cells, overwriting them is enough
+            ImageProtocol::Iterm => write!(out, "{:1$}", "", Self::WIDTH),
+        }
+    }
+    /// Whether the terminal can show an image in this format.
+    pub fn supports(self, data: &[u8]) -> bool {
+        match self {
+            ImageProtocol::Kitty => extension(data) == "png",
+            ImageProtocol::Iterm => true,
+        }
+    }
+}
+
+/// Running text shown after the cover of the current MPD song in a terminal.
+pub struct RunningTextWithArt {
+    text: RunningText,
+    protocol: ImageProtocol,
+    art: Option<PathBuf>,
+    /// Escape sequences updating the image, empty when it stays the same.
+    image: String,
+}
+
+impl RunningTextWithArt {
+    pub fn new(mut text: RunningText, protocol: ImageProtocol) -> Self {
+        if !text.get_source_mut().track_art() {
+            log::warn!("Album art is only shown for --mpd");
+        }
+        text.reserve_columns(ImageProtocol::WIDTH);
+        Self {
+            text,
+            protocol,
+            art: None,
+            image: String::new(),
+        }
+    }
+    fn update_image(&mut self) {
+        let art = self.text.get_source().album_art().map(Path::to_owned);
+        if art == self.art {
+            return;
+        }
+        self.image.push('\r');
+        // the escape sequences go into a String, writing them never fails
+        let _ = match art.as_deref().map(|path| (path, fs::read(path))) {
+            Some((_, Ok(data))) if self.protocol.supports(&data) => {
+                self.protocol.write_image(&data, &mut self.image)
+            }
+            Some((path, Ok(_))) => {
+                log::info!(
+                    "Not showing {}, kitty only shows PNG images",
+                    path.display()
+                );
+                self.protocol.clear_image(&mut self.image)
+            }
+            Some((path, Err(e))) => {
+                log::warn!("Failed reading album art {}: {e}", path.display());
+                self.protocol.clear_image(&mut self.image)
+            }
+            None => self.protocol.clear_image(&mut self.image),
+        };
+        self.art = art;
+    }
+}
+
+impl Frames for RunningTextWithArt {
+    /// The text and escape sequences to write before it.
+    type Frame<'a> = (TextFrame<'a>, &'a str);
+
+    fn advance(&mut self) -> Option<anyhow::Result<()>> {
+        let result = self.text.advance()?;
+        self.image.clear();
+        if self.text.is_refreshed() {
+            self.update_image();
+        }
+        Some(result)
+    }
+    fn frame(&self) -> Self::Frame<'_> {
+        (self.text.frame(), &self.image)
+    }
+    fn frame_changed(&self) -> bool {
+        !self.image.is_empty() || self.text.frame_changed()
+    }
+    fn force_refresh(&mut self) {
+        self.text.force_refresh();
+    }
+    fn scroll_once(&mut self) {
+        self.text.scroll_once();
+    }
+    fn resize(&mut self) {
+        self.text.resize();
+    }
+    fn is_finished(&self) -> bool {
+        self.text.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time::Duration};
+
+    use mpd::Song;
+
+    use super::{ArtCache, ImageProtocol};
+
+    #[test]
+    fn art_cache_test() {
+        let dir = std::env::temp_dir().join(format!("mergneh-art-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = ArtCache::with_dir(dir.clone(), 10);
+        let song = |file: &str| Song {
+            file: file.to_owned(),
+            ..Default::default()
+        };
+        let (first, second) = (
+            ArtCache::key(&song("a.flac")),
+            ArtCache::key(&song("b.flac")),
+        );
+        assert_ne!(first, second);
+        assert_eq!(cache.lookup(&first), None);
+
+        let path = cache
+            .store(&first, &[0x89, b'P', b'N', b'G', 0, 0])
+            .unwrap();
+        assert_eq!(path.extension().unwrap(), "png");
+        assert_eq!(cache.lookup(&first), Some(path.clone()));
+
+        // mtime resolution can be coarse
+        thread::sleep(Duration::from_millis(20));
+        let path = cache.store(&second, &[0xff, 0xd8, 0xff, 0, 0, 0]).unwrap();
+        assert_eq!(path.extension().unwrap(), "jpg");
+        assert_eq!(cache.lookup(&first), None);
+        assert_eq!(cache.lookup(&second), Some(path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "art-color")]
+    #[test]
+    fn dominant_color_test() {
+        use image::{Rgba, RgbaImage};
+
+        use super::{dominant_color_of, Color};
+
+        // a white background with a smaller red accent
+        let mut image = RgbaImage::from_pixel(10, 10, Rgba([250, 250, 250, 255]));
+        for x in 0..10 {
+            for y in 0..3 {
+                image.put_pixel(x, y, Rgba([200, 30, 40, 255]));
+            }
+        }
+        assert_eq!(dominant_color_of(&image), Some(Color([200, 30, 40])));
+
+        let gray = RgbaImage::from_pixel(4, 4, Rgba([20, 20, 20, 255]));
+        assert_eq!(dominant_color_of(&gray), Some(Color([20, 20, 20])));
+        let transparent = RgbaImage::from_pixel(4, 4, Rgba([200, 30, 40, 0]));
+        assert_eq!(dominant_color_of(&transparent), None);
+    }
+
+    #[test]
+    fn image_protocol_test() {
+        let png = [0x89, b'P', b'N', b'G', 0, 0];
+        let mut out = String::new();
+        ImageProtocol::Kitty.write_image(&png, &mut out).unwrap();
+        assert_eq!(
+            out,
+            "\x1b_Ga=T,f=100,i=28007,c=2,r=1,C=1,q=2,m=0;iVBORwAA\x1b\\"
+        );
+
+        // payloads over 4096 bytes are split
+        out.clear();
+        ImageProtocol::Kitty
+            .write_image(&[0; 4000], &mut out)
+            .unwrap();
+        assert_eq!(out.matches("\x1b_G").count(), 2);
+        assert!(out.contains(",m=1;"));
+        assert!(out.contains("\x1b\\\x1b_Gm=0;"));
+
+        out.clear();
+        ImageProtocol::Iterm.write_image(&png, &mut out).unwrap();
+        assert!(out.contains("]1337;File=inline=1;width=2;height=1;"));
+        assert!(out.contains(":iVBORwAA\x07"));
+
+        assert!(ImageProtocol::Kitty.supports(&png));
+        assert!(!ImageProtocol::Kitty.supports(&[0xff, 0xd8, 0xff, 0]));
+        assert!(ImageProtocol::Iterm.supports(&[0xff, 0xd8, 0xff, 0]));
+        assert!("sixel".parse::<ImageProtocol>().is_err());
+    }
+}
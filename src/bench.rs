@@ -0,0 +1,160 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt::{self, Display},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use clap::ArgMatches;
+
+#[cfg(feature = "mpd")]
+use crate::mpd::{self, MpdFormatter, StatusIconsSet};
+use crate::{events::Frames, text_source::Content, RunningText};
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts heap allocations, the `mg` binary installs it as the global allocator with the bench
+/// feature. Without it every report shows zero allocations.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn count(size: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed);
+}
+
+fn counters() -> (usize, usize) {
+    (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed),
+    )
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub name: &'static str,
+    pub frames: usize,
+    pub elapsed: Duration,
+    pub allocations: usize,
+    pub bytes: usize,
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frames = self.frames.max(1);
+        write!(
+            f,
+            "{:<8} {:>10.1?}/frame {:>8.2} allocations/frame {:>10.1} bytes/frame ({} frames in {:.2?})",
+            self.name,
+            self.elapsed.div_f64(frames as f64),
+            self.allocations as f64 / frames as f64,
+            self.bytes as f64 / frames as f64,
+            self.frames,
+            self.elapsed,
+        )
+    }
+}
+
+/// Runs `frame` once to warm up, then `frames` more times counting time and allocations.
+pub fn measure(name: &'static str, frames: usize, mut frame: impl FnMut()) -> Report {
+    frame();
+    let (allocations, bytes) = counters();
+    let start = Instant::now();
+    for _ in 0..frames {
+        frame();
+    }
+    let elapsed = start.elapsed();
+    let (allocations_after, bytes_after) = counters();
+    Report {
+        name,
+        frames,
+        elapsed,
+        allocations: allocations_after - allocations,
+        bytes: bytes_after - bytes,
+    }
+}
+
+/// Text of `len` characters mixing words, punctuation and multi-byte characters.
+pub fn synthetic_content(len: usize) -> String {
+    const WORDS: [&str; 8] = [
+        "Artist",
+        "-",
+        "Title",
+        "(Remastered)",
+        "Sigur",
+        "Rós",
+        "Сплин",
+        "♫",
+    ];
+    WORDS
+        .iter()
+        .cycle()
+        .flat_map(|w| w.chars().chain([' ']))
+        .take(len)
+        .collect()
+}
+
+/// Renders synthetic content with the options given on the command line and prints what every
+/// frame costs.
+pub fn run(matches: &mut ArgMatches, frames: usize, len: usize) -> anyhow::Result<()> {
+    let content = Content::new(
+        synthetic_content(len),
+        matches.remove_one("prefix").unwrap(),
+        matches.remove_one("suffix").unwrap(),
+    );
+    #[cfg(feature = "mpd")]
+    let (format, icons, default) = (
+        matches.remove_one::<MpdFormatter>("format").unwrap(),
+        StatusIconsSet::new(
+            matches.remove_one("status-icons").unwrap(),
+            matches.remove_one("consume-icons").unwrap(),
+            matches.remove_one("random-icons").unwrap(),
+            matches.remove_one("repeat-icons").unwrap(),
+            matches.remove_one("single-icons").unwrap(),
+            matches.remove_one("output-icons").unwrap(),
+            matches.remove_one("error-icons").unwrap(),
+        ),
+        matches.remove_one::<String>("default-placeholder").unwrap(),
+    );
+    let mut text = RunningText::from_matches_with_source(Box::new(content), matches)?;
+    println!(
+        "{}",
+        measure("scroll", frames, || {
+            black_box(text.advance());
+            black_box(text.frame());
+        })
+    );
+    #[cfg(feature = "mpd")]
+    mpd::with_sample_context(|ctx| -> anyhow::Result<()> {
+        let mut buffer = String::new();
+        // errors only depend on the format, one frame is enough to catch them
+        format.format(&icons, ctx, &default, &mut buffer)?;
+        let report = measure("format", frames, || {
+            buffer.clear();
+            let _ = format.format(&icons, ctx, &default, &mut buffer);
+            black_box(&buffer);
+        });
+        println!("{report}");
+        Ok(())
+    })?;
+    Ok(())
+}
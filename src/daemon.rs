@@ -0,0 +1,462 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::{ArgMatches, Id};
+
+use crate::{
+    events::{EventLoop, Frames, Message},
+    running_text::{RunningText, TextFrame},
+    sink::Sink,
+    text_source::{self, Content, TextSource},
+    timer::TimerCommand,
+    utils::{runtime_dir, Encoding},
+};
+
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    Text(String),
+    File(PathBuf),
+    Cmd(Vec<String>),
+    Pause,
+    Resume,
+    Scroll,
+    Reload,
+    Status,
+    Metrics,
+    Quit,
+    Timer(TimerCommand),
+}
+
+impl FromStr for ControlCommand {
+    type Err = anyhow::Error;
+
+    /// Takes arguments separated with NUL, as [`send_command`] frames them, or with spaces, as
+    /// a line typed by hand.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let framed = s.contains('\0');
+        let (name, arg) = s.split_once(['\0', ' ']).unwrap_or((s, ""));
+        Ok(match (name, arg) {
+            ("text" | "file" | "cmd" | "timer", "") => {
+                anyhow::bail!("'{name}' requires an argument")
+            }
+            ("text", text) => ControlCommand::Text(text.replace('\0', " ")),
+            ("file", path) => ControlCommand::File(path.replace('\0', " ").into()),
+            ("cmd", args) if framed => {
+                ControlCommand::Cmd(args.split('\0').map(str::to_owned).collect())
+            }
+            ("cmd", args) => {
+                ControlCommand::Cmd(args.split_whitespace().map(str::to_owned).collect())
+            }
+            ("pause", "") => ControlCommand::Pause,
+            ("resume", "") => ControlCommand::Resume,
+            ("scroll", "") => ControlCommand::Scroll,
+            ("reload", "") => ControlCommand::Reload,
+            ("status", "") => ControlCommand::Status,
+            ("metrics", "") => ControlCommand::Metrics,
+            ("quit", "") => ControlCommand::Quit,
+            ("timer", cmd) => ControlCommand::Timer(cmd.replace('\0', " ").parse()?),
+            ("pause" | "resume" | "scroll" | "reload" | "status" | "metrics" | "quit", _) => {
+                anyhow::bail!("'{name}' does not take arguments")
+            }
+            _ => anyhow::bail!("unknown command '{name}'"),
+        })
+    }
+}
+
+pub fn default_socket_path() -> PathBuf {
+    runtime_dir().join("mergneh.sock")
+}
+
+/// Joins the arguments of a command with NUL, so an argument may contain spaces.
+fn frame_command(args: &[String]) -> anyhow::Result<String> {
+    if let Some(arg) = args.iter().find(|a| a.contains(['\0', '\n'])) {
+        anyhow::bail!("{arg:?}: arguments can't contain NUL or newlines");
+    }
+    Ok(args.join("\0"))
+}
+
+pub fn send_command(socket: &Path, args: &[String]) -> anyhow::Result<()> {
+    let command = frame_command(args)?;
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("Failed connecting to {}", socket.display()))?;
+    writeln!(stream, "{command}")?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "ok" {
+            return Ok(());
+        }
+        if let Some(e) = line.strip_prefix("error: ") {
+            anyhow::bail!("{e}");
+        }
+        println!("{line}");
+    }
+    // the daemon may exit before replying to quit
+    Ok(())
+}
+
+struct Socket(PathBuf);
+
+impl Socket {
+    fn bind(path: PathBuf) -> anyhow::Result<(Self, UnixListener)> {
+        if UnixStream::connect(&path).is_ok() {
+            anyhow::bail!("Another daemon is already listening on {}", path.display());
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed binding to {}", path.display()))?;
+        Ok((Socket(path), listener))
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn listen(listener: UnixListener, requests: Sender<Message>) {
+    for stream in listener.incoming().flatten() {
+        let requests = requests.clone();
+        thread::spawn(move || handle_client(stream, requests));
+    }
+}
+
+fn handle_client(stream: UnixStream, requests: Sender<Message>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let result = match line?.parse() {
+            Ok(cmd) => {
+                let (tx, rx) = mpsc::channel();
+                if requests.send(Message::Control(cmd, tx)).is_err() {
+                    break;
+                }
+                rx.recv().unwrap_or(Ok(String::new()))
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(output) => {
+                for line in output.lines() {
+                    writeln!(writer, "{line}")?;
+                }
+                writeln!(writer, "ok")?
+            }
+            Err(e) => writeln!(writer, "error: {e:#}")?,
+        }
+    }
+    Ok(())
+}
+
+pub fn run(
+    mut text: RunningText,
+    duration: Duration,
+    socket: PathBuf,
+    matches: ArgMatches,
+) -> anyhow::Result<()> {
+    let (socket, listener) = Socket::bind(socket)?;
+    log::info!("Listening for commands on {}", socket.0.display());
+    let events = EventLoop::from_options(duration, text.loop_options())?;
+    text.watch(events.waker())?;
+    let sender = events.sender();
+    thread::spawn(move || listen(listener, sender));
+
+    let mut out = text.stdout();
+    let daemon = Daemon {
+        text,
+        matches,
+        frame_rate: FrameRate::new(),
+        failed: false,
+        quit: false,
+    };
+    events.run(daemon, |frame| out.write(frame.into()))
+}
+
+/// The text along with what the control commands need.
+struct Daemon {
+    text: RunningText,
+    matches: ArgMatches,
+    frame_rate: FrameRate,
+    /// Whether the last frame failed to render, errors are logged and the daemon goes on.
+    failed: bool,
+    quit: bool,
+}
+
+impl Frames for Daemon {
+    type Frame<'a> = TextFrame<'a>;
+
+    fn advance(&mut self) -> Option<anyhow::Result<()>> {
+        let result = self.text.advance()?;
+        self.failed = result.is_err();
+        match result {
+            Ok(()) if self.text.frame_changed() => self.frame_rate.push(),
+            Ok(()) => {}
+            Err(e) => log::error!("{e:#}"),
+        }
+        Some(Ok(()))
+    }
+    fn frame(&self) -> TextFrame<'_> {
+        self.text.frame()
+    }
+    fn frame_changed(&self) -> bool {
+        !self.failed && self.text.frame_changed()
+    }
+    fn force_refresh(&mut self) {
+        self.text.force_refresh();
+    }
+    fn scroll_once(&mut self) {
+        self.text.scroll_once();
+    }
+    fn is_finished(&self) -> bool {
+        self.quit
+    }
+    fn control(&mut self, cmd: ControlCommand, events: &mut EventLoop) -> anyhow::Result<String> {
+        let text = &mut self.text;
+        match cmd {
+            ControlCommand::Pause | ControlCommand::Resume => {
+                events.set_paused(cmd == ControlCommand::Pause);
+                Ok(String::new())
+            }
+            ControlCommand::Scroll => {
+                text.scroll_once();
+                Ok(String::new())
+            }
+            ControlCommand::Status => Ok(status(text, events, &mut self.frame_rate)),
+            ControlCommand::Metrics => Ok(text.metrics().to_string()),
+            ControlCommand::Quit => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            ControlCommand::Timer(cmd) => text.get_source_mut().control_timer(cmd).map(|()| {
+                text.force_refresh();
+                String::new()
+            }),
+            ControlCommand::Reload => {
+                // whatever came through stdin is gone, reading it again would give nothing or
+                // race the reader that follows it
+                if self.matches.get_one::<Id>("sources").map(Id::as_str) == Some("stdin") {
+                    anyhow::bail!("A text read from stdin can't be reloaded");
+                }
+                let new_text = RunningText::from_matches(&mut self.matches());
+                replace_text(&mut self.text, new_text, events)
+            }
+            cmd => {
+                let new_text = source_from_command(cmd, &self.matches)
+                    .and_then(|s| RunningText::from_matches_with_source(s, &mut self.matches()));
+                replace_text(&mut self.text, new_text, events)
+            }
+        }
+    }
+}
+
+impl Daemon {
+    /// The command line a text is built again from, the recording goes on in the file that is
+    /// already open instead of starting over.
+    fn matches(&self) -> ArgMatches {
+        let mut matches = self.matches.clone();
+        matches.remove_one::<PathBuf>("record");
+        matches
+    }
+}
+
+fn replace_text(
+    text: &mut RunningText,
+    new_text: anyhow::Result<RunningText>,
+    events: &mut EventLoop,
+) -> anyhow::Result<String> {
+    let mut new_text = new_text?;
+    new_text.watch(events.waker())?;
+    events.set_options(new_text.loop_options())?;
+    *text = new_text.replacing(text);
+    Ok(String::new())
+}
+
+/// Frames printed during the last few seconds.
+struct FrameRate {
+    started: Instant,
+    frames: VecDeque<Instant>,
+}
+
+impl FrameRate {
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        FrameRate {
+            started: Instant::now(),
+            frames: VecDeque::new(),
+        }
+    }
+    fn push(&mut self) {
+        self.frames.push_back(Instant::now());
+        self.expire();
+    }
+    fn expire(&mut self) {
+        while self
+            .frames
+            .front()
+            .is_some_and(|t| t.elapsed() > Self::WINDOW)
+        {
+            self.frames.pop_front();
+        }
+    }
+    fn per_second(&mut self) -> f64 {
+        self.expire();
+        let window = self.started.elapsed().min(Self::WINDOW).as_secs_f64();
+        self.frames.len() as f64 / window
+    }
+}
+
+fn status(text: &RunningText, events: &EventLoop, frame_rate: &mut FrameRate) -> String {
+    let source = text.get_source();
+    let (offset, len) = text.offset();
+    let mut status = String::new();
+    let _ = writeln!(status, "source: {}", source.describe());
+    if let Some(instance) = text.instance() {
+        let _ = writeln!(status, "instance: {instance}");
+    }
+    if let Some(connected) = source.is_connected() {
+        let state = if connected {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        let _ = writeln!(status, "connection: {state}");
+    }
+    let state = if events.is_paused() {
+        "paused"
+    } else {
+        "running"
+    };
+    let _ = writeln!(status, "state: {state}");
+    if let Some(offset) = events.refresh_signal() {
+        let _ = writeln!(status, "refresh signal: RTMIN+{offset}");
+    }
+    let _ = writeln!(
+        status,
+        "last error: {}",
+        text.last_error().unwrap_or("none")
+    );
+    let _ = writeln!(status, "content: {}", text.get_content());
+    let _ = writeln!(status, "offset: {offset}/{len}");
+    let _ = writeln!(
+        status,
+        "frame rate: {:.1}/s (tick {})",
+        frame_rate.per_second(),
+        humantime::format_duration(events.duration())
+    );
+    status
+}
+
+/// Builds the source a command replaces the contents with, decoding and running it with the
+/// options the daemon was started with.
+fn source_from_command(
+    cmd: ControlCommand,
+    matches: &ArgMatches,
+) -> anyhow::Result<Box<dyn TextSource>> {
+    let prefix = matches.get_one::<String>("prefix").unwrap().clone();
+    let suffix = matches.get_one::<String>("suffix").unwrap().clone();
+    let encoding = *matches.get_one::<Encoding>("encoding").unwrap();
+    Ok(match cmd {
+        ControlCommand::Text(text) => Box::new(Content::new(text, prefix, suffix)),
+        ControlCommand::File(path) => Box::new(Content::new(
+            encoding.decode(
+                fs::read(&path).with_context(|| format!("Failed reading {}", path.display()))?,
+            )?,
+            prefix,
+            suffix,
+        )),
+        ControlCommand::Cmd(args) => Box::new(text_source::cmd_source(
+            args, matches, encoding, prefix, suffix,
+        )),
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{frame_command, ControlCommand};
+    use crate::timer::TimerCommand;
+
+    #[test]
+    fn control_command_parse_test() {
+        assert_eq!(
+            "text hello world".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Text("hello world".to_owned())
+        );
+        assert_eq!(
+            "file /tmp/some file".parse::<ControlCommand>().unwrap(),
+            ControlCommand::File("/tmp/some file".into())
+        );
+        assert_eq!(
+            "cmd mpc current -f %title%"
+                .parse::<ControlCommand>()
+                .unwrap(),
+            ControlCommand::Cmd(vec![
+                "mpc".into(),
+                "current".into(),
+                "-f".into(),
+                "%title%".into()
+            ])
+        );
+        assert_eq!(
+            "pause".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Pause
+        );
+        assert_eq!(
+            "quit".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Quit
+        );
+        assert_eq!(
+            "scroll".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Scroll
+        );
+        assert!("cmd".parse::<ControlCommand>().is_err());
+        assert_eq!(
+            "status".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Status
+        );
+        assert_eq!(
+            "metrics".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Metrics
+        );
+        assert_eq!(
+            "timer set 25m".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Timer(TimerCommand::Set(Duration::from_secs(25 * 60)))
+        );
+        assert!("timer".parse::<ControlCommand>().is_err());
+        assert!("timer\0set\x0025m".parse::<ControlCommand>().is_ok());
+        assert!("pause now".parse::<ControlCommand>().is_err());
+        assert!("jump 5".parse::<ControlCommand>().is_err());
+    }
+
+    #[test]
+    fn framed_command_test() {
+        let args = |args: &[&str]| args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>();
+        let framed = frame_command(&args(&["cmd", "sh", "-c", "echo hi"])).unwrap();
+        assert_eq!(
+            framed.parse::<ControlCommand>().unwrap(),
+            ControlCommand::Cmd(args(&["sh", "-c", "echo hi"]))
+        );
+        let framed = frame_command(&args(&["text", "two  spaces", "and more"])).unwrap();
+        assert_eq!(
+            framed.parse::<ControlCommand>().unwrap(),
+            ControlCommand::Text("two  spaces and more".to_owned())
+        );
+        assert!(frame_command(&args(&["text", "two\nlines"])).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::VecDeque,
+    io,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2, SIGWINCH},
+    iterator::{Handle, Signals},
+};
+
+#[cfg(feature = "daemon")]
+use crate::daemon::ControlCommand;
+use crate::{power::PowerMonitor, systemd::Notifier};
+
+pub enum Message {
+    Refresh,
+    TogglePause,
+    Scroll,
+    Resize,
+    Exit,
+    #[cfg(feature = "daemon")]
+    Control(ControlCommand, mpsc::Sender<anyhow::Result<String>>),
+    #[cfg(feature = "i3bar")]
+    Click(u32),
+}
+
+pub enum Event {
+    Render,
+    Refresh,
+    Scroll,
+    Resize,
+    Exit,
+    #[cfg(feature = "daemon")]
+    Control(ControlCommand, mpsc::Sender<anyhow::Result<String>>),
+    #[cfg(feature = "i3bar")]
+    Click(u32),
+}
+
+/// Frames rendered into buffers owned by the producer, so steady-state rendering does not
+/// allocate.
+pub trait Frames {
+    type Frame<'a>
+    where
+        Self: 'a;
+
+    /// Renders the next frame, `None` after the final one.
+    fn advance(&mut self) -> Option<anyhow::Result<()>>;
+    /// The frame rendered by the last successful [`Frames::advance`].
+    fn frame(&self) -> Self::Frame<'_>;
+    fn frame_changed(&self) -> bool;
+    fn force_refresh(&mut self);
+    /// Scrolls through the text once when it only scrolls on demand.
+    fn scroll_once(&mut self) {}
+    /// Called when the terminal was resized.
+    fn resize(&mut self) {}
+    /// Whether the last frame was the final one.
+    fn is_finished(&self) -> bool {
+        false
+    }
+    /// Runs a command of the control socket, the answer is sent back to the client.
+    #[cfg(feature = "daemon")]
+    fn control(&mut self, _cmd: ControlCommand, _events: &mut EventLoop) -> anyhow::Result<String> {
+        anyhow::bail!("commands are only taken by the daemon")
+    }
+    /// Handles a click on the i3bar block, the text is rendered again after it.
+    #[cfg(feature = "i3bar")]
+    fn click(&mut self, _button: u32, _events: &mut EventLoop) {}
+}
+
+/// Waits on a single channel that everything waking the text up sends to: signals, the control
+/// socket, i3bar clicks and push sources (MPD's idle connection, stdin) each block on a thread of
+/// their own and send a [`Message`], and the wait ends early for them or at the next tick.
+///
+/// There is no async runtime: the `mpd` client and the other sources are blocking, so they would
+/// need a thread each under tokio as well. What it means for shutdown is that a thread blocked
+/// in a read can't be cancelled, whoever owns the thing it reads from has to unblock it (the
+/// MPD watcher shuts its connection down when dropped, the signal threads are closed through
+/// their handles, the stdin reader ends on the next line or at EOF).
+pub struct EventLoop {
+    duration: Duration,
+    next_tick: Instant,
+    paused: bool,
+    receiver: Receiver<Message>,
+    sender: mpsc::Sender<Message>,
+    notifier: Option<Notifier>,
+    deadline: Option<Instant>,
+    aligned: bool,
+    min_interval: Option<Duration>,
+    last_render: Option<Instant>,
+    /// Messages received while waiting out `min_interval`.
+    pending: VecDeque<Message>,
+    /// Offset from SIGRTMIN of the signal that refreshes too.
+    refresh_signal: Option<i32>,
+    /// Signals forwarded on threads of their own, closed when the options change.
+    forwarded: Vec<Handle>,
+    /// Ticks at least [`EventLoop::LOW_POWER_TICK`] apart while this reports running on battery.
+    low_power: Option<PowerMonitor>,
+}
+
+/// How the loop driving a text ticks and what else wakes it up, see [`EventLoop::from_options`].
+#[derive(Debug, Clone, Default)]
+pub struct LoopOptions {
+    /// Ends the loop once this point in time has passed.
+    pub deadline: Option<Instant>,
+    /// Ticks on wall-clock multiples of the duration (e.g. every second edge) instead of counting
+    /// from the start.
+    pub aligned: bool,
+    /// Keeps frames at least this far apart however often the source wakes the loop up,
+    /// refreshes arriving in between are merged into one.
+    pub min_interval: Option<Duration>,
+    /// Refreshes on SIGRTMIN+offset like on SIGUSR1, so the number given to waybar's `signal`
+    /// option can be sent to mergneh as well. The offset is 1 or more, SIGRTMIN itself is the
+    /// scroll signal.
+    pub refresh_signal: Option<i32>,
+    /// Scrolls through the text once on SIGRTMIN, for texts that only scroll on demand.
+    pub scroll_signal: bool,
+    /// Ticks less often while this reports running on battery.
+    pub low_power: Option<PowerMonitor>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Waker(pub(crate) mpsc::Sender<Message>);
+
+impl Waker {
+    pub fn wake(&self) -> bool {
+        self.0.send(Message::Refresh).is_ok()
+    }
+}
+
+impl EventLoop {
+    const LOW_POWER_TICK: Duration = Duration::from_secs(5);
+
+    pub fn new(duration: Duration) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let mut signals = Signals::new([SIGINT, SIGTERM, SIGUSR1, SIGUSR2, SIGWINCH])?;
+        let signal_sender = sender.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                log::debug!("Received signal {signal}");
+                let message = match signal {
+                    SIGUSR1 => Message::Refresh,
+                    SIGUSR2 => Message::TogglePause,
+                    SIGWINCH => Message::Resize,
+                    _ => Message::Exit,
+                };
+                if signal_sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(EventLoop {
+            duration,
+            next_tick: Instant::now(),
+            paused: false,
+            receiver,
+            sender,
+            notifier: Notifier::from_env(),
+            deadline: None,
+            aligned: false,
+            min_interval: None,
+            last_render: None,
+            pending: VecDeque::new(),
+            refresh_signal: None,
+            forwarded: Vec::new(),
+            low_power: None,
+        })
+    }
+    /// Ticks every `duration` with everything else set as in `options`.
+    pub fn from_options(duration: Duration, options: &LoopOptions) -> io::Result<Self> {
+        let mut events = Self::new(duration)?;
+        events.set_options(options)?;
+        Ok(events)
+    }
+    /// Replaces everything [`EventLoop::from_options`] set but the tick duration, e.g. for a text
+    /// built again on a reload.
+    pub fn set_options(&mut self, options: &LoopOptions) -> io::Result<()> {
+        for handle in self.forwarded.drain(..) {
+            handle.close();
+        }
+        self.refresh_signal = None;
+        self.deadline = options.deadline;
+        self.aligned = options.aligned;
+        self.min_interval = options.min_interval;
+        self.low_power.clone_from(&options.low_power);
+        if let Some(offset) = options.refresh_signal {
+            self.forward_refresh_signal(offset)?;
+        }
+        if options.scroll_signal {
+            self.forward_signal(libc::SIGRTMIN(), || Message::Scroll)?;
+        }
+        Ok(())
+    }
+    fn forward_refresh_signal(&mut self, offset: i32) -> io::Result<()> {
+        if offset < 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("refresh signal SIGRTMIN+{offset}, the offset has to be 1 or more"),
+            ));
+        }
+        self.forward_signal(libc::SIGRTMIN() + offset, || Message::Refresh)?;
+        self.refresh_signal = Some(offset);
+        Ok(())
+    }
+    fn forward_signal(&mut self, signal: i32, message: fn() -> Message) -> io::Result<()> {
+        let mut signals = Signals::new([signal])?;
+        self.forwarded.push(signals.handle());
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                log::debug!("Received signal {signal}");
+                if sender.send(message()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+    pub fn refresh_signal(&self) -> Option<i32> {
+        self.refresh_signal
+    }
+    pub fn sender(&self) -> mpsc::Sender<Message> {
+        self.sender.clone()
+    }
+    pub fn waker(&self) -> Waker {
+        Waker(self.sender())
+    }
+    pub fn run<I: Frames>(
+        mut self,
+        mut iter: I,
+        mut render: impl FnMut(I::Frame<'_>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        while let Some(event) = self.next() {
+            match event {
+                Event::Refresh => iter.force_refresh(),
+                Event::Scroll => iter.scroll_once(),
+                Event::Resize => iter.resize(),
+                #[cfg(feature = "daemon")]
+                Event::Control(cmd, reply) => {
+                    log::debug!("Received command {cmd:?}");
+                    let _ = reply.send(iter.control(cmd, &mut self));
+                    if iter.is_finished() {
+                        break;
+                    }
+                    // commands change what the next frame shows without rendering one
+                    continue;
+                }
+                #[cfg(feature = "i3bar")]
+                Event::Click(button) => iter.click(button, &mut self),
+                Event::Render | Event::Exit => {}
+            }
+            let Some(result) = iter.advance() else {
+                break;
+            };
+            result?;
+            if iter.frame_changed() {
+                render(iter.frame())?;
+            }
+            self.rendered();
+            if matches!(event, Event::Exit) || iter.is_finished() {
+                break;
+            }
+        }
+        Ok(())
+    }
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused && !paused {
+            self.next_tick = Instant::now();
+        }
+        self.paused = paused;
+    }
+    fn following_tick(&self, now: Instant) -> Instant {
+        let duration = match &self.low_power {
+            Some(monitor) if monitor.on_battery() => self.duration.max(Self::LOW_POWER_TICK),
+            _ => self.duration,
+        };
+        if !self.aligned {
+            return (self.next_tick + duration).max(now);
+        }
+        let period = duration.as_nanos().max(1);
+        // waking up a bit before the edge by the wall clock must not tick twice around it
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            + duration / 2;
+        let wait = period - since_epoch.as_nanos() % period;
+        now + duration / 2 + Duration::from_nanos(wait as u64)
+    }
+    fn resync_gap(&self) -> Duration {
+        (self.duration * 4).max(Duration::from_secs(1))
+    }
+    pub fn rendered(&mut self) {
+        self.last_render = Some(Instant::now());
+        if let Some(notifier) = &mut self.notifier {
+            notifier.ready();
+            notifier.pet_watchdog();
+        }
+    }
+    /// Waits until the next frame is allowed, merging refreshes into `event`.
+    fn throttle(&mut self, mut event: Event) -> Event {
+        let (Some(min_interval), Some(last_render)) = (self.min_interval, self.last_render) else {
+            return event;
+        };
+        if matches!(event, Event::Exit) {
+            return event;
+        }
+        let ready_at = last_render + min_interval;
+        while let Some(wait) = ready_at.checked_duration_since(Instant::now()) {
+            match self.receiver.recv_timeout(wait) {
+                Ok(Message::Refresh) if matches!(event, Event::Render | Event::Refresh) => {
+                    event = Event::Refresh
+                }
+                Ok(Message::Exit) => return Event::Exit,
+                Ok(message) => self.pending.push_back(message),
+                Err(_) => break,
+            }
+        }
+        event
+    }
+    fn receive(&mut self) -> Option<Message> {
+        if let Some(message) = self.pending.pop_front() {
+            return Some(message);
+        }
+        let watchdog = self.notifier.as_ref().and_then(Notifier::watchdog_interval);
+        loop {
+            let now = Instant::now();
+            if self.deadline.is_some_and(|d| d <= now) {
+                return Some(Message::Exit);
+            }
+            let tick = (!self.paused).then(|| self.next_tick.saturating_duration_since(now));
+            let deadline = self.deadline.map(|d| d.saturating_duration_since(now));
+            let result = match tick.into_iter().chain(watchdog).chain(deadline).min() {
+                Some(timeout) => self.receiver.recv_timeout(timeout),
+                None => self
+                    .receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match result {
+                Ok(message) => return Some(message),
+                Err(RecvTimeoutError::Timeout)
+                    if !self.paused && Instant::now() >= self.next_tick =>
+                {
+                    return None
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(notifier) = &self.notifier {
+                        notifier.pet_watchdog();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Some(Message::Exit),
+            }
+        }
+    }
+    fn next_unthrottled(&mut self) -> Option<Event> {
+        loop {
+            let Some(message) = self.receive() else {
+                let now = Instant::now();
+                let late = now.saturating_duration_since(self.next_tick);
+                self.next_tick = self.following_tick(now);
+                // the process was stopped (e.g. SIGSTOP from a hidden bar) or the machine slept,
+                // whatever was shown is stale by now
+                if late > self.resync_gap() {
+                    log::debug!("Resuming after {late:?}, refreshing");
+                    return Some(Event::Refresh);
+                }
+                return Some(Event::Render);
+            };
+            match message {
+                Message::Refresh => return Some(Event::Refresh),
+                Message::TogglePause => self.set_paused(!self.paused),
+                Message::Scroll => return Some(Event::Scroll),
+                Message::Resize => return Some(Event::Resize),
+                Message::Exit => return Some(Event::Exit),
+                #[cfg(feature = "daemon")]
+                Message::Control(cmd, reply) => return Some(Event::Control(cmd, reply)),
+                #[cfg(feature = "i3bar")]
+                Message::Click(button) => return Some(Event::Click(button)),
+            }
+        }
+    }
+}
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        if let Some(notifier) = &self.notifier {
+            notifier.stopping();
+        }
+    }
+}
+
+impl Iterator for EventLoop {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.next_unthrottled()?;
+        Some(self.throttle(event))
+    }
+}
@@ -0,0 +1,37 @@
+use std::{
+    process::{Command, Stdio},
+    thread,
+};
+
+#[derive(Debug, Clone)]
+pub struct Hook {
+    command: String,
+}
+
+impl Hook {
+    pub fn new(command: String) -> Self {
+        Hook { command }
+    }
+    /// Runs the command through `sh -c` without waiting for it to finish.
+    pub fn spawn<K: AsRef<str>, V: AsRef<str>>(&self, env: impl IntoIterator<Item = (K, V)>) {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(&self.command).stdin(Stdio::null());
+        for (key, value) in env {
+            cmd.env(format!("MERGNEH_{}", key.as_ref()), value.as_ref());
+        }
+        let command = self.command.clone();
+        match cmd.spawn() {
+            Ok(mut child) => {
+                // reap the child so it does not stay around as a zombie
+                thread::spawn(move || match child.wait() {
+                    Ok(status) if !status.success() => {
+                        log::warn!("Hook '{command}' exited with {status}")
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Failed waiting for hook '{command}': {e}"),
+                });
+            }
+            Err(e) => log::warn!("Failed running hook '{command}': {e}"),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    io::{self, BufRead},
+    str::FromStr,
+    sync::mpsc::Sender,
+    thread,
+    time::Duration,
+};
+
+#[cfg(feature = "mpd")]
+use crate::mpd::PlayerCommand;
+use crate::{
+    events::{EventLoop, Frames, Message},
+    hook::Hook,
+    running_text::{RunningText, TextFrame},
+    sink::{Frame, Sink, StdoutSink},
+    utils::{JsonArray, JsonEscaped},
+};
+
+#[derive(Debug, Clone)]
+pub enum Action {
+    #[cfg(feature = "mpd")]
+    Player(PlayerCommand),
+    Refresh,
+    Pause,
+    Scroll,
+    Command(Hook),
+}
+
+/// `BUTTON:ACTION` given on the command line.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    button: u32,
+    action: Action,
+}
+
+impl FromStr for Binding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (button, action) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected BUTTON:ACTION"))?;
+        let button = button
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid button '{button}': {e}"))?;
+        let action = match action {
+            #[cfg(feature = "mpd")]
+            "toggle" => Action::Player(PlayerCommand::Toggle),
+            #[cfg(feature = "mpd")]
+            "next" => Action::Player(PlayerCommand::Next),
+            #[cfg(feature = "mpd")]
+            "prev" => Action::Player(PlayerCommand::Prev),
+            "refresh" => Action::Refresh,
+            "pause" => Action::Pause,
+            "scroll" => Action::Scroll,
+            "" => anyhow::bail!("empty action for button {button}"),
+            command => Action::Command(Hook::new(command.to_owned())),
+        };
+        Ok(Binding { button, action })
+    }
+}
+
+/// Left click toggles playback, scrolling up and down switches songs.
+#[cfg(feature = "mpd")]
+fn mpd_bindings() -> Vec<Binding> {
+    [
+        (1, PlayerCommand::Toggle),
+        (4, PlayerCommand::Next),
+        (5, PlayerCommand::Prev),
+    ]
+    .into_iter()
+    .map(|(button, cmd)| Binding {
+        button,
+        action: Action::Player(cmd),
+    })
+    .collect()
+}
+
+pub fn run(
+    mut text: RunningText,
+    duration: Duration,
+    name: String,
+    bindings: Vec<Binding>,
+    on_click: Option<Hook>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "mpd")]
+    let bindings = match bindings.is_empty() && text.get_source().name() == "mpd" {
+        true => mpd_bindings(),
+        false => bindings,
+    };
+    let events = EventLoop::from_options(duration, text.loop_options())?;
+    text.watch(events.waker())?;
+    let sender = events.sender();
+    thread::spawn(move || read_clicks(io::stdin().lock(), sender));
+
+    let mut out = I3barOutput::new(&text, &name)?;
+    let block = Block {
+        text,
+        bindings,
+        on_click,
+    };
+    events.run(block, |frame| out.write(frame.into()))
+}
+
+/// The text along with what clicks on its block do.
+struct Block {
+    text: RunningText,
+    bindings: Vec<Binding>,
+    on_click: Option<Hook>,
+}
+
+impl Frames for Block {
+    type Frame<'a> = TextFrame<'a>;
+
+    fn advance(&mut self) -> Option<anyhow::Result<()>> {
+        self.text.advance()
+    }
+    fn frame(&self) -> TextFrame<'_> {
+        self.text.frame()
+    }
+    fn frame_changed(&self) -> bool {
+        self.text.frame_changed()
+    }
+    fn force_refresh(&mut self) {
+        self.text.force_refresh();
+    }
+    fn scroll_once(&mut self) {
+        self.text.scroll_once();
+    }
+    fn is_finished(&self) -> bool {
+        self.text.is_finished()
+    }
+    fn click(&mut self, button: u32, events: &mut EventLoop) {
+        click(
+            &mut self.text,
+            events,
+            button,
+            &self.bindings,
+            self.on_click.as_ref(),
+        );
+        self.text.force_refresh();
+    }
+}
+
+/// Writes the protocol header, then every frame as a status line of one block.
+pub struct I3barOutput {
+    out: StdoutSink,
+    block: String,
+}
+
+impl I3barOutput {
+    pub fn new(text: &RunningText, name: &str) -> io::Result<Self> {
+        // the instance tells blocks of several running copies apart in click events
+        let block = match text.instance() {
+            Some(instance) => format!(
+                "\"name\":\"{}\",\"instance\":\"{}\"",
+                JsonEscaped(name),
+                JsonEscaped(instance)
+            ),
+            None => format!("\"name\":\"{}\"", JsonEscaped(name)),
+        };
+        let mut out = text.stdout();
+        out.write_frame(format_args!("{{\"version\":1,\"click_events\":true}}\n[\n"))?;
+        Ok(Self { out, block })
+    }
+}
+
+impl Sink for I3barOutput {
+    fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        // custom keys start with an underscore in the i3bar protocol
+        Ok(self.out.write_frame(format_args!(
+            "[{{{},\"full_text\":\"{}\",\"_changed\":{},\"_generation\":{}}}],\n",
+            self.block,
+            JsonEscaped(frame.text),
+            JsonArray(frame.changes.names()),
+            frame.generation
+        ))?)
+    }
+    fn set_dedup(&mut self, dedup: bool) {
+        self.out.set_dedup(dedup);
+    }
+}
+
+fn click(
+    text: &mut RunningText,
+    events: &mut EventLoop,
+    button: u32,
+    bindings: &[Binding],
+    on_click: Option<&Hook>,
+) {
+    log::debug!("Button {button} clicked");
+    // the last binding for a button wins, like repeated options usually do
+    let action = bindings
+        .iter()
+        .rev()
+        .find(|b| b.button == button)
+        .map(|b| &b.action);
+    let hook = match action {
+        #[cfg(feature = "mpd")]
+        Some(Action::Player(cmd)) => {
+            if let Err(e) = text.get_source_mut().send(*cmd) {
+                log::warn!("Failed handling button {button}: {e:#}");
+            }
+            return;
+        }
+        // clicks refresh the source anyway
+        Some(Action::Refresh) => return,
+        Some(Action::Pause) => {
+            events.set_paused(!events.is_paused());
+            return;
+        }
+        Some(Action::Scroll) => {
+            text.scroll_once();
+            return;
+        }
+        Some(Action::Command(hook)) => hook,
+        None => match on_click {
+            Some(hook) => hook,
+            None => return,
+        },
+    };
+    let mut env = text.env();
+    env.push(("BUTTON", button.to_string()));
+    hook.spawn(env);
+}
+
+/// Click events come as an endless JSON array, one object per line.
+fn read_clicks(input: impl BufRead, sender: Sender<Message>) {
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("Stopped reading click events: {e}");
+                return;
+            }
+        };
+        let Some(button) = parse_button(&line) else {
+            continue;
+        };
+        if sender.send(Message::Click(button)).is_err() {
+            return;
+        }
+    }
+}
+
+fn parse_button(event: &str) -> Option<u32> {
+    const KEY: &str = "\"button\"";
+    let rest = &event[event.find(KEY)? + KEY.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_button, Action, Binding};
+
+    #[test]
+    fn parse_button_test() {
+        assert_eq!(parse_button("["), None);
+        assert_eq!(
+            parse_button(r#"{"name":"mergneh","button":1,"x":1800,"y":10}"#),
+            Some(1)
+        );
+        assert_eq!(
+            parse_button(r#",{"name": "mergneh", "button": 5}"#),
+            Some(5)
+        );
+        assert_eq!(parse_button(r#"{"button":"left"}"#), None);
+    }
+
+    #[test]
+    fn binding_parse_test() {
+        let binding: Binding = "3:notify-send clicked".parse().unwrap();
+        assert_eq!(binding.button, 3);
+        assert!(matches!(binding.action, Action::Command(_)));
+        assert!(matches!(
+            "2:pause".parse::<Binding>().unwrap().action,
+            Action::Pause
+        ));
+        assert!("left:pause".parse::<Binding>().is_err());
+        assert!("2:".parse::<Binding>().is_err());
+        assert!("pause".parse::<Binding>().is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::fd::AsRawFd,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use crate::utils::runtime_dir;
+
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A named instance, alive for as long as it holds a lock on its pid file. The pid in the file
+/// is only trusted while someone holds the lock, so a stale file left after a crash never gets
+/// an unrelated process that reused the pid signalled.
+#[derive(Debug)]
+pub struct Instance {
+    pid_file: File,
+}
+
+impl Instance {
+    pub fn acquire(name: &str, replace: bool) -> anyhow::Result<Self> {
+        let path = runtime_dir().join(format!("mergneh-{name}.pid"));
+        let mut pid_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if !try_lock(&pid_file)? {
+            let pid = read_pid(&mut pid_file)?;
+            let running = match pid {
+                Some(pid) => format!("pid {pid}"),
+                None => "pid unknown".to_owned(),
+            };
+            if !replace {
+                anyhow::bail!(
+                    "Instance '{name}' is already running ({running}), use --replace to take over"
+                );
+            }
+            let Some(pid) = pid else {
+                anyhow::bail!("Instance '{name}' is running but did not write its pid yet");
+            };
+            log::info!("Replacing instance '{name}' ({running})");
+            if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            // the lock is released when the instance exits
+            let start = Instant::now();
+            while !try_lock(&pid_file)? {
+                if start.elapsed() > TAKEOVER_TIMEOUT {
+                    anyhow::bail!("Instance '{name}' ({running}) did not exit in time");
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+        pid_file.set_len(0)?;
+        pid_file.rewind()?;
+        write!(pid_file, "{}", process::id())?;
+        Ok(Instance { pid_file })
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        // the file stays, a replacing instance may be waiting for its lock already
+        let _ = self.pid_file.set_len(0);
+    }
+}
+
+/// Takes the lock on the pid file unless another instance holds it.
+fn try_lock(file: &File) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(false),
+        e => Err(e),
+    }
+}
+
+fn read_pid(file: &mut File) -> io::Result<Option<libc::pid_t>> {
+    let mut pid = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut pid)?;
+    Ok(pid.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use super::Instance;
+    use crate::utils::runtime_dir;
+
+    #[test]
+    fn stale_pid_file_test() {
+        let name = format!("test-{}", process::id());
+        let path = runtime_dir().join(format!("mergneh-{name}.pid"));
+        // pid 1 is alive but holds no lock, so it is not taken for a running instance
+        fs::write(&path, "1").unwrap();
+        let instance = Instance::acquire(&name, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            process::id().to_string()
+        );
+        assert!(Instance::acquire(&name, false).is_err());
+        drop(instance);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        let _instance = Instance::acquire(&name, false).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,38 @@
+#[cfg(feature = "art")]
+pub mod art;
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+pub mod events;
+pub mod hook;
+#[cfg(feature = "i3bar")]
+pub mod i3bar;
+pub mod instance;
+pub mod lines;
+#[cfg(feature = "lyrics")]
+pub mod lyrics;
+#[cfg(feature = "man")]
+pub mod man;
+#[cfg(feature = "mock-mpd")]
+pub mod mock_mpd;
+#[cfg(feature = "mpd")]
+pub mod mpd;
+#[cfg(feature = "plugin")]
+pub mod plugin;
+pub mod power;
+pub mod record;
+pub mod running_text;
+pub mod sink;
+pub mod systemd;
+pub mod text_source;
+pub mod timer;
+pub mod utils;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "waybar")]
+pub mod waybar;
+
+pub use running_text::RunningText;
+pub use sink::{Frame, Sink};
+pub use text_source::{CmdSource, Content, ContentChange, RunStats, TextSource};
@@ -0,0 +1,268 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+use crate::{
+    text_source::{Content, ContentChange, RunStats, TextSource},
+    utils::Encoding,
+};
+
+/// When [`LinesSource`] moves on to another line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pace {
+    Every(Duration),
+    /// After the line scrolled through once (every tick when it fits).
+    Loop,
+}
+
+impl FromStr for Pace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "loop" => Ok(Pace::Loop),
+            _ => Ok(Pace::Every(
+                humantime::parse_duration(s).context("expected a duration or 'loop'")?,
+            )),
+        }
+    }
+}
+
+/// Shows a random line of a file at a time, a fortune ticker.
+#[derive(Debug)]
+pub struct LinesSource {
+    path: PathBuf,
+    lines: Vec<String>,
+    pace: Pace,
+    /// Lines left to show before any is repeated, drawn from the back.
+    bag: Option<Vec<usize>>,
+    rng: Rng,
+    current: usize,
+    shown_at: Instant,
+    /// Loops of the running text so far and when the line was shown.
+    loops: usize,
+    shown_at_loop: usize,
+    prefix: String,
+    suffix: String,
+}
+
+impl LinesSource {
+    pub fn open(
+        path: &Path,
+        encoding: Encoding,
+        prefix: String,
+        suffix: String,
+    ) -> anyhow::Result<Self> {
+        let text = encoding.decode(fs::read(path)?)?;
+        let lines = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        anyhow::ensure!(!lines.is_empty(), "{} has no lines", path.display());
+        Ok(Self::new(path.to_owned(), lines, prefix, suffix))
+    }
+    fn new(path: PathBuf, lines: Vec<String>, prefix: String, suffix: String) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+            ^ u64::from(std::process::id());
+        let mut source = Self {
+            path,
+            lines,
+            pace: Pace::Loop,
+            bag: None,
+            rng: Rng(seed),
+            current: 0,
+            shown_at: Instant::now(),
+            loops: 0,
+            shown_at_loop: 0,
+            prefix,
+            suffix,
+        };
+        source.current = source.draw();
+        source
+    }
+    pub fn with_pace(mut self, pace: Pace) -> Self {
+        self.pace = pace;
+        self
+    }
+    /// Shows every line once, in random order, before any is repeated.
+    pub fn with_shuffle(mut self, shuffle: bool) -> Self {
+        self.bag = shuffle.then(Vec::new);
+        self.current = self.draw();
+        self
+    }
+    /// Makes the order of the lines the same on every run.
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        if let Some(seed) = seed {
+            self.rng = Rng(seed);
+            self.bag = self.bag.as_ref().map(|_| Vec::new());
+            self.current = self.draw();
+        }
+        self
+    }
+    fn draw(&mut self) -> usize {
+        let len = self.lines.len();
+        let Some(bag) = &mut self.bag else {
+            return self.rng.below(len);
+        };
+        if bag.is_empty() {
+            bag.extend(0..len);
+            // Fisher-Yates
+            for i in (1..len).rev() {
+                bag.swap(i, self.rng.below(i + 1));
+            }
+        }
+        bag.pop().unwrap()
+    }
+}
+
+impl TextSource for LinesSource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        Ok(Content::new(
+            self.lines[self.current].clone(),
+            self.prefix.clone(),
+            self.suffix.clone(),
+        ))
+    }
+    fn poll(
+        &mut self,
+        content: &mut String,
+        _: &mut String,
+        _: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        let next = match self.pace {
+            Pace::Every(interval) => self.shown_at.elapsed() >= interval,
+            Pace::Loop => self.loops > self.shown_at_loop,
+        };
+        if !next {
+            return Ok(ContentChange::empty());
+        }
+        (self.shown_at, self.shown_at_loop) = (Instant::now(), self.loops);
+        self.current = self.draw();
+        if *content == self.lines[self.current] {
+            return Ok(ContentChange::empty());
+        }
+        self.lines[self.current].clone_into(content);
+        Ok(ContentChange::Running)
+    }
+    fn set_stats(&mut self, stats: RunStats) {
+        self.loops = stats.loops;
+    }
+    fn describe(&self) -> String {
+        format!("{} lines from {}", self.lines.len(), self.path.display())
+    }
+    fn name(&self) -> &'static str {
+        "lines"
+    }
+    fn env(&self) -> Vec<(&'static str, String)> {
+        vec![("LINE", (self.current + 1).to_string())]
+    }
+}
+
+/// SplitMix64, good enough to pick lines and cheap to seed.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, path::PathBuf};
+
+    use super::{LinesSource, Pace};
+    use crate::text_source::{ContentChange, RunStats, TextSource};
+
+    fn source(shuffle: bool, seed: u64) -> LinesSource {
+        let lines = ["one", "two", "three", "four", "five"].map(str::to_owned);
+        LinesSource::new(
+            PathBuf::from("quotes"),
+            lines.to_vec(),
+            String::new(),
+            String::new(),
+        )
+        .with_shuffle(shuffle)
+        .with_seed(Some(seed))
+    }
+
+    fn next_loop(source: &mut LinesSource, content: &mut String) -> ContentChange {
+        source.set_stats(RunStats {
+            loops: source.loops + 1,
+            ..Default::default()
+        });
+        let (mut prefix, mut suffix) = Default::default();
+        source.poll(content, &mut prefix, &mut suffix).unwrap()
+    }
+
+    #[test]
+    fn pace_parse_test() {
+        assert_eq!("loop".parse::<Pace>().unwrap(), Pace::Loop);
+        assert_eq!(
+            "5m".parse::<Pace>().unwrap(),
+            Pace::Every(std::time::Duration::from_secs(300))
+        );
+        assert!("often".parse::<Pace>().is_err());
+    }
+
+    #[test]
+    fn seeded_shuffle_test() {
+        let shown = |seed| {
+            let mut source = source(true, seed);
+            let mut content = source.get_initial_content().unwrap().running;
+            let mut shown = vec![content.clone()];
+            for _ in 0..9 {
+                next_loop(&mut source, &mut content);
+                shown.push(content.clone());
+            }
+            shown
+        };
+        let first = shown(7);
+        assert_eq!(first, shown(7));
+        // every line once before any is repeated
+        for round in first.chunks(5) {
+            assert_eq!(round.iter().collect::<BTreeSet<_>>().len(), 5);
+        }
+
+        let mut source = source(true, 7);
+        let mut content = source.get_initial_content().unwrap().running;
+        next_loop(&mut source, &mut content);
+        source.set_stats(RunStats {
+            loops: source.loops,
+            ..Default::default()
+        });
+        let (mut prefix, mut suffix) = Default::default();
+        let changes = source.poll(&mut content, &mut prefix, &mut suffix);
+        assert!(changes.unwrap().is_empty());
+        assert_eq!(content, first[1]);
+    }
+
+    #[test]
+    fn random_lines_test() {
+        let mut source = source(false, 3);
+        let mut content = source.get_initial_content().unwrap().running;
+        let mut seen = BTreeSet::from([content.clone()]);
+        for _ in 0..100 {
+            next_loop(&mut source, &mut content);
+            seen.insert(content.clone());
+        }
+        assert_eq!(seen.len(), 5);
+    }
+}
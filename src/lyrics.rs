@@ -0,0 +1,136 @@
+use std::{fs, io, ops::Range, path::Path, time::Duration};
+
+#[derive(Debug, PartialEq)]
+pub enum Lyrics {
+    Plain(String),
+    Synced {
+        text: String,
+        lines: Vec<(Duration, Range<usize>)>,
+    },
+}
+
+impl Lyrics {
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(s) => Ok(Some(Self::parse(&s))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        let mut offset = 0i64;
+        let mut entries = Vec::new();
+        for line in s.lines() {
+            let mut rest = line.trim();
+            let mut timestamps = Vec::new();
+            while let Some(tag) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                let (tag, after) = tag;
+                if let Some(ms) = tag.strip_prefix("offset:") {
+                    offset = ms.trim().parse().unwrap_or(offset);
+                } else if let Some(ts) = parse_timestamp(tag) {
+                    timestamps.push(ts);
+                }
+                rest = after;
+            }
+            entries.extend(timestamps.into_iter().map(|ts| (ts, rest.trim())));
+        }
+        if entries.is_empty() {
+            return Self::Plain(s.trim_end().to_owned());
+        }
+        // positive offset means that lyrics should appear sooner
+        let shift = |ts: Duration| match offset {
+            0 => ts,
+            o if o > 0 => ts.saturating_sub(Duration::from_millis(o as u64)),
+            o => ts + Duration::from_millis(o.unsigned_abs()),
+        };
+        entries.sort_by_key(|(ts, _)| *ts);
+        let mut text = String::new();
+        let mut lines = Vec::with_capacity(entries.len());
+        for (ts, line) in entries {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            let start = text.len();
+            text.push_str(line);
+            lines.push((shift(ts), start..text.len()));
+        }
+        Self::Synced { text, lines }
+    }
+
+    pub fn lines_at(&self, elapsed: Option<Duration>, count: usize) -> Option<&str> {
+        match self {
+            Self::Plain(text) if count == 0 => Some(text),
+            Self::Plain(text) => Some(match text.match_indices('\n').nth(count - 1) {
+                Some((i, _)) => &text[..i],
+                None => text,
+            }),
+            Self::Synced { text, lines } => {
+                let current = lines
+                    .partition_point(|(ts, _)| *ts <= elapsed.unwrap_or_default())
+                    .checked_sub(1)?;
+                let last = (current + count.max(1)).min(lines.len()) - 1;
+                Some(&text[lines[current].1.start..lines[last].1.end])
+            }
+        }
+    }
+}
+
+fn parse_timestamp(tag: &str) -> Option<Duration> {
+    let (min, sec) = tag.split_once(':')?;
+    let min = min.parse::<u64>().ok()?;
+    let sec = sec
+        .parse::<f64>()
+        .ok()
+        .filter(|s| (0.0..60.0).contains(s))?;
+    Some(Duration::from_secs(min * 60) + Duration::from_secs_f64(sec))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Lyrics;
+
+    const LRC: &str = "[ar:Someone]
+[ti:Something]
+[00:01.00]First line
+[00:05.50][00:20.00]Chorus
+[00:10.25] Second line
+";
+
+    #[test]
+    fn lrc_parse_test() {
+        let lyrics = Lyrics::parse(LRC);
+        let at = |secs: f64, count| lyrics.lines_at(Some(Duration::from_secs_f64(secs)), count);
+        assert_eq!(at(0.5, 1), None);
+        assert_eq!(at(1.0, 1), Some("First line"));
+        assert_eq!(at(4.0, 0), Some("First line"));
+        assert_eq!(at(6.0, 1), Some("Chorus"));
+        assert_eq!(at(6.0, 2), Some("Chorus\nSecond line"));
+        assert_eq!(at(19.0, 5), Some("Second line\nChorus"));
+        assert_eq!(at(300.0, 1), Some("Chorus"));
+    }
+
+    #[test]
+    fn lrc_offset_test() {
+        let lyrics = Lyrics::parse("[offset:+500]\n[00:01.00]Line\n[01:00]Other");
+        assert_eq!(
+            lyrics.lines_at(Some(Duration::from_millis(500)), 1),
+            Some("Line")
+        );
+        assert_eq!(
+            lyrics.lines_at(Some(Duration::from_millis(59_500)), 1),
+            Some("Other")
+        );
+    }
+
+    #[test]
+    fn plain_lyrics_test() {
+        let lyrics = Lyrics::parse("First\nSecond\nThird\n");
+        assert_eq!(lyrics, Lyrics::Plain("First\nSecond\nThird".to_owned()));
+        assert_eq!(lyrics.lines_at(None, 0), Some("First\nSecond\nThird"));
+        assert_eq!(lyrics.lines_at(None, 2), Some("First\nSecond"));
+        assert_eq!(lyrics.lines_at(None, 5), Some("First\nSecond\nThird"));
+    }
+}
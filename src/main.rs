@@ -1,46 +1,209 @@
-mod running_text;
-mod utils;
-mod text_source;
-#[cfg(feature = "mpd")]
-mod mpd;
-#[cfg(feature = "waybar")]
-mod waybar;
-
 use std::{
-    fs,
+    fs::{self, File},
     io,
     path::PathBuf,
     time::Duration, ffi::OsString,
 };
 #[cfg(feature = "mpd")]
-use std::net::SocketAddr;
+use std::{any::TypeId, net::SocketAddr};
 
 use clap::{
     arg, command, crate_description, crate_name, value_parser, ArgAction, ArgGroup, ArgMatches, Command, ValueHint
 };
-use text_source::TextSource;
+use env_logger::Target;
+use log::LevelFilter;
 #[cfg(feature = "waybar")]
-use waybar::Tooltip;
+use mergneh::waybar::{ModuleOptions, Tooltip, TooltipOptions};
+#[cfg(all(feature = "waybar", feature = "mpd"))]
+use mergneh::waybar::JsonField;
+#[cfg(feature = "bench")]
+use mergneh::bench;
+#[cfg(feature = "daemon")]
+use mergneh::daemon;
+#[cfg(feature = "art")]
+use mergneh::art;
+#[cfg(feature = "i3bar")]
+use mergneh::{hook::Hook, i3bar};
+#[cfg(feature = "plugin")]
+use mergneh::plugin;
+
+use mergneh::{instance::Instance, lines, record::Recording, sink, utils, RunningText};
+
+#[cfg(feature = "mpd")]
+use mergneh::mpd::{self, StatusIcons, StatusIconsSet, StateStatusIcons, MpdFormatter, StateFormat};
+
+#[cfg(feature = "bench")]
+#[global_allocator]
+static ALLOCATOR: bench::CountingAllocator = bench::CountingAllocator;
+
+fn init_logger(matches: &mut ArgMatches) -> anyhow::Result<()> {
+    let verbosity = 2 + matches.remove_one::<u8>("verbose").unwrap() as i8 - matches.remove_one::<u8>("quiet").unwrap() as i8;
+    let level = match verbosity {
+        ..=0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(level).parse_default_env();
+    if let Some(path) = matches.remove_one::<PathBuf>("log-file") {
+        logger.target(Target::Pipe(Box::new(File::options().create(true).append(true).open(path)?)));
+    }
+    logger.init();
+    Ok(())
+}
+
+const SOURCE_NAMES: &[&str] = &["string", "file", "stdin", "cmd", "timer", "lines", #[cfg(feature = "plugin")] "plugin", #[cfg(feature = "mpd")] "mpd"];
+
+/// Optional features with the options and subcommands they add, so using one of a feature the
+/// binary was built without is told apart from a typo.
+const FEATURES: &[(&str, bool, &[&str])] = &[
+    ("mpd", cfg!(feature = "mpd"), &[
+        "--mpd", "--format", "-L", "--prefix-format", "-R", "--suffix-format", "--separator-format",
+        "--status-icons", "--repeat-icons", "--consume-icons", "--random-icons", "--single-icons",
+        "--output-icons", "--error-icons", "--up-next", "--up-next-format", "--volume-flash",
+        "--volume-flash-format", "--crossfade", "--crossfade-format", "--idle-format", "--ignore-changes",
+        "--change-detection", "--lenient-format", "-D", "--default-placeholder", "--notify",
+        "--notify-interval", "--mpd-query-interval", "--mpd-outputs-ttl",
+    ]),
+    ("lyrics", cfg!(feature = "lyrics"), &["--music-dir"]),
+    ("art", cfg!(feature = "art"), &["--art", "--art-cache-size"]),
+    ("art-color", cfg!(feature = "art-color"), &[]),
+    ("plugin", cfg!(feature = "plugin"), &["--plugin", "--plugin-config", "--plugin-format"]),
+    ("wasm", cfg!(feature = "wasm"), &["--wasm-filter"]),
+    ("waybar", cfg!(feature = "waybar"), &["waybar"]),
+    ("i3bar", cfg!(feature = "i3bar"), &["i3bar"]),
+    ("daemon", cfg!(feature = "daemon"), &["daemon", "ctl"]),
+    ("bench", cfg!(feature = "bench"), &["bench"]),
+    ("man", cfg!(feature = "man"), &["man"]),
+];
+
+/// Version followed by the features the binary was built with, for `--version`.
+fn long_version() -> String {
+    let features = FEATURES.iter()
+        .map(|(name, enabled, _)| format!("{}{name}", if *enabled { '+' } else { '-' }))
+        .collect::<Vec<_>>();
+    format!("{}\nfeatures: {}", clap::crate_version!(), features.join(" "))
+}
+
+/// The first argument only a feature the binary was built without understands, and the feature.
+fn missing_feature(args: &[OsString]) -> Option<(String, &'static str)> {
+    args.iter().skip(1).find_map(|arg| {
+        let arg = arg.to_string_lossy();
+        let name = arg.split_once('=').map_or(&*arg, |(name, _)| name);
+        FEATURES.iter()
+            .find(|(_, enabled, args)| !enabled && args.contains(&name))
+            .map(|(feature, ..)| (name.to_owned(), *feature))
+    })
+}
+
+/// Formats are parsed along with the command line, before it is known whether `--lenient-format`
+/// was given, so they are parsed leniently and again by [`check_formats`] once it is.
+#[cfg(feature = "mpd")]
+fn lenient<T: 'static, E: 'static>(
+    parse: fn(&str, mpd::ParseOptions) -> Result<T, E>,
+) -> impl Fn(&str) -> Result<T, E> + Clone + Send + Sync + 'static {
+    move |s| parse(s, mpd::ParseOptions { lenient: true })
+}
 
-use crate::running_text::RunningText;
+/// Fails on the unknown placeholders of the formats given unless `--lenient-format` was.
+#[cfg(feature = "mpd")]
+fn check_formats(cli: &mut Command, matches: &ArgMatches) -> Result<(), clap::Error> {
+    let options = mpd::ParseOptions { lenient: matches.get_flag("lenient-format") };
+    match format_error(cli, matches, options) {
+        Some(message) => Err(cli.error(clap::error::ErrorKind::ValueValidation, message)),
+        None => Ok(()),
+    }
+}
 
 #[cfg(feature = "mpd")]
-use crate::mpd::{StatusIcons, StateStatusIcons, MpdFormatter};
-
-fn text_from_matches(matches: &mut ArgMatches) -> anyhow::Result<RunningText> {
-    RunningText::new(
-        TextSource::try_from(&mut *matches)?,
-        matches.remove_one::<u64>("window").unwrap() as usize,
-        matches.remove_one("separator").unwrap(),
-        matches.remove_one("newline").unwrap(),
-        matches.remove_one("dont-repeat").unwrap(),
-        matches.remove_one("reset-on-change").unwrap(),
-    )
+fn format_error(cmd: &Command, matches: &ArgMatches, options: mpd::ParseOptions) -> Option<String> {
+    for arg in cmd.get_arguments() {
+        let parsed = arg.get_value_parser().type_id();
+        let parse: fn(&str, mpd::ParseOptions) -> anyhow::Result<()> = if parsed == TypeId::of::<MpdFormatter>() {
+            |s, options| Ok(MpdFormatter::parse_with(s, options).map(drop)?)
+        } else if parsed == TypeId::of::<StateFormat>() {
+            |s, options| Ok(StateFormat::parse_with(s, options).map(drop)?)
+        } else {
+            #[cfg(feature = "waybar")]
+            if parsed == TypeId::of::<JsonField>() {
+                |s, options| JsonField::parse_with(s, options).map(drop)
+            } else {
+                continue;
+            }
+            #[cfg(not(feature = "waybar"))]
+            continue;
+        };
+        let Ok(Some(values)) = matches.try_get_raw(arg.get_id().as_str()) else { continue };
+        for value in values {
+            let value = value.to_string_lossy();
+            if let Err(e) = parse(&value, options) {
+                return Some(format!("invalid value '{value}' for '{arg}': {e}"));
+            }
+        }
+    }
+    let (name, sub_matches) = matches.subcommand()?;
+    format_error(cmd.find_subcommand(name)?, sub_matches, options)
+}
+
+fn print_placeholders(source: Option<&str>, matches: &ArgMatches) -> anyhow::Result<()> {
+    // only the sources with placeholders add rows
+    #[cfg_attr(not(feature = "mpd"), allow(unused_mut))]
+    let mut rows = vec![("PLACEHOLDER".to_owned(), "SPEC", "SAMPLE".to_owned())];
+    #[cfg(feature = "mpd")]
+    if source.is_none_or(|s| s == "mpd") {
+        let icons = StatusIconsSet::new(
+            matches.get_one::<StateStatusIcons>("status-icons").unwrap().clone(),
+            matches.get_one::<StatusIcons>("consume-icons").unwrap().clone(),
+            matches.get_one::<StatusIcons>("random-icons").unwrap().clone(),
+            matches.get_one::<StatusIcons>("repeat-icons").unwrap().clone(),
+            matches.get_one::<StatusIcons>("single-icons").unwrap().clone(),
+            matches.get_one::<StatusIcons>("output-icons").unwrap().clone(),
+            matches.get_one::<StatusIcons>("error-icons").unwrap().clone(),
+        );
+        let default = matches.get_one::<String>("default-placeholder").unwrap();
+        for (ph, sample) in mpd::placeholder_samples(&icons, default)? {
+            let sample = if sample.is_empty() { "(empty)".to_owned() } else { sample };
+            rows.push((format!("{{{}}}", ph.name()), ph.spec().unwrap_or_default(), sample));
+        }
+    }
+    #[cfg(not(feature = "mpd"))]
+    let _ = matches;
+    if rows.len() == 1 {
+        println!("The {} source has no placeholders.", source.unwrap_or("selected"));
+    } else {
+        let name_width = rows.iter().map(|(name, ..)| name.chars().count()).max().unwrap_or_default();
+        let spec_width = rows.iter().map(|(_, spec, _)| spec.chars().count()).max().unwrap_or_default();
+        for (name, spec, sample) in rows {
+            println!("{name:name_width$}  {spec:spec_width$}  {sample}");
+        }
+    }
+    println!("Any source: {{source}} and {{error}} in --error-format are replaced with the source kind and the error.");
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
+    match run() {
+        // the bar went away, there is nobody left to show an error to
+        Err(e) if sink::is_broken_pipe(&e) => {
+            log::debug!("Output closed: {e:#}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn cli() -> Command {
+    // the optional features add the rest
+    #[cfg_attr(
+        not(any(feature = "mpd", feature = "plugin", feature = "wasm", feature = "waybar", feature = "i3bar", feature = "daemon", feature = "bench", feature = "man")),
+        allow(unused_mut)
+    )]
     let mut cli = command!(crate_name!())
         .about(crate_description!())
+        .long_version(long_version())
         .arg(arg!(-w --window <WINDOW> "Window size").value_parser(value_parser!(u64).range(1..)).default_value("32"))
         .arg(arg!(-s --separator <SEP> "String to print between content").default_value(""))
         .arg(arg!(-n --newline <NL> "String to replace newlines with").default_value(""))
@@ -48,27 +211,114 @@ fn main() -> anyhow::Result<()> {
         .arg(arg!(-r --suffix <SUFFIX> "String to print after running text").default_value(""))
         .arg(arg!(-'1' --"dont-repeat" "Do not repeat contents if it fits in the window size").action(ArgAction::SetFalse))
         .arg(arg!(--"reset-on-change" "Reset text window on content change"))
+        .arg(arg!(--"hide-empty" "Do not print prefix and suffix either when the contents are empty"))
+        .arg(arg!(--"max-length" <LENGTH> "Truncate contents to this many characters (grapheme clusters) before scrolling, guards against a runaway command")
+             .value_parser(value_parser!(u64).range(1..)))
+        .arg(arg!(--ellipsis <ELLIPSIS> "String marking where contents were truncated")
+             .default_value("…"))
+        .arg(arg!(--"truncate-middle" "Do not scroll, cut contents longer than the window in the middle instead"))
+        .arg(arg!(--"lead-mark" <MARK> "Mark laid over the start of the window once the contents scrolled past it (e.g. '…')"))
+        .arg(arg!(--"trail-mark" <MARK> "Mark laid over the end of the window while the contents go on beyond it (e.g. '…')"))
+        .arg(arg!(--"scroll-affixes" "Scroll the prefix and the suffix along with the contents instead of keeping them in place around the window"))
+        .arg(arg!(--"on-demand" "Keep the text still, scroll through it once on SIGRTMIN, a 'scroll' command or a --scroll-trigger modification"))
+        .arg(arg!(--"scroll-trigger" <FILE> "Scroll once whenever this file is modified (e.g. touched from a click handler)")
+             .value_parser(value_parser!(PathBuf))
+             .value_hint(ValueHint::FilePath)
+             .requires("on-demand"))
+        .arg(arg!(--"no-dedup" "Output every rendered frame, even if it is the same as the previous one"))
+        .arg(arg!(--terminator <TERMINATOR> "What ends frames printed by --once, iter and daemon: newline, cr (overwrite in place), nul or none")
+             .value_parser(value_parser!(sink::Terminator))
+             .default_value("newline"))
+        .arg(arg!(--"no-bidi" "Do not reorder right-to-left text (Arabic, Hebrew) for display"))
+        .arg(arg!(--nfc "Compose accented characters (Unicode NFC) in contents, so decomposed tags do not break apart at the window edges"))
+        .arg(arg!(-v --verbose ... "Log more, can be repeated (RUST_LOG overrides this)")
+             .action(ArgAction::Count))
+        .arg(arg!(-q --quiet ... "Log less, can be repeated")
+             .action(ArgAction::Count))
+        .arg(arg!(--"log-file" <FILE> "Append logs to a file instead of stderr")
+             .value_parser(value_parser!(PathBuf))
+             .value_hint(ValueHint::FilePath))
+        .arg(arg!(--"refresh-interval" <DURATION> "How often to poll the source [default: every tick]")
+             .value_parser(value_parser!(humantime::Duration)))
+        .arg(arg!(--"refresh-signal" <N> "Also refresh on SIGRTMIN+N (1 or more), e.g. the number given to waybar's \"signal\" option")
+             .value_parser(value_parser!(i32).range(1..=i64::from(libc::SIGRTMAX() - libc::SIGRTMIN()))))
+        .arg(arg!(--"low-power" "While on battery keep the text still and tick at most every 5s, resuming on AC (checked every 30s)"))
+        .arg(arg!(--"align-ticks" "Render on wall-clock multiples of the tick duration (e.g. exactly when the second changes) instead of counting from the start"))
+        .arg(arg!(--"max-fps" <N> "Render at most this many frames a second however often the source changes, 0 for no limit")
+             .value_parser(value_parser!(u32))
+             .default_value("60"))
+        .arg(arg!(--"error-format" <FORMAT> "Text to show (and scroll) while the source fails, {source} is replaced with the source kind, {error} with the error")
+             .default_value("error: {error}"))
+        .arg(arg!(--once "Render a single frame and exit, with a non-zero exit code if the source fails"))
+        .arg(arg!(--"run-for" <DURATION> "Exit after running for this long")
+             .value_parser(value_parser!(humantime::Duration)))
+        .arg(arg!(--"max-loops" <N> "Exit after scrolling through the text this many times")
+             .value_parser(value_parser!(u64).range(1..)))
+        .arg(arg!(--"on-change" <CMD> "Run a shell command whenever the contents change, values are passed in MERGNEH_* environment variables"))
+        .arg(arg!(--instance <NAME> "Name of this instance, only one instance with a given name can run at a time"))
+        .arg(arg!(--replace "Make a running instance with the same name exit and take its place")
+             .requires("instance"))
+        .arg(arg!(--tee <FILE> "Also write plain text frames to a file or a named pipe (can be repeated)")
+             .value_parser(value_parser!(PathBuf))
+             .value_hint(ValueHint::FilePath)
+             .action(ArgAction::Append))
+        .arg(arg!(--"tee-interval" <DURATION> "Write to each --tee file at most once per this interval, the latest frame held back is written when it is over")
+             .value_parser(value_parser!(humantime::Duration))
+             .requires("tee"))
+        .arg(arg!(--record <FILE> "Write the source's contents and the rendered frames to a file with timestamps, for 'mg replay'")
+             .value_parser(value_parser!(PathBuf))
+             .value_hint(ValueHint::FilePath))
         .next_help_heading("Sources")
         .arg(arg!(<SOURCE> "    Same as --file, if file with this name does not exist or is a directory, it will behave as --string"))
         .arg(arg!(-f --file <FILE> "Pull contents from a file (BEWARE: it loads whole file into memory!)"))
         .arg(arg!(-S --string <STRING> "Use a string as contents"))
         .arg(arg!(--stdin "Pull contents from stdin (BEWARE: it loads whole input into memory just like --file)"))
+        .arg(arg!(--follow "Keep reading stdin and show its latest line instead of the whole input").requires("stdin"))
         .arg(arg!(--cmd <ARGS> ... "Execute a command and use its output as contents (use a ';' as a terminator)")
              .value_parser(value_parser!(OsString))
              .value_hint(ValueHint::CommandName)
              .num_args(1..)
              .value_terminator(";"))
+        .arg(arg!(--"cmd-shell" "Run --cmd through 'sh -c' (arguments are joined with spaces) instead of executing it directly")
+             .requires("cmd"))
+        .arg(arg!(--"cmd-timeout" <DURATION> "Kill a command (--cmd or --tooltip-cmd) running for longer than this and keep its previous output")
+             .value_parser(value_parser!(humantime::Duration)))
+        .arg(arg!(--color <WHEN> "Keep escape sequences (colors) from sources: auto (only in a terminal and without NO_COLOR), always or never")
+             .value_parser(value_parser!(utils::ColorMode))
+             .default_value("auto"))
+        .arg(arg!(--encoding <ENCODING> "Encoding of commands' output, files and stdin: auto (UTF-8, UTF-16 with a BOM or Latin-1), utf-8, utf-8-lossy or latin1")
+             .value_parser(value_parser!(utils::Encoding))
+             .default_value("auto"))
+        .arg(arg!(--"cmd-max-backoff" <DURATION> "Longest wait before re-running a failing --cmd or --plugin, the wait doubles on every failure")
+             .value_parser(value_parser!(humantime::Duration))
+             .default_value("1m"))
+        .arg(arg!(--timer [DURATION] "Show a stopwatch, or a countdown from DURATION, controlled with 'mg ctl timer' under daemon")
+             .value_parser(value_parser!(humantime::Duration)))
+        .arg(arg!(--lines <FILE> "Show a random line of the file at a time, e.g. quotes")
+             .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--"lines-every" <EVERY> "When --lines shows another line: a duration, or 'loop' once the line scrolled through")
+             .value_parser(value_parser!(lines::Pace))
+             .default_value("loop"))
+        .arg(arg!(--shuffle "Show every line of --lines once before repeating any").requires("lines"))
+        .arg(arg!(--seed <SEED> "Seed for the order of --lines, the same seed gives the same order")
+             .value_parser(value_parser!(u64))
+             .requires("lines"))
         .group(
             ArgGroup::new("sources")
             .required(true)
-            .args(["SOURCE", "file", "string", "stdin", "cmd"]),
+            .args(["SOURCE", "file", "string", "stdin", "cmd", "timer", "lines"]),
             )
         .subcommand_required(true)
+        .subcommand_negates_reqs(true)
         .subcommand(
             Command::new("run")
                 .arg(arg!(-d --duration <DURATION> "Tick duration")
+                     .visible_alias("scroll-interval")
                      .value_parser(value_parser!(humantime::Duration))
                      .default_value("1s"))
+                .arg(arg!(--"auto-window" "Size the window to fill the terminal line after the prefix and suffix, instead of --window"))
+                .arg(arg!(--ansi "Hide the cursor and redraw the line in place, the terminal is restored on exit"))
+                .arg(arg!(--"edge-fade" "Dim the outermost characters of a scrolling window"))
                 .about("Run text in a terminal")
         )
         .subcommand(
@@ -78,28 +328,154 @@ fn main() -> anyhow::Result<()> {
                      .value_hint(ValueHint::FilePath))
                 .about("Print just one iteration")
                 .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("replay")
+                .arg(arg!(<FILE> "Recording written with --record")
+                     .value_parser(value_parser!(PathBuf))
+                     .value_hint(ValueHint::FilePath))
+                .about("Render a recording frame by frame with the options it was made with, and check the frames against the recorded ones")
+        )
+        .subcommand(
+            Command::new("placeholders")
+                .arg(arg!(--source <SOURCE> "Only list placeholders of this source")
+                     .value_parser(clap::builder::PossibleValuesParser::new(SOURCE_NAMES)))
+                .about("List format placeholders with sample values")
         );
     #[cfg(feature = "waybar")] {
+        #[cfg_attr(not(feature = "mpd"), allow(unused_mut))]
         let mut cmd = Command::new("waybar")
             .arg(arg!(-d --duration <DURATION> "Tick duration")
+                 .visible_alias("scroll-interval")
                  .value_parser(value_parser!(humantime::Duration))
                  .default_value("1s"))
             .arg(arg!([TOOLTIP] "Tooltip to show on hover"))
             .arg(arg!(--"tooltip-cmd" <ARGS> ... "Use output of a command for tooltip")
                  .value_parser(value_parser!(OsString))
                  .num_args(1..))
+            .arg(arg!(--"tooltip-cmd-shell" "Run --tooltip-cmd through 'sh -c' (arguments are joined with spaces)")
+                 .requires("tooltip-cmd"))
+            .arg(arg!(--"tooltip-file" <FILE> "Use contents of a file for tooltip (re-read on every tick)")
+                 .value_parser(value_parser!(PathBuf))
+                 .value_hint(ValueHint::FilePath))
+            .arg(arg!(--"escape-markup" "Escape '&', '<' and '>' of the contents in the text, so they show up as they are with Pango markup; the prefix and suffix may still use markup"))
+            .arg(arg!(--"strip-control" "Remove control characters from the contents instead of passing them on escaped"))
+            .arg(arg!(--"tooltip-strip-newlines" "Remove newlines from tooltip instead of showing a multiline tooltip"))
+            .arg(arg!(--"tooltip-max-length" <LENGTH> "Truncate tooltip to this many characters (grapheme clusters)")
+                 .value_parser(value_parser!(u64).range(1..)))
+            .arg(arg!(--"tooltip-ellipsis" <ELLIPSIS> "String to end a truncated tooltip with")
+                 .default_value("…"))
             .group(ArgGroup::new("tooltips")
                    .multiple(false)
-                   .args(["TOOLTIP", "tooltip-cmd"]))
+                   .args(["TOOLTIP", "tooltip-cmd", "tooltip-file"]))
             .about("Run text with custom module in waybar (JSON output)");
         #[cfg(feature = "mpd")] {
             cmd = cmd.arg(arg!(-t --"tooltip-format" [FORMAT] "Tooltip format with MPD placeholder support [default: {artist} - {title}]")
-                          .value_parser(value_parser!(MpdFormatter))
+                          .value_parser(lenient(MpdFormatter::parse_with))
                           .default_missing_value("{artist} - {title}")
-                          .group("tooltips"));
+                          .group("tooltips"))
+                .arg(arg!(--"tooltip-queue" [COUNT] "Show next songs in the queue as tooltip [default: 5]")
+                     .value_parser(value_parser!(u64).range(1..))
+                     .default_missing_value("5")
+                     .group("tooltips"))
+                .arg(arg!(--"queue-format" <FORMAT> "Format of every song line in a queue tooltip")
+                     .value_parser(lenient(MpdFormatter::parse_with))
+                     .default_value("{artist} - {title}"))
+                .arg(arg!(--"tooltip-history" [COUNT] "Show recently played songs with the time they started as tooltip [default: 5]")
+                     .value_parser(value_parser!(u64).range(1..))
+                     .default_missing_value("5")
+                     .group("tooltips"))
+                .arg(arg!(--"history-format" <FORMAT> "Format of every song line in a history tooltip")
+                     .value_parser(lenient(MpdFormatter::parse_with))
+                     .default_value("{artist} - {title}"))
+                .arg(arg!(--"class-format" <FORMAT> "Module CSS classes with MPD placeholder support, separated by whitespace")
+                     .value_parser(lenient(MpdFormatter::parse_with)))
+                .arg(arg!(--field <KEY_FORMAT> "KEY=FORMAT adds KEY to the JSON output rendered with MPD placeholder support (can be repeated)")
+                     .value_parser(lenient(JsonField::parse_with))
+                     .action(ArgAction::Append));
         }
         cli = cli.subcommand(cmd);
     }
+    #[cfg(feature = "daemon")] {
+        cli = cli
+            .subcommand(
+                Command::new("daemon")
+                    .arg(arg!(-d --duration <DURATION> "Tick duration")
+                         .visible_alias("scroll-interval")
+                         .value_parser(value_parser!(humantime::Duration))
+                         .default_value("1s"))
+                    .arg(arg!(--socket <PATH> "Control socket to listen on [default: $XDG_RUNTIME_DIR/mergneh.sock]")
+                         .value_parser(value_parser!(PathBuf))
+                         .value_hint(ValueHint::FilePath))
+                    .about("Run text and listen for commands on a control socket")
+            )
+            .subcommand(
+                Command::new("ctl")
+                    .arg(arg!(--socket <PATH> "Control socket of a running daemon [default: $XDG_RUNTIME_DIR/mergneh.sock]")
+                         .value_parser(value_parser!(PathBuf))
+                         .value_hint(ValueHint::FilePath))
+                    .arg(arg!(<COMMAND> ... "Command to send: text <TEXT>, file <PATH>, cmd <ARGS>..., pause, resume, scroll, reload, status, metrics, quit or timer start|stop|reset|set <DURATION>")
+                         .num_args(1..)
+                         .trailing_var_arg(true)
+                         .allow_hyphen_values(true))
+                    .about("Send a command to a running daemon")
+                    .arg_required_else_help(true)
+            );
+    }
+    #[cfg(feature = "i3bar")] {
+        cli = cli.subcommand(
+            Command::new("i3bar")
+                .arg(arg!(-d --duration <DURATION> "Tick duration")
+                     .visible_alias("scroll-interval")
+                     .value_parser(value_parser!(humantime::Duration))
+                     .default_value("1s"))
+                .arg(arg!(--name <NAME> "Block name")
+                     .default_value("mergneh"))
+                .arg(arg!(-b --button <BINDING> "Action for a mouse button as BUTTON:ACTION, ACTION is toggle, next, prev (MPD), refresh, pause, scroll or a shell command [default with --mpd: 1:toggle 4:next 5:prev]")
+                     .value_parser(value_parser!(i3bar::Binding))
+                     .action(ArgAction::Append))
+                .arg(arg!(--"on-click" <CMD> "Shell command to run for buttons without an action (the button is in $MERGNEH_BUTTON)"))
+                .about("Run text as an i3bar/swaybar status command with click events")
+        );
+    }
+    #[cfg(feature = "bench")] {
+        cli = cli.subcommand(
+            Command::new("bench")
+                .arg(arg!(-n --frames <N> "Number of frames to render")
+                     .value_parser(value_parser!(usize))
+                     .default_value("100000"))
+                .arg(arg!(--length <CHARS> "Length of the synthetic contents")
+                     .value_parser(value_parser!(usize))
+                     .default_value("200"))
+                .about("Render synthetic contents and report time and allocations per frame")
+        );
+    }
+    #[cfg(feature = "man")] {
+        cli = cli.subcommand(Command::new("man").about("Print a man page"));
+    }
+    #[cfg(feature = "plugin")] {
+        cli = cli
+        .arg(arg!(--plugin <ARGS> ... "Run a plugin speaking JSON lines and use the text it reports as contents (use a ';' as a terminator)")
+             .group("sources")
+             .value_parser(value_parser!(OsString))
+             .value_hint(ValueHint::CommandName)
+             .num_args(1..)
+             .value_terminator(";"))
+        .arg(arg!(--"plugin-config" <KEY_VALUE> "KEY=VALUE sent to the plugin on startup (can be repeated)")
+             .value_parser(value_parser!(plugin::ConfigEntry))
+             .action(ArgAction::Append)
+             .requires("plugin"))
+        .arg(arg!(--"plugin-format" <FORMAT> "Render the fields reported by the plugin instead of its text, e.g. '{city}: {temp}'")
+             .value_parser(value_parser!(plugin::PluginFormat))
+             .requires("plugin"));
+    }
+    #[cfg(feature = "wasm")] {
+        cli = cli.arg(arg!(--"wasm-filter" <FILE> "Transform contents with a WASM module before they are laid out (can be repeated, filters run in order)")
+                      .help_heading(None::<&str>)
+                      .value_parser(value_parser!(PathBuf))
+                      .value_hint(ValueHint::FilePath)
+                      .action(ArgAction::Append));
+    }
     #[cfg(feature = "mpd")] {
         cli = cli
         .arg(
@@ -138,38 +514,221 @@ fn main() -> anyhow::Result<()> {
             .default_value("")
             .requires("mpd")
         ) 
+        .arg(
+            arg!(--"output-icons" <ICONS> "Output icons to use")
+            .value_parser(value_parser!(StatusIcons))
+            .default_value("")
+            .requires("mpd")
+        )
+        .arg(
+            arg!(--"error-icons" <ICONS> "Icons showing whether MPD reports an error, for {errorIcon}")
+            .value_parser(value_parser!(StatusIcons))
+            .default_value("")
+            .requires("mpd")
+        )
         .arg(
             arg!(--format <FORMAT> "Format string to use in running text")
-                .value_parser(value_parser!(MpdFormatter))
+                .value_parser(lenient(MpdFormatter::parse_with))
                 .default_value("{artist} - {title}")
                 .requires("mpd")
         )
         .arg(
-            arg!(-L --"prefix-format" <FORMAT> "Format string to use in prefix")
-                .value_parser(value_parser!(MpdFormatter))
+            arg!(-L --"prefix-format" <FORMAT> "Format string to use in prefix, STATE=FORMAT (play, pause or stop) applies in that state only, can be repeated")
+                .value_parser(lenient(StateFormat::parse_with))
+                .action(ArgAction::Append)
                 .conflicts_with("prefix")
                 .requires("mpd")
         )
         .arg(
-            arg!(-R --"suffix-format" <FORMAT> "Format string to use in suffix")
-                .value_parser(value_parser!(MpdFormatter))
+            arg!(-R --"suffix-format" <FORMAT> "Format string to use in suffix, STATE=FORMAT (play, pause or stop) applies in that state only, can be repeated")
+                .value_parser(lenient(StateFormat::parse_with))
+                .action(ArgAction::Append)
                 .conflicts_with("suffix")
                 .requires("mpd")
         )
+        .arg(
+            arg!(--"separator-format" <FORMAT> "Separator with MPD placeholder support, STATE=FORMAT (play, pause or stop) applies in that state only, can be repeated")
+                .value_parser(lenient(StateFormat::parse_with))
+                .action(ArgAction::Append)
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"up-next" <DURATION> "Show --up-next-format in place of the suffix during the last DURATION of a song")
+                .value_parser(value_parser!(humantime::Duration))
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"up-next-format" <FORMAT> "Suffix format used near the end of a song with --up-next")
+                .value_parser(lenient(MpdFormatter::parse_with))
+                .default_value("next: {nextArtist} - {nextTitle}")
+                .requires("up-next")
+        )
+        .arg(
+            arg!(--"volume-flash" <DURATION> "Show --volume-flash-format in place of the text for DURATION after the volume changes")
+                .value_parser(value_parser!(humantime::Duration))
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"volume-flash-format" <FORMAT> "Format shown when the volume changes with --volume-flash")
+                .value_parser(lenient(MpdFormatter::parse_with))
+                .default_value("{volumeBar} {volume}%")
+                .requires("volume-flash")
+        )
+        .arg(
+            arg!(--crossfade "Show --crossfade-format in place of the text while MPD crossfades into the next song")
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"crossfade-format" <FORMAT> "Format shown during a crossfade with --crossfade")
+                .value_parser(lenient(MpdFormatter::parse_with))
+                .default_value("{artist} - {title} ⇢ {nextArtist} - {nextTitle}")
+                .requires("crossfade")
+        )
+        .arg(
+            arg!(--"idle-format" <FORMAT> "Format shown in place of the text while the queue is empty, e.g. \"queue empty, add some music\"")
+                .value_parser(lenient(MpdFormatter::parse_with))
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"ignore-changes" <PLACEHOLDERS> "Placeholders (comma separated names, e.g. elapsedTime) whose changes alone don't update the text, it is brought up to date with the next other change")
+                .value_parser(clap::builder::PossibleValuesParser::new(mpd::Placeholder::all().iter().map(mpd::Placeholder::name).chain(["if"])))
+                .value_delimiter(',')
+                .hide_possible_values(true)
+                .action(ArgAction::Append)
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"change-detection" <MODE> "What counts as a change for --reset-on-change and --on-change: any change of the text (content), or only another song (song), other updates are shown in place")
+                .value_parser(["content", "song"])
+                .default_value("content")
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"lenient-format" "Show unknown placeholders as they are (with a warning) instead of failing, for configs shared with newer versions")
+                .requires("mpd")
+        )
         .arg(
             arg!(-D --"default-placeholder" <PLACEHOLDER> "Default placeholder for missing values")
                 .default_value("N/A")
                 .requires("mpd")
+        )
+        .arg(
+            arg!(--notify "Post a desktop notification (with notify-send) showing the title, artist and cover when the song changes")
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"notify-interval" <DURATION> "Shortest time between two notifications, songs skipped quicker are merged into one notification")
+                .value_parser(value_parser!(humantime::Duration))
+                .default_value("5s")
+                .requires("notify")
+        )
+        .arg(
+            arg!(--"mpd-query-interval" <DURATION> "Ask MPD at most this often unless it reports a change, the elapsed time is counted in between")
+                .value_parser(value_parser!(humantime::Duration))
+                .requires("mpd")
+        )
+        .arg(
+            arg!(--"mpd-outputs-ttl" <DURATION> "Reuse the outputs for {outputs} for this long unless MPD reports a change of them, instead of asking on every query")
+                .value_parser(value_parser!(humantime::Duration))
+                .requires("mpd")
         );
     }
-    let mut matches = cli.get_matches();
-    let mut text = text_from_matches(&mut matches)?;
+    #[cfg(feature = "lyrics")] {
+        cli = cli.arg(
+            arg!(--"music-dir" <DIR> "MPD music directory to look for .lrc lyrics files in")
+                .value_parser(value_parser!(PathBuf))
+                .value_hint(ValueHint::DirPath)
+                .requires("mpd")
+        );
+    }
+    #[cfg(feature = "art")] {
+        cli = cli.arg(
+            arg!(--"art-cache-size" <MIB> "Size limit of the album art cache in MiB, least recently used images are evicted")
+                .value_parser(value_parser!(u64))
+                .default_value("64")
+                .requires("mpd")
+        )
+        .mut_subcommand("run", |run| run
+            .arg(arg!(--art <PROTOCOL> "Show the album art before the text using the kitty or iterm (iTerm2, WezTerm) graphics protocol")
+                 .value_parser(value_parser!(art::ImageProtocol))));
+    }
+    cli
+}
+
+fn run() -> anyhow::Result<()> {
+    let mut cli = cli();
+    let args = std::env::args_os().collect::<Vec<_>>();
+    let mut matches = match cli.try_get_matches_from_mut(&args) {
+        Ok(matches) => matches,
+        Err(e) => match missing_feature(&args).filter(|_| e.use_stderr()) {
+            Some((arg, feature)) => cli.error(
+                clap::error::ErrorKind::UnknownArgument,
+                format!("'{arg}' needs the {feature} feature, this mg was built without it (see 'mg --version')"),
+            ).exit(),
+            None => e.exit(),
+        },
+    };
+    #[cfg(feature = "mpd")]
+    check_formats(&mut cli, &matches).unwrap_or_else(|e| e.exit());
+    init_logger(&mut matches)?;
+    #[cfg(feature = "man")] {
+        if let Some(("man", _)) = matches.subcommand() {
+            return Ok(mergneh::man::render(cli, &mut io::stdout())?);
+        }
+    }
+    #[cfg(feature = "daemon")] {
+        if let Some(("ctl", sub_matches)) = matches.subcommand() {
+            let socket = sub_matches.get_one::<PathBuf>("socket").cloned().unwrap_or_else(daemon::default_socket_path);
+            let command = sub_matches.get_many::<String>("COMMAND").unwrap().cloned().collect::<Vec<_>>();
+            return daemon::send_command(&socket, &command);
+        }
+    }
+    #[cfg(feature = "bench")] {
+        if let Some(("bench", sub_matches)) = matches.subcommand() {
+            let frames = *sub_matches.get_one::<usize>("frames").unwrap();
+            let length = *sub_matches.get_one::<usize>("length").unwrap();
+            return bench::run(&mut matches, frames, length);
+        }
+    }
+    if let Some(("replay", sub_matches)) = matches.subcommand() {
+        let recording = Recording::load(sub_matches.get_one::<PathBuf>("FILE").unwrap())?;
+        let args = recording.args().map(str::to_owned).collect::<Vec<_>>();
+        let mut matches = cli.try_get_matches_from_mut(args)?;
+        #[cfg(feature = "mpd")]
+        check_formats(&mut cli, &matches)?;
+        return recording.replay(&mut matches);
+    }
+    if let Some(("placeholders", sub_matches)) = matches.subcommand() {
+        return print_placeholders(sub_matches.get_one::<String>("source").map(String::as_str), &matches);
+    }
+    if !matches.contains_id("sources") {
+        cli.error(clap::error::ErrorKind::MissingRequiredArgument, "a source is required").exit();
+    }
+    let _instance = match matches.get_one::<String>("instance").cloned() {
+        Some(name) => Some(Instance::acquire(&name, matches.remove_one("replace").unwrap())?),
+        None => None,
+    };
+    #[cfg(feature = "daemon")]
+    let original_matches = matches.clone();
+    #[cfg(feature = "waybar")]
+    let cmd_timeout = matches.get_one::<humantime::Duration>("cmd-timeout").map(|&d| d.into());
+    #[cfg(feature = "waybar")]
+    let encoding = *matches.get_one::<utils::Encoding>("encoding").unwrap();
+    let mut text = RunningText::from_matches(&mut matches)?;
     let (cmd, mut sub_matches) = matches.remove_subcommand().unwrap();
     match cmd.as_str() {
         "run" => {
             let duration: Duration = sub_matches
                 .remove_one::<humantime::Duration>("duration")
                 .unwrap().into();
+            text = text
+                .with_auto_window(sub_matches.remove_one("auto-window").unwrap())
+                .with_ansi(sub_matches.remove_one("ansi").unwrap())
+                .with_edge_fade(sub_matches.remove_one("edge-fade").unwrap());
+            #[cfg(feature = "art")]
+            if let Some(protocol) = sub_matches.remove_one("art") {
+                return text.run_on_terminal_with_art(duration, protocol);
+            }
             text.run_on_terminal(duration)?;
         }
         "iter" => {
@@ -192,26 +751,96 @@ fn main() -> anyhow::Result<()> {
             let i = text.print_once(i, prev_content.as_str())?;
             fs::write(iter_file, format!("{i} {}", text.get_raw_content()))?;
         }
+        #[cfg(feature = "daemon")]
+        "daemon" => {
+            let duration: Duration = sub_matches
+                .remove_one::<humantime::Duration>("duration")
+                .unwrap().into();
+            let socket = sub_matches.remove_one("socket").unwrap_or_else(daemon::default_socket_path);
+            daemon::run(text, duration, socket, original_matches)?;
+        }
+        #[cfg(feature = "i3bar")]
+        "i3bar" => {
+            let duration: Duration = sub_matches
+                .remove_one::<humantime::Duration>("duration")
+                .unwrap().into();
+            i3bar::run(
+                text,
+                duration,
+                sub_matches.remove_one("name").unwrap(),
+                sub_matches.remove_many("button").map(Iterator::collect).unwrap_or_default(),
+                sub_matches.remove_one("on-click").map(Hook::new),
+            )?;
+        }
         #[cfg(feature = "waybar")]
         "waybar" => {
             let duration: Duration = sub_matches
                 .remove_one::<humantime::Duration>("duration")
                 .unwrap().into();
-            #[cfg(feature = "mpd")] {
-                let tooltip = sub_matches.remove_one::<MpdFormatter>("tooltip-format")
-                    .map(Tooltip::Mpd)
-                    .or(sub_matches.remove_one("TOOLTIP").map(Tooltip::Simple))
-                    .or(sub_matches.remove_many::<OsString>("tooltip-cmd").map(|vs| Tooltip::Cmd(vs.collect())));
-                text.run_in_waybar(duration, tooltip)?;
-            }
-            #[cfg(not(feature = "mpd"))] {
-                let tooltip = sub_matches.remove_one("TOOLTIP").map(Tooltip::Simple)
-                    .or(sub_matches.remove_many::<OsString>("tooltip-cmd").map(|vs| Tooltip::Cmd(vs.collect())));
-                text.run_in_waybar(duration, tooltip)?;
-            }
+            let tooltip = sub_matches.remove_one("TOOLTIP").map(Tooltip::Simple)
+                .or(sub_matches.remove_many::<OsString>("tooltip-cmd").map(|vs| {
+                    let cmd = match sub_matches.remove_one::<bool>("tooltip-cmd-shell").unwrap() {
+                        true => utils::Command::shell(vs),
+                        false => vs.collect(),
+                    };
+                    Tooltip::Cmd(cmd.with_timeout(cmd_timeout).with_encoding(encoding))
+                }))
+                .or(sub_matches.remove_one("tooltip-file").map(Tooltip::File));
+            #[cfg(feature = "mpd")]
+            let tooltip = tooltip
+                .or(sub_matches.remove_one::<MpdFormatter>("tooltip-format").map(Tooltip::Mpd))
+                .or(sub_matches.remove_one::<u64>("tooltip-queue").map(|count| Tooltip::Queue {
+                    format: sub_matches.remove_one("queue-format").unwrap(),
+                    count: count as usize,
+                }))
+                .or(sub_matches.remove_one::<u64>("tooltip-history").map(|count| Tooltip::History {
+                    format: sub_matches.remove_one("history-format").unwrap(),
+                    count: count as usize,
+                }));
+            let module = ModuleOptions {
+                tooltip,
+                tooltip_options: TooltipOptions {
+                    strip_newlines: sub_matches.remove_one("tooltip-strip-newlines").unwrap(),
+                    max_length: sub_matches.remove_one::<u64>("tooltip-max-length").map(|l| l as usize),
+                    ellipsis: sub_matches.remove_one("tooltip-ellipsis").unwrap(),
+                },
+                #[cfg(feature = "mpd")]
+                class: sub_matches.remove_one("class-format"),
+                #[cfg(feature = "mpd")]
+                fields: sub_matches.remove_many("field").map(Iterator::collect).unwrap_or_default(),
+            };
+            text = text.with_escape_markup(sub_matches.remove_one("escape-markup").unwrap());
+            text.run_in_waybar(duration, module)?;
         }
         _ => unreachable!(),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Command;
+
+    use super::{cli, FEATURES};
+
+    /// Whether the option or subcommand is defined anywhere in the command line.
+    fn is_defined(cmd: &Command, name: &str) -> bool {
+        let defined = match (name.strip_prefix("--"), name.strip_prefix('-')) {
+            (Some(long), _) => cmd.get_arguments().any(|arg| arg.get_long() == Some(long)),
+            (None, Some(short)) => cmd.get_arguments().any(|arg| arg.get_short().is_some_and(|c| short == c.to_string())),
+            (None, None) => cmd.find_subcommand(name).is_some(),
+        };
+        defined || cmd.get_subcommands().any(|sub| is_defined(sub, name))
+    }
+
+    #[test]
+    fn features_test() {
+        let cli = cli();
+        for (feature, _, names) in FEATURES.iter().filter(|(_, enabled, _)| *enabled) {
+            for name in *names {
+                assert!(is_defined(&cli, name), "{name} of the {feature} feature is not defined");
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::{self, Write};
+
+use clap::{Arg, Command};
+use clap_mangen::Man;
+
+pub fn render(cmd: Command, out: &mut dyn Write) -> io::Result<()> {
+    let man = Man::new(cmd.clone());
+    man.render_title(out)?;
+    man.render_name_section(out)?;
+    man.render_synopsis_section(out)?;
+    man.render_description_section(out)?;
+    man.render_options_section(out)?;
+    render_commands(&cmd, out)?;
+    out.write_all(SOURCES.as_bytes())?;
+    #[cfg(feature = "mpd")]
+    out.write_all(PLACEHOLDERS.as_bytes())?;
+    out.write_all(OUTPUTS.as_bytes())?;
+    out.write_all(SIGNALS.as_bytes())?;
+    out.write_all(ENVIRONMENT.as_bytes())?;
+    out.write_all(EXIT_STATUS.as_bytes())?;
+    man.render_version_section(out)?;
+    man.render_authors_section(out)
+}
+
+fn render_commands(cmd: &Command, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, ".SH COMMANDS")?;
+    for sub in cmd.get_subcommands().filter(|s| s.get_name() != "help") {
+        writeln!(out, ".SS {}", sub.get_name())?;
+        if let Some(about) = sub.get_about() {
+            writeln!(out, "{}", escape(&about.to_string()))?;
+        }
+        for arg in sub
+            .get_arguments()
+            .filter(|a| !a.is_hide_set() && !matches!(a.get_id().as_str(), "help" | "version"))
+        {
+            render_arg(arg, out)?;
+        }
+    }
+    Ok(())
+}
+
+fn render_arg(arg: &Arg, out: &mut dyn Write) -> io::Result<()> {
+    let mut flags = Vec::new();
+    if let Some(short) = arg.get_short() {
+        flags.push(format!("\\fB\\-{short}\\fR"));
+    }
+    if let Some(long) = arg.get_long() {
+        flags.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+    }
+    let values = arg
+        .get_value_names()
+        .into_iter()
+        .flatten()
+        .map(|v| format!("<{v}>"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    writeln!(out, ".TP")?;
+    match (flags.is_empty(), arg.get_action().takes_values()) {
+        (true, _) => writeln!(out, "{}", escape(&values))?,
+        (false, true) => writeln!(out, "{} {}", flags.join(", "), escape(&values))?,
+        (false, false) => writeln!(out, "{}", flags.join(", "))?,
+    }
+    if let Some(help) = arg.get_help() {
+        write!(out, "{}", escape(&help.to_string()))?;
+    }
+    let defaults = arg.get_default_values();
+    if !defaults.is_empty() {
+        let defaults = defaults
+            .iter()
+            .map(|d| d.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(out, " [default: {}]", escape(&defaults))?;
+    }
+    writeln!(out)
+}
+
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\\\").replace('-', "\\-");
+    // a leading dot or quote would be taken for a request
+    match text.starts_with(['.', '\'']) {
+        true => format!("\\&{text}"),
+        false => text,
+    }
+}
+
+const SOURCES: &str = r#".SH SOURCES
+Exactly one source provides the text:
+.TP
+\fISOURCE\fR, \fB\-\-file\fR, \fB\-\-string\fR
+static text, read once at startup
+.TP
+\fB\-\-stdin\fR
+text read from the standard input
+.TP
+\fB\-\-cmd\fR
+output of a command, re\-run every tick (or every \fB\-\-refresh\-interval\fR); a command that fails or exits with a non\-zero status is retried after 1s, 2s, 4s and so on, up to \fB\-\-cmd\-max\-backoff\fR; with \fB\-\-cmd\-timeout\fR a hung command is killed along with its children and the previous output stays
+.TP
+\fB\-\-plugin\fR
+text and fields printed as JSON lines by a long running executable (with the plugin feature), which receives the \fB\-\-plugin\-config\fR pairs in a handshake line on its standard input; a plugin that exits is restarted like a failing command
+.TP
+\fB\-\-timer\fR
+a stopwatch, or a countdown from the given duration; under \fBdaemon\fR it is started, stopped, reset or set with \fBmg ctl timer\fR
+.TP
+\fB\-\-lines\fR
+a random line of a file, another one after the line scrolled through or every \fB\-\-lines\-every\fR; \fB\-\-shuffle\fR shows every line before repeating any and \fB\-\-seed\fR fixes the order
+.TP
+\fB\-\-mpd\fR
+status of an MPD server rendered with \fB\-\-format\fR, \fB\-\-prefix\-format\fR and \fB\-\-suffix\-format\fR, updated as soon as MPD reports a change
+"#;
+
+#[cfg(feature = "mpd")]
+const PLACEHOLDERS: &str = r#".SH FORMAT
+MPD formats are plain text with placeholders in braces, literal braces are doubled (\fB{{\fR and \fB}}\fR).
+Missing values are replaced with \fB\-\-default\-placeholder\fR.
+.TP
+\fB{artist}\fR, \fB{albumArtist}\fR, \fB{album}\fR, \fB{title}\fR, \fB{date}\fR, \fB{filename}\fR
+tags of the current song
+.TP
+\fB{elapsedTime}\fR, \fB{totalTime}\fR
+times of the current song, a strftime\-like format can follow a colon: \fB{elapsedTime:%M min %S sec}\fR (the default is \fB%M:%S\fR)
+.TP
+\fB{date:\fR\fIFORMAT\fR\fB}\fR, \fB{lastModified}\fR
+the Date tag read as a date and written with a strftime\-like format, e.g. \fB{date:%Y}\fR, and when the song file was last modified (the default is \fB%Y\-%m\-%d\fR)
+.TP
+\fB{volume}\fR, \fB{songPosition}\fR, \fB{queueLength}\fR
+player status
+.TP
+\fB{queueIndex}\fR
+position of the current song counted from 1 and the queue length, e.g. \fB4/23\fR; another separator can follow a colon: \fB{queueIndex: of }\fR
+.TP
+\fB{volume:\fR\fIOPTIONS\fR\fB}\fR
+volume with comma separated options: \fB%\fR adds a percent sign, \fBpad\fR\fIN\fR pads it to N characters and \fBmuted=\fR\fITEXT\fR (last) shows TEXT at 0 or without a mixer
+.TP
+\fB{volumeBar}\fR
+volume drawn as a bar, the width can follow a colon (the default is 10); shown in place of the text after a volume change with \fB\-\-volume\-flash\fR
+.TP
+\fB{nextArtist}\fR, \fB{nextTitle}\fR
+tags of the song played next, also shown in place of the suffix near the end of a song with \fB\-\-up\-next\fR and in place of the text during a crossfade with \fB\-\-crossfade\fR
+.TP
+\fB{previousArtist}\fR, \fB{previousTitle}\fR
+tags of the song played before the current one
+.TP
+\fB{sourceName}\fR, \fB{instance}\fR
+kind of the source (\fBmpd\fR) and the name given with \fB\-\-instance\fR
+.TP
+\fB{uptime}\fR, \fB{frame}\fR
+time since start (a time format can follow a colon, the default is \fB%H:%M:%S\fR) and the number of frames rendered so far
+.TP
+\fB{outputs}\fR, \fB{outputIcon:\fR\fINAME\fR\fB}\fR
+names of enabled audio outputs, icon from \fB\-\-output\-icons\fR showing whether the output NAME is enabled
+.TP
+\fB{mpdError}\fR, \fB{errorIcon}\fR
+error reported by MPD (e.g. an output failing to open), empty without one, and an icon from \fB\-\-error\-icons\fR showing whether there is one
+.TP
+\fB{stateIcon}\fR, \fB{consumeIcon}\fR, \fB{randomIcon}\fR, \fB{repeatIcon}\fR, \fB{singleIcon}\fR
+icons from \fB\-\-status\-icons\fR and the respective \fB\-\-*\-icons\fR options, a padding can follow a colon: \fB{stateIcon:1}\fR
+.TP
+\fB{lyrics}\fR
+current line of the song's .lrc file (with the lyrics feature), \fB{lyrics:N}\fR renders N lines
+.TP
+\fB{albumArt}\fR
+path to the cached cover of the current song (with the art feature)
+.TP
+\fB{artColor}\fR
+dominant color of the cover as \fB#rrggbb\fR, \fB{artColor:hex}\fR leaves out the \fB#\fR and \fB{artColor:rgb}\fR gives \fBr;g;b\fR (with the art\-color feature)
+.TP
+\fB{include:\fR\fIPATH\fR\fB}\fR
+a format fragment read from a file
+.TP
+\fB{if:\fR\fICONDITION\fR\fB:\fR\fITEXT\fR\fB}\fR
+TEXT if the condition holds, e.g. \fB{if:volume=0:muted}\fR; a placeholder is compared with \fB=\fR, \fB!=\fR, \fB<\fR, \fB<=\fR, \fB>\fR or \fB>=\fR
+.TP
+\fB{[}\fR...\fB{]}\fR
+a group left out when a placeholder in it has no value, e.g. \fB{[}[{elapsedTime}/{totalTime}]{]}\fR hides the times of streams
+.TP
+\fISTATE\fR\fB=\fR\fIFORMAT\fR
+given to \fB\-\-prefix\-format\fR, \fB\-\-suffix\-format\fR or \fB\-\-separator\-format\fR, a format used only in the given state (\fBplay\fR, \fBpause\fR or \fBstop\fR); other states use the format given without a state, \fB\-\-prefix\fR, \fB\-\-suffix\fR or \fB\-\-separator\fR
+"#;
+
+const OUTPUTS: &str = r#".SH OUTPUTS
+Frames go to the standard output: overwritten in place by \fBrun\fR, one per line by \fBdaemon\fR, as JSON objects by \fBwaybar\fR and as an i3bar protocol stream by \fBi3bar\fR.
+Each \fB\-\-tee\fR file additionally receives plain text frames: a regular file holds only the latest frame, a named pipe gets a stream of lines.
+"#;
+
+const SIGNALS: &str = r#".SH SIGNALS
+.TP
+\fBSIGUSR1\fR
+refresh the source and render a frame immediately
+.TP
+\fBSIGUSR2\fR
+pause or resume scrolling
+.TP
+\fBSIGRTMIN\fR
+scroll through the text once with \fB\-\-on\-demand\fR
+.TP
+\fBSIGRTMIN+\fR\fIN\fR
+refresh like \fBSIGUSR1\fR with \fB\-\-refresh\-signal\fR \fIN\fR
+.TP
+\fBSIGWINCH\fR
+resize the window to the terminal with \fBrun \-\-auto\-window\fR
+.TP
+\fBSIGINT\fR, \fBSIGTERM\fR
+render a final frame and exit
+"#;
+
+const ENVIRONMENT: &str = r#".SH ENVIRONMENT
+.TP
+\fBRUST_LOG\fR
+log filter, overrides \fB\-v\fR and \fB\-q\fR
+.TP
+\fBXDG_RUNTIME_DIR\fR
+directory for the control socket and instance pid files
+.TP
+\fBNOTIFY_SOCKET\fR, \fBWATCHDOG_USEC\fR
+set by systemd to receive readiness notifications and watchdog pings
+.TP
+\fBMERGNEH_*\fR
+contents and source values passed to the \fB\-\-on\-change\fR and \fB\-\-tooltip\-cmd\fR commands
+"#;
+
+const EXIT_STATUS: &str = r#".SH "EXIT STATUS"
+0 on success (also when whatever reads the output goes away), 1 if the source or the output fails, 2 on invalid arguments.
+"#;
@@ -0,0 +1,997 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use mpd::{
+    song::{Id, QueuePlace},
+    Output, Song, State, Status, Version,
+};
+
+/// An in-process MPD server speaking the part of the protocol [`crate::mpd::MpdSource`] uses
+/// (status, current song, queue, outputs, idle, playback commands and album art), so tests do
+/// not need a running mpd. It listens on a random local port and stops when dropped.
+#[derive(Debug)]
+pub struct MockMpd {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    server: Mutex<Server>,
+    changed: Condvar,
+}
+
+#[derive(Debug)]
+struct Server {
+    version: Version,
+    queue: Vec<Song>,
+    status: Status,
+    outputs: Vec<Output>,
+    art: HashMap<String, Vec<u8>>,
+    /// Every subsystem change so far, `idle` reports the ones since the client last idled.
+    events: Vec<&'static str>,
+    commands: Vec<String>,
+    /// Commands refused as if the client lacked the permission for them.
+    forbidden: Vec<String>,
+    connections: Vec<TcpStream>,
+    down: bool,
+    stopped: bool,
+}
+
+/// Album art is sent in chunks of this size, like MPD does by default.
+const ART_CHUNK: usize = 8192;
+
+/// What `commands` lists, the commands below that a client may send.
+const COMMANDS: [&str; 16] = [
+    "albumart",
+    "commands",
+    "consume",
+    "currentsong",
+    "idle",
+    "next",
+    "noidle",
+    "outputs",
+    "partition",
+    "pause",
+    "ping",
+    "play",
+    "playlistid",
+    "playlistinfo",
+    "previous",
+    "status",
+];
+
+impl MockMpd {
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            server: Mutex::new(Server {
+                version: Version(0, 23, 5),
+                queue: Vec::new(),
+                status: Status {
+                    volume: 100,
+                    ..Default::default()
+                },
+                outputs: Vec::new(),
+                art: HashMap::new(),
+                events: Vec::new(),
+                commands: Vec::new(),
+                forbidden: Vec::new(),
+                connections: Vec::new(),
+                down: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let accepting = Arc::clone(&shared);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let mut server = accepting.lock();
+                if server.stopped {
+                    return;
+                }
+                // refused right after connecting, like a server that is going down
+                if server.down {
+                    continue;
+                }
+                if let Ok(stream) = stream.try_clone() {
+                    server.connections.push(stream);
+                }
+                drop(server);
+                let shared = Arc::clone(&accepting);
+                thread::spawn(move || {
+                    if let Err(e) = shared.serve(stream) {
+                        log::debug!("Mock MPD connection closed: {e}");
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, shared })
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Protocol version greeting new connections.
+    pub fn set_version(&self, version: Version) {
+        self.shared.lock().version = version;
+    }
+    /// Replaces the queue, songs get their positions and ids from the order.
+    pub fn set_queue(&self, songs: Vec<Song>) {
+        let mut server = self.shared.lock();
+        server.queue = songs;
+        for (pos, song) in server.queue.iter_mut().enumerate() {
+            song.place = Some(place(pos));
+        }
+        server.status.queue_len = server.queue.len() as u32;
+        server.status.queue_version += 1;
+        if server.current().is_none() {
+            server.stop();
+        }
+        self.shared.notify(server, "playlist");
+    }
+    /// Starts playing the song at `pos` in the queue.
+    pub fn play(&self, pos: usize) {
+        let mut server = self.shared.lock();
+        server.play(pos);
+        self.shared.notify(server, "player");
+    }
+    pub fn set_state(&self, state: State) {
+        let mut server = self.shared.lock();
+        match state {
+            State::Stop => server.stop(),
+            _ if server.current().is_none() => server.play(0),
+            _ => server.status.state = state,
+        }
+        self.shared.notify(server, "player");
+    }
+    /// Moves playback on without an idle event, like time passing.
+    pub fn set_elapsed(&self, elapsed: Duration) {
+        self.shared.lock().status.elapsed = Some(elapsed);
+    }
+    pub fn set_volume(&self, volume: i8) {
+        let mut server = self.shared.lock();
+        server.status.volume = volume;
+        self.shared.notify(server, "mixer");
+    }
+    /// Changes playback options (random, repeat, single, consume, crossfade and the like).
+    pub fn update_status(&self, update: impl FnOnce(&mut Status)) {
+        let mut server = self.shared.lock();
+        update(&mut server.status);
+        self.shared.notify(server, "options");
+    }
+    pub fn set_outputs(&self, outputs: Vec<Output>) {
+        let mut server = self.shared.lock();
+        server.outputs = outputs;
+        self.shared.notify(server, "output");
+    }
+    /// Cover returned by `albumart` for the song file.
+    pub fn set_art(&self, file: &str, data: Vec<u8>) {
+        self.shared.lock().art.insert(file.to_owned(), data);
+    }
+    /// Drops every connection and refuses new ones while down, clients see a lost server.
+    pub fn set_down(&self, down: bool) {
+        let mut server = self.shared.lock();
+        server.down = down;
+        if down {
+            for stream in server.connections.drain(..) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.shared.changed.notify_all();
+    }
+    /// Refuses `command` like MPD does without the permission for it, `commands` stops listing it.
+    pub fn forbid(&self, command: &str) {
+        self.shared.lock().forbidden.push(command.to_owned());
+    }
+    /// Command lines received so far, from all connections.
+    pub fn commands(&self) -> Vec<String> {
+        self.shared.lock().commands.clone()
+    }
+}
+
+impl Drop for MockMpd {
+    fn drop(&mut self) {
+        self.set_down(true);
+        self.shared.lock().stopped = true;
+        // wakes the accepting thread up so that it notices
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Server> {
+        self.server.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn notify(&self, mut server: MutexGuard<'_, Server>, subsystem: &'static str) {
+        server.events.push(subsystem);
+        self.changed.notify_all();
+    }
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let mut out = stream.try_clone()?;
+        // like MPD, changes pile up from the connection on until the client idles
+        let (Version(major, minor, patch), mut seen) = {
+            let server = self.lock();
+            (server.version, server.events.len())
+        };
+        writeln!(out, "OK MPD {major}.{minor}.{patch}")?;
+        let mut response = Vec::new();
+        // commands between command_list_begin and command_list_end, answered together
+        let mut list: Option<Vec<Vec<String>>> = None;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let args = split_args(&line);
+            let Some((command, args)) = args.split_first() else {
+                continue;
+            };
+            response.clear();
+            let mut server = self.lock();
+            server.commands.push(line.clone());
+            match (command.as_str(), &mut list) {
+                ("command_list_begin", None) => {
+                    list = Some(Vec::new());
+                    continue;
+                }
+                ("command_list_end", Some(_)) => {
+                    let mut events = Vec::new();
+                    let mut failed = false;
+                    for args in list.take().unwrap() {
+                        let (command, args) = args.split_first().unwrap();
+                        let start = response.len();
+                        events.extend(server.run(command, args, &mut response));
+                        // one OK ends the whole list, the first error ends it early
+                        failed = response[start..].starts_with(b"ACK");
+                        if failed {
+                            break;
+                        }
+                        response.truncate(response.len() - b"OK\n".len());
+                    }
+                    if !failed {
+                        response.extend_from_slice(b"OK\n");
+                    }
+                    server.events.extend(events);
+                    self.changed.notify_all();
+                    drop(server);
+                    out.write_all(&response)?;
+                    continue;
+                }
+                (_, Some(list)) => {
+                    list.push(split_args(&line));
+                    continue;
+                }
+                _ => {}
+            }
+            match command.as_str() {
+                "idle" => {
+                    let changed = loop {
+                        if server.down {
+                            return Err(io::ErrorKind::ConnectionAborted.into());
+                        }
+                        let mut changed = server.events[seen..]
+                            .iter()
+                            .filter(|e| args.is_empty() || args.iter().any(|a| a == *e))
+                            .copied()
+                            .collect::<Vec<_>>();
+                        changed.sort_unstable();
+                        changed.dedup();
+                        if !changed.is_empty() {
+                            break changed;
+                        }
+                        server = self
+                            .changed
+                            .wait(server)
+                            .unwrap_or_else(PoisonError::into_inner);
+                    };
+                    for subsystem in changed {
+                        writeln!(response, "changed: {subsystem}")?;
+                    }
+                    writeln!(response, "OK")?;
+                    seen = server.events.len();
+                }
+                "close" => return Ok(()),
+                _ => {
+                    if let Some(event) = server.run(command, args, &mut response) {
+                        self.notify(server, event);
+                    }
+                }
+            }
+            out.write_all(&response)?;
+        }
+        Ok(())
+    }
+}
+
+impl Server {
+    fn current(&self) -> Option<&Song> {
+        self.queue.get(self.status.song?.pos as usize)
+    }
+    fn play(&mut self, pos: usize) {
+        let Some(song) = self.queue.get(pos) else {
+            return self.stop();
+        };
+        let duration = song.duration;
+        self.status.state = State::Play;
+        self.status.song = Some(place(pos));
+        self.status.nextsong = (pos + 1 < self.queue.len()).then(|| place(pos + 1));
+        self.status.elapsed = Some(Duration::ZERO);
+        self.status.duration = duration;
+    }
+    fn stop(&mut self) {
+        self.status.state = State::Stop;
+        self.status.elapsed = None;
+        self.status.duration = None;
+        if self.current().is_none() {
+            self.status.song = None;
+            self.status.nextsong = None;
+        }
+    }
+    /// Writes the response to a command, returns the subsystem it changed.
+    fn run(&mut self, command: &str, args: &[String], out: &mut Vec<u8>) -> Option<&'static str> {
+        let pos = self.status.song.map(|s| s.pos as usize);
+        if self.forbidden.iter().any(|c| c == command) {
+            return ack(
+                out,
+                4,
+                command,
+                &format!("you don't have permission for \"{command}\""),
+            );
+        }
+        let event = match (command, args) {
+            ("commands", []) => {
+                COMMANDS
+                    .iter()
+                    .filter(|c| !self.forbidden.iter().any(|f| f == *c))
+                    .for_each(|c| {
+                        let _ = writeln!(out, "command: {c}");
+                    });
+                None
+            }
+            ("status", []) => {
+                self.write_status(out);
+                None
+            }
+            ("currentsong", []) => {
+                if let Some(song) = self.current() {
+                    write_song(song, out);
+                }
+                None
+            }
+            ("playlistinfo", []) => {
+                self.queue.iter().for_each(|song| write_song(song, out));
+                None
+            }
+            ("playlistid", [id]) => {
+                let song = self
+                    .queue
+                    .iter()
+                    .find(|s| s.place.is_some_and(|place| place.id.0.to_string() == *id));
+                match song {
+                    Some(song) => write_song(song, out),
+                    None => return ack(out, 50, command, "No such song"),
+                }
+                None
+            }
+            ("outputs", []) => {
+                for output in &self.outputs {
+                    let _ = write!(
+                        out,
+                        "outputid: {}\noutputname: {}\noutputenabled: {}\n",
+                        output.id,
+                        output.name,
+                        u8::from(output.enabled)
+                    );
+                }
+                None
+            }
+            ("albumart", [file, offset]) => {
+                let (Some(data), Ok(offset)) = (self.art.get(file), offset.parse::<usize>()) else {
+                    return ack(out, 50, command, "No file exists");
+                };
+                let chunk = &data[offset.min(data.len())..(offset + ART_CHUNK).min(data.len())];
+                let _ = write!(out, "size: {}\nbinary: {}\n", data.len(), chunk.len());
+                out.extend_from_slice(chunk);
+                out.push(b'\n');
+                None
+            }
+            ("replay_gain_status", []) => {
+                out.extend_from_slice(b"replay_gain_mode: off\n");
+                None
+            }
+            ("ping" | "noidle", []) => None,
+            ("play", []) => {
+                self.play(pos.unwrap_or(0));
+                Some("player")
+            }
+            ("play", [p]) => {
+                self.play(p.parse().unwrap_or(usize::MAX));
+                Some("player")
+            }
+            ("pause", []) => {
+                self.status.state = match self.status.state {
+                    State::Play => State::Pause,
+                    State::Pause => State::Play,
+                    State::Stop => State::Stop,
+                };
+                Some("player")
+            }
+            ("stop", []) => {
+                self.stop();
+                Some("player")
+            }
+            ("next", []) => {
+                match pos {
+                    Some(pos) => self.play(pos + 1),
+                    None => self.stop(),
+                }
+                Some("player")
+            }
+            ("previous", []) => {
+                self.play(pos.unwrap_or(0).saturating_sub(1));
+                Some("player")
+            }
+            ("setvol", [volume]) => match volume.parse() {
+                Ok(volume) => {
+                    self.status.volume = volume;
+                    Some("mixer")
+                }
+                Err(_) => return ack(out, 2, command, "Number expected"),
+            },
+            _ => return ack(out, 5, command, &format!("unknown command \"{command}\"")),
+        };
+        out.extend_from_slice(b"OK\n");
+        event
+    }
+    fn write_status(&self, out: &mut Vec<u8>) {
+        let status = &self.status;
+        let mut s = String::new();
+        let flag = u8::from;
+        let _ = write!(
+            s,
+            "volume: {}\nrepeat: {}\nrandom: {}\nsingle: {}\nconsume: {}\nplaylist: {}\n\
+             playlistlength: {}\nmixrampdb: 0.000000\nstate: {}\n",
+            status.volume,
+            flag(status.repeat),
+            flag(status.random),
+            flag(status.single),
+            flag(status.consume),
+            status.queue_version,
+            status.queue_len,
+            match status.state {
+                State::Play => "play",
+                State::Pause => "pause",
+                State::Stop => "stop",
+            }
+        );
+        if let Some(song) = status.song {
+            let _ = write!(s, "song: {}\nsongid: {}\n", song.pos, song.id);
+        }
+        if let Some(song) = status.nextsong {
+            let _ = write!(s, "nextsong: {}\nnextsongid: {}\n", song.pos, song.id);
+        }
+        if let Some(crossfade) = status.crossfade {
+            let _ = writeln!(s, "xfade: {}", crossfade.as_secs());
+        }
+        if let (Some(elapsed), Some(duration)) = (status.elapsed, status.duration) {
+            let _ = write!(
+                s,
+                "time: {}:{}\nelapsed: {:.3}\nduration: {:.3}\n",
+                elapsed.as_secs(),
+                duration.as_secs(),
+                elapsed.as_secs_f64(),
+                duration.as_secs_f64()
+            );
+        }
+        if let Some(error) = &status.error {
+            let _ = writeln!(s, "error: {error}");
+        }
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+fn place(pos: usize) -> QueuePlace {
+    QueuePlace {
+        id: Id(pos as u32 + 1),
+        pos: pos as u32,
+        prio: 0,
+    }
+}
+
+fn write_song(song: &Song, out: &mut Vec<u8>) {
+    let mut s = format!("file: {}\n", song.file);
+    let fields = [
+        ("Last-Modified", &song.last_mod),
+        ("Artist", &song.artist),
+        ("Title", &song.title),
+        ("Name", &song.name),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            let _ = writeln!(s, "{key}: {value}");
+        }
+    }
+    for (key, value) in &song.tags {
+        let _ = writeln!(s, "{key}: {value}");
+    }
+    if let Some(duration) = song.duration {
+        let _ = write!(
+            s,
+            "Time: {}\nduration: {:.3}\n",
+            duration.as_secs(),
+            duration.as_secs_f64()
+        );
+    }
+    if let Some(place) = song.place {
+        let _ = write!(s, "Pos: {}\nId: {}\n", place.pos, place.id);
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn ack(out: &mut Vec<u8>, code: u32, command: &str, message: &str) -> Option<&'static str> {
+    let _ = writeln!(out, "ACK [{code}@0] {{{command}}} {message}");
+    None
+}
+
+/// Splits a command line into arguments, double quoted ones can have spaces and backslash
+/// escapes.
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => arg.extend(chars.next()),
+                    c => arg.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{self, RecvTimeoutError},
+        thread,
+        time::Duration,
+    };
+
+    use mpd::{Client, Idle, Song, Subsystem, Version};
+
+    use super::{split_args, MockMpd};
+    #[cfg(feature = "art")]
+    use crate::art::ArtCache;
+    use crate::{
+        events::{Frames, Waker},
+        mpd::{MpdFormatter, MpdSource, PlayerCommand, StateFormats, StatusIconsSet},
+        text_source::{ContentChange, TextSource},
+        RunningText,
+    };
+
+    fn song(artist: &str, title: &str) -> Song {
+        Song {
+            file: format!("{artist}/{title}.flac"),
+            artist: Some(artist.to_owned()),
+            title: Some(title.to_owned()),
+            duration: Some(Duration::from_secs(200)),
+            ..Default::default()
+        }
+    }
+
+    fn source(mock: &MockMpd) -> MpdSource {
+        source_with_format(mock, "{artist} - {title}")
+    }
+
+    fn source_with_format(mock: &MockMpd, format: &str) -> MpdSource {
+        let empty = || StateFormats::new(MpdFormatter::only_string(String::new()), []);
+        MpdSource::new(
+            mock.addr(),
+            format.parse().unwrap(),
+            empty(),
+            empty(),
+            None,
+            StatusIconsSet::new(
+                "psx".parse().unwrap(),
+                "cC".parse().unwrap(),
+                "rR".parse().unwrap(),
+                "lL".parse().unwrap(),
+                "sS".parse().unwrap(),
+                "oO".parse().unwrap(),
+                "eE".parse().unwrap(),
+            ),
+            "N/A".to_owned(),
+            #[cfg(feature = "lyrics")]
+            None,
+            #[cfg(feature = "art")]
+            ArtCache::with_dir(std::env::temp_dir().join("mergneh-mock-art"), 0),
+        )
+        .unwrap()
+    }
+
+    fn poll(source: &mut MpdSource) -> anyhow::Result<(ContentChange, String)> {
+        let (mut content, mut prefix, mut suffix) = Default::default();
+        let change = source.poll(&mut content, &mut prefix, &mut suffix)?;
+        Ok((change, content))
+    }
+
+    #[test]
+    fn split_args_test() {
+        assert_eq!(split_args("status"), ["status"]);
+        assert_eq!(
+            split_args(r#"albumart "A/B \"C\".flac" 0"#),
+            ["albumart", r#"A/B "C".flac"#, "0"]
+        );
+        assert_eq!(
+            split_args("  idle player  mixer "),
+            ["idle", "player", "mixer"]
+        );
+    }
+
+    #[test]
+    fn render_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+        ]);
+        mock.play(0);
+        let mut source = source(&mock);
+        assert_eq!(
+            source.get_initial_content().unwrap().running,
+            "Artist A - Title A"
+        );
+        let (change, _) = poll(&mut source).unwrap();
+        assert!(!change.contains(ContentChange::Running));
+
+        source.send(PlayerCommand::Next).unwrap();
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Artist B - Title B");
+        assert!(mock.commands().iter().any(|c| c == "next"));
+    }
+
+    #[test]
+    fn query_interval_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+        ]);
+        mock.play(0);
+        let mut source = source(&mock).with_query_interval(Some(Duration::from_secs(3600)));
+        source.get_initial_content().unwrap();
+        let queries = || mock.commands().iter().filter(|c| *c == "status").count();
+        let asked = queries();
+        poll(&mut source).unwrap();
+        poll(&mut source).unwrap();
+        assert_eq!(queries(), asked);
+
+        // a command is answered right away
+        source.send(PlayerCommand::Next).unwrap();
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Artist B - Title B");
+        assert_eq!(queries(), asked + 1);
+    }
+
+    #[test]
+    fn outputs_ttl_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![song("Artist A", "Title A")]);
+        mock.play(0);
+        let mut source = source_with_format(&mock, "{title} {outputs}")
+            .with_outputs_ttl(Some(Duration::from_millis(300)));
+        source.get_initial_content().unwrap();
+        let lookups = || mock.commands().iter().filter(|c| *c == "outputs").count();
+        let asked = lookups();
+        poll(&mut source).unwrap();
+        poll(&mut source).unwrap();
+        assert_eq!(lookups(), asked);
+
+        thread::sleep(Duration::from_millis(300));
+        poll(&mut source).unwrap();
+        assert_eq!(lookups(), asked + 1);
+    }
+
+    #[test]
+    fn up_next_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+        ]);
+        mock.play(0);
+        mock.set_elapsed(Duration::from_secs(100));
+        let mut source = source(&mock).with_up_next(
+            Some(Duration::from_secs(30)),
+            "next: {nextArtist} - {nextTitle}".parse().unwrap(),
+        );
+        let (mut content, mut prefix, mut suffix) = Default::default();
+        source.poll(&mut content, &mut prefix, &mut suffix).unwrap();
+        assert_eq!(suffix, "");
+
+        mock.set_elapsed(Duration::from_secs(180));
+        let change = source.poll(&mut content, &mut prefix, &mut suffix).unwrap();
+        assert!(change.contains(ContentChange::Suffix));
+        assert_eq!(suffix, "next: Artist B - Title B");
+
+        // nothing follows the last song
+        mock.play(1);
+        mock.set_elapsed(Duration::from_secs(180));
+        source.poll(&mut content, &mut prefix, &mut suffix).unwrap();
+        assert_eq!(suffix, "");
+    }
+
+    #[test]
+    fn crossfade_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+        ]);
+        mock.play(0);
+        mock.set_elapsed(Duration::from_secs(194));
+        let mut source =
+            source(&mock).with_crossfade_format(Some("{title} ⇢ {nextTitle}".parse().unwrap()));
+        // no crossfade set up in MPD
+        assert_eq!(
+            source.get_initial_content().unwrap().running,
+            "Artist A - Title A"
+        );
+
+        mock.update_status(|status| status.crossfade = Some(Duration::from_secs(5)));
+        let (change, _) = poll(&mut source).unwrap();
+        assert!(!change.contains(ContentChange::Running));
+
+        mock.set_elapsed(Duration::from_secs(196));
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Title A ⇢ Title B");
+
+        mock.play(1);
+        let (_, content) = poll(&mut source).unwrap();
+        assert_eq!(content, "Artist B - Title B");
+    }
+
+    #[test]
+    fn history_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+            song("Artist C", "Title C"),
+        ]);
+        mock.play(0);
+        let mut source = source(&mock);
+        source.track_history(2);
+        let previous: MpdFormatter = "{previousArtist} - {previousTitle}".parse().unwrap();
+        let render = |source: &MpdSource, format: &MpdFormatter| {
+            let mut s = String::new();
+            format.format_with_source(source, &mut s).unwrap();
+            s
+        };
+        assert_eq!(render(&source, &previous), "N/A - N/A");
+
+        for pos in 1..3 {
+            mock.play(pos);
+            poll(&mut source).unwrap();
+        }
+        assert_eq!(render(&source, &previous), "Artist B - Title B");
+        let mut history = String::new();
+        source
+            .format_history(&"{title}".parse().unwrap(), 5, &mut history)
+            .unwrap();
+        let titles: Vec<_> = history
+            .lines()
+            .map(|l| l.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(titles, ["Title B", "Title A"]);
+    }
+
+    #[test]
+    fn volume_flash_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![song("Artist", "Title")]);
+        mock.play(0);
+        mock.set_volume(100);
+        let mut source = source(&mock).with_volume_flash(
+            Some(Duration::from_millis(200)),
+            "{volumeBar:5} {volume}%".parse().unwrap(),
+        );
+        assert_eq!(
+            source.get_initial_content().unwrap().running,
+            "Artist - Title"
+        );
+        let (change, _) = poll(&mut source).unwrap();
+        assert!(change.is_empty());
+
+        mock.set_volume(40);
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "██░░░ 40%");
+
+        thread::sleep(Duration::from_millis(250));
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Artist - Title");
+    }
+
+    #[test]
+    fn idle_format_test() {
+        let mock = MockMpd::start().unwrap();
+        let mut source =
+            source(&mock).with_idle_format(Some("queue empty, add some music".parse().unwrap()));
+        assert_eq!(
+            source.get_initial_content().unwrap().running,
+            "queue empty, add some music"
+        );
+
+        mock.set_queue(vec![song("Artist", "Title")]);
+        mock.play(0);
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Artist - Title");
+
+        mock.set_queue(Vec::new());
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "queue empty, add some music");
+    }
+
+    #[test]
+    fn ignored_changes_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+        ]);
+        mock.play(0);
+        mock.set_volume(50);
+        let mut source = source_with_format(&mock, "{title} {volume}%")
+            .with_ignored_changes(["volume".to_owned()]);
+        source.get_initial_content().unwrap();
+        mock.set_volume(60);
+        let (change, _) = poll(&mut source).unwrap();
+        assert!(change.is_empty());
+
+        // caught up with the next change that counts
+        mock.play(1);
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Title B 60%");
+    }
+
+    #[test]
+    fn identity_changes_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+        ]);
+        mock.play(0);
+        mock.set_volume(50);
+        let source = source_with_format(&mock, "{title} {volume}%");
+        let mut text = RunningText::new(
+            Box::new(source),
+            4,
+            " ".to_owned(),
+            String::new(),
+            true,
+            true,
+        )
+        .unwrap()
+        .with_identity_changes(true);
+        let mut next = || {
+            text.advance().unwrap().unwrap();
+            text.frame().text.to_owned()
+        };
+        for _ in 0..3 {
+            next();
+        }
+        // the volume is updated in place
+        mock.set_volume(60);
+        assert_eq!(next(), "le A");
+        assert_eq!(next(), "e A ");
+        // another song starts over
+        mock.play(1);
+        assert_eq!(next(), "Titl");
+    }
+
+    #[test]
+    fn idle_test() {
+        let mock = MockMpd::start().unwrap();
+        let mut client = Client::connect(mock.addr()).unwrap();
+        let waiting = thread::spawn(move || client.wait(&[Subsystem::Mixer]).unwrap());
+        // changes nobody waits for are not reported
+        mock.set_queue(vec![song("Artist", "Title")]);
+        thread::sleep(Duration::from_millis(50));
+        mock.set_volume(40);
+        assert_eq!(waiting.join().unwrap(), [Subsystem::Mixer]);
+    }
+
+    #[test]
+    fn watch_stops_with_source_test() {
+        let mock = MockMpd::start().unwrap();
+        let mut source = source(&mock);
+        let (sender, receiver) = mpsc::channel();
+        source.watch(Waker(sender)).unwrap();
+        drop(source);
+        // the watching thread drops its waker as it ends, without waiting for an event
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(1)),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn server_features_test() {
+        let mock = MockMpd::start().unwrap();
+        let describe = |mock: &MockMpd| source(mock).describe();
+        assert!(describe(&mock).ends_with("(protocol 0.23.5, without consume oneshot)"));
+        mock.set_version(Version(0, 21, 0));
+        assert!(
+            describe(&mock).ends_with("(protocol 0.21.0, without partitions and consume oneshot)")
+        );
+        // new enough, but not allowed
+        mock.set_version(Version(0, 24, 0));
+        mock.forbid("albumart");
+        assert!(describe(&mock).ends_with("(protocol 0.24.0, without albumart)"));
+    }
+
+    #[test]
+    fn reconnect_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_queue(vec![
+            song("Artist A", "Title A"),
+            song("Artist B", "Title B"),
+        ]);
+        mock.play(0);
+        let mut source = source(&mock);
+        mock.set_down(true);
+        assert!(poll(&mut source).is_err());
+        assert!(poll(&mut source).is_err());
+        // the song changed while the connection was lost
+        mock.play(1);
+        mock.set_down(false);
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Artist B - Title B");
+    }
+
+    #[test]
+    fn down_at_start_test() {
+        let mock = MockMpd::start().unwrap();
+        mock.set_down(true);
+        let mut source = source(&mock);
+        assert!(poll(&mut source).is_err());
+        mock.set_queue(vec![song("Artist", "Title")]);
+        mock.play(0);
+        mock.set_down(false);
+        let (change, content) = poll(&mut source).unwrap();
+        assert!(change.contains(ContentChange::Running));
+        assert_eq!(content, "Artist - Title");
+    }
+}
@@ -1,22 +1,49 @@
+#[cfg(any(feature = "lyrics", feature = "art"))]
+use std::path::{Path, PathBuf};
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::VecDeque,
     error::Error,
     fmt::Display,
     fmt::{self, Write},
-    net::SocketAddr,
+    fs, io, iter, mem,
+    net::{Shutdown, SocketAddr, TcpStream},
     num::ParseIntError,
+    ops::Range,
+    ptr,
     str::FromStr,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use chrono::{
-    format::{Item, StrftimeItems},
-    NaiveTime,
+    format::{DelayedFormat, Item, Numeric, Pad, StrftimeItems},
+    DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime,
 };
-use mpd::{song::QueuePlace, Client, Song, State, Status};
+use mpd::{
+    song::{Id, QueuePlace},
+    Client, Idle, Output, Song, State, Status, Subsystem, Version,
+};
+use unicode_width::UnicodeWidthChar;
 
-use crate::text_source::ContentChange;
+#[cfg(feature = "art")]
+use crate::art::ArtCache;
+#[cfg(feature = "art-color")]
+use crate::art::{dominant_color, Color, ColorFormat};
+#[cfg(feature = "lyrics")]
+use crate::lyrics::Lyrics;
+#[cfg(feature = "serde")]
+use crate::utils::serde_with_str;
+use crate::{
+    events::Waker,
+    hook::Hook,
+    text_source::{Content, ContentChange, RunStats, SourceKind, TextSource},
+};
 
 #[derive(Debug)]
 pub enum IconSetParseError<const N: usize> {
@@ -50,6 +77,13 @@ impl StateStatusIcons {
             State::Pause => self.pause,
         }
     }
+    pub fn write<T: Write>(&self, state: State, pad: usize, f: &mut T) -> fmt::Result {
+        write_icon(self.get_icon(state), pad, f)
+    }
+    /// Display cells taken by the icon of `state` and its padding.
+    pub fn width(&self, state: State, pad: usize) -> usize {
+        icon_width(self.get_icon(state)) + pad
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -68,12 +102,31 @@ impl StatusIcons {
     }
 
     pub fn write<T: Write>(&self, state: bool, pad: usize, f: &mut T) -> std::fmt::Result {
-        if let Some(c) = self.get_icon(state) {
-            write!(f, "{}{}", c, " ".repeat(pad))
-        } else {
-            Ok(())
+        match self.get_icon(state) {
+            Some(c) => write_icon(c, pad, f),
+            None => Ok(()),
         }
     }
+    /// Display cells taken by the icon of `state` and its padding, 0 without an icon.
+    pub fn width(&self, state: bool, pad: usize) -> usize {
+        self.get_icon(state).map_or(0, |c| icon_width(c) + pad)
+    }
+}
+
+/// Shared by all icon paddings, so the common ones are written without formatting.
+const PADDING: &str = "                                ";
+
+fn write_icon<T: Write>(icon: char, pad: usize, f: &mut T) -> fmt::Result {
+    f.write_char(icon)?;
+    match PADDING.get(..pad) {
+        Some(padding) => f.write_str(padding),
+        None => write!(f, "{:pad$}", ""),
+    }
+}
+
+/// Cells a terminal uses for the icon, an emoji takes two.
+fn icon_width(icon: char) -> usize {
+    icon.width().unwrap_or(0)
 }
 
 #[derive(Debug)]
@@ -83,6 +136,8 @@ pub struct StatusIconsSet {
     random: StatusIcons,
     repeat: StatusIcons,
     single: StatusIcons,
+    output: StatusIcons,
+    error: StatusIcons,
 }
 
 impl StatusIconsSet {
@@ -92,6 +147,8 @@ impl StatusIconsSet {
         random_icons: StatusIcons,
         repeat_icons: StatusIcons,
         single_icons: StatusIcons,
+        output_icons: StatusIcons,
+        error_icons: StatusIcons,
     ) -> Self {
         Self {
             state: state_icons,
@@ -99,6 +156,8 @@ impl StatusIconsSet {
             random: random_icons,
             repeat: repeat_icons,
             single: single_icons,
+            output: output_icons,
+            error: error_icons,
         }
     }
 
@@ -108,11 +167,165 @@ impl StatusIconsSet {
             Placeholder::RandomIcon(pad) => self.random.write(value, *pad, f),
             Placeholder::RepeatIcon(pad) => self.repeat.write(value, *pad, f),
             Placeholder::SingleIcon(pad) => self.single.write(value, *pad, f),
+            Placeholder::OutputIcon(_) => self.output.write(value, 0, f),
+            Placeholder::ErrorIcon(pad) => self.error.write(value, *pad, f),
             _ => Ok(()),
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct TimeFormat {
+    spec: String,
+    items: Vec<Item<'static>>,
+}
+
+impl TimeFormat {
+    const DEFAULT: &'static str = "%M:%S";
+    const UPTIME: &'static str = "%H:%M:%S";
+    const DATE: &'static str = "%Y-%m-%d";
+
+    pub fn items(&self) -> &[Item<'static>] {
+        &self.items
+    }
+    /// Writes `d` as a time of day. Hours, minutes and seconds are written directly (hours go past
+    /// 23), chrono's formatter allocates on every call.
+    pub fn write<T: Write>(&self, d: Duration, f: &mut T) -> fmt::Result {
+        let secs = d.as_secs();
+        let time = NaiveTime::from_num_seconds_from_midnight_opt(secs as _, d.subsec_nanos());
+        for item in &self.items {
+            match (item, time) {
+                (Item::Literal(s) | Item::Space(s), _) => f.write_str(s)?,
+                (Item::OwnedLiteral(s) | Item::OwnedSpace(s), _) => f.write_str(s)?,
+                (Item::Numeric(Numeric::Hour, pad), _) => write_padded(secs / 3600, pad, f)?,
+                (Item::Numeric(Numeric::Minute, pad), _) => write_padded(secs / 60 % 60, pad, f)?,
+                (Item::Numeric(Numeric::Second, pad), _) => write_padded(secs % 60, pad, f)?,
+                (item, time) => write!(f, "{}", DelayedFormat::new(None, time, iter::once(item)))?,
+            }
+        }
+        Ok(())
+    }
+    /// Writes a date the way MPD reports it (a year, a month, a day or an RFC 3339 time), a date
+    /// that is none of these is written as it is.
+    pub fn write_date<T: Write>(&self, date: &str, f: &mut T) -> fmt::Result {
+        match parse_date(date) {
+            Some(date) => write!(f, "{}", date.format_with_items(self.items.iter())),
+            None => f.write_str(date),
+        }
+    }
+}
+
+/// Missing months and days count as the first, so `2024` is read as 2024-01-01.
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Some(time.with_timezone(&Local).naive_local());
+    }
+    let mut parts = date.get(..10).unwrap_or(date).splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let mut next = || parts.next().map_or(Ok(1), str::parse);
+    let (month, day) = (next().ok()?, next().ok()?);
+    NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(0, 0, 0)
+}
+
+/// Two digits wide, like chrono pads hours, minutes and seconds.
+fn write_padded<T: Write>(value: u64, pad: &Pad, f: &mut T) -> fmt::Result {
+    match pad {
+        Pad::Zero => write!(f, "{value:02}"),
+        Pad::Space => write!(f, "{value:2}"),
+        Pad::None => write!(f, "{value}"),
+    }
+}
+
+impl Default for TimeFormat {
+    fn default() -> Self {
+        Self::DEFAULT.parse().unwrap()
+    }
+}
+
+impl PartialEq for TimeFormat {
+    fn eq(&self, other: &Self) -> bool {
+        self.items == other.items
+    }
+}
+
+impl FromStr for TimeFormat {
+    type Err = chrono::format::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            items: StrftimeItems::new(s).parse_to_owned()?,
+            spec: s.to_owned(),
+        })
+    }
+}
+
+impl Display for TimeFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+/// How `{volume}` is written: `{volume:pad3,%,muted=mute}` pads the number to 3 characters, adds a
+/// percent sign and shows "mute" at 0 or without a mixer.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VolumeFormat {
+    width: usize,
+    percent: bool,
+    muted: Option<String>,
+}
+
+impl VolumeFormat {
+    fn write<T: Write>(&self, volume: i8, f: &mut T) -> fmt::Result {
+        let width = self.width + usize::from(self.percent);
+        match &self.muted {
+            // MPD reports -1 without a mixer
+            Some(muted) if volume <= 0 => write!(f, "{muted:>width$}"),
+            _ if self.percent => write!(f, "{volume:>0$}%", self.width),
+            _ => write!(f, "{volume:>width$}"),
+        }
+    }
+}
+
+impl FromStr for VolumeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut format = VolumeFormat::default();
+        let mut rest = s;
+        while !rest.is_empty() {
+            // the muted text takes the rest, commas included
+            if let Some(muted) = rest.strip_prefix("muted=") {
+                format.muted = Some(muted.to_owned());
+                break;
+            }
+            let (option, next) = rest.split_once(',').unwrap_or((rest, ""));
+            match option {
+                "%" => format.percent = true,
+                _ => match option.strip_prefix("pad") {
+                    Some(width) => format.width = width.parse()?,
+                    None => anyhow::bail!(
+                        "unknown volume option '{option}', expected %, padN or muted=TEXT"
+                    ),
+                },
+            }
+            rest = next;
+        }
+        Ok(format)
+    }
+}
+
+impl Display for VolumeFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let options = [
+            (self.width != 0).then(|| format!("pad{}", self.width)),
+            self.percent.then(|| "%".to_owned()),
+            self.muted.as_ref().map(|muted| format!("muted={muted}")),
+        ];
+        let options = options.into_iter().flatten().collect::<Vec<_>>();
+        write!(f, "{}", options.join(","))
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Placeholder {
     String(String),
@@ -121,86 +334,502 @@ pub enum Placeholder {
     Album,
     Title,
     Filename,
-    Date,
-    TotalTime(Vec<Item<'static>>),
-    ElapsedTime(Vec<Item<'static>>),
-    Volume,
+    /// The Date tag, as tagged or written with a date format.
+    Date(Option<TimeFormat>),
+    /// When the song file was last modified.
+    LastModified(TimeFormat),
+    TotalTime(TimeFormat),
+    ElapsedTime(TimeFormat),
+    Volume(VolumeFormat),
+    VolumeBar(usize),
     SongPosition,
     QueueLength,
+    /// Position of the current song counted from 1 and the queue length, with the separator.
+    QueueIndex(String),
+    NextArtist,
+    NextTitle,
+    PreviousArtist,
+    PreviousTitle,
     StateIcon(usize),
     ConsumeIcon(usize),
     RandomIcon(usize),
     RepeatIcon(usize),
     SingleIcon(usize),
+    /// Whether MPD reports an error (e.g. an output failing to open).
+    ErrorIcon(usize),
+    MpdError,
+    Outputs,
+    OutputIcon(String),
+    SourceName,
+    Instance,
+    Uptime(TimeFormat),
+    Frame,
+    #[cfg(feature = "lyrics")]
+    Lyrics(usize),
+    #[cfg(feature = "art")]
+    AlbumArt,
+    #[cfg(feature = "art-color")]
+    ArtColor(ColorFormat),
+    If(Box<Condition>),
+    /// `{[}` and `{]}` around text left out when a placeholder in it has no value, like the
+    /// times of a stream or of an empty queue.
+    GroupStart,
+    GroupEnd,
+    /// A placeholder this version doesn't know, kept with its braces by a lenient parse.
+    Unknown(String),
+}
+
+/// `{if:volume=0:muted}` renders the text only when the comparison holds.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Condition {
+    placeholder: Placeholder,
+    op: &'static str,
+    value: String,
+    text: String,
+}
+
+impl Condition {
+    const OPS: [&'static str; 6] = ["!=", "<=", ">=", "=", "<", ">"];
+
+    fn parse(spec: &str) -> Result<Self, MpdFormatParseErrorKind> {
+        let invalid = || MpdFormatParseErrorKind::InvalidCondition(spec.to_owned());
+        let (comparison, text) = spec.split_once(':').ok_or_else(invalid)?;
+        let start = comparison.find(['!', '<', '>', '=']).ok_or_else(invalid)?;
+        let (name, rest) = comparison.split_at(start);
+        let op = Self::OPS
+            .into_iter()
+            .find(|op| rest.starts_with(op))
+            .ok_or_else(invalid)?;
+        let placeholder = Placeholder::all()
+            .into_iter()
+            .find(|ph| ph.name() == name)
+            .ok_or_else(|| MpdFormatParseErrorKind::UnknownPlaceholder(name.to_owned()))?;
+        Ok(Condition {
+            placeholder,
+            op,
+            value: rest[op.len()..].to_owned(),
+            text: text.to_owned(),
+        })
+    }
+
+    fn holds(&self, ctx: &PlaceholderContext) -> bool {
+        let ordering = match self.placeholder.get(ctx) {
+            PlaceholderValue::String(s) | PlaceholderValue::OptionalString(Some(s)) => {
+                self.compare(s)
+            }
+            PlaceholderValue::Volume(v, _) | PlaceholderValue::VolumeBar(v, _) => {
+                self.compare_number(v.into())
+            }
+            PlaceholderValue::OptionalDuration(Some(d), _) => {
+                self.compare_number(d.as_secs() as i64)
+            }
+            PlaceholderValue::OptionalDate(Some(date), format) => {
+                let mut value = String::new();
+                let _ = format.write_date(date, &mut value);
+                self.compare(&value)
+            }
+            PlaceholderValue::OptionalQueueIndex(Some(place), ..) => {
+                self.compare_number(i64::from(place.pos) + 1)
+            }
+            PlaceholderValue::OptionalQueuePlace(Some(place)) => {
+                self.compare_number(place.id.0.into())
+            }
+            PlaceholderValue::Len(l) => self.compare_number(l.into()),
+            PlaceholderValue::Count(c) => self.compare_number(c as i64),
+            PlaceholderValue::Bool(b) | PlaceholderValue::OptionalBool(Some(b)) => {
+                self.compare_number(b.into())
+            }
+            PlaceholderValue::Outputs(Some(outputs)) => {
+                self.compare(&EnabledOutputs(outputs).to_string())
+            }
+            PlaceholderValue::State(state, _) => self.compare(match state {
+                State::Play => "play",
+                State::Pause => "pause",
+                State::Stop => "stop",
+            }),
+            #[cfg(feature = "art-color")]
+            PlaceholderValue::OptionalColor(Some(color), format) => {
+                let mut value = String::new();
+                let _ = format.write(color, &mut value);
+                self.compare(&value)
+            }
+            // nothing to compare with
+            _ => return false,
+        };
+        match self.op {
+            "=" => ordering.is_eq(),
+            "!=" => ordering.is_ne(),
+            "<" => ordering.is_lt(),
+            ">" => ordering.is_gt(),
+            "<=" => ordering.is_le(),
+            _ => ordering.is_ge(),
+        }
+    }
+    /// Numbers are compared as numbers, everything else as text.
+    fn compare(&self, value: &str) -> Ordering {
+        match (value.parse::<i64>(), self.value.parse::<i64>()) {
+            (Ok(number), Ok(expected)) => number.cmp(&expected),
+            _ => value.cmp(&self.value),
+        }
+    }
+    fn compare_number(&self, value: i64) -> Ordering {
+        match self.value.parse::<i64>() {
+            Ok(expected) => value.cmp(&expected),
+            Err(_) => value.to_string().cmp(&self.value),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.placeholder.name();
+        write!(f, "{name}{}{}:{}", self.op, self.value, self.text)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PlaceholderContext<'a> {
+    pub song: Option<&'a Song>,
+    pub status: &'a Status,
+    /// Only fetched when a format needs them.
+    pub outputs: Option<&'a [Output]>,
+    /// Song after the current one, only fetched when a format needs it.
+    pub next_song: Option<&'a Song>,
+    /// Song played before the current one.
+    pub previous_song: Option<&'a Song>,
+    pub source_name: &'a str,
+    pub instance: Option<&'a str>,
+    pub stats: RunStats,
+    #[cfg(feature = "lyrics")]
+    pub lyrics: Option<&'a Lyrics>,
+    #[cfg(feature = "art")]
+    pub album_art: Option<&'a Path>,
+    #[cfg(feature = "art-color")]
+    pub art_color: Option<Color>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum PlaceholderValue<'a> {
     String(&'a str),
     OptionalString(Option<&'a str>),
-    Volume(i8),
-    OptionalDuration(Option<Duration>, &'a Vec<Item<'static>>),
+    Volume(i8, &'a VolumeFormat),
+    VolumeBar(i8, usize),
+    OptionalDuration(Option<Duration>, &'a TimeFormat),
+    OptionalDate(Option<&'a str>, &'a TimeFormat),
     OptionalQueuePlace(Option<QueuePlace>),
+    OptionalQueueIndex(Option<QueuePlace>, u32, &'a str),
     Len(u32),
+    Count(usize),
     Bool(bool),
+    OptionalBool(Option<bool>),
+    Outputs(Option<&'a [Output]>),
     State(State, usize),
+    #[cfg(feature = "art-color")]
+    OptionalColor(Option<Color>, ColorFormat),
+}
+
+impl PlaceholderValue<'_> {
+    /// Whether there is nothing to show, so the default is written instead.
+    fn is_missing(&self) -> bool {
+        match self {
+            PlaceholderValue::OptionalString(op) => op.is_none(),
+            // MPD reports -1 without a mixer
+            PlaceholderValue::Volume(v, _) | PlaceholderValue::VolumeBar(v, _) => *v < 0,
+            PlaceholderValue::OptionalDuration(op, _) => op.is_none(),
+            PlaceholderValue::OptionalDate(op, _) => op.is_none(),
+            PlaceholderValue::OptionalQueuePlace(op)
+            | PlaceholderValue::OptionalQueueIndex(op, ..) => op.is_none(),
+            PlaceholderValue::OptionalBool(op) => op.is_none(),
+            PlaceholderValue::Outputs(op) => op.is_none(),
+            #[cfg(feature = "art-color")]
+            PlaceholderValue::OptionalColor(op, _) => op.is_none(),
+            _ => false,
+        }
+    }
 }
 
 impl Placeholder {
-    pub fn get<'a>(&'a self, song: Option<&'a Song>, status: &Status) -> PlaceholderValue<'a> {
-        let mut tags: HashMap<&str, &str> = song
-            .map(|s| {
-                s.tags
-                    .iter()
-                    .map(|(k, v)| (k.as_str(), v.as_str()))
-                    .collect()
-            })
-            .unwrap_or_default();
+    const VOLUME_BAR_WIDTH: usize = 10;
+    const QUEUE_INDEX_SEPARATOR: &'static str = "/";
+
+    /// Every placeholder with its default spec, names are looked up here when parsing.
+    pub fn all() -> Vec<Placeholder> {
+        vec![
+            Placeholder::Album,
+            Placeholder::AlbumArtist,
+            Placeholder::Artist,
+            Placeholder::ConsumeIcon(0),
+            Placeholder::Date(None),
+            Placeholder::ElapsedTime(TimeFormat::default()),
+            Placeholder::ErrorIcon(0),
+            Placeholder::Filename,
+            Placeholder::Frame,
+            Placeholder::Instance,
+            Placeholder::LastModified(TimeFormat::DATE.parse().unwrap()),
+            Placeholder::MpdError,
+            Placeholder::NextArtist,
+            Placeholder::NextTitle,
+            Placeholder::Outputs,
+            Placeholder::PreviousArtist,
+            Placeholder::PreviousTitle,
+            Placeholder::QueueIndex(Placeholder::QUEUE_INDEX_SEPARATOR.to_owned()),
+            Placeholder::QueueLength,
+            Placeholder::RandomIcon(0),
+            Placeholder::RepeatIcon(0),
+            Placeholder::SingleIcon(0),
+            Placeholder::SongPosition,
+            Placeholder::SourceName,
+            Placeholder::StateIcon(0),
+            Placeholder::Title,
+            Placeholder::TotalTime(TimeFormat::default()),
+            Placeholder::Uptime(TimeFormat::UPTIME.parse().unwrap()),
+            Placeholder::Volume(VolumeFormat::default()),
+            Placeholder::VolumeBar(Placeholder::VOLUME_BAR_WIDTH),
+            #[cfg(feature = "lyrics")]
+            Placeholder::Lyrics(0),
+            #[cfg(feature = "art")]
+            Placeholder::AlbumArt,
+            #[cfg(feature = "art-color")]
+            Placeholder::ArtColor(ColorFormat::default()),
+        ]
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Placeholder::String(_) => "",
+            Placeholder::Album => "album",
+            Placeholder::AlbumArtist => "albumArtist",
+            Placeholder::Artist => "artist",
+            Placeholder::ConsumeIcon(_) => "consumeIcon",
+            Placeholder::Date(_) => "date",
+            Placeholder::ElapsedTime(_) => "elapsedTime",
+            Placeholder::Filename => "filename",
+            Placeholder::Instance => "instance",
+            Placeholder::LastModified(_) => "lastModified",
+            Placeholder::Frame => "frame",
+            Placeholder::NextArtist => "nextArtist",
+            Placeholder::NextTitle => "nextTitle",
+            Placeholder::PreviousArtist => "previousArtist",
+            Placeholder::PreviousTitle => "previousTitle",
+            Placeholder::Outputs => "outputs",
+            Placeholder::OutputIcon(_) => "outputIcon",
+            Placeholder::QueueLength => "queueLength",
+            Placeholder::QueueIndex(_) => "queueIndex",
+            Placeholder::RandomIcon(_) => "randomIcon",
+            Placeholder::RepeatIcon(_) => "repeatIcon",
+            Placeholder::SingleIcon(_) => "singleIcon",
+            Placeholder::ErrorIcon(_) => "errorIcon",
+            Placeholder::MpdError => "mpdError",
+            Placeholder::SongPosition => "songPosition",
+            Placeholder::SourceName => "sourceName",
+            Placeholder::StateIcon(_) => "stateIcon",
+            Placeholder::Title => "title",
+            Placeholder::TotalTime(_) => "totalTime",
+            Placeholder::Uptime(_) => "uptime",
+            Placeholder::Volume(_) => "volume",
+            Placeholder::VolumeBar(_) => "volumeBar",
+            #[cfg(feature = "lyrics")]
+            Placeholder::Lyrics(_) => "lyrics",
+            #[cfg(feature = "art")]
+            Placeholder::AlbumArt => "albumArt",
+            #[cfg(feature = "art-color")]
+            Placeholder::ArtColor(_) => "artColor",
+            Placeholder::If(_) => "if",
+            Placeholder::GroupStart => "[",
+            Placeholder::GroupEnd => "]",
+            Placeholder::Unknown(_) => "",
+        }
+    }
+    /// What can follow the name after a ':'.
+    pub fn spec(&self) -> Option<&'static str> {
+        match self {
+            Placeholder::ElapsedTime(_) | Placeholder::TotalTime(_) => {
+                Some("time format (default %M:%S)")
+            }
+            Placeholder::Uptime(_) => Some("time format (default %H:%M:%S)"),
+            Placeholder::Date(_) => Some("date format, e.g. %Y (default as tagged)"),
+            Placeholder::LastModified(_) => Some("date format (default %Y-%m-%d)"),
+            Placeholder::ConsumeIcon(_)
+            | Placeholder::RandomIcon(_)
+            | Placeholder::RepeatIcon(_)
+            | Placeholder::SingleIcon(_)
+            | Placeholder::ErrorIcon(_)
+            | Placeholder::StateIcon(_) => Some("padding"),
+            Placeholder::OutputIcon(_) => Some("output name"),
+            Placeholder::Volume(_) => Some("%, padN and muted=TEXT separated by commas"),
+            Placeholder::VolumeBar(_) => Some("width (default 10)"),
+            Placeholder::QueueIndex(_) => Some("separator (default /)"),
+            #[cfg(feature = "lyrics")]
+            Placeholder::Lyrics(_) => Some("line count"),
+            #[cfg(feature = "art-color")]
+            Placeholder::ArtColor(_) => Some("hex (rrggbb) or rgb (r;g;b), default #rrggbb"),
+            Placeholder::If(_) => Some("condition and text, e.g. volume=0:muted"),
+            _ => None,
+        }
+    }
+    pub fn get<'a>(&'a self, ctx: &PlaceholderContext<'a>) -> PlaceholderValue<'a> {
+        let PlaceholderContext { song, status, .. } = *ctx;
+        // the last value of a repeated tag wins
+        let tag = |name: &str| {
+            let (_, value) = song?.tags.iter().rev().find(|(k, _)| k == name)?;
+            Some(value.as_str())
+        };
         match self {
             Placeholder::String(s) => PlaceholderValue::String(s),
             Placeholder::Artist => PlaceholderValue::OptionalString(
                 song.map(|s| s.artist.as_deref()).unwrap_or_default(),
             ),
-            Placeholder::AlbumArtist => {
-                PlaceholderValue::OptionalString(tags.remove("AlbumArtist"))
-            }
-            Placeholder::Album => PlaceholderValue::OptionalString(tags.remove("Album")),
+            Placeholder::AlbumArtist => PlaceholderValue::OptionalString(tag("AlbumArtist")),
+            Placeholder::Album => PlaceholderValue::OptionalString(tag("Album")),
             Placeholder::Title => PlaceholderValue::OptionalString(
                 song.map(|s| s.title.as_deref()).unwrap_or_default(),
             ),
             Placeholder::Filename => {
                 PlaceholderValue::OptionalString(song.map(|s| s.file.as_str()))
             }
-            Placeholder::Date => PlaceholderValue::OptionalString(tags.remove("Date")),
-            Placeholder::Volume => PlaceholderValue::Volume(status.volume),
+            Placeholder::Date(None) => PlaceholderValue::OptionalString(tag("Date")),
+            Placeholder::Date(Some(fmt)) => PlaceholderValue::OptionalDate(tag("Date"), fmt),
+            Placeholder::LastModified(fmt) => {
+                PlaceholderValue::OptionalDate(song.and_then(|s| s.last_mod.as_deref()), fmt)
+            }
+            Placeholder::Volume(format) => PlaceholderValue::Volume(status.volume, format),
+            Placeholder::VolumeBar(width) => PlaceholderValue::VolumeBar(status.volume, *width),
             Placeholder::ElapsedTime(fmt) => {
                 PlaceholderValue::OptionalDuration(status.elapsed, fmt)
             }
             Placeholder::TotalTime(fmt) => PlaceholderValue::OptionalDuration(status.duration, fmt),
             Placeholder::SongPosition => PlaceholderValue::OptionalQueuePlace(status.song),
             Placeholder::QueueLength => PlaceholderValue::Len(status.queue_len),
+            Placeholder::QueueIndex(separator) => {
+                PlaceholderValue::OptionalQueueIndex(status.song, status.queue_len, separator)
+            }
+            Placeholder::NextArtist => {
+                PlaceholderValue::OptionalString(ctx.next_song.and_then(|s| s.artist.as_deref()))
+            }
+            Placeholder::NextTitle => {
+                PlaceholderValue::OptionalString(ctx.next_song.and_then(|s| s.title.as_deref()))
+            }
+            Placeholder::PreviousArtist => PlaceholderValue::OptionalString(
+                ctx.previous_song.and_then(|s| s.artist.as_deref()),
+            ),
+            Placeholder::PreviousTitle => {
+                PlaceholderValue::OptionalString(ctx.previous_song.and_then(|s| s.title.as_deref()))
+            }
             Placeholder::StateIcon(pad) => PlaceholderValue::State(status.state, *pad),
             Placeholder::ConsumeIcon(_) => PlaceholderValue::Bool(status.consume),
             Placeholder::RandomIcon(_) => PlaceholderValue::Bool(status.random),
             Placeholder::RepeatIcon(_) => PlaceholderValue::Bool(status.repeat),
             Placeholder::SingleIcon(_) => PlaceholderValue::Bool(status.single),
+            Placeholder::ErrorIcon(_) => PlaceholderValue::Bool(status.error.is_some()),
+            // no error is nothing to show, not a missing value
+            Placeholder::MpdError => {
+                PlaceholderValue::String(status.error.as_deref().unwrap_or(""))
+            }
+            Placeholder::Outputs => PlaceholderValue::Outputs(ctx.outputs),
+            Placeholder::SourceName => PlaceholderValue::String(ctx.source_name),
+            Placeholder::Instance => PlaceholderValue::OptionalString(ctx.instance),
+            Placeholder::Uptime(fmt) => {
+                PlaceholderValue::OptionalDuration(Some(ctx.stats.uptime), fmt)
+            }
+            Placeholder::Frame => PlaceholderValue::Count(ctx.stats.frame),
+            Placeholder::OutputIcon(name) => PlaceholderValue::OptionalBool(
+                ctx.outputs
+                    .and_then(|outputs| outputs.iter().find(|o| &o.name == name))
+                    .map(|o| o.enabled),
+            ),
+            #[cfg(feature = "lyrics")]
+            Placeholder::Lyrics(count) => PlaceholderValue::OptionalString(
+                ctx.lyrics.and_then(|l| l.lines_at(status.elapsed, *count)),
+            ),
+            #[cfg(feature = "art")]
+            Placeholder::AlbumArt => {
+                PlaceholderValue::OptionalString(ctx.album_art.and_then(Path::to_str))
+            }
+            #[cfg(feature = "art-color")]
+            Placeholder::ArtColor(format) => {
+                PlaceholderValue::OptionalColor(ctx.art_color, *format)
+            }
+            Placeholder::If(cond) => {
+                PlaceholderValue::String(if cond.holds(ctx) { &cond.text } else { "" })
+            }
+            Placeholder::GroupStart | Placeholder::GroupEnd => PlaceholderValue::String(""),
+            Placeholder::Unknown(literal) => PlaceholderValue::String(literal),
         }
     }
+    fn needs_outputs(&self) -> bool {
+        match self {
+            Placeholder::Outputs | Placeholder::OutputIcon(_) => true,
+            Placeholder::If(cond) => cond.placeholder.needs_outputs(),
+            _ => false,
+        }
+    }
+    fn needs_next_song(&self) -> bool {
+        match self {
+            Placeholder::NextArtist | Placeholder::NextTitle => true,
+            Placeholder::If(cond) => cond.placeholder.needs_next_song(),
+            _ => false,
+        }
+    }
+    #[cfg(feature = "art")]
+    fn needs_art(&self) -> bool {
+        match self {
+            Placeholder::AlbumArt => true,
+            #[cfg(feature = "art-color")]
+            Placeholder::ArtColor(_) => true,
+            Placeholder::If(cond) => cond.placeholder.needs_art(),
+            _ => false,
+        }
+    }
+}
+
+/// Whether a song is playing its last `within`.
+fn is_ending(status: &Status, within: Duration) -> bool {
+    status.state == State::Play
+        && status
+            .duration
+            .zip(status.elapsed)
+            .is_some_and(|(total, elapsed)| total.saturating_sub(elapsed) <= within)
+}
+
+/// Names of enabled outputs separated by commas.
+struct EnabledOutputs<'a>(&'a [Output]);
+
+impl Display for EnabledOutputs<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, output) in self.0.iter().filter(|o| o.enabled).enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(&output.name)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct MpdFormatter(Vec<Placeholder>);
 
 #[derive(Debug)]
-pub enum MpdFormatParseError {
+pub enum MpdFormatParseErrorKind {
     UnknownPlaceholder(String),
     RedundantFormat(String),
     DurationParseError(chrono::format::ParseError),
+    DateFormatParseError(chrono::format::ParseError),
     PadParseError(ParseIntError),
+    WidthParseError(ParseIntError),
+    VolumeFormatParseError(anyhow::Error),
+    #[cfg(feature = "lyrics")]
+    LineCountParseError(ParseIntError),
+    #[cfg(feature = "art-color")]
+    ColorFormatParseError(anyhow::Error),
     UnmatchedParenthesis,
+    UnmatchedGroup,
+    IncludeError(String, io::Error),
+    IncludedFormatError(String, Box<MpdFormatParseError>),
+    IncludeDepthExceeded(String),
+    InvalidCondition(String),
 }
 
-impl Display for MpdFormatParseError {
+impl Display for MpdFormatParseErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::UnknownPlaceholder(placeholder) => {
@@ -212,107 +841,1205 @@ impl Display for MpdFormatParseError {
             Self::DurationParseError(e) => {
                 write!(f, "Invalid duration format: {e}")
             }
+            Self::DateFormatParseError(e) => write!(f, "Invalid date format: {e}"),
             Self::PadParseError(e) => write!(f, "Padding parse error: {e}"),
+            Self::WidthParseError(e) => write!(f, "Width parse error: {e}"),
+            Self::VolumeFormatParseError(e) => write!(f, "Volume format parse error: {e}"),
+            #[cfg(feature = "lyrics")]
+            Self::LineCountParseError(e) => write!(f, "Line count parse error: {e}"),
+            #[cfg(feature = "art-color")]
+            Self::ColorFormatParseError(e) => write!(f, "Color format parse error: {e}"),
             Self::UnmatchedParenthesis => write!(f, "Unmatched '{{' or '}}"),
+            Self::UnmatchedGroup => write!(f, "Unmatched '{{[}}' or '{{]}}'"),
+            Self::IncludeError(path, e) => write!(f, "Failed to include '{path}': {e}"),
+            Self::IncludedFormatError(path, e) => {
+                write!(f, "In format included from '{path}': {e}")
+            }
+            Self::IncludeDepthExceeded(path) => write!(
+                f,
+                "Too many nested includes (max {MAX_INCLUDE_DEPTH}) while including '{path}'"
+            ),
+            Self::InvalidCondition(cond) => write!(
+                f,
+                "Invalid condition '{cond}' (expected NAME<OP>VALUE:TEXT, OP is one of = != < > <= >=)"
+            ),
         }
     }
 }
+
+#[derive(Debug)]
+pub struct MpdFormatParseError {
+    kind: MpdFormatParseErrorKind,
+    span: Range<usize>,
+    format: String,
+}
+
+impl MpdFormatParseError {
+    fn new(kind: MpdFormatParseErrorKind, span: Range<usize>, format: &str) -> Self {
+        Self {
+            kind,
+            span,
+            format: format.to_owned(),
+        }
+    }
+}
+
+impl Display for MpdFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let line_start = self.format[..self.span.start]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let line_end = self.format[self.span.start..]
+            .find('\n')
+            .map_or(self.format.len(), |i| self.span.start + i);
+        let span_end = self.span.end.min(line_end);
+        let column = self.format[line_start..self.span.start].chars().count();
+        let width = self.format[self.span.start..span_end]
+            .chars()
+            .count()
+            .max(1);
+        writeln!(f, "{} (at position {})", self.kind, self.span.start)?;
+        writeln!(f, "  {}", &self.format[line_start..line_end])?;
+        write!(f, "  {}{}", " ".repeat(column), "^".repeat(width))
+    }
+}
 impl Error for MpdFormatParseError {}
 
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+fn warn_unknown(format: &MpdFormatter) {
+    for placeholder in format.unknown_placeholders() {
+        log::warn!("Unknown placeholder {placeholder} is shown as it is");
+    }
+}
+
+/// How a format is parsed, [`FromStr`] parses with the defaults.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    /// Show unknown placeholders as they are instead of failing, so a config written for a newer
+    /// version still runs.
+    pub lenient: bool,
+}
+
+/// Playback controls for interactive outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerCommand {
+    Toggle,
+    Next,
+    Prev,
+}
+
+/// Protocol additions that older servers reject.
+///
+/// Only album art is asked for so far. The status of partitions and of the oneshot consume mode
+/// is dropped by the `mpd` crate (`consume: oneshot` is read as off), so they are only detected
+/// and reported for now, placeholders showing them would need the raw status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerFeature {
+    AlbumArt,
+    Partitions,
+    ConsumeOneshot,
+}
+
+impl ServerFeature {
+    pub const ALL: [ServerFeature; 3] = [
+        ServerFeature::AlbumArt,
+        ServerFeature::Partitions,
+        ServerFeature::ConsumeOneshot,
+    ];
+
+    pub fn min_version(self) -> Version {
+        match self {
+            ServerFeature::AlbumArt => Version(0, 21, 0),
+            ServerFeature::Partitions => Version(0, 22, 0),
+            ServerFeature::ConsumeOneshot => Version(0, 24, 0),
+        }
+    }
+    /// The command that has to be allowed for the feature, a server may forbid it to a client
+    /// without the permission even when its version has it.
+    pub fn command(self) -> &'static str {
+        match self {
+            ServerFeature::AlbumArt => "albumart",
+            ServerFeature::Partitions => "partition",
+            ServerFeature::ConsumeOneshot => "consume",
+        }
+    }
+    /// Features of the server `client` is connected to, judged by its version and the commands
+    /// it allows.
+    pub fn detect(client: &mut Client) -> Vec<ServerFeature> {
+        let version = client.version;
+        let commands = client
+            .commands()
+            .inspect_err(|e| log::debug!("Failed listing MPD commands: {e}"))
+            .ok();
+        ServerFeature::ALL
+            .into_iter()
+            .filter(|feature| version >= feature.min_version())
+            .filter(|feature| {
+                commands
+                    .as_ref()
+                    .is_none_or(|commands| commands.iter().any(|c| c == feature.command()))
+            })
+            .collect()
+    }
+}
+
+impl Display for ServerFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ServerFeature::AlbumArt => "albumart",
+            ServerFeature::Partitions => "partitions",
+            ServerFeature::ConsumeOneshot => "consume oneshot",
+        })
+    }
+}
+
+fn version_string(Version(major, minor, patch): Version) -> String {
+    format!("{major}.{minor}.{patch}")
+}
+
+/// The protocol version along with the features the server lacks, e.g. `0.21.3, without
+/// partitions and consume oneshot`.
+fn protocol_string(version: Version, features: &[ServerFeature]) -> String {
+    let mut protocol = version_string(version);
+    let missing = ServerFeature::ALL
+        .into_iter()
+        .filter(|feature| !features.contains(feature))
+        .map(|feature| feature.to_string())
+        .collect::<Vec<_>>();
+    if let Some((last, rest)) = missing.split_last() {
+        protocol.push_str(", without ");
+        if !rest.is_empty() {
+            protocol.push_str(&rest.join(", "));
+            protocol.push_str(" and ");
+        }
+        protocol.push_str(last);
+    }
+    protocol
+}
+
 #[derive(Debug)]
 pub struct MpdSource {
-    client: Client,
+    addr: SocketAddr,
+    /// Missing until the first successful connection.
+    client: Option<Client>,
+    version: Version,
+    /// What the server supports, detected on every connection.
+    features: Vec<ServerFeature>,
+    disconnected: bool,
+    reconnects: u64,
+    watcher: Option<Watcher>,
+    waker: Option<Waker>,
     current_song: Option<Song>,
     current_status: Status,
+    /// Queries MPD at most this often unless it reports a change, elapsed time is counted
+    /// in between.
+    query_interval: Option<Duration>,
+    last_query: Option<Instant>,
+    /// Elapsed time of the song in the last answer.
+    queried_elapsed: Option<Duration>,
+    /// Set by the watcher when MPD reports a change.
+    changed: Arc<AtomicBool>,
+    outputs: Option<Vec<Output>>,
+    track_outputs: bool,
+    /// How long `outputs` are reused unless the watcher reports a change of them.
+    outputs_ttl: Option<Duration>,
+    outputs_queried: Option<Instant>,
+    outputs_changed: Arc<AtomicBool>,
+    next_song: Option<Song>,
+    track_next_song: bool,
+    up_next: Option<UpNext>,
+    volume_flash: Option<VolumeFlash>,
+    /// Running text format used while the queue is empty.
+    idle_format: Option<MpdFormatter>,
+    /// Running text format used while MPD crossfades into the next song.
+    crossfade_format: Option<MpdFormatter>,
+    /// Names of placeholders whose changes alone don't re-render anything.
+    ignored_changes: Vec<String>,
+    /// Songs played before the current one, most recent first.
+    history: VecDeque<PlayedSong>,
+    history_len: usize,
+    song_started: DateTime<Local>,
     running_format: MpdFormatter,
-    prefix_format: MpdFormatter,
-    suffix_format: MpdFormatter,
+    prefix_format: StateFormats,
+    suffix_format: StateFormats,
+    separator_format: Option<StateFormats>,
+    separator: String,
     icons: StatusIconsSet,
     default_placeholder: String,
+    instance: Option<String>,
+    notification: Option<SongNotification>,
+    /// Stats the content was last rendered with and the ones for the next poll.
+    stats: RunStats,
+    next_stats: RunStats,
+    #[cfg(feature = "waybar")]
+    upcoming: Option<UpcomingSongs>,
+    #[cfg(feature = "lyrics")]
+    music_dir: Option<PathBuf>,
+    #[cfg(feature = "lyrics")]
+    lyrics: Option<Lyrics>,
+    #[cfg(feature = "art")]
+    art_cache: ArtCache,
+    #[cfg(feature = "art")]
+    track_art: bool,
+    /// Cache key of the song `album_art` belongs to.
+    #[cfg(feature = "art")]
+    art_key: Option<String>,
+    #[cfg(feature = "art")]
+    album_art: Option<PathBuf>,
+    #[cfg(feature = "art-color")]
+    art_color: Option<Color>,
+}
+
+/// Desktop notification for a new song, changes coming quicker than `interval` are merged into
+/// one notification for the last song.
+#[derive(Debug)]
+struct SongNotification {
+    hook: Hook,
+    interval: Duration,
+    last: Option<Instant>,
+    pending: bool,
+}
+
+const NOTIFY_COMMAND: &str = r#"notify-send -a mergneh ${MERGNEH_ALBUM_ART:+-i "$MERGNEH_ALBUM_ART"} "${MERGNEH_TITLE:-$MERGNEH_FILENAME}" "$MERGNEH_ARTIST""#;
+
+#[derive(Debug)]
+struct PlayedSong {
+    song: Song,
+    started: DateTime<Local>,
+}
+
+/// Running text replaced with `format` for `duration` after the volume changes.
+#[derive(Debug)]
+struct VolumeFlash {
+    duration: Duration,
+    format: MpdFormatter,
+    until: Option<Instant>,
+    /// Whether the last rendered text is the flash.
+    shown: bool,
+}
+
+/// Suffix replaced with the next song during the last `within` of the current one.
+#[derive(Debug)]
+struct UpNext {
+    within: Duration,
+    format: MpdFormatter,
+}
+
+#[cfg(feature = "waybar")]
+#[derive(Debug)]
+struct UpcomingSongs {
+    queue_version: u32,
+    start: usize,
+    songs: Vec<Song>,
+}
+
+/// The connection a thread waits on for MPD's events, shut down along with the source so the
+/// thread ends right away rather than on the next event. The thread blocks in `idle` and
+/// can't be cancelled otherwise, see [`crate::events::EventLoop`] for why it isn't a task.
+#[derive(Debug)]
+struct Watcher {
+    stream: TcpStream,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, AtomicOrdering::Relaxed);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
 }
 
 impl MpdSource {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         addr: SocketAddr,
         fmt: MpdFormatter,
-        prefix: MpdFormatter,
-        suffix: MpdFormatter,
+        prefix: StateFormats,
+        suffix: StateFormats,
+        separator: Option<StateFormats>,
         icons: StatusIconsSet,
         default_placeholder: String,
+        #[cfg(feature = "lyrics")] music_dir: Option<PathBuf>,
+        #[cfg(feature = "art")] art_cache: ArtCache,
     ) -> anyhow::Result<Self> {
-        let mut client = Client::connect(addr).context("MPD connection error")?;
-        Ok(Self {
-            current_song: client.currentsong().context("MPD server error")?,
-            current_status: client.status().context("MPD server error")?,
+        let formats = || {
+            [&fmt]
+                .into_iter()
+                .chain(prefix.iter())
+                .chain(suffix.iter())
+                .chain(separator.iter().flat_map(StateFormats::iter))
+        };
+        formats().for_each(warn_unknown);
+        let track_outputs = formats().any(MpdFormatter::needs_outputs);
+        let track_next_song = formats().any(MpdFormatter::needs_next_song);
+        #[cfg(feature = "art")]
+        let track_art = formats().any(|format| format.iter().any(Placeholder::needs_art));
+        let mut client = match Client::connect(addr) {
+            Ok(client) => Some(client),
+            // the error is shown until the server comes up
+            Err(e) => {
+                log::warn!("MPD connection error: {e}");
+                None
+            }
+        };
+        let mut source = Self {
+            #[cfg(feature = "lyrics")]
+            lyrics: None,
+            #[cfg(feature = "lyrics")]
+            music_dir,
+            current_song: None,
+            current_status: Status::default(),
+            query_interval: None,
+            last_query: None,
+            queried_elapsed: None,
+            changed: Arc::new(AtomicBool::new(false)),
+            outputs: None,
+            track_outputs,
+            outputs_ttl: None,
+            outputs_queried: None,
+            outputs_changed: Arc::new(AtomicBool::new(false)),
+            next_song: None,
+            track_next_song,
+            up_next: None,
+            volume_flash: None,
+            idle_format: None,
+            crossfade_format: None,
+            ignored_changes: Vec::new(),
+            history: VecDeque::new(),
+            // enough for {previousTitle}, a history tooltip asks for more
+            history_len: 1,
+            song_started: Local::now(),
+            addr,
+            version: client.as_ref().map_or(Version(0, 0, 0), |c| c.version),
+            features: client
+                .as_mut()
+                .map(ServerFeature::detect)
+                .unwrap_or_default(),
+            disconnected: client.is_none(),
+            reconnects: 0,
             client,
+            watcher: None,
+            waker: None,
             running_format: fmt,
             prefix_format: prefix,
             suffix_format: suffix,
+            separator_format: separator,
+            separator: String::new(),
             icons,
             default_placeholder,
+            instance: None,
+            notification: None,
+            stats: RunStats::default(),
+            next_stats: RunStats::default(),
+            #[cfg(feature = "waybar")]
+            upcoming: None,
+            #[cfg(feature = "art")]
+            art_cache,
+            #[cfg(feature = "art")]
+            track_art,
+            #[cfg(feature = "art")]
+            art_key: None,
+            #[cfg(feature = "art")]
+            album_art: None,
+            #[cfg(feature = "art-color")]
+            art_color: None,
+        };
+        if let Some(client) = &source.client {
+            log::info!(
+                "Connected to MPD at {addr} (protocol {})",
+                protocol_string(client.version, &source.features)
+            );
+            source.load_state()?;
+        }
+        Ok(source)
+    }
+    /// Fetches what the formats show without rendering anything.
+    fn load_state(&mut self) -> anyhow::Result<()> {
+        let lookup_outputs = self.is_outputs_lookup_due();
+        let client = self.client()?;
+        let song = client.currentsong().context("MPD server error")?;
+        let status = client.status().context("MPD server error")?;
+        let outputs = match lookup_outputs {
+            true => Some(client.outputs().context("MPD server error")?),
+            false => None,
+        };
+        if lookup_outputs {
+            self.outputs_queried = Some(Instant::now());
+        }
+        #[cfg(feature = "lyrics")]
+        {
+            self.lyrics = load_lyrics(self.music_dir.as_deref(), song.as_ref());
+        }
+        #[cfg(feature = "art")]
+        if let Some((key, path)) = self.next_art(song.as_ref()) {
+            #[cfg(feature = "art-color")]
+            {
+                self.art_color = path.as_deref().and_then(dominant_color);
+            }
+            (self.art_key, self.album_art) = (key, path);
+        }
+        self.queried(&status);
+        self.next_song = self.fetch_next_song(&status)?;
+        (self.current_song, self.current_status, self.outputs) = (song, status, outputs);
+        Ok(())
+    }
+    /// Asks for the song after the current one unless the queue and the place are unchanged.
+    fn fetch_next_song(&mut self, status: &Status) -> anyhow::Result<Option<Song>> {
+        let Some(place) = status.nextsong.filter(|_| self.track_next_song) else {
+            return Ok(None);
+        };
+        if status.queue_version == self.current_status.queue_version
+            && self.next_song.as_ref().and_then(|s| s.place) == Some(place)
+        {
+            return Ok(self.next_song.clone());
+        }
+        self.client()?.playlistid(place.id).map_err(|e| {
+            self.disconnected = matches!(e, mpd::error::Error::Io(_));
+            anyhow::Error::new(e).context("MPD server error")
+        })
+    }
+    fn queried(&mut self, status: &Status) {
+        self.last_query = Some(Instant::now());
+        self.queried_elapsed = status.elapsed;
+        self.changed.store(false, AtomicOrdering::Relaxed);
+    }
+    /// Whether MPD has to be asked, otherwise the last answer is still good enough.
+    fn is_query_due(&self) -> bool {
+        match (self.query_interval, self.last_query) {
+            (Some(interval), Some(last)) => {
+                last.elapsed() >= interval || self.changed.load(AtomicOrdering::Relaxed)
+            }
+            _ => true,
+        }
+    }
+    /// Whether the outputs have to be asked for, rather than reused from the last answer.
+    fn is_outputs_lookup_due(&self) -> bool {
+        if !self.track_outputs {
+            return false;
+        }
+        match (self.outputs_ttl, self.outputs_queried) {
+            (Some(ttl), Some(last)) => {
+                last.elapsed() >= ttl || self.outputs_changed.swap(false, AtomicOrdering::Relaxed)
+            }
+            _ => true,
+        }
+    }
+    /// The last status with the elapsed time of a playing song counted up to now.
+    fn interpolated_status(&self) -> Status {
+        let mut status = self.current_status.clone();
+        if let (State::Play, Some(last)) = (status.state, self.last_query) {
+            status.elapsed = self.queried_elapsed.map(|elapsed| {
+                let elapsed = elapsed + last.elapsed();
+                status.duration.map_or(elapsed, |total| elapsed.min(total))
+            });
+        }
+        status
+    }
+    fn query(&mut self) -> anyhow::Result<(Option<Song>, Status, Option<Vec<Output>>)> {
+        let lookup_outputs = self.is_outputs_lookup_due();
+        let cached_outputs = self.outputs.clone();
+        let client = self.client()?;
+        match client.currentsong().and_then(|song| {
+            let status = client.status()?;
+            let outputs = match lookup_outputs {
+                true => Some(client.outputs()?),
+                false => cached_outputs,
+            };
+            Ok((song, status, outputs))
+        }) {
+            Ok(state) => {
+                self.queried(&state.1);
+                if lookup_outputs {
+                    self.outputs_queried = Some(Instant::now());
+                }
+                Ok(state)
+            }
+            Err(e) => {
+                self.disconnected = matches!(e, mpd::error::Error::Io(_));
+                Err(anyhow::Error::new(e).context("MPD server error"))
+            }
+        }
+    }
+    fn client(&mut self) -> anyhow::Result<&mut Client> {
+        self.client
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to MPD"))
+    }
+    pub fn get(
+        &mut self,
+        content: &mut String,
+        prefix: &mut String,
+        suffix: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        // the status before the first answer is made up
+        let known = self.last_query.is_some();
+        if self.disconnected {
+            log::info!("Reconnecting to MPD at {}", self.addr);
+            let mut client = Client::connect(self.addr).context("MPD connection error")?;
+            self.features = ServerFeature::detect(&mut client);
+            match self.version {
+                // the server was down at startup
+                Version(0, 0, 0) => log::info!(
+                    "Connected to MPD at {} (protocol {})",
+                    self.addr,
+                    protocol_string(client.version, &self.features)
+                ),
+                // the server was upgraded or replaced while we were away
+                version if version != client.version => log::info!(
+                    "MPD protocol changed from {} to {}",
+                    version_string(version),
+                    protocol_string(client.version, &self.features)
+                ),
+                _ => {}
+            }
+            self.version = client.version;
+            self.client = Some(client);
+            self.disconnected = false;
+            self.reconnects += 1;
+            // anything could have changed while we were away
+            self.outputs_queried = None;
+            if let Some(waker) = self.waker.take() {
+                self.watch(waker)?;
+            }
+        }
+        let (song, status, outputs) = match self.is_query_due() {
+            true => self.query()?,
+            false => (
+                self.current_song.clone(),
+                self.interpolated_status(),
+                self.outputs.clone(),
+            ),
+        };
+        let next_song = self.fetch_next_song(&status)?;
+        let (was_flashing, flashing) = match &mut self.volume_flash {
+            Some(flash) => {
+                if known && self.current_status.volume != status.volume {
+                    flash.until = Some(Instant::now() + flash.duration);
+                }
+                let flashing = flash.until.is_some_and(|until| Instant::now() < until);
+                (mem::replace(&mut flash.shown, flashing), flashing)
+            }
+            None => (false, false),
+        };
+        let song_changed = song.as_ref().map(|s| &s.file) != self.current_song().map(|s| &s.file);
+        #[cfg(feature = "lyrics")]
+        let lyrics = song_changed
+            .then(|| load_lyrics(self.music_dir.as_deref(), song.as_ref()))
+            .flatten();
+        #[cfg(feature = "art")]
+        let art = self.next_art(song.as_ref());
+        #[cfg(feature = "art-color")]
+        let art_color = art
+            .as_ref()
+            .map(|(_, path)| path.as_deref().and_then(dominant_color));
+        let old_ctx = self.context();
+        let new_ctx = PlaceholderContext {
+            song: song.as_ref(),
+            status: &status,
+            outputs: outputs.as_deref(),
+            next_song: next_song.as_ref(),
+            previous_song: match song_changed {
+                true => self.current_song(),
+                false => self.history.front().map(|played| &played.song),
+            },
+            source_name: self.name(),
+            instance: self.instance.as_deref(),
+            stats: self.next_stats,
+            #[cfg(feature = "lyrics")]
+            lyrics: if song_changed {
+                lyrics.as_ref()
+            } else {
+                self.lyrics.as_ref()
+            },
+            #[cfg(feature = "art")]
+            album_art: match &art {
+                Some((_, path)) => path.as_deref(),
+                None => self.album_art.as_deref(),
+            },
+            #[cfg(feature = "art-color")]
+            art_color: art_color.unwrap_or(self.art_color),
+        };
+        let mut change = ContentChange::empty();
+        let (old_state, new_state) = (old_ctx.status.state, status.state);
+        let prefix_format = &self.prefix_format;
+        // I made this because I think this looks hilarious and I don't want to repeat this
+        macro_rules! change {
+            {
+                $($var:ident if $type:ident in $old:expr => $new:expr;)*
+            } => {
+                $(
+                    // a state switch can pick another format altogether
+                    change.set(
+                        ContentChange::$type,
+                        !ptr::eq($old, $new)
+                            || $new.iter().any(|ph| self.is_changed(ph, &old_ctx, &new_ctx)),
+                    );
+                )*
+                $(
+                    if change.contains(ContentChange::$type) {
+                        $var.clear();
+                        $new.format(
+                            &self.icons,
+                            &new_ctx,
+                            &self.default_placeholder,
+                            $var,
+                        )?;
+                    }
+                )*
+            };
+        }
+        change! {
+            prefix if Prefix in prefix_format.get(old_state) => prefix_format.get(new_state);
+            suffix if Suffix in self.suffix_format_at(&old_ctx) => self.suffix_format_at(&new_ctx);
+            content if Running in self.running_format_at(&old_ctx, was_flashing) => self.running_format_at(&new_ctx, flashing);
+        }
+        let separator = match &self.separator_format {
+            Some(formats) => {
+                let (old, new) = (formats.get(old_state), formats.get(new_state));
+                match ptr::eq(old, new)
+                    && !new.iter().any(|ph| self.is_changed(ph, &old_ctx, &new_ctx))
+                {
+                    true => None,
+                    false => {
+                        let mut separator = String::new();
+                        new.format(
+                            &self.icons,
+                            &new_ctx,
+                            &self.default_placeholder,
+                            &mut separator,
+                        )?;
+                        Some(separator)
+                    }
+                }
+            }
+            None => None,
+        };
+        #[cfg(feature = "lyrics")]
+        if song_changed {
+            self.lyrics = lyrics;
+        }
+        #[cfg(feature = "art")]
+        if let Some((key, path)) = art {
+            (self.art_key, self.album_art) = (key, path);
+        }
+        #[cfg(feature = "art-color")]
+        if let Some(color) = art_color {
+            self.art_color = color;
+        }
+        if song_changed {
+            let started = mem::replace(&mut self.song_started, Local::now());
+            if let Some(song) = self.current_song.take() {
+                self.history.push_front(PlayedSong { song, started });
+                self.history.truncate(self.history_len);
+            }
+        }
+        (self.current_song, self.current_status, self.outputs) = (song, status, outputs);
+        self.next_song = next_song;
+        self.stats = self.next_stats;
+        if let Some(separator) = separator {
+            self.separator = separator;
+            change |= ContentChange::Separator;
+        }
+        if song_changed {
+            change |= ContentChange::Identity;
+        }
+        if let Some(notification) = &mut self.notification {
+            notification.pending |= song_changed && self.current_song.is_some();
+        }
+        self.notify();
+        Ok(change)
+    }
+    fn notify(&mut self) {
+        let Some(notification) = &mut self.notification else {
+            return;
+        };
+        // a song changed while paused is announced once it plays
+        if !notification.pending
+            || self.current_status.state != State::Play
+            || notification
+                .last
+                .is_some_and(|last| last.elapsed() < notification.interval)
+        {
+            return;
+        }
+        notification.pending = false;
+        notification.last = Some(Instant::now());
+        let hook = notification.hook.clone();
+        hook.spawn(self.env());
+    }
+    /// Asks MPD at most once per `interval` unless it reports a change, the elapsed time of a
+    /// playing song is counted in between.
+    pub fn with_query_interval(mut self, interval: Option<Duration>) -> Self {
+        self.query_interval = interval;
+        self
+    }
+    /// Reuses the outputs for `{outputs}` for `ttl`, unless MPD reports a change of them. The
+    /// next song and the album art need no TTL, they are only asked for again when the song or
+    /// the queue changes.
+    pub fn with_outputs_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.outputs_ttl = ttl;
+        self
+    }
+    /// Placeholders (by name) that are only brought up to date when something else changes, so
+    /// e.g. `{elapsedTime}` doesn't restart scrolling every second.
+    pub fn with_ignored_changes(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.ignored_changes = names.into_iter().collect();
+        self
+    }
+    /// Shows `format` in place of the running text for `duration` whenever the volume changes.
+    pub fn with_volume_flash(mut self, duration: Option<Duration>, format: MpdFormatter) -> Self {
+        if duration.is_some() {
+            self.track(&format);
+        }
+        self.volume_flash = duration.map(|duration| VolumeFlash {
+            duration,
+            format,
+            until: None,
+            shown: false,
+        });
+        self
+    }
+    /// Shows `format` in place of the running text while the queue is empty.
+    pub fn with_idle_format(mut self, format: Option<MpdFormatter>) -> Self {
+        if let Some(format) = &format {
+            self.track(format);
+        }
+        self.idle_format = format;
+        self
+    }
+    /// Shows `format` in place of the running text while MPD crossfades into the next song, e.g.
+    /// both titles.
+    pub fn with_crossfade_format(mut self, format: Option<MpdFormatter>) -> Self {
+        if let Some(format) = &format {
+            self.track(format);
+            self.track_next_song = true;
+        }
+        self.crossfade_format = format;
+        self
+    }
+    /// Replaces the suffix with `format` during the last `within` of a playing song, as long as
+    /// another song follows.
+    pub fn with_up_next(mut self, within: Option<Duration>, format: MpdFormatter) -> Self {
+        self.track_next_song |= within.is_some();
+        self.up_next = within.map(|within| UpNext { within, format });
+        self
+    }
+    /// Name given with `--instance`, shown by `{instance}`.
+    pub fn with_instance(mut self, instance: Option<String>) -> Self {
+        self.instance = instance;
+        self
+    }
+    /// Posts a desktop notification with `notify-send` whenever the song changes, at most once
+    /// per `interval`.
+    pub fn with_notify(mut self, interval: Option<Duration>) -> Self {
+        self.notification = interval.map(|interval| SongNotification {
+            hook: Hook::new(NOTIFY_COMMAND.to_owned()),
+            interval,
+            last: None,
+            pending: false,
+        });
+        // the cover goes into the notification
+        #[cfg(feature = "art")]
+        {
+            self.track_art |= self.notification.is_some();
+        }
+        self
+    }
+    /// The song's cache key and art if it is not the one already loaded.
+    #[cfg(feature = "art")]
+    fn next_art(&mut self, song: Option<&Song>) -> Option<(Option<String>, Option<PathBuf>)> {
+        if !self.track_art {
+            return None;
+        }
+        let key = song.map(ArtCache::key);
+        if key == self.art_key {
+            return None;
+        }
+        let supported = self.features.contains(&ServerFeature::AlbumArt);
+        let client = self
+            .client
+            .as_mut()
+            .map(|client| supported.then_some(client))?;
+        let art = song
+            .zip(key.as_deref())
+            .and_then(|(song, key)| load_art(client, &self.art_cache, song, key));
+        Some((key, art))
+    }
+    pub fn running_format(&self) -> &MpdFormatter {
+        &self.running_format
+    }
+    fn is_changed(
+        &self,
+        ph: &Placeholder,
+        old: &PlaceholderContext,
+        new: &PlaceholderContext,
+    ) -> bool {
+        !self.ignored_changes.iter().any(|name| name == ph.name()) && ph.get(old) != ph.get(new)
+    }
+    fn running_format_at(&self, ctx: &PlaceholderContext, flashing: bool) -> &MpdFormatter {
+        let status = ctx.status;
+        match (
+            &self.volume_flash,
+            &self.crossfade_format,
+            &self.idle_format,
+        ) {
+            (Some(flash), ..) if flashing => &flash.format,
+            (_, Some(crossfade), _)
+                if ctx.next_song.is_some()
+                    && status
+                        .crossfade
+                        .is_some_and(|xfade| !xfade.is_zero() && is_ending(status, xfade)) =>
+            {
+                crossfade
+            }
+            (.., Some(idle)) if status.queue_len == 0 => idle,
+            _ => &self.running_format,
+        }
+    }
+    /// Prefix format for the current state.
+    pub fn prefix_format(&self) -> &MpdFormatter {
+        self.prefix_format.get(self.current_status.state)
+    }
+    /// Suffix format for the current state, or the up next one near the end of the song.
+    pub fn suffix_format(&self) -> &MpdFormatter {
+        self.suffix_format_at(&self.context())
+    }
+    fn suffix_format_at(&self, ctx: &PlaceholderContext) -> &MpdFormatter {
+        let status = ctx.status;
+        match &self.up_next {
+            Some(up_next) if ctx.next_song.is_some() && is_ending(status, up_next.within) => {
+                &up_next.format
+            }
+            _ => self.suffix_format.get(status.state),
+        }
+    }
+    pub fn icons(&self) -> &StatusIconsSet {
+        &self.icons
+    }
+    pub fn current_song(&self) -> Option<&Song> {
+        self.current_song.as_ref()
+    }
+    pub fn current_status(&self) -> &Status {
+        &self.current_status
+    }
+    pub fn version(&self) -> Version {
+        self.version
+    }
+    pub fn context(&self) -> PlaceholderContext<'_> {
+        PlaceholderContext {
+            song: self.current_song(),
+            status: self.current_status(),
+            outputs: self.outputs.as_deref(),
+            next_song: self.next_song.as_ref(),
+            previous_song: self.history.front().map(|played| &played.song),
+            source_name: self.name(),
+            instance: self.instance.as_deref(),
+            stats: self.stats,
+            #[cfg(feature = "lyrics")]
+            lyrics: self.lyrics.as_ref(),
+            #[cfg(feature = "art")]
+            album_art: self.album_art.as_deref(),
+            #[cfg(feature = "art-color")]
+            art_color: self.art_color,
+        }
+    }
+}
+
+impl TextSource for MpdSource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        let mut content = Content::new(String::new(), String::new(), String::new());
+        self.running_format_at(&self.context(), false)
+            .format_with_source(self, &mut content.running)?;
+        self.prefix_format()
+            .format_with_source(self, &mut content.prefix)?;
+        self.suffix_format()
+            .format_with_source(self, &mut content.suffix)?;
+        if let Some(formats) = &self.separator_format {
+            let mut separator = String::new();
+            formats
+                .get(self.current_status.state)
+                .format_with_source(self, &mut separator)?;
+            self.separator = separator;
+        }
+        Ok(content)
+    }
+    fn poll(
+        &mut self,
+        content: &mut String,
+        prefix: &mut String,
+        suffix: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        self.get(content, prefix, suffix)
+    }
+    fn kind(&self) -> SourceKind {
+        SourceKind::Push
+    }
+    fn watch(&mut self, waker: Waker) -> anyhow::Result<()> {
+        if self.disconnected {
+            // watching starts over after reconnecting
+            self.waker = Some(waker);
+            return Ok(());
+        }
+        let stream = TcpStream::connect(self.addr).context("MPD connection error")?;
+        let watcher = Watcher {
+            stream: stream.try_clone()?,
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let stopped = Arc::clone(&watcher.stopped);
+        let mut client = Client::new(stream).context("MPD connection error")?;
+        self.watcher = Some(watcher);
+        let changed = Arc::clone(&self.changed);
+        let outputs_changed = Arc::clone(&self.outputs_changed);
+        self.waker = Some(waker.clone());
+        thread::spawn(move || {
+            let subsystems = [
+                Subsystem::Player,
+                Subsystem::Mixer,
+                Subsystem::Options,
+                Subsystem::Queue,
+                Subsystem::Output,
+            ];
+            loop {
+                match client.wait(&subsystems) {
+                    Ok(subsystems) => {
+                        log::debug!("MPD subsystems changed: {subsystems:?}");
+                        changed.store(true, AtomicOrdering::Relaxed);
+                        if subsystems.contains(&Subsystem::Output) {
+                            outputs_changed.store(true, AtomicOrdering::Relaxed);
+                        }
+                    }
+                    Err(_) if stopped.load(AtomicOrdering::Relaxed) => break,
+                    Err(e) => {
+                        log::warn!("Stopped watching MPD for changes: {e}");
+                        break;
+                    }
+                }
+                if stopped.load(AtomicOrdering::Relaxed) || !waker.wake() {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        format!(
+            "mpd {} (protocol {})",
+            self.addr,
+            match self.version {
+                Version(0, 0, 0) => version_string(self.version),
+                version => protocol_string(version, &self.features),
+            }
+        )
+    }
+    fn name(&self) -> &'static str {
+        "mpd"
+    }
+    fn set_stats(&mut self, stats: RunStats) {
+        self.next_stats = stats;
+    }
+    fn separator(&self) -> Option<&str> {
+        self.separator_format
+            .as_ref()
+            .map(|_| self.separator.as_str())
+    }
+    fn is_connected(&self) -> Option<bool> {
+        Some(!self.disconnected)
+    }
+    fn reconnects(&self) -> Option<u64> {
+        Some(self.reconnects)
+    }
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let status = &self.current_status;
+        let mut env = vec![
+            (
+                "STATE",
+                match status.state {
+                    State::Play => "play",
+                    State::Pause => "pause",
+                    State::Stop => "stop",
+                }
+                .to_owned(),
+            ),
+            ("VOLUME", status.volume.to_string()),
+            ("QUEUE_LENGTH", status.queue_len.to_string()),
+        ];
+        if let Some(place) = status.song {
+            env.push(("SONG_POSITION", place.id.to_string()));
+        }
+        if let Some(elapsed) = status.elapsed {
+            env.push(("ELAPSED_TIME", elapsed.as_secs().to_string()));
+        }
+        if let Some(duration) = status.duration {
+            env.push(("TOTAL_TIME", duration.as_secs().to_string()));
+        }
+        if let Some(song) = &self.current_song {
+            env.push(("FILENAME", song.file.clone()));
+            env.extend(song.title.clone().map(|t| ("TITLE", t)));
+            env.extend(song.artist.clone().map(|a| ("ARTIST", a)));
+            for (tag, name) in [
+                ("Album", "ALBUM"),
+                ("AlbumArtist", "ALBUM_ARTIST"),
+                ("Date", "DATE"),
+            ] {
+                if let Some((_, value)) = song.tags.iter().find(|(k, _)| k == tag) {
+                    env.push((name, value.clone()));
+                }
+            }
+        }
+        if let Some(outputs) = &self.outputs {
+            env.push(("OUTPUTS", EnabledOutputs(outputs).to_string()));
+        }
+        #[cfg(feature = "art")]
+        if let Some(path) = &self.album_art {
+            env.push(("ALBUM_ART", path.display().to_string()));
+        }
+        env
+    }
+    fn send(&mut self, cmd: PlayerCommand) -> anyhow::Result<()> {
+        let stopped = self.current_status.state == State::Stop;
+        let client = self.client()?;
+        let result = match cmd {
+            // pausing does nothing when stopped
+            PlayerCommand::Toggle if stopped => client.play(),
+            PlayerCommand::Toggle => client.toggle_pause(),
+            PlayerCommand::Next => client.next(),
+            PlayerCommand::Prev => client.prev(),
+        };
+        // the answer to the next query can't wait for the watcher
+        self.changed.store(true, AtomicOrdering::Relaxed);
+        result.map_err(|e| {
+            self.disconnected = matches!(e, mpd::error::Error::Io(_));
+            anyhow::Error::new(e).context("MPD server error")
         })
     }
-    pub fn get(
+    fn format(&mut self, format: &MpdFormatter, f: &mut String) -> anyhow::Result<()> {
+        format.format_with_source(self, f)
+    }
+    #[cfg(feature = "waybar")]
+    fn format_upcoming(
         &mut self,
-        content: &mut String,
-        prefix: &mut String,
-        suffix: &mut String,
-    ) -> anyhow::Result<ContentChange> {
-        let song = self.client.currentsong().context("MPD server error")?;
-        let status = self.client.status().context("MPD server error")?;
-        let mut change = ContentChange::empty();
-        // I made this because I think this looks hilarious and I don't want to repeat this
-        macro_rules! change {
-            {
-                $($var:ident if $type:ident in $fmt:ident;)*
-            } => {
-                $(
-                    change.set(
-                        ContentChange::$type,
-                        self.$fmt
-                        .iter()
-                        .any(|ph| ph.get(self.current_song(), self.current_status()) != ph.get(song.as_ref(), &status)),
-                    );
-                )*
-                $(
-                    if change.contains(ContentChange::$type) {
-                        $var.clear();
-                        self.$fmt.format(
-                            &self.icons,
-                            song.as_ref(),
-                            &status,
-                            &self.default_placeholder,
-                            $var,
-                        )?;
-                    }
-                )*
+        fmt: &MpdFormatter,
+        count: usize,
+        f: &mut String,
+    ) -> anyhow::Result<()> {
+        let status = &self.current_status;
+        // in random mode only the next song is known, everything after it is a guess anyway
+        let start = status
+            .nextsong
+            .or(status.song.map(|qp| QueuePlace {
+                pos: qp.pos + 1,
+                ..qp
+            }))
+            .map_or(0, |qp| qp.pos as usize);
+        let upcoming = match self.upcoming.take() {
+            Some(u) if u.queue_version == status.queue_version && u.start == start => u,
+            _ => UpcomingSongs {
+                queue_version: status.queue_version,
+                start,
+                songs: self.client()?.queue().context("MPD server error")?,
+            },
+        };
+        let ctx = self.context();
+        for (i, song) in upcoming.songs.iter().skip(start).take(count).enumerate() {
+            if i != 0 {
+                f.push('\n');
+            }
+            let ctx = PlaceholderContext {
+                song: Some(song),
+                ..ctx
             };
+            fmt.format(&self.icons, &ctx, &self.default_placeholder, f)?;
         }
-        change! {
-            prefix if Prefix in prefix_format;
-            suffix if Suffix in suffix_format;
-            content if Running in running_format;
+        self.upcoming = Some(upcoming);
+        Ok(())
+    }
+    fn format_history(
+        &mut self,
+        fmt: &MpdFormatter,
+        count: usize,
+        f: &mut String,
+    ) -> anyhow::Result<()> {
+        let ctx = self.context();
+        for (i, played) in self.history.iter().take(count).enumerate() {
+            if i != 0 {
+                f.push('\n');
+            }
+            write!(f, "{} ", played.started.format("%H:%M"))?;
+            let ctx = PlaceholderContext {
+                song: Some(&played.song),
+                ..ctx
+            };
+            fmt.format(&self.icons, &ctx, &self.default_placeholder, f)?;
         }
-        (self.current_song, self.current_status) = (song, status);
-        Ok(change)
+        Ok(())
     }
-    pub fn running_format(&self) -> &MpdFormatter {
-        &self.running_format
+    fn track(&mut self, format: &MpdFormatter) {
+        warn_unknown(format);
+        self.track_outputs |= format.needs_outputs();
+        self.track_next_song |= format.needs_next_song();
+        #[cfg(feature = "art")]
+        {
+            self.track_art |= format.iter().any(Placeholder::needs_art);
+        }
     }
-    pub fn prefix_format(&self) -> &MpdFormatter {
-        &self.prefix_format
+    fn track_history(&mut self, len: usize) {
+        self.history_len = self.history_len.max(len);
     }
-    pub fn suffix_format(&self) -> &MpdFormatter {
-        &self.suffix_format
+    #[cfg(feature = "art")]
+    fn track_art(&mut self) -> bool {
+        self.track_art = true;
+        true
     }
-    pub fn icons(&self) -> &StatusIconsSet {
-        &self.icons
+    #[cfg(feature = "art")]
+    fn album_art(&self) -> Option<&Path> {
+        self.album_art.as_deref()
     }
-    pub fn current_song(&self) -> Option<&Song> {
-        self.current_song.as_ref()
+}
+
+/// Only asks MPD when the cache misses, songs without art are asked for again when they come back.
+/// `client` is `None` for a server that can't send album art.
+#[cfg(feature = "art")]
+fn load_art(
+    client: Option<&mut Client>,
+    cache: &ArtCache,
+    song: &Song,
+    key: &str,
+) -> Option<PathBuf> {
+    if let Some(path) = cache.lookup(key) {
+        log::debug!(
+            "Album art for {} is cached at {}",
+            song.file,
+            path.display()
+        );
+        return Some(path);
     }
-    pub fn current_status(&self) -> &Status {
-        &self.current_status
+    let data = match client?.albumart(song) {
+        Ok(data) => data,
+        Err(e) => {
+            log::debug!("No album art for {}: {e}", song.file);
+            return None;
+        }
+    };
+    cache
+        .store(key, &data)
+        .inspect_err(|e| log::warn!("Failed caching album art: {e}"))
+        .ok()
+}
+
+#[cfg(feature = "lyrics")]
+fn load_lyrics(music_dir: Option<&Path>, song: Option<&Song>) -> Option<Lyrics> {
+    let path = music_dir?.join(&song?.file).with_extension("lrc");
+    // missing or unreadable lyrics are not worth interrupting playback display for
+    match Lyrics::load(&path) {
+        Ok(lyrics) => {
+            log::debug!("Lyrics for {}: {}", path.display(), lyrics.is_some());
+            lyrics
+        }
+        Err(e) => {
+            log::warn!("Failed reading lyrics from {}: {e}", path.display());
+            None
+        }
     }
 }
 
@@ -323,8 +2050,7 @@ impl MpdFormatter {
     pub fn format_with_source(&self, source: &MpdSource, f: &mut String) -> anyhow::Result<()> {
         self.format(
             source.icons(),
-            source.current_song(),
-            source.current_status(),
+            &source.context(),
             &source.default_placeholder,
             f,
         )
@@ -332,31 +2058,55 @@ impl MpdFormatter {
     pub fn format(
         &self,
         icons: &StatusIconsSet,
-        song: Option<&Song>,
-        status: &Status,
+        ctx: &PlaceholderContext,
         default: &str,
         f: &mut String,
     ) -> anyhow::Result<()> {
+        // where each open group starts in `f` and whether a placeholder in it had no value
+        let mut groups: Vec<(usize, bool)> = Vec::new();
         for ph in self.iter() {
-            match ph.get(song, status) {
+            match ph {
+                Placeholder::GroupStart => groups.push((f.len(), false)),
+                Placeholder::GroupEnd => {
+                    if let Some((start, true)) = groups.pop() {
+                        f.truncate(start);
+                    }
+                }
+                _ => {}
+            }
+            let value = ph.get(ctx);
+            if value.is_missing() {
+                if let Some((_, missing)) = groups.last_mut() {
+                    *missing = true;
+                }
+            }
+            match value {
                 PlaceholderValue::String(s) => write!(f, "{}", s)?,
                 PlaceholderValue::OptionalString(s) => write!(f, "{}", s.unwrap_or(default))?,
-                PlaceholderValue::Volume(v) => write!(f, "{}", v)?,
+                PlaceholderValue::Volume(v, format) => format.write(v, f)?,
+                // MPD reports -1 without a mixer
+                PlaceholderValue::VolumeBar(v, width) => match usize::try_from(v) {
+                    Ok(v) => {
+                        let filled = (v.min(100) * width + 50) / 100;
+                        f.extend(iter::repeat_n('█', filled));
+                        f.extend(iter::repeat_n('░', width - filled));
+                    }
+                    Err(_) => write!(f, "{}", default)?,
+                },
                 PlaceholderValue::Len(l) => write!(f, "{}", l)?,
+                PlaceholderValue::Count(c) => write!(f, "{}", c)?,
                 PlaceholderValue::OptionalDuration(op, fmt) => match op {
-                    Some(d) => write!(
-                        f,
-                        "{}",
-                        chrono::format::DelayedFormat::new(
-                            None,
-                            NaiveTime::from_num_seconds_from_midnight_opt(
-                                d.as_secs() as _,
-                                d.subsec_nanos() as _
-                            ),
-                            fmt.iter()
-                        )
-                    )
-                    .map_err(|e| anyhow::anyhow!(e).context("Unsupported time specifier"))?,
+                    Some(d) => fmt
+                        .write(d, f)
+                        .map_err(|e| anyhow::anyhow!(e).context("Unsupported time specifier"))?,
+                    None => write!(f, "{}", default)?,
+                },
+                PlaceholderValue::OptionalDate(op, fmt) => match op {
+                    Some(date) => fmt.write_date(date, f)?,
+                    None => write!(f, "{}", default)?,
+                },
+                PlaceholderValue::OptionalQueueIndex(op, len, separator) => match op {
+                    Some(qp) => write!(f, "{}{separator}{len}", qp.pos + 1)?,
                     None => write!(f, "{}", default)?,
                 },
                 PlaceholderValue::OptionalQueuePlace(op) => match op {
@@ -364,9 +2114,20 @@ impl MpdFormatter {
                     None => write!(f, "{}", default),
                 }?,
                 PlaceholderValue::Bool(b) => icons.write_bool(ph, b, f)?,
-                PlaceholderValue::State(s, pad) => {
-                    write!(f, "{}{}", icons.state.get_icon(s), " ".repeat(pad))?
-                }
+                PlaceholderValue::OptionalBool(op) => match op {
+                    Some(b) => icons.write_bool(ph, b, f)?,
+                    None => write!(f, "{}", default)?,
+                },
+                PlaceholderValue::Outputs(op) => match op {
+                    Some(outputs) => write!(f, "{}", EnabledOutputs(outputs))?,
+                    None => write!(f, "{}", default)?,
+                },
+                PlaceholderValue::State(s, pad) => icons.state.write(s, pad, f)?,
+                #[cfg(feature = "art-color")]
+                PlaceholderValue::OptionalColor(op, format) => match op {
+                    Some(color) => format.write(color, f)?,
+                    None => write!(f, "{}", default)?,
+                },
             };
         }
         Ok(())
@@ -375,6 +2136,114 @@ impl MpdFormatter {
     pub fn iter(&self) -> std::slice::Iter<'_, Placeholder> {
         self.0.iter()
     }
+    pub fn needs_outputs(&self) -> bool {
+        self.iter().any(Placeholder::needs_outputs)
+    }
+    pub fn needs_next_song(&self) -> bool {
+        self.iter().any(Placeholder::needs_next_song)
+    }
+    /// Placeholders left as they are by a lenient parse, with their braces.
+    pub fn unknown_placeholders(&self) -> impl Iterator<Item = &str> {
+        self.iter().filter_map(|ph| match ph {
+            Placeholder::Unknown(literal) => Some(literal.as_str()),
+            _ => None,
+        })
+    }
+}
+
+/// Every placeholder rendered for a made-up song.
+pub fn placeholder_samples(
+    icons: &StatusIconsSet,
+    default: &str,
+) -> anyhow::Result<Vec<(Placeholder, String)>> {
+    with_sample_context(|ctx| {
+        Placeholder::all()
+            .into_iter()
+            .map(|ph| {
+                let mut value = String::new();
+                MpdFormatter(vec![ph.clone()]).format(icons, ctx, default, &mut value)?;
+                Ok((ph, value))
+            })
+            .collect()
+    })
+}
+
+/// Calls `f` with every placeholder set for a made-up playing song.
+pub fn with_sample_context<R>(f: impl FnOnce(&PlaceholderContext) -> R) -> R {
+    let song = Song {
+        file: "Artist/Album/03 Title.flac".to_owned(),
+        title: Some("Title".to_owned()),
+        artist: Some("Artist".to_owned()),
+        tags: vec![
+            ("Album".to_owned(), "Album".to_owned()),
+            ("AlbumArtist".to_owned(), "Album Artist".to_owned()),
+            ("Date".to_owned(), "2024".to_owned()),
+        ],
+        last_mod: Some("2024-05-17T12:00:00Z".to_owned()),
+        ..Default::default()
+    };
+    let previous_song = Song {
+        file: "Artist/Album/02 Previous Title.flac".to_owned(),
+        title: Some("Previous Title".to_owned()),
+        artist: Some("Previous Artist".to_owned()),
+        ..Default::default()
+    };
+    let next_song = Song {
+        file: "Artist/Album/04 Next Title.flac".to_owned(),
+        title: Some("Next Title".to_owned()),
+        artist: Some("Next Artist".to_owned()),
+        ..Default::default()
+    };
+    let status = Status {
+        volume: 80,
+        random: true,
+        queue_len: 12,
+        state: State::Play,
+        song: Some(QueuePlace {
+            id: Id(3),
+            pos: 2,
+            prio: 0,
+        }),
+        elapsed: Some(Duration::from_secs(83)),
+        duration: Some(Duration::from_secs(215)),
+        ..Default::default()
+    };
+    #[cfg(feature = "lyrics")]
+    let lyrics =
+        Lyrics::parse("[01:15.00]Previous line\n[01:20.00]Current line\n[01:30.00]Next line");
+    let outputs = [
+        Output {
+            id: 0,
+            name: "DAC".to_owned(),
+            enabled: true,
+        },
+        Output {
+            id: 1,
+            name: "Bluetooth".to_owned(),
+            enabled: false,
+        },
+    ];
+    let ctx = PlaceholderContext {
+        song: Some(&song),
+        status: &status,
+        outputs: Some(&outputs),
+        next_song: Some(&next_song),
+        previous_song: Some(&previous_song),
+        source_name: "mpd",
+        instance: None,
+        stats: RunStats {
+            frame: 1234,
+            uptime: Duration::from_secs(12345),
+            ..Default::default()
+        },
+        #[cfg(feature = "lyrics")]
+        lyrics: Some(&lyrics),
+        #[cfg(feature = "art")]
+        album_art: Some(Path::new("~/.cache/mergneh/art/3f2a9c1e5b7d4086.jpg")),
+        #[cfg(feature = "art-color")]
+        art_color: Some(Color([0xd0, 0x5a, 0x3c])),
+    };
+    f(&ctx)
 }
 
 impl Display for MpdFormatter {
@@ -389,29 +2258,52 @@ impl Display for MpdFormatter {
                     };
                 }
             } else {
-                write!(
-                    f,
-                    "{}",
-                    match ph {
-                        Placeholder::Album => "{album}",
-                        Placeholder::AlbumArtist => "{albumArtist}",
-                        Placeholder::Artist => "{artist}",
-                        Placeholder::ConsumeIcon(_) => "{consumeIcon}",
-                        Placeholder::Date => "{date}",
-                        Placeholder::ElapsedTime(_) => "{elapsedTime}",
-                        Placeholder::Filename => "{filename}",
-                        Placeholder::QueueLength => "{queueLength}",
-                        Placeholder::RandomIcon(_) => "{randomIcon}",
-                        Placeholder::RepeatIcon(_) => "{repeatIcon}",
-                        Placeholder::SingleIcon(_) => "{singleIcon}",
-                        Placeholder::SongPosition => "{songPosition}",
-                        Placeholder::StateIcon(_) => "{stateIcon}",
-                        Placeholder::Title => "{title}",
-                        Placeholder::TotalTime(_) => "{totalTime}",
-                        Placeholder::Volume => "{volume}",
-                        Placeholder::String(_) => unreachable!(),
+                let name = ph.name();
+                match ph {
+                    Placeholder::ElapsedTime(t) | Placeholder::TotalTime(t)
+                        if t.spec != TimeFormat::DEFAULT =>
+                    {
+                        write!(f, "{{{name}:{t}}}")?
+                    }
+                    Placeholder::Uptime(t) if t.spec != TimeFormat::UPTIME => {
+                        write!(f, "{{{name}:{t}}}")?
+                    }
+                    Placeholder::Date(Some(t)) => write!(f, "{{{name}:{t}}}")?,
+                    Placeholder::LastModified(t) if t.spec != TimeFormat::DATE => {
+                        write!(f, "{{{name}:{t}}}")?
+                    }
+                    Placeholder::ConsumeIcon(pad)
+                    | Placeholder::RandomIcon(pad)
+                    | Placeholder::RepeatIcon(pad)
+                    | Placeholder::SingleIcon(pad)
+                    | Placeholder::ErrorIcon(pad)
+                    | Placeholder::StateIcon(pad)
+                        if *pad != 0 =>
+                    {
+                        write!(f, "{{{name}:{pad}}}")?
+                    }
+                    #[cfg(feature = "lyrics")]
+                    Placeholder::Lyrics(count) if *count != 0 => write!(f, "{{{name}:{count}}}")?,
+                    #[cfg(feature = "art-color")]
+                    Placeholder::ArtColor(format) if *format != ColorFormat::default() => {
+                        write!(f, "{{{name}:{}}}", format.name())?
+                    }
+                    Placeholder::OutputIcon(output) => write!(f, "{{{name}:{output}}}")?,
+                    Placeholder::Volume(format) if *format != VolumeFormat::default() => {
+                        write!(f, "{{{name}:{format}}}")?
+                    }
+                    Placeholder::QueueIndex(separator)
+                        if separator != Placeholder::QUEUE_INDEX_SEPARATOR =>
+                    {
+                        write!(f, "{{{name}:{separator}}}")?
                     }
-                )?;
+                    Placeholder::VolumeBar(width) if *width != Placeholder::VOLUME_BAR_WIDTH => {
+                        write!(f, "{{{name}:{width}}}")?
+                    }
+                    Placeholder::If(cond) => write!(f, "{{{name}:{cond}}}")?,
+                    Placeholder::Unknown(literal) => f.write_str(literal)?,
+                    _ => write!(f, "{{{name}}}")?,
+                }
             }
         }
         Ok(())
@@ -422,7 +2314,35 @@ impl FromStr for MpdFormatter {
     type Err = MpdFormatParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(s, ParseOptions::default())
+    }
+}
+
+impl MpdFormatter {
+    pub fn parse_with(s: &str, options: ParseOptions) -> Result<Self, MpdFormatParseError> {
+        Self::parse(s, 0, options.lenient)
+    }
+
+    fn include(path: &str, depth: usize, lenient: bool) -> Result<Self, MpdFormatParseErrorKind> {
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(MpdFormatParseErrorKind::IncludeDepthExceeded(
+                path.to_owned(),
+            ));
+        }
+        let fragment = fs::read_to_string(path)
+            .map_err(|e| MpdFormatParseErrorKind::IncludeError(path.to_owned(), e))?;
+        Self::parse(fragment.trim_end_matches(['\n', '\r']), depth + 1, lenient)
+            .map_err(|e| MpdFormatParseErrorKind::IncludedFormatError(path.to_owned(), Box::new(e)))
+    }
+
+    fn parse(s: &str, depth: usize, lenient: bool) -> Result<Self, MpdFormatParseError> {
+        use MpdFormatParseErrorKind as Kind;
+        let err = |kind, span| MpdFormatParseError::new(kind, span, s);
+        // every slice we work with is a suffix of `s`, so this is its byte offset in `s`
+        let offset = |slice: &str| s.len() - slice.len();
         let mut placeholders = Vec::new();
+        // spans of the `{[}` not closed yet
+        let mut groups = Vec::new();
         let mut raw = String::new();
         let mut parse_slice = s;
         while !parse_slice.is_empty() {
@@ -433,6 +2353,7 @@ impl FromStr for MpdFormatter {
                     break;
                 }
             };
+            let start = offset(parse_slice) + left_par;
             if let Some('}') = &parse_slice[left_par..].chars().next() {
                 match parse_slice[left_par + 1..].chars().next() {
                     Some('}') => {
@@ -440,7 +2361,7 @@ impl FromStr for MpdFormatter {
                         parse_slice = &parse_slice[left_par + 2..];
                         continue;
                     }
-                    _ => return Err(MpdFormatParseError::UnmatchedParenthesis),
+                    _ => return Err(err(Kind::UnmatchedParenthesis, start..start + 1)),
                 };
             }
 
@@ -458,71 +2379,133 @@ impl FromStr for MpdFormatter {
 
             let right_par = match parse_slice.find(['{', '}']) {
                 Some(i) => i,
-                None => return Err(MpdFormatParseError::UnmatchedParenthesis),
+                None => return Err(err(Kind::UnmatchedParenthesis, start..s.len())),
             };
+            let end = offset(parse_slice) + right_par + 1;
             if let Some('{') = parse_slice[right_par..].chars().next() {
-                return Err(MpdFormatParseError::UnmatchedParenthesis);
+                return Err(err(Kind::UnmatchedParenthesis, start..end));
             }
             let ph_spec = &parse_slice[..right_par];
+            let spec_span = start + 1..end - 1;
+            let known = |name: &str| {
+                Placeholder::all().iter().any(|ph| ph.name() == name)
+                    || matches!(name, "outputIcon" | "if" | "include" | "[" | "]")
+            };
+            if lenient && !known(ph_spec.split_once(':').map_or(ph_spec, |(name, _)| name)) {
+                placeholders.push(Placeholder::Unknown(format!("{{{ph_spec}}}")));
+                parse_slice = &parse_slice[right_par + 1..];
+                continue;
+            }
+            if let Some(("include", path)) = ph_spec.split_once(':') {
+                placeholders.extend(
+                    Self::include(path, depth, lenient)
+                        .map_err(|kind| err(kind, start..end))?
+                        .0,
+                );
+                parse_slice = &parse_slice[right_par + 1..];
+                continue;
+            }
             placeholders.push(if let Some((ph_type, ph_fmt)) = ph_spec.split_once(':') {
+                let fmt_span = spec_span.start + ph_type.len() + 1..spec_span.end;
                 match ph_type {
-                    "date" => Placeholder::Date,
+                    "date" => Placeholder::Date(Some(
+                        ph_fmt
+                            .parse()
+                            .map_err(|e| err(Kind::DateFormatParseError(e), fmt_span))?,
+                    )),
+                    "lastModified" => Placeholder::LastModified(
+                        ph_fmt
+                            .parse()
+                            .map_err(|e| err(Kind::DateFormatParseError(e), fmt_span))?,
+                    ),
                     "elapsedTime" => Placeholder::ElapsedTime(
-                        StrftimeItems::new(ph_fmt)
-                            .parse_to_owned()
-                            .map_err(MpdFormatParseError::DurationParseError)?,
+                        ph_fmt
+                            .parse()
+                            .map_err(|e| err(Kind::DurationParseError(e), fmt_span))?,
                     ),
                     "totalTime" => Placeholder::TotalTime(
-                        StrftimeItems::new(ph_fmt)
-                            .parse_to_owned()
-                            .map_err(MpdFormatParseError::DurationParseError)?,
+                        ph_fmt
+                            .parse()
+                            .map_err(|e| err(Kind::DurationParseError(e), fmt_span))?,
+                    ),
+                    "uptime" => Placeholder::Uptime(
+                        ph_fmt
+                            .parse()
+                            .map_err(|e| err(Kind::DurationParseError(e), fmt_span))?,
                     ),
-                    "consumeIcon" | "repeatIcon" | "stateIcon" | "singleIcon" | "randomIcon" => {
+                    "consumeIcon" | "repeatIcon" | "stateIcon" | "singleIcon" | "randomIcon"
+                    | "errorIcon" => {
                         let pad = ph_fmt
                             .parse::<usize>()
-                            .map_err(MpdFormatParseError::PadParseError)?;
+                            .map_err(|e| err(Kind::PadParseError(e), fmt_span))?;
                         match ph_type {
                             "consumeIcon" => Placeholder::ConsumeIcon(pad),
                             "repeatIcon" => Placeholder::RepeatIcon(pad),
                             "stateIcon" => Placeholder::StateIcon(pad),
                             "singleIcon" => Placeholder::SingleIcon(pad),
                             "randomIcon" => Placeholder::RandomIcon(pad),
+                            "errorIcon" => Placeholder::ErrorIcon(pad),
                             _ => unreachable!(),
                         }
                     }
-                    _ => return Err(MpdFormatParseError::RedundantFormat(ph_type.to_owned())),
-                }
-            } else {
-                match ph_spec {
-                    "album" => Placeholder::Album,
-                    "albumArtist" => Placeholder::AlbumArtist,
-                    "artist" => Placeholder::Artist,
-                    "consumeIcon" => Placeholder::ConsumeIcon(0),
-                    "date" => Placeholder::Date,
-                    "elapsedTime" => Placeholder::ElapsedTime(
-                        StrftimeItems::new("%M:%S").parse_to_owned().unwrap(),
+                    "outputIcon" => Placeholder::OutputIcon(ph_fmt.to_owned()),
+                    "queueIndex" => Placeholder::QueueIndex(ph_fmt.to_owned()),
+                    "volume" => Placeholder::Volume(
+                        ph_fmt
+                            .parse()
+                            .map_err(|e| err(Kind::VolumeFormatParseError(e), fmt_span))?,
                     ),
-                    "filename" => Placeholder::Filename,
-                    "queueLength" => Placeholder::QueueLength,
-                    "randomIcon" => Placeholder::RandomIcon(0),
-                    "repeatIcon" => Placeholder::RepeatIcon(0),
-                    "singleIcon" => Placeholder::SingleIcon(0),
-                    "songPosition" => Placeholder::SongPosition,
-                    "stateIcon" => Placeholder::StateIcon(0),
-                    "title" => Placeholder::Title,
-                    "totalTime" => Placeholder::TotalTime(
-                        StrftimeItems::new("%M:%S").parse_to_owned().unwrap(),
+                    "volumeBar" => Placeholder::VolumeBar(
+                        ph_fmt
+                            .parse::<usize>()
+                            .map_err(|e| err(Kind::WidthParseError(e), fmt_span))?,
+                    ),
+                    #[cfg(feature = "lyrics")]
+                    "lyrics" => Placeholder::Lyrics(
+                        ph_fmt
+                            .parse::<usize>()
+                            .map_err(|e| err(Kind::LineCountParseError(e), fmt_span))?,
+                    ),
+                    #[cfg(feature = "art-color")]
+                    "artColor" => Placeholder::ArtColor(
+                        ph_fmt
+                            .parse()
+                            .map_err(|e| err(Kind::ColorFormatParseError(e), fmt_span))?,
                     ),
-                    "volume" => Placeholder::Volume,
+                    "if" => Placeholder::If(Box::new(
+                        Condition::parse(ph_fmt).map_err(|kind| err(kind, fmt_span))?,
+                    )),
                     _ => {
-                        return Err(MpdFormatParseError::UnknownPlaceholder(
-                            parse_slice[..right_par].to_owned(),
+                        return Err(err(
+                            Kind::RedundantFormat(ph_type.to_owned()),
+                            spec_span.start..spec_span.start + ph_type.len(),
                         ))
                     }
                 }
+            } else if ph_spec == "[" {
+                groups.push(start..end);
+                Placeholder::GroupStart
+            } else if ph_spec == "]" {
+                if groups.pop().is_none() {
+                    return Err(err(Kind::UnmatchedGroup, start..end));
+                }
+                Placeholder::GroupEnd
+            } else {
+                match Placeholder::all()
+                    .into_iter()
+                    .find(|ph| ph.name() == ph_spec)
+                {
+                    Some(ph) => ph,
+                    None => {
+                        return Err(err(Kind::UnknownPlaceholder(ph_spec.to_owned()), spec_span))
+                    }
+                }
             });
             parse_slice = &parse_slice[right_par + 1..];
         }
+        if let Some(span) = groups.pop() {
+            return Err(err(Kind::UnmatchedGroup, span));
+        }
         if !raw.is_empty() {
             placeholders.push(Placeholder::String(raw));
         }
@@ -530,6 +2513,116 @@ impl FromStr for MpdFormatter {
     }
 }
 
+impl Display for StateStatusIcons {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.play, self.pause, self.stop)
+    }
+}
+
+impl Display for StatusIcons {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.enabled)?;
+        match self.disabled {
+            Some(c) => write!(f, "{}", c),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A format used in one player state only, `STATE=FORMAT` on the command line, or in every
+/// state without the `STATE=` part.
+#[derive(Debug, Clone)]
+pub struct StateFormat {
+    state: Option<State>,
+    format: MpdFormatter,
+}
+
+const STATES: [(&str, State); 3] = [
+    ("play", State::Play),
+    ("pause", State::Pause),
+    ("stop", State::Stop),
+];
+
+impl FromStr for StateFormat {
+    type Err = MpdFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(s, ParseOptions::default())
+    }
+}
+
+impl StateFormat {
+    pub fn parse_with(s: &str, options: ParseOptions) -> Result<Self, MpdFormatParseError> {
+        let (state, format) = STATES
+            .iter()
+            .find_map(|(name, state)| {
+                let format = s.strip_prefix(name)?.strip_prefix('=')?;
+                Some((Some(*state), format))
+            })
+            .unwrap_or((None, s));
+        let offset = s.len() - format.len();
+        let format = MpdFormatter::parse_with(format, options).map_err(|e| {
+            // point at the format as it was given
+            MpdFormatParseError {
+                span: e.span.start + offset..e.span.end + offset,
+                format: s.to_owned(),
+                ..e
+            }
+        })?;
+        Ok(Self { state, format })
+    }
+}
+
+impl Display for StateFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some((name, _)) = STATES.iter().find(|(_, s)| Some(*s) == self.state) {
+            write!(f, "{name}=")?;
+        }
+        write!(f, "{}", self.format)
+    }
+}
+
+/// Formats picked by the player state, falling back to a default one.
+#[derive(Debug, Clone, Default)]
+pub struct StateFormats {
+    default: MpdFormatter,
+    states: [Option<MpdFormatter>; 3],
+}
+
+impl StateFormats {
+    /// Later formats override earlier ones for the same state.
+    pub fn new(mut default: MpdFormatter, formats: impl IntoIterator<Item = StateFormat>) -> Self {
+        let mut states = [None, None, None];
+        for StateFormat { state, format } in formats {
+            match state {
+                Some(state) => states[Self::index(state)] = Some(format),
+                None => default = format,
+            }
+        }
+        Self { default, states }
+    }
+    fn index(state: State) -> usize {
+        match state {
+            State::Play => 0,
+            State::Pause => 1,
+            State::Stop => 2,
+        }
+    }
+    pub fn get(&self, state: State) -> &MpdFormatter {
+        self.states[Self::index(state)]
+            .as_ref()
+            .unwrap_or(&self.default)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &MpdFormatter> {
+        [&self.default]
+            .into_iter()
+            .chain(self.states.iter().flatten())
+    }
+}
+
+#[cfg(feature = "serde")]
+serde_with_str!(MpdFormatter, StateStatusIcons, StatusIcons, StateFormat);
+
 macro_rules! next_or_err {
     ($iter:ident => $type:ident: $($field:ident),+) => {
         $type {
@@ -569,8 +2662,13 @@ impl FromStr for StatusIcons {
 
 #[cfg(test)]
 mod tests {
-    use crate::mpd::{MpdFormatParseError, MpdFormatter, Placeholder};
-    use chrono::format::StrftimeItems;
+    use mpd::{Output, Status};
+
+    use crate::mpd::{
+        with_sample_context, MpdFormatParseErrorKind, MpdFormatter, ParseOptions, Placeholder,
+        PlaceholderContext, StatusIconsSet, TimeFormat, VolumeFormat,
+    };
+    use crate::text_source::RunStats;
     macro_rules! ph {
         ($p:ident) => {
             Placeholder::$p
@@ -579,7 +2677,7 @@ mod tests {
             Placeholder::$p($v)
         };
         ($p:ident(*$v:literal)) => {
-            Placeholder::$p(StrftimeItems::new($v).parse_to_owned().unwrap())
+            Placeholder::$p($v.parse().unwrap())
         };
         ($str:literal) => {
             Placeholder::String($str.to_owned())
@@ -594,7 +2692,7 @@ mod tests {
         }
         macro_rules! assert_err {
             ($str:literal => $err:ident$(($s:literal))?) => {
-                assert!(matches!($str.parse::<MpdFormatter>().unwrap_err(), MpdFormatParseError::$err$((s) if s.as_str() == $s)?));
+                assert!(matches!($str.parse::<MpdFormatter>().unwrap_err().kind, MpdFormatParseErrorKind::$err$((s) if s.as_str() == $s)?));
             };
         }
         assert_ok!("rawstr" => ["rawstr"]);
@@ -613,7 +2711,9 @@ mod tests {
         assert_ok!("{{{artist}}}{title}" => ["{", Artist, "}", Title]);
         assert_ok!("{artist}{title}" => ["", Artist, "", Title, ""]);
         assert_ok!("}}{{{artist}}}{title}}}" => ["}{", Artist, "}", Title, "}"]);
+        assert_ok!("{outputs} {outputIcon:DAC}" => [Outputs, " ", OutputIcon(*"DAC")]);
         assert_err!("{artst}" => UnknownPlaceholder("artst"));
+        assert_err!("{outputIcon}" => UnknownPlaceholder("outputIcon"));
         assert_err!("{}artist}}" => UnknownPlaceholder(""));
         assert_err!("{ar}tst}" => UnknownPlaceholder("ar"));
         assert_err!("{artist}}" => UnmatchedParenthesis);
@@ -625,6 +2725,14 @@ mod tests {
         assert_err!("{{{artist}}}}" => UnmatchedParenthesis);
     }
 
+    #[test]
+    fn placeholder_list_test() {
+        for ph in Placeholder::all() {
+            let format = format!("{{{}}}", ph.name());
+            assert_eq!(format.parse::<MpdFormatter>().unwrap().0, vec![ph]);
+        }
+    }
+
     #[test]
     fn format_display_test() {
         macro_rules! assert {
@@ -648,13 +2756,491 @@ mod tests {
         assert!("rawstr");
         assert!("");
         assert!("{artist} - {title}");
+        assert!("{stateIcon:1} [{elapsedTime:%S}/{totalTime}]");
         assert!("{{}}");
         assert!("{{{artist}}}");
         assert!("{{{artist}{title}}}");
         assert!("{artist} {title}}}");
         assert!("}}{{}}}}");
         assert!("{{{artist}}}{title}");
+        assert!("{outputs} {outputIcon:DAC}");
+        assert!("{volumeBar} {volumeBar:20}");
+        assert!("{volume:pad3,%} {volume:muted=mute, off}");
+        assert!("{queueIndex} {queueIndex: of }");
+        assert!("{date} {date:%Y} {lastModified} {lastModified:%d.%m.%Y}");
+        assert!("{if:volume=0:muted} {if:totalTime>600:long-track}");
         assert!("{artist}{title}");
         assert!("}}{{{artist}}}{title}}}");
     }
+
+    #[test]
+    fn format_render_test() {
+        let status = Status {
+            volume: 0,
+            duration: Some(std::time::Duration::from_secs(700)),
+            ..Default::default()
+        };
+        let outputs = [
+            Output {
+                id: 0,
+                name: "DAC".to_owned(),
+                enabled: true,
+            },
+            Output {
+                id: 1,
+                name: "Bluetooth".to_owned(),
+                enabled: false,
+            },
+            Output {
+                id: 2,
+                name: "HDMI".to_owned(),
+                enabled: true,
+            },
+        ];
+        let ctx = PlaceholderContext {
+            song: None,
+            status: &status,
+            outputs: Some(&outputs),
+            next_song: None,
+            previous_song: None,
+            source_name: "mpd",
+            instance: Some("bar"),
+            stats: RunStats {
+                frame: 42,
+                uptime: std::time::Duration::from_secs(90061),
+                ..Default::default()
+            },
+            #[cfg(feature = "lyrics")]
+            lyrics: None,
+            #[cfg(feature = "art")]
+            album_art: None,
+            #[cfg(feature = "art-color")]
+            art_color: Some(crate::art::Color([0x1a, 0x2b, 0xfc])),
+        };
+        let icons = StatusIconsSet::new(
+            "psx".parse().unwrap(),
+            "cC".parse().unwrap(),
+            "rR".parse().unwrap(),
+            "lL".parse().unwrap(),
+            "sS".parse().unwrap(),
+            "oO".parse().unwrap(),
+            "eE".parse().unwrap(),
+        );
+        macro_rules! assert_renders {
+            ($str:literal => $expected:literal) => {
+                let mut out = String::new();
+                $str.parse::<MpdFormatter>()
+                    .unwrap()
+                    .format(&icons, &ctx, "N/A", &mut out)
+                    .unwrap();
+                assert_eq!(out, $expected);
+            };
+        }
+        assert_renders!("{if:volume=0:muted}" => "muted");
+        assert_renders!("{if:volume!=0:loud}" => "");
+        assert_renders!("{if:totalTime>600:long track: yes}" => "long track: yes");
+        assert_renders!("{if:totalTime<=600:short}" => "");
+        assert_renders!("{if:title=x:titled}" => "");
+        assert_renders!("{outputs}" => "DAC, HDMI");
+        assert_renders!("{outputIcon:DAC}{outputIcon:Bluetooth}{outputIcon:USB}" => "oON/A");
+        assert_renders!("{if:outputs=DAC, HDMI:both}" => "both");
+        assert_renders!("{sourceName}@{instance}" => "mpd@bar");
+        assert_renders!("{volumeBar:4}{if:volumeBar=0: muted}" => "░░░░ muted");
+        assert_renders!("{volume}|{volume:%}|{volume:pad3,%,muted=M}" => "0|0%|   M");
+        assert_renders!("{queueIndex}" => "N/A");
+        assert_renders!("{uptime} {uptime:%Mm} #{frame}{if:frame>40: late}" => "25:01:01 01m #42 late");
+        #[cfg(feature = "art-color")]
+        {
+            assert_renders!("{artColor} {artColor:hex} {artColor:rgb}" => "#1a2bfc 1a2bfc 26;43;252");
+            assert_renders!("{if:artColor=#1a2bfc:blue}" => "blue");
+            assert_eq!(
+                "{artColor:rgb}"
+                    .parse::<MpdFormatter>()
+                    .unwrap()
+                    .to_string(),
+                "{artColor:rgb}"
+            );
+            assert!("{artColor:hsl}".parse::<MpdFormatter>().is_err());
+        }
+        assert!(matches!(
+            "{if:volume:muted}"
+                .parse::<MpdFormatter>()
+                .unwrap_err()
+                .kind,
+            MpdFormatParseErrorKind::InvalidCondition(_)
+        ));
+        assert!(matches!(
+            "{if:volum=0:muted}"
+                .parse::<MpdFormatter>()
+                .unwrap_err()
+                .kind,
+            MpdFormatParseErrorKind::UnknownPlaceholder(_)
+        ));
+    }
+
+    #[test]
+    fn format_group_test() {
+        let icons = StatusIconsSet::new(
+            "psx".parse().unwrap(),
+            "cC".parse().unwrap(),
+            "rR".parse().unwrap(),
+            "lL".parse().unwrap(),
+            "sS".parse().unwrap(),
+            "oO".parse().unwrap(),
+            "eE".parse().unwrap(),
+        );
+        let render = |format: &str, elapsed: Option<u64>, duration: Option<u64>| {
+            let status = Status {
+                elapsed: elapsed.map(std::time::Duration::from_secs),
+                duration: duration.map(std::time::Duration::from_secs),
+                ..Default::default()
+            };
+            let ctx = PlaceholderContext {
+                song: None,
+                status: &status,
+                outputs: None,
+                next_song: None,
+                previous_song: None,
+                source_name: "mpd",
+                instance: None,
+                stats: RunStats::default(),
+                #[cfg(feature = "lyrics")]
+                lyrics: None,
+                #[cfg(feature = "art")]
+                album_art: None,
+                #[cfg(feature = "art-color")]
+                art_color: None,
+            };
+            let mut out = String::new();
+            format
+                .parse::<MpdFormatter>()
+                .unwrap()
+                .format(&icons, &ctx, "N/A", &mut out)
+                .unwrap();
+            out
+        };
+        let times = "{stateIcon}{[} [{elapsedTime}/{totalTime}]{]}";
+        assert_eq!(render(times, Some(83), Some(215)), "x [01:23/03:35]");
+        // a stream
+        assert_eq!(render(times, Some(83), None), "x");
+        // an empty queue
+        assert_eq!(render(times, None, None), "x");
+        assert_eq!(
+            render("{[}{elapsedTime}{[} of {totalTime}{]}{]}|", Some(83), None),
+            "01:23|"
+        );
+        assert_eq!(render("{[}{[}{totalTime}{]} {]}|", None, None), " |");
+        assert_eq!(render("{[}{if:totalTime>600:long}{]}", None, None), "");
+
+        for format in ["{[} [{elapsedTime}/{totalTime}]{]}", "{[}{[}{]}{{[}}{]}"] {
+            assert_eq!(format.parse::<MpdFormatter>().unwrap().to_string(), format);
+        }
+        for (format, span) in [("{[}{title}", 0..3), ("{title}{]}{[}", 7..10)] {
+            let err = format.parse::<MpdFormatter>().unwrap_err();
+            assert!(matches!(err.kind, MpdFormatParseErrorKind::UnmatchedGroup));
+            assert_eq!(err.span, span);
+        }
+    }
+
+    #[test]
+    fn volume_format_test() {
+        let render = |spec: &str, volume| {
+            let mut out = String::new();
+            spec.parse::<VolumeFormat>()
+                .unwrap()
+                .write(volume, &mut out)
+                .unwrap();
+            out
+        };
+        assert_eq!(render("", 9), "9");
+        assert_eq!(render("%", 100), "100%");
+        assert_eq!(render("pad3,%", 9), "  9%");
+        assert_eq!(render("pad3", -1), " -1");
+        assert_eq!(render("%,muted=🔇", -1), "🔇");
+        assert_eq!(render("muted=off, muted", 0), "off, muted");
+        assert_eq!(render("muted=off", 35), "35");
+        assert!("pad".parse::<VolumeFormat>().is_err());
+        assert!("%%".parse::<VolumeFormat>().is_err());
+    }
+
+    #[test]
+    fn queue_index_test() {
+        let icons = StatusIconsSet::new(
+            "psx".parse().unwrap(),
+            "cC".parse().unwrap(),
+            "rR".parse().unwrap(),
+            "lL".parse().unwrap(),
+            "sS".parse().unwrap(),
+            "oO".parse().unwrap(),
+            "eE".parse().unwrap(),
+        );
+        for (format, expected) in [
+            ("{queueIndex}", "3/12"),
+            ("{queueIndex: of }", "3 of 12"),
+            ("{if:queueIndex=3:third}", "third"),
+        ] {
+            let mut out = String::new();
+            let format = format.parse::<MpdFormatter>().unwrap();
+            with_sample_context(|ctx| format.format(&icons, ctx, "N/A", &mut out)).unwrap();
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn date_format_test() {
+        let icons = StatusIconsSet::new(
+            "psx".parse().unwrap(),
+            "cC".parse().unwrap(),
+            "rR".parse().unwrap(),
+            "lL".parse().unwrap(),
+            "sS".parse().unwrap(),
+            "oO".parse().unwrap(),
+            "eE".parse().unwrap(),
+        );
+        for (format, expected) in [
+            ("{date}", "2024"),
+            ("{date:%Y}|{date:%d.%m.%Y}", "2024|01.01.2024"),
+            ("{lastModified:%Y}", "2024"),
+            ("{if:lastModified>=2024-05-01:fresh}", "fresh"),
+        ] {
+            let mut out = String::new();
+            let format = format.parse::<MpdFormatter>().unwrap();
+            with_sample_context(|ctx| format.format(&icons, ctx, "N/A", &mut out)).unwrap();
+            assert_eq!(out, expected);
+        }
+
+        let format: TimeFormat = "%d.%m.%Y".parse().unwrap();
+        for (date, expected) in [
+            ("1999-03", "01.03.1999"),
+            ("1999-03-28", "28.03.1999"),
+            ("1999-03-28T10:00", "28.03.1999"),
+            ("around 1999", "around 1999"),
+            ("1999-13", "1999-13"),
+        ] {
+            let mut out = String::new();
+            format.write_date(date, &mut out).unwrap();
+            assert_eq!(out, expected, "{date}");
+        }
+        assert!("{date:%Q}".parse::<MpdFormatter>().is_err());
+    }
+
+    #[test]
+    fn mpd_error_test() {
+        let icons = StatusIconsSet::new(
+            "psx".parse().unwrap(),
+            "cC".parse().unwrap(),
+            "rR".parse().unwrap(),
+            "lL".parse().unwrap(),
+            "sS".parse().unwrap(),
+            "oO".parse().unwrap(),
+            "!".parse().unwrap(),
+        );
+        let format = "[{errorIcon:1}{mpdError}]".parse::<MpdFormatter>().unwrap();
+        let status = Status {
+            error: Some("Failed to open \"ALSA\": Device or resource busy".to_owned()),
+            ..Default::default()
+        };
+        let render = |status: &Status| {
+            let mut out = String::new();
+            with_sample_context(|ctx| {
+                let ctx = PlaceholderContext { status, ..*ctx };
+                format.format(&icons, &ctx, "N/A", &mut out)
+            })
+            .unwrap();
+            out
+        };
+        assert_eq!(
+            render(&status),
+            "[! Failed to open \"ALSA\": Device or resource busy]"
+        );
+        assert_eq!(render(&Status::default()), "[]");
+    }
+
+    #[test]
+    fn time_format_test() {
+        use chrono::{format::DelayedFormat, NaiveTime};
+        use std::time::Duration;
+
+        for spec in [
+            "%M:%S",
+            "%H:%M:%S",
+            "%-M min %_S sec",
+            "%I %p",
+            "%T.%3f",
+            "%%%k",
+        ] {
+            let format: TimeFormat = spec.parse().unwrap();
+            for d in [0, 7, 83, 3599, 45296, 86399].map(Duration::from_secs) {
+                let mut out = String::new();
+                format.write(d, &mut out).unwrap();
+                let time = NaiveTime::from_num_seconds_from_midnight_opt(d.as_secs() as _, 0);
+                let expected = DelayedFormat::new(None, time, format.items().iter()).to_string();
+                assert_eq!(out, expected, "{spec} {d:?}");
+            }
+            // only chrono's own items are limited to a day
+            let mut out = String::new();
+            let result = format.write(Duration::from_secs(90000), &mut out);
+            assert_eq!(
+                result.is_err(),
+                matches!(spec, "%I %p" | "%T.%3f"),
+                "{spec}"
+            );
+        }
+    }
+
+    #[test]
+    fn format_include_test() {
+        let dir = std::env::temp_dir().join(format!("mergneh-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fragment = dir.join("fragment");
+        let nested = dir.join("nested");
+        let cyclic = dir.join("cyclic");
+        std::fs::write(&fragment, "{artist} - {title}\n").unwrap();
+        std::fs::write(&nested, format!("[{{include:{}}}]", fragment.display())).unwrap();
+        std::fs::write(&cyclic, format!("{{include:{}}}", cyclic.display())).unwrap();
+
+        let parse = |s: String| s.parse::<MpdFormatter>().map(|f| f.0);
+        assert_eq!(
+            parse(format!("{{include:{}}} {{stateIcon}}", fragment.display())).unwrap(),
+            vec![
+                ph!(Artist),
+                ph!(" - "),
+                ph!(Title),
+                ph!(" "),
+                ph!(StateIcon(#0))
+            ]
+        );
+        assert_eq!(
+            parse(format!("{{include:{}}}", nested.display())).unwrap(),
+            vec![ph!("["), ph!(Artist), ph!(" - "), ph!(Title), ph!("]")]
+        );
+        assert!(matches!(
+            parse(format!("{{include:{}}}", dir.join("missing").display()))
+                .unwrap_err()
+                .kind,
+            MpdFormatParseErrorKind::IncludeError(..)
+        ));
+        assert!(matches!(
+            parse(format!("{{include:{}}}", cyclic.display()))
+                .unwrap_err()
+                .kind,
+            MpdFormatParseErrorKind::IncludedFormatError(..)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn format_error_span_test() {
+        macro_rules! assert_span {
+            ($str:literal => $span:expr) => {
+                assert_eq!($str.parse::<MpdFormatter>().unwrap_err().span, $span)
+            };
+        }
+        assert_span!("{artst}" => 1..6);
+        assert_span!("a - {artist}}" => 12..13);
+        assert_span!("{artist} - {title" => 11..17);
+        assert_span!("{art{ist}" => 0..5);
+        assert_span!("{title:1}" => 1..6);
+        assert_span!("[{elapsedTime:%Q}]" => 14..16);
+        assert_span!("{stateIcon:x}" => 11..12);
+        assert_eq!(
+            "{artist} - {titel}".parse::<MpdFormatter>().unwrap_err().to_string(),
+            "Unknown placeholder 'titel' (at position 12)\n  {artist} - {titel}\n              ^^^^^"
+        );
+    }
+
+    #[test]
+    fn lenient_format_test() {
+        let lenient = ParseOptions { lenient: true };
+        let format =
+            MpdFormatter::parse_with("{artist} - {rating} {{{queueIdx:/}}}", lenient).unwrap();
+        assert_eq!(
+            format.unknown_placeholders().collect::<Vec<_>>(),
+            ["{rating}", "{queueIdx:/}"]
+        );
+        assert_eq!(format.to_string(), "{artist} - {rating} {{{queueIdx:/}}}");
+        let icons = StatusIconsSet::new(
+            "psx".parse().unwrap(),
+            "cC".parse().unwrap(),
+            "rR".parse().unwrap(),
+            "lL".parse().unwrap(),
+            "sS".parse().unwrap(),
+            "oO".parse().unwrap(),
+            "eE".parse().unwrap(),
+        );
+        let mut out = String::new();
+        with_sample_context(|ctx| format.format(&icons, ctx, "N/A", &mut out)).unwrap();
+        assert_eq!(out, "Artist - {rating} {{queueIdx:/}}");
+        // known placeholders are still checked
+        assert!(MpdFormatter::parse_with("{title:1}", lenient).is_err());
+        assert!("{rating}".parse::<MpdFormatter>().is_err());
+    }
+
+    #[test]
+    fn state_formats_test() {
+        use crate::mpd::{StateFormat, StateFormats};
+        use mpd::State;
+
+        let formats = [
+            "pause= {stateIcon} ",
+            " | ",
+            "stop=",
+            "pause=paused={volume}",
+        ]
+        .map(|s| s.parse::<StateFormat>().unwrap());
+        assert_eq!(formats[0].to_string(), "pause= {stateIcon} ");
+        assert_eq!(formats[3].to_string(), "pause=paused={volume}");
+        let formats = StateFormats::new(MpdFormatter::only_string("-".to_owned()), formats);
+        assert_eq!(formats.get(State::Play).to_string(), " | ");
+        assert_eq!(formats.get(State::Pause).to_string(), "paused={volume}");
+        assert_eq!(formats.get(State::Stop).to_string(), "");
+        assert_eq!(formats.iter().count(), 3);
+        // `playing=` is not a state, it is a part of the format
+        assert!("playing={title}".parse::<StateFormat>().is_ok());
+        assert_eq!(
+            "stop={titel}".parse::<StateFormat>().unwrap_err().span,
+            6..11
+        );
+    }
+
+    #[test]
+    fn icon_padding_test() {
+        use crate::mpd::{StateStatusIcons, StatusIcons};
+        let icons: StatusIcons = "\u{f026}🔇".parse().unwrap();
+        let mut out = String::new();
+        icons.write(true, 2, &mut out).unwrap();
+        icons.write(false, 40, &mut out).unwrap();
+        assert_eq!(out, format!("\u{f026}  🔇{}", " ".repeat(40)));
+        assert_eq!(icons.width(true, 2), 3);
+        assert_eq!(icons.width(false, 1), 3);
+        let icons: StatusIcons = "a".parse().unwrap();
+        assert_eq!(icons.width(false, 3), 0);
+        let icons: StateStatusIcons = "psx".parse().unwrap();
+        assert_eq!(icons.width(mpd::State::Pause, 1), 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip_test() {
+        use crate::mpd::{StateStatusIcons, StatusIcons};
+        macro_rules! assert_round_trip {
+            ($type:ty: $str:literal) => {
+                let value: $type = serde_json::from_str(concat!('"', $str, '"')).unwrap();
+                assert_eq!(value.to_string(), $str);
+                assert_eq!(
+                    serde_json::to_string(&value).unwrap(),
+                    concat!('"', $str, '"')
+                );
+            };
+        }
+        assert_round_trip!(MpdFormatter: "{artist} - {title}");
+        assert_round_trip!(MpdFormatter: "}}{{{artist}}}{elapsedTime}");
+        assert_round_trip!(MpdFormatter: "[{elapsedTime:%M min %S sec}/{totalTime}] {stateIcon:2}");
+        assert_round_trip!(StateStatusIcons: "abc");
+        assert_round_trip!(StatusIcons: "ab");
+        assert_round_trip!(StatusIcons: "a");
+        assert_round_trip!(crate::mpd::StateFormat: "pause= {stateIcon} ");
+        assert!(serde_json::from_str::<MpdFormatter>("\"{artst}\"").is_err());
+        assert!(serde_json::from_str::<StatusIcons>("\"abc\"").is_err());
+    }
 }
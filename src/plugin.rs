@@ -0,0 +1,499 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    process::{self, Child, ChildStdin, Stdio},
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    events::Waker,
+    text_source::{backoff_delay, Content, ContentChange, SourceKind, TextSource},
+    utils::Command,
+};
+
+/// Version of the protocol sent in the handshake, bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How long the first message is waited for before the text starts running empty.
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A line written by the plugin, absent keys keep their previous values.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct Message {
+    pub text: Option<String>,
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+    pub fields: Option<BTreeMap<String, serde_json::Value>>,
+    /// Shown as the source error until a message without it arrives.
+    pub error: Option<String>,
+}
+
+enum Output {
+    Message(Message),
+    Closed,
+}
+
+/// `{field}` placeholders replaced with fields reported by the plugin, literal braces are
+/// doubled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginFormat(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field(String),
+}
+
+impl FromStr for PluginFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let (name, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or_else(|| anyhow::anyhow!("unclosed '{{' in '{s}'"))?;
+                    anyhow::ensure!(!name.is_empty(), "empty field name in '{s}'");
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field(name.to_owned()));
+                    chars = rest.chars();
+                }
+                '}' => anyhow::bail!("unmatched '}}' in '{s}'"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(PluginFormat(segments))
+    }
+}
+
+impl PluginFormat {
+    /// Missing fields are replaced with `default`.
+    pub fn format(&self, fields: &BTreeMap<String, String>, default: &str, out: &mut String) {
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Field(name) => {
+                    out.push_str(fields.get(name).map_or(default, String::as_str))
+                }
+            }
+        }
+    }
+}
+
+/// `KEY=VALUE` given with `--plugin-config`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigEntry(pub String, pub String);
+
+impl FromStr for ConfigEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected KEY=VALUE"))?;
+        anyhow::ensure!(!key.is_empty(), "empty key in '{s}'");
+        Ok(ConfigEntry(key.to_owned(), value.to_owned()))
+    }
+}
+
+struct Running {
+    child: Child,
+    // closed when the source goes away, so the plugin sees EOF
+    _stdin: ChildStdin,
+    receiver: Receiver<Output>,
+}
+
+/// Runs an executable speaking JSON lines: it gets a handshake with the configuration on its
+/// standard input and prints [`Message`]s, one per line.
+pub struct PluginSource {
+    cmd: process::Command,
+    description: String,
+    config: Vec<(String, String)>,
+    format: Option<PluginFormat>,
+    default: String,
+    text: String,
+    prefix: String,
+    suffix: String,
+    fields: BTreeMap<String, String>,
+    error: Option<String>,
+    running: Option<Running>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    max_backoff: Duration,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl PluginSource {
+    pub fn new(cmd: Command, prefix: String, suffix: String) -> Self {
+        let description = cmd.to_string();
+        let mut cmd = process::Command::from(cmd);
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+        Self {
+            cmd,
+            description,
+            config: Vec::new(),
+            format: None,
+            default: String::new(),
+            text: String::new(),
+            prefix,
+            suffix,
+            fields: BTreeMap::new(),
+            error: None,
+            running: None,
+            waker: Arc::new(Mutex::new(None)),
+            max_backoff: Duration::from_secs(60),
+            failures: 0,
+            retry_at: None,
+        }
+    }
+    /// `KEY=VALUE` pairs sent to the plugin in the handshake.
+    pub fn with_config(mut self, config: impl IntoIterator<Item = ConfigEntry>) -> Self {
+        self.config = config.into_iter().map(|ConfigEntry(k, v)| (k, v)).collect();
+        self
+    }
+    /// Renders the fields instead of using the plugin's text.
+    pub fn with_format(mut self, format: Option<PluginFormat>, default: String) -> Self {
+        self.format = format;
+        self.default = default;
+        self
+    }
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+    fn handshake(&self) -> String {
+        let config = self
+            .config
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<BTreeMap<_, _>>();
+        serde_json::json!({
+            "protocol": PROTOCOL_VERSION,
+            "version": env!("CARGO_PKG_VERSION"),
+            "config": config,
+        })
+        .to_string()
+    }
+    fn spawn(&mut self) -> anyhow::Result<()> {
+        let mut child = self.cmd.spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        writeln!(stdin, "{}", self.handshake())?;
+        stdin.flush()?;
+        let (sender, receiver) = mpsc::channel();
+        let waker = Arc::clone(&self.waker);
+        thread::spawn(move || read_messages(BufReader::new(stdout), sender, waker));
+        self.running = Some(Running {
+            child,
+            _stdin: stdin,
+            receiver,
+        });
+        Ok(())
+    }
+    fn fail(&mut self, error: String) -> anyhow::Error {
+        self.failures += 1;
+        let delay = backoff_delay(self.failures, self.max_backoff);
+        log::warn!(
+            "Plugin failed {} time(s) in a row, restarting in {delay:?}",
+            self.failures
+        );
+        self.retry_at = Some(Instant::now() + delay);
+        self.error = Some(error.clone());
+        anyhow::anyhow!(error)
+    }
+    fn apply(&mut self, message: Message) -> ContentChange {
+        let mut change = ContentChange::empty();
+        if self.failures > 0 {
+            log::info!("Plugin is running again");
+            self.failures = 0;
+        }
+        self.error = message.error;
+        if let Some(fields) = message.fields {
+            self.fields = fields
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Null => String::new(),
+                        value => value.to_string(),
+                    };
+                    (name, value)
+                })
+                .collect();
+            if self.format.is_some() {
+                change |= ContentChange::Running;
+            }
+        }
+        for (new, old, part) in [
+            (message.text, &mut self.text, ContentChange::Running),
+            (message.prefix, &mut self.prefix, ContentChange::Prefix),
+            (message.suffix, &mut self.suffix, ContentChange::Suffix),
+        ] {
+            if let Some(new) = new.filter(|new| new != old) {
+                *old = new;
+                change |= part;
+            }
+        }
+        change
+    }
+    fn render(&self, content: &mut String) {
+        content.clear();
+        match &self.format {
+            Some(format) => {
+                let mut fields = self.fields.clone();
+                fields
+                    .entry("text".to_owned())
+                    .or_insert_with(|| self.text.clone());
+                format.format(&fields, &self.default, content);
+            }
+            None => content.push_str(&self.text),
+        }
+    }
+    /// Applies every message received since the last call.
+    fn receive(&mut self) -> anyhow::Result<ContentChange> {
+        let mut change = ContentChange::empty();
+        loop {
+            let Some(running) = &mut self.running else {
+                if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                    anyhow::bail!(
+                        "{}",
+                        self.error.as_deref().unwrap_or("plugin is not running")
+                    );
+                }
+                if let Err(e) = self.spawn() {
+                    return Err(self.fail(format!("Failed starting plugin: {e}")));
+                }
+                continue;
+            };
+            match running.receiver.try_recv() {
+                Ok(Output::Message(message)) => change |= self.apply(message),
+                Ok(Output::Closed) | Err(mpsc::TryRecvError::Disconnected) => {
+                    let mut running = self.running.take().unwrap();
+                    let status = running.child.wait()?;
+                    return Err(self.fail(format!("Plugin exited: {status}")));
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+            }
+        }
+        match &self.error {
+            Some(error) => anyhow::bail!("{error}"),
+            None => Ok(change),
+        }
+    }
+}
+
+impl TextSource for PluginSource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        self.spawn()?;
+        let running = self.running.as_mut().unwrap();
+        match running.receiver.recv_timeout(FIRST_MESSAGE_TIMEOUT) {
+            Ok(Output::Message(message)) => {
+                self.apply(message);
+            }
+            Ok(Output::Closed) | Err(RecvTimeoutError::Disconnected) => {
+                log::debug!("Plugin exited before sending anything");
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                log::debug!("No message from the plugin in {FIRST_MESSAGE_TIMEOUT:?}")
+            }
+        }
+        let mut running = String::new();
+        self.render(&mut running);
+        Ok(Content::new(
+            running,
+            self.prefix.clone(),
+            self.suffix.clone(),
+        ))
+    }
+    fn poll(
+        &mut self,
+        content: &mut String,
+        prefix: &mut String,
+        suffix: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        let change = self.receive()?;
+        if change.contains(ContentChange::Running) {
+            self.render(content);
+        }
+        if change.contains(ContentChange::Prefix) {
+            self.prefix.clone_into(prefix);
+        }
+        if change.contains(ContentChange::Suffix) {
+            self.suffix.clone_into(suffix);
+        }
+        Ok(change)
+    }
+    fn kind(&self) -> SourceKind {
+        SourceKind::Push
+    }
+    fn watch(&mut self, waker: Waker) -> anyhow::Result<()> {
+        *self.waker.lock().unwrap() = Some(waker);
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        format!("plugin {}", self.description)
+    }
+    fn name(&self) -> &'static str {
+        "plugin"
+    }
+    fn is_connected(&self) -> Option<bool> {
+        Some(self.running.is_some())
+    }
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut fields = String::new();
+        for (name, value) in &self.fields {
+            let _ = writeln!(fields, "{name}={value}");
+        }
+        vec![("PLUGIN_FIELDS", fields)]
+    }
+}
+
+impl Drop for PluginSource {
+    fn drop(&mut self) {
+        if let Some(mut running) = self.running.take() {
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+    }
+}
+
+fn read_messages(input: impl BufRead, sender: Sender<Output>, waker: Arc<Mutex<Option<Waker>>>) {
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("Stopped reading the plugin output: {e}");
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = match serde_json::from_str::<Message>(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("Ignoring invalid plugin message '{line}': {e}");
+                continue;
+            }
+        };
+        if sender.send(Output::Message(message)).is_err() {
+            return;
+        }
+        if let Some(waker) = &*waker.lock().unwrap() {
+            waker.wake();
+        }
+    }
+    let _ = sender.send(Output::Closed);
+    if let Some(waker) = &*waker.lock().unwrap() {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, thread, time::Duration};
+
+    use super::{Message, PluginFormat, PluginSource};
+    use crate::{utils::Command, ContentChange, TextSource};
+
+    #[test]
+    fn message_parse_test() {
+        let message: Message =
+            serde_json::from_str(r#"{"text":"hi","fields":{"temp":21.5,"city":"Oslo"}}"#).unwrap();
+        assert_eq!(message.text.as_deref(), Some("hi"));
+        assert_eq!(message.prefix, None);
+        assert_eq!(message.fields.unwrap().len(), 2);
+        assert!(serde_json::from_str::<Message>(r#"{"text":1}"#).is_err());
+    }
+
+    #[test]
+    fn format_test() {
+        let fields = BTreeMap::from([
+            ("city".to_owned(), "Oslo".to_owned()),
+            ("temp".to_owned(), "21.5".to_owned()),
+        ]);
+        let format: PluginFormat = "{city}: {temp}°C {{{wind}}}".parse().unwrap();
+        let mut out = String::new();
+        format.format(&fields, "?", &mut out);
+        assert_eq!(out, "Oslo: 21.5°C {?}");
+        assert!("{city".parse::<PluginFormat>().is_err());
+        assert!("}".parse::<PluginFormat>().is_err());
+        assert!("{}".parse::<PluginFormat>().is_err());
+    }
+
+    #[test]
+    fn plugin_test() {
+        let script = r#"echo '{"text":"first","prefix":"> "}'; read -r _; read -r _"#;
+        let mut source = PluginSource::new(Command::shell([script]), String::new(), String::new());
+        let content = source.get_initial_content().unwrap();
+        assert_eq!(content.running, "first");
+        assert_eq!(content.prefix, "> ");
+        assert_eq!(source.is_connected(), Some(true));
+
+        let mut source = PluginSource::new(
+            Command::shell([r#"read -r handshake; echo "{\"fields\":{\"hello\":$handshake}}""#]),
+            String::new(),
+            String::new(),
+        )
+        .with_config(["city=Oslo".parse().unwrap()])
+        .with_format(Some("{hello}".parse().unwrap()), String::new());
+        let content = source.get_initial_content().unwrap();
+        let handshake: serde_json::Value = serde_json::from_str(&content.running).unwrap();
+        assert_eq!(handshake["protocol"], 1);
+        assert_eq!(handshake["config"]["city"], "Oslo");
+
+        // the plugin has exited after its only message
+        let (mut running, mut prefix, mut suffix) = (String::new(), String::new(), String::new());
+        thread::sleep(Duration::from_millis(100));
+        let error = source
+            .poll(&mut running, &mut prefix, &mut suffix)
+            .unwrap_err();
+        assert!(error.to_string().contains("exited"), "{error}");
+        assert_eq!(source.is_connected(), Some(false));
+    }
+
+    #[test]
+    fn apply_test() {
+        let mut source = PluginSource::new(Command::shell(["true"]), String::new(), String::new());
+        let change = source.apply(Message {
+            text: Some("a".to_owned()),
+            suffix: Some(String::new()),
+            ..Default::default()
+        });
+        assert_eq!(change, ContentChange::Running);
+        let change = source.apply(Message {
+            text: Some("a".to_owned()),
+            error: Some("offline".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(change, ContentChange::empty());
+        assert_eq!(source.error.as_deref(), Some("offline"));
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Whether the machine runs on battery, read from sysfs at startup and then every
+/// [`PowerMonitor::INTERVAL`] in the background.
+#[derive(Debug, Clone)]
+pub struct PowerMonitor(Arc<AtomicBool>);
+
+impl PowerMonitor {
+    const INTERVAL: Duration = Duration::from_secs(30);
+    const SUPPLIES: &'static str = "/sys/class/power_supply";
+
+    pub fn start() -> Self {
+        let supplies = Path::new(Self::SUPPLIES);
+        let state = Arc::new(AtomicBool::new(on_battery(supplies)));
+        let shared = Arc::downgrade(&state);
+        thread::spawn(move || loop {
+            thread::sleep(Self::INTERVAL);
+            // nobody is interested anymore
+            let Some(state) = shared.upgrade() else {
+                break;
+            };
+            let now = on_battery(supplies);
+            if state.swap(now, Ordering::Relaxed) != now {
+                log::info!("Running on {}", if now { "battery" } else { "AC" });
+            }
+        });
+        Self(state)
+    }
+    pub fn on_battery(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A machine without power supplies in sysfs (a desktop, or not Linux) counts as plugged in.
+fn on_battery(supplies: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(supplies) else {
+        return false;
+    };
+    let read = |path: &Path, name: &str| fs::read_to_string(path.join(name)).unwrap_or_default();
+    let mut discharging = false;
+    for path in entries.flatten().map(|entry| entry.path()) {
+        match read(&path, "type").trim() {
+            "Mains" | "USB" if read(&path, "online").trim() == "1" => return false,
+            "Battery" => discharging |= read(&path, "status").trim() == "Discharging",
+            _ => {}
+        }
+    }
+    discharging
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::on_battery;
+
+    #[test]
+    fn on_battery_test() {
+        let dir = std::env::temp_dir().join(format!("mergneh-power-{}", std::process::id()));
+        let supply = |name: &str, files: &[(&str, &str)]| {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            for (file, value) in files {
+                fs::write(dir.join(name).join(file), format!("{value}\n")).unwrap();
+            }
+        };
+        assert!(!on_battery(&dir));
+
+        supply("BAT0", &[("type", "Battery"), ("status", "Discharging")]);
+        assert!(on_battery(&dir));
+        supply("AC", &[("type", "Mains"), ("online", "0")]);
+        assert!(on_battery(&dir));
+        supply("AC", &[("type", "Mains"), ("online", "1")]);
+        assert!(!on_battery(&dir));
+
+        supply("AC", &[("type", "Mains"), ("online", "0")]);
+        supply("BAT0", &[("type", "Battery"), ("status", "Full")]);
+        assert!(!on_battery(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    env, fs,
+    fs::File,
+    io::{LineWriter, Write},
+    iter::Peekable,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+    vec,
+};
+
+use clap::ArgMatches;
+
+use crate::{
+    events::Frames,
+    sink::Sink,
+    text_source::{Content, ContentChange, RunStats, TextSource},
+    utils::JsonEscaped,
+    RunningText,
+};
+
+/// Writes what a source reported and the frames rendered from it, one event per line as
+/// `<milliseconds> <frame> <kind> <text>` separated by tabs, with the text escaped like a JSON
+/// string. The first line holds the command line, so a replay lays the text out the same way.
+pub struct Recorder {
+    out: Option<LineWriter<File>>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("Failed creating {}: {e}", path.display()))?;
+        let mut out = LineWriter::new(file);
+        write!(out, "args")?;
+        for arg in env::args_os().skip(1) {
+            write!(out, "\t{}", JsonEscaped(&arg.to_string_lossy()))?;
+        }
+        writeln!(out)?;
+        Ok(Self {
+            out: Some(out),
+            started: Instant::now(),
+        })
+    }
+    fn event(&mut self, frame: Option<usize>, kind: &str, text: &str) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+        let frame = frame.map_or("init".to_owned(), |f| f.to_string());
+        let written = writeln!(
+            out,
+            "{}\t{frame}\t{kind}\t{}",
+            self.started.elapsed().as_millis(),
+            JsonEscaped(text)
+        );
+        // a full disk shouldn't take the text down with it
+        if let Err(e) = written {
+            log::error!("Recording stopped: {e}");
+            self.out = None;
+        }
+    }
+    /// Records the parts of the contents in `changes` as polled for `frame`, or as the initial
+    /// contents without a frame.
+    pub fn snapshot(
+        &mut self,
+        frame: Option<usize>,
+        changes: ContentChange,
+        [running, prefix, suffix]: [&str; 3],
+        separator: Option<&str>,
+    ) {
+        for (part, text) in [
+            (ContentChange::Running, running),
+            (ContentChange::Prefix, prefix),
+            (ContentChange::Suffix, suffix),
+        ] {
+            if changes.contains(part) {
+                self.event(frame, part.names().next().unwrap(), text);
+            }
+        }
+        if let Some(separator) = separator.filter(|_| changes.contains(ContentChange::Separator)) {
+            self.event(frame, "separator", separator);
+        }
+    }
+    pub fn error(&mut self, frame: usize, error: &str) {
+        self.event(Some(frame), "error", error);
+    }
+    pub fn frame(&mut self, frame: usize, text: &str) {
+        self.event(Some(frame), "frame", text);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Part(ContentChange, String),
+    Error(String),
+}
+
+/// A file written by [`Recorder`].
+#[derive(Debug)]
+pub struct Recording {
+    args: Vec<String>,
+    /// Source events by the frame they were polled for, the initial contents come first.
+    events: Vec<(Option<usize>, Event)>,
+    frames: Vec<(usize, String)>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed reading {}: {e}", path.display()))?
+            .parse()
+            .map_err(|e: anyhow::Error| e.context(format!("Invalid recording {}", path.display())))
+    }
+    /// Command line the recording was made with, as [`clap::Command::get_matches_from`] takes it.
+    pub fn args(&self) -> impl Iterator<Item = &str> {
+        ["mg"]
+            .into_iter()
+            .chain(self.args.iter().map(String::as_str))
+    }
+    /// Renders the recorded contents with the options in `matches` frame by frame and prints the
+    /// frames. Fails if any differs from the recorded one.
+    pub fn replay(self, matches: &mut ArgMatches) -> anyhow::Result<()> {
+        // the replay must not write where the recorded run did
+        matches.remove_one::<PathBuf>("record");
+        matches.remove_many::<PathBuf>("tee");
+        matches.remove_one::<String>("on-change");
+        let Some(last) = self
+            .frames
+            .iter()
+            .map(|(f, _)| *f)
+            .chain(self.events.iter().filter_map(|(f, _)| *f))
+            .max()
+        else {
+            return Ok(());
+        };
+        let mut recorded = self.frames.into_iter().peekable();
+        let source = ReplaySource {
+            events: self.events.into_iter().peekable(),
+            separator: None,
+            frame: 0,
+        };
+        let mut text = RunningText::from_matches_with_source(Box::new(source), matches)?
+            .with_refresh_interval(None);
+        let mut out = text.stdout();
+        let (mut compared, mut differing) = (0, 0);
+        for frame in 0..=last {
+            let Some(result) = text.advance() else {
+                break;
+            };
+            result?;
+            let rendered = text.frame().text;
+            if text.frame_changed() {
+                out.write(text.frame().into())?;
+            }
+            if let Some((_, expected)) = recorded.next_if(|(f, _)| *f == frame) {
+                compared += 1;
+                if expected != rendered {
+                    differing += 1;
+                    log::warn!("Frame {frame} was {expected:?}, replayed as {rendered:?}");
+                }
+            }
+        }
+        if differing > 0 {
+            anyhow::bail!("{differing} of {compared} recorded frames differ from the replay");
+        }
+        Ok(())
+    }
+    fn push_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let [_, frame, kind, text]: [&str; 4] = line
+            .splitn(4, '\t')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("expected 4 fields separated by tabs"))?;
+        let text = unescape(text)?;
+        let frame = match frame {
+            "init" => None,
+            frame => Some(frame.parse::<usize>()?),
+        };
+        let event = match kind {
+            "running" => Event::Part(ContentChange::Running, text),
+            "prefix" => Event::Part(ContentChange::Prefix, text),
+            "suffix" => Event::Part(ContentChange::Suffix, text),
+            "separator" => Event::Part(ContentChange::Separator, text),
+            "error" => Event::Error(text),
+            "frame" => {
+                let frame = frame.ok_or_else(|| anyhow::anyhow!("a frame without a number"))?;
+                self.frames.push((frame, text));
+                return Ok(());
+            }
+            kind => anyhow::bail!("unknown event '{kind}'"),
+        };
+        self.events.push((frame, event));
+        Ok(())
+    }
+}
+
+impl FromStr for Recording {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines();
+        let mut fields = lines.next().unwrap_or_default().split('\t');
+        if fields.next() != Some("args") {
+            anyhow::bail!("line 1: the command line is missing");
+        }
+        let args = fields.map(unescape).collect::<anyhow::Result<_>>()?;
+        let mut recording = Recording {
+            args,
+            events: Vec::new(),
+            frames: Vec::new(),
+        };
+        for (i, line) in lines.enumerate() {
+            recording
+                .push_line(line)
+                .map_err(|e| e.context(format!("line {}", i + 2)))?;
+        }
+        Ok(recording)
+    }
+}
+
+/// Reverses [`JsonEscaped`].
+fn unescape(text: &str) -> anyhow::Result<String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some(c @ ('"' | '\\')) => c,
+            Some('u') => {
+                let code = chars.by_ref().take(4).collect::<String>();
+                u32::from_str_radix(&code, 16)
+                    .ok()
+                    .filter(|_| code.len() == 4)
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| anyhow::anyhow!("invalid escape '\\u{code}'"))?
+            }
+            _ => anyhow::bail!("invalid escape in {text:?}"),
+        });
+    }
+    Ok(unescaped)
+}
+
+/// Gives back the recorded contents at the frames they were polled for.
+struct ReplaySource {
+    events: Peekable<vec::IntoIter<(Option<usize>, Event)>>,
+    separator: Option<String>,
+    frame: usize,
+}
+
+impl ReplaySource {
+    /// Applies the events recorded up to `frame`, `None` for the initial contents only.
+    fn apply(
+        &mut self,
+        frame: Option<usize>,
+        content: &mut String,
+        prefix: &mut String,
+        suffix: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        let mut changes = ContentChange::empty();
+        while let Some((_, event)) = self.events.next_if(|(f, _)| *f <= frame) {
+            let (part, text) = match event {
+                Event::Part(part, text) => (part, text),
+                Event::Error(e) => return Err(anyhow::anyhow!(e)),
+            };
+            changes |= part;
+            match part {
+                ContentChange::Running => *content = text,
+                ContentChange::Prefix => *prefix = text,
+                ContentChange::Suffix => *suffix = text,
+                _ => self.separator = Some(text),
+            }
+        }
+        Ok(changes)
+    }
+}
+
+impl TextSource for ReplaySource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        let mut content = Content::new(String::new(), String::new(), String::new());
+        self.apply(
+            None,
+            &mut content.running,
+            &mut content.prefix,
+            &mut content.suffix,
+        )?;
+        Ok(content)
+    }
+    fn poll(
+        &mut self,
+        content: &mut String,
+        prefix: &mut String,
+        suffix: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        self.apply(Some(self.frame), content, prefix, suffix)
+    }
+    fn set_stats(&mut self, stats: RunStats) {
+        self.frame = stats.frame;
+    }
+    fn separator(&self) -> Option<&str> {
+        self.separator.as_deref()
+    }
+    fn describe(&self) -> String {
+        "replay".to_owned()
+    }
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unescape, Recording, ReplaySource};
+    use crate::{
+        text_source::{ContentChange, RunStats, TextSource},
+        utils::JsonEscaped,
+    };
+
+    const RECORDING: &str = "args\t--mpd\trun\n\
+        0\tinit\trunning\tSigur Rós - Hoppípolla\n\
+        0\tinit\tprefix\t\\u266b \n\
+        3\t0\tframe\t\\u266b Sigur Rós\n\
+        1004\t1\tframe\t\\u266b igur Rós \n\
+        2010\t2\trunning\tJónsi\\tGo\n\
+        2010\t2\tprefix\t\n\
+        2011\t2\tframe\tJónsi Go\n\
+        3002\t3\terror\tConnection refused\n";
+
+    #[test]
+    fn unescape_test() {
+        for text in ["", "a \"quoted\" \\ path\n", "tab\there", "\u{1b}[1m"] {
+            assert_eq!(unescape(&JsonEscaped(text).to_string()).unwrap(), text);
+        }
+        assert!(unescape("\\x").is_err());
+        assert!(unescape("\\u12").is_err());
+    }
+
+    #[test]
+    fn parse_test() {
+        let recording = RECORDING.parse::<Recording>().unwrap();
+        assert_eq!(recording.args().collect::<Vec<_>>(), ["mg", "--mpd", "run"]);
+        assert_eq!(recording.events.len(), 5);
+        assert_eq!(recording.frames.len(), 3);
+        assert_eq!(recording.frames[2], (2, "Jónsi Go".to_owned()));
+
+        assert!("0\t0\tframe\ttext".parse::<Recording>().is_err());
+        assert!("args\n0\t0\tframe".parse::<Recording>().is_err());
+        assert!("args\n0\tinit\tframe\ttext".parse::<Recording>().is_err());
+        assert!("args\n0\t0\tscroll\ttext".parse::<Recording>().is_err());
+    }
+
+    #[test]
+    fn replay_source_test() {
+        let recording = RECORDING.parse::<Recording>().unwrap();
+        let mut source = ReplaySource {
+            events: recording.events.into_iter().peekable(),
+            separator: None,
+            frame: 0,
+        };
+        let mut content = source.get_initial_content().unwrap();
+        assert_eq!(content.running, "Sigur Rós - Hoppípolla");
+        assert_eq!(content.prefix, "♫ ");
+
+        let mut poll = |source: &mut ReplaySource, frame| {
+            source.set_stats(RunStats {
+                frame,
+                ..Default::default()
+            });
+            source.poll(
+                &mut content.running,
+                &mut content.prefix,
+                &mut content.suffix,
+            )
+        };
+        assert_eq!(poll(&mut source, 0).unwrap(), ContentChange::empty());
+        assert_eq!(
+            poll(&mut source, 2).unwrap(),
+            ContentChange::Running | ContentChange::Prefix
+        );
+        assert_eq!(
+            poll(&mut source, 3).unwrap_err().to_string(),
+            "Connection refused"
+        );
+        assert_eq!(poll(&mut source, 4).unwrap(), ContentChange::empty());
+        assert_eq!(content.running, "Jónsi\tGo");
+        assert!(content.prefix.is_empty());
+    }
+}
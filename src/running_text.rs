@@ -1,38 +1,208 @@
 use std::{
-    io::{self, Write},
-    time::Duration,
+    fmt::{self, Display},
+    fs, io, mem,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
-use ticker::Ticker;
-
+#[cfg(feature = "art")]
+use crate::art::{ImageProtocol, RunningTextWithArt};
+#[cfg(feature = "wasm")]
+use crate::wasm::WasmFilter;
 #[cfg(feature = "waybar")]
-use crate::waybar::{RunningTextWithTooltip, Tooltip};
+use crate::waybar::{ModuleOptions, RunningTextWithTooltip, Tooltip, WaybarOutput};
 use crate::{
-    text_source::{Content, ContentChange},
-    utils::replace_newline,
-    TextSource,
+    events::{EventLoop, Frames, LoopOptions, Waker},
+    hook::Hook,
+    power::PowerMonitor,
+    record::Recorder,
+    sink::{FileSink, Frame, Sink, StdoutSink, TerminalGuard, TerminalSink, Terminator},
+    text_source::{
+        self, Content, ContentChange, RunStats, ScrolledAffixes, SourceKind, TextSource,
+    },
+    utils::{
+        normalize_nfc, reorder_bidi, replace_newline, strip_escapes, terminal_width,
+        truncate_graphemes, truncate_graphemes_middle, ColorMode, MarkupEscaped,
+    },
 };
+use clap::ArgMatches;
+
+/// How content from the source becomes the text that is scrolled. The steps run in the order of
+/// the fields, always on the content as the source gave it, whenever it or the options change.
+#[derive(Default)]
+pub struct LayoutOptions {
+    /// WASM modules the content goes through, in order, before anything else.
+    #[cfg(feature = "wasm")]
+    pub filters: Vec<WasmFilter>,
+    /// Removes escape sequences (e.g. colors of a command's output), for outputs that can't
+    /// show them.
+    pub strip_escapes: bool,
+    /// Removes control characters left after newlines were replaced.
+    pub strip_control: bool,
+    /// Normalizes to NFC, for sources giving decomposed text.
+    pub nfc: bool,
+    /// Grapheme clusters the content is truncated to, the last one replaced by `ellipsis`.
+    pub max_length: Option<usize>,
+    pub ellipsis: String,
+    /// Cuts overlong content to the window in the middle instead of scrolling it.
+    pub truncate_middle: bool,
+    /// Reorders right-to-left runs for display, last so that nothing works on visual order.
+    pub bidi: bool,
+}
 
 pub struct RunningText {
-    source: TextSource,
+    source: Box<dyn TextSource>,
+    /// Content as the source gave it with newlines replaced, laid out into `content`.
+    source_content: String,
+    /// Laid out content followed by the separator.
     content: String,
+    layout: LayoutOptions,
     newline: String,
     separator: String,
+    /// Characters in `separator`, counted once whenever it is set.
+    separator_char_len: usize,
     prefix: String,
     suffix: String,
     window_size: usize,
+    /// Terminal width the window fills after the prefix, the suffix and `reserved_columns`.
+    columns: Option<usize>,
+    reserved_columns: usize,
+    ansi: bool,
     repeat: bool,
     reset_on_change: bool,
+    /// Only a change of [`ContentChange::Identity`] resets the position and runs the hook.
+    identity_changes: bool,
     text: String,
     full_content_char_len: usize,
     content_char_len: usize,
     i: usize,
     byte_offset: usize,
+    pending_changes: ContentChange,
+    /// Parts of the contents changed in the last frame and how many frames had any change.
+    changes: ContentChange,
+    generation: u64,
+    frame_changed: bool,
+    refresh_interval: Option<Duration>,
+    last_refresh: Option<Instant>,
+    refreshed: bool,
+    /// Sinks getting every frame besides the output the text runs on (`--tee` files).
+    sinks: Vec<Box<dyn Sink>>,
+    recorder: Option<Recorder>,
+    error_format: Option<String>,
+    last_error: Option<String>,
+    /// Rendered `error_format` and how far it is scrolled.
+    error_text: String,
+    error_offset: usize,
+    failing: bool,
+    on_change: Option<Hook>,
+    hook_pending: bool,
+    once: bool,
+    frames: usize,
+    max_loops: Option<usize>,
+    loops: usize,
+    loop_options: LoopOptions,
+    /// Whether the last frame was rendered on battery with `low_power`.
+    resting: bool,
+    source_errors: u64,
+    render_time: Duration,
+    /// Escapes the contents (but not the prefix and the suffix) of every frame for Pango markup.
+    escape_markup: bool,
+    rtl: bool,
+    /// Marks laid over the leading and the trailing edge of a scrolling window.
+    lead_mark: String,
+    trail_mark: String,
+    edge_fade: bool,
+    hide_empty: bool,
+    on_demand: bool,
+    scrolling: bool,
+    /// File whose modification starts scrolling on demand and when it was last modified.
+    scroll_trigger: Option<(PathBuf, Option<SystemTime>)>,
+    dedup: bool,
+    terminator: Terminator,
+    instance: Option<String>,
+    started: Instant,
+}
+
+/// A rendered frame and what changed in the contents since the previous one, displayed as the
+/// text alone.
+#[derive(Debug, Clone, Copy)]
+pub struct TextFrame<'a> {
+    pub text: &'a str,
+    pub changes: ContentChange,
+    /// Number of frames so far with changed contents, consumers can tell real changes from
+    /// scrolling by it.
+    pub generation: u64,
+}
+
+impl Display for TextFrame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.text)
+    }
+}
+
+/// Counters since the text was created, displayed in the Prometheus text format.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub instance: Option<String>,
+    pub frames: usize,
+    pub source_errors: u64,
+    pub reconnects: Option<u64>,
+    /// Time spent rendering all `frames`.
+    pub render_time: Duration,
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let labels = match &self.instance {
+            Some(instance) => {
+                let instance = instance
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                format!("{{instance=\"{instance}\"}}")
+            }
+            None => String::new(),
+        };
+        let average = match self.frames {
+            0 => 0.0,
+            frames => self.render_time.as_secs_f64() / frames as f64,
+        };
+        let mut metric = |name, kind, help, value: &dyn Display| {
+            writeln!(f, "# HELP mergneh_{name} {help}")?;
+            writeln!(f, "# TYPE mergneh_{name} {kind}")?;
+            writeln!(f, "mergneh_{name}{labels} {value}")
+        };
+        metric("frames_total", "counter", "Frames rendered.", &self.frames)?;
+        metric(
+            "source_errors_total",
+            "counter",
+            "Polls of the source that failed.",
+            &self.source_errors,
+        )?;
+        if let Some(reconnects) = self.reconnects {
+            metric(
+                "reconnects_total",
+                "counter",
+                "Times the source connected to its server again.",
+                &reconnects,
+            )?;
+        }
+        metric(
+            "render_seconds_average",
+            "gauge",
+            "Average time spent rendering a frame.",
+            &average,
+        )
+    }
 }
 
+// content is already in visual order, so renderers that apply bidi themselves (Pango) must not reorder it again
+const LRO: char = '\u{202D}';
+const PDF: char = '\u{202C}';
+
 impl RunningText {
     pub fn new(
-        mut source: TextSource,
+        mut source: Box<dyn TextSource>,
         window_size: usize,
         mut separator: String,
         newline: String,
@@ -44,6 +214,9 @@ impl RunningText {
             prefix,
             suffix,
         } = source.get_initial_content()?;
+        if let Some(s) = source.separator() {
+            s.clone_into(&mut separator);
+        }
         replace_newline(&mut content, &newline);
         replace_newline(&mut separator, &newline);
         let content_len = content.len();
@@ -57,109 +230,754 @@ impl RunningText {
                 String::new()
             },
             full_content_char_len: count + content[content_len..].chars().count(),
+            source_content: content[..content_len].to_owned(),
             content,
+            layout: LayoutOptions::default(),
             newline,
+            separator_char_len: separator.chars().count(),
             separator,
             prefix,
             suffix,
             window_size,
+            columns: None,
+            reserved_columns: 0,
+            ansi: false,
             repeat,
             reset_on_change,
+            identity_changes: false,
             content_char_len: count,
             i: 0,
             byte_offset: 0,
+            pending_changes: ContentChange::all(),
+            changes: ContentChange::empty(),
+            generation: 0,
+            frame_changed: true,
+            refresh_interval: None,
+            last_refresh: Some(Instant::now()),
+            refreshed: true,
+            sinks: Vec::new(),
+            recorder: None,
+            error_format: None,
+            last_error: None,
+            error_text: String::new(),
+            error_offset: 0,
+            failing: false,
+            on_change: None,
+            hook_pending: true,
+            once: false,
+            frames: 0,
+            max_loops: None,
+            loops: 0,
+            loop_options: LoopOptions::default(),
+            resting: false,
+            source_errors: 0,
+            render_time: Duration::ZERO,
+            escape_markup: false,
+            rtl: false,
+            lead_mark: String::new(),
+            trail_mark: String::new(),
+            edge_fade: false,
+            hide_empty: false,
+            on_demand: false,
+            scrolling: false,
+            scroll_trigger: None,
+            dedup: true,
+            terminator: Terminator::Newline,
+            instance: None,
+            started: Instant::now(),
         })
     }
+    pub fn from_matches(matches: &mut ArgMatches) -> anyhow::Result<Self> {
+        let source = text_source::from_matches(&mut *matches)?;
+        Self::from_matches_with_source(source, matches)
+    }
+    pub fn from_matches_with_source(
+        source: Box<dyn TextSource>,
+        matches: &mut ArgMatches,
+    ) -> anyhow::Result<Self> {
+        // a single frame has no time to show an error, failing is more useful
+        let once = matches.remove_one::<bool>("once").unwrap();
+        // bars show escape sequences as garbage
+        let terminal = !matches!(matches.subcommand_name(), Some("waybar" | "i3bar"))
+            && terminal_width().is_some();
+        let color = matches
+            .remove_one::<ColorMode>("color")
+            .unwrap()
+            .enabled(terminal);
+        let layout = LayoutOptions {
+            #[cfg(feature = "wasm")]
+            filters: matches
+                .remove_many::<PathBuf>("wasm-filter")
+                .into_iter()
+                .flatten()
+                .map(|path| WasmFilter::load(&path))
+                .collect::<anyhow::Result<_>>()?,
+            strip_escapes: !color,
+            // an option of waybar, laid out together with the others
+            strip_control: matches
+                .subcommand_matches("waybar")
+                .is_some_and(|m| m.get_flag("strip-control")),
+            nfc: matches.remove_one("nfc").unwrap(),
+            max_length: matches.remove_one::<u64>("max-length").map(|l| l as usize),
+            ellipsis: matches.remove_one("ellipsis").unwrap(),
+            truncate_middle: matches.remove_one("truncate-middle").unwrap(),
+            bidi: !matches.remove_one::<bool>("no-bidi").unwrap(),
+        };
+        let source: Box<dyn TextSource> = match matches.remove_one("scroll-affixes").unwrap() {
+            true => Box::new(ScrolledAffixes::new(source)),
+            false => source,
+        };
+        let text = Self::new(
+            source,
+            matches.remove_one::<u64>("window").unwrap() as usize,
+            matches.remove_one("separator").unwrap(),
+            matches.remove_one("newline").unwrap(),
+            matches.remove_one("dont-repeat").unwrap(),
+            matches.remove_one("reset-on-change").unwrap(),
+        )?
+        .with_recorder(
+            matches
+                .remove_one::<PathBuf>("record")
+                .map(|path| Recorder::create(&path))
+                .transpose()?,
+        )
+        .with_refresh_interval(
+            matches
+                .remove_one::<humantime::Duration>("refresh-interval")
+                .map(Into::into),
+        )
+        .with_sinks(
+            matches
+                .remove_many::<PathBuf>("tee")
+                .into_iter()
+                .flatten()
+                .map(|path| {
+                    Ok(Box::new(
+                        FileSink::open(path)?.with_min_interval(
+                            matches
+                                .get_one::<humantime::Duration>("tee-interval")
+                                .map(|&d| d.into()),
+                        ),
+                    ) as Box<dyn Sink>)
+                })
+                .collect::<io::Result<_>>()?,
+        )
+        .with_dedup(!matches.remove_one::<bool>("no-dedup").unwrap())
+        // only MPD tells songs apart
+        .with_identity_changes(
+            matches
+                .try_remove_one::<String>("change-detection")
+                .ok()
+                .flatten()
+                .is_some_and(|mode| mode == "song"),
+        )
+        .with_terminator(matches.remove_one("terminator").unwrap())
+        .with_error_format(matches.remove_one("error-format").filter(|_| !once))
+        .with_once(once)
+        .with_layout(layout)
+        .with_edge_marks(
+            matches.remove_one("lead-mark").unwrap_or_default(),
+            matches.remove_one("trail-mark").unwrap_or_default(),
+        )
+        .with_hide_empty(matches.remove_one("hide-empty").unwrap())
+        .with_on_demand(matches.remove_one("on-demand").unwrap())
+        .with_scroll_trigger(matches.remove_one("scroll-trigger"))
+        .with_max_loops(matches.remove_one::<u64>("max-loops").map(|n| n as usize))
+        .with_run_for(
+            matches
+                .remove_one::<humantime::Duration>("run-for")
+                .map(Into::into),
+        )
+        .with_align_ticks(matches.remove_one("align-ticks").unwrap())
+        .with_max_fps(matches.remove_one("max-fps").unwrap())
+        .with_refresh_signal(matches.remove_one("refresh-signal"))
+        .with_low_power(matches.remove_one("low-power").unwrap())
+        .with_on_change(matches.remove_one("on-change").map(Hook::new))
+        .with_instance(matches.get_one::<String>("instance").cloned());
+        Ok(text)
+    }
+    /// Sizes the window to fill the terminal line, the window given to [`RunningText::new`] stays
+    /// when the output is not a terminal.
+    pub fn with_auto_window(mut self, auto: bool) -> Self {
+        if auto {
+            self.columns = terminal_width();
+            if self.columns.is_none() {
+                log::debug!("Output is not a terminal, keeping the window size");
+            }
+            self.fit_window();
+        }
+        self
+    }
+    /// Redraws the line in place with the cursor hidden when running in a terminal.
+    pub fn with_ansi(mut self, ansi: bool) -> Self {
+        self.ansi = ansi;
+        self
+    }
+    /// Erases what a shorter frame would leave of the previous one.
+    fn clear_line(&self) -> &'static str {
+        match self.ansi || self.columns.is_some() {
+            true => "\x1b[K",
+            false => "",
+        }
+    }
+    /// Leaves columns before the text to something else (e.g. album art) when the window fills
+    /// the terminal.
+    pub fn reserve_columns(&mut self, columns: usize) {
+        self.reserved_columns = columns;
+        self.fit_window();
+    }
+    fn fit_window(&mut self) {
+        let Some(columns) = self.columns else {
+            return;
+        };
+        let taken =
+            self.reserved_columns + self.prefix.chars().count() + self.suffix.chars().count();
+        let window_size = columns.saturating_sub(taken).max(1);
+        if window_size != self.window_size {
+            log::debug!("Window size is {window_size} for {columns} columns");
+            self.window_size = window_size;
+            if self.layout.truncate_middle {
+                self.layout_content();
+            }
+            // the text has to be laid out for the new window
+            self.pending_changes.insert(ContentChange::Running);
+        }
+    }
+    /// Lays the content out anew with all of the options, see [`LayoutOptions`].
+    pub fn with_layout(mut self, layout: LayoutOptions) -> Self {
+        self.layout = layout;
+        self.layout_content();
+        self
+    }
+    /// Changes one of the layout options and lays the content out anew.
+    fn relayout(mut self, set: impl FnOnce(&mut LayoutOptions)) -> Self {
+        set(&mut self.layout);
+        self.layout_content();
+        self
+    }
+    pub fn with_bidi(self, bidi: bool) -> Self {
+        self.relayout(|layout| layout.bidi = bidi)
+    }
+    pub fn with_strip_escapes(self, strip_escapes: bool) -> Self {
+        self.relayout(|layout| layout.strip_escapes = strip_escapes)
+    }
+    pub fn with_strip_control(self, strip_control: bool) -> Self {
+        self.relayout(|layout| layout.strip_control = strip_control)
+    }
+    /// Writes `&`, `<` and `>` of the contents as entities, so a title can't break a bar
+    /// rendering the text as Pango markup; the prefix and the suffix may still use markup.
+    pub fn with_escape_markup(mut self, escape_markup: bool) -> Self {
+        self.escape_markup = escape_markup;
+        if !self.text.is_empty() && self.does_content_fit() {
+            self.write_whole_text();
+        }
+        self
+    }
+    pub fn with_nfc(self, nfc: bool) -> Self {
+        self.relayout(|layout| layout.nfc = nfc)
+    }
+    /// Runs content through WASM modules, in order, on the text as the source gave it.
+    #[cfg(feature = "wasm")]
+    pub fn with_filters(self, filters: Vec<WasmFilter>) -> Self {
+        self.relayout(|layout| layout.filters = filters)
+    }
+    pub fn with_max_length(self, max_length: Option<usize>, ellipsis: String) -> Self {
+        self.relayout(|layout| (layout.max_length, layout.ellipsis) = (max_length, ellipsis))
+    }
+    /// The ellipsis comes from [`RunningText::with_max_length`].
+    pub fn with_truncate_middle(self, truncate_middle: bool) -> Self {
+        self.relayout(|layout| layout.truncate_middle = truncate_middle)
+    }
+    /// Shows over the edges of a scrolling window that the text goes on beyond them, the
+    /// leading mark only once the start has scrolled out.
+    pub fn with_edge_marks(mut self, lead_mark: String, trail_mark: String) -> Self {
+        self.lead_mark = lead_mark;
+        self.trail_mark = trail_mark;
+        self
+    }
+    /// Dims the outermost characters of a scrolling window in a terminal, unless escape
+    /// sequences are stripped.
+    pub fn with_edge_fade(mut self, edge_fade: bool) -> Self {
+        self.edge_fade = edge_fade && !self.layout.strip_escapes;
+        self
+    }
+    /// Renders empty frames instead of a bare prefix and suffix while there is no content.
+    pub fn with_hide_empty(mut self, hide_empty: bool) -> Self {
+        self.hide_empty = hide_empty;
+        self
+    }
+    /// Keeps the text still until [`Frames::scroll_once`] is called.
+    pub fn with_on_demand(mut self, on_demand: bool) -> Self {
+        self.on_demand = on_demand;
+        self.loop_options.scroll_signal = on_demand;
+        self
+    }
+    /// Scrolls once whenever the file is modified, e.g. `touch`ed by a bar's click handler.
+    pub fn with_scroll_trigger(mut self, path: Option<PathBuf>) -> Self {
+        self.scroll_trigger = path.map(|path| {
+            let modified = modified(&path);
+            (path, modified)
+        });
+        self
+    }
+    pub fn with_max_loops(mut self, max_loops: Option<usize>) -> Self {
+        self.max_loops = max_loops;
+        self
+    }
+    pub fn with_run_for(mut self, duration: Option<Duration>) -> Self {
+        self.loop_options.deadline = duration.map(|d| Instant::now() + d);
+        self
+    }
+    /// Renders on wall-clock multiples of the tick duration, see [`LoopOptions::aligned`].
+    pub fn with_align_ticks(mut self, align_ticks: bool) -> Self {
+        self.loop_options.aligned = align_ticks;
+        self
+    }
+    /// Caps how many frames a second are rendered, 0 lifts the cap.
+    pub fn with_max_fps(mut self, max_fps: u32) -> Self {
+        self.loop_options.min_interval = (max_fps > 0).then(|| Duration::from_secs(1) / max_fps);
+        self
+    }
+    /// Refreshes on SIGRTMIN+`offset`, see [`LoopOptions::refresh_signal`].
+    pub fn with_refresh_signal(mut self, offset: Option<i32>) -> Self {
+        self.loop_options.refresh_signal = offset;
+        self
+    }
+    /// Keeps the text still and ticks less often while the machine runs on battery.
+    pub fn with_low_power(mut self, low_power: bool) -> Self {
+        self.loop_options.low_power = low_power.then(PowerMonitor::start);
+        self
+    }
+    /// Settings of the [`EventLoop`] driving the text.
+    pub fn loop_options(&self) -> &LoopOptions {
+        &self.loop_options
+    }
+    pub fn with_once(mut self, once: bool) -> Self {
+        self.once = once;
+        self
+    }
+    pub fn with_on_change(mut self, hook: Option<Hook>) -> Self {
+        self.on_change = hook;
+        self
+    }
+    pub fn with_instance(mut self, instance: Option<String>) -> Self {
+        self.instance = instance;
+        self
+    }
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            instance: self.instance.clone(),
+            frames: self.frames,
+            source_errors: self.source_errors,
+            reconnects: self.source.reconnects(),
+            render_time: self.render_time,
+        }
+    }
+    pub fn with_error_format(mut self, format: Option<String>) -> Self {
+        self.error_format = format;
+        self
+    }
+    /// Records the contents and the frames, starting with the contents given by the source on
+    /// creation, so it has to come before anything transforms them.
+    pub fn with_recorder(mut self, recorder: Option<Recorder>) -> Self {
+        self.recorder = recorder;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.snapshot(
+                None,
+                ContentChange::all(),
+                [&self.source_content, &self.prefix, &self.suffix],
+                self.source.separator(),
+            );
+        }
+        self
+    }
+    /// Goes on with the recording and the frame count of the text this one replaces, the new
+    /// contents are recorded as polled for the next frame.
+    pub fn replacing(mut self, old: &mut RunningText) -> Self {
+        self.frames = old.frames;
+        self.recorder = old.recorder.take();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.snapshot(
+                Some(self.frames),
+                ContentChange::all(),
+                [&self.source_content, &self.prefix, &self.suffix],
+                self.source.separator(),
+            );
+        }
+        self
+    }
+    pub fn with_sinks(mut self, sinks: Vec<Box<dyn Sink>>) -> Self {
+        self.sinks = sinks;
+        self
+    }
+    /// Adds a sink getting every frame, whatever the text runs on.
+    pub fn with_sink(mut self, mut sink: impl Sink + 'static) -> Self {
+        sink.set_dedup(self.dedup);
+        self.sinks.push(Box::new(sink));
+        self
+    }
+    /// Whether outputs skip frames identical to the ones they got last.
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        for sink in &mut self.sinks {
+            sink.set_dedup(dedup);
+        }
+        self
+    }
+    /// Ends frames printed one after another (`--once`, `iter` and `daemon`).
+    pub fn with_terminator(mut self, terminator: Terminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+    pub fn terminator(&self) -> Terminator {
+        self.terminator
+    }
+    pub fn stdout(&self) -> StdoutSink {
+        StdoutSink::new()
+            .with_dedup(self.dedup)
+            .with_terminator(self.terminator)
+    }
+    /// Counts only a different item (e.g. another song) as a change for `reset_on_change` and the
+    /// on-change hook, other updates are shown in place.
+    pub fn with_identity_changes(mut self, identity_changes: bool) -> Self {
+        self.identity_changes = identity_changes;
+        self
+    }
+    pub fn with_refresh_interval(mut self, interval: Option<Duration>) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+    pub fn is_refreshed(&self) -> bool {
+        self.refreshed
+    }
     pub fn get_raw_content(&self) -> &str {
         &self.content
     }
-    pub fn run_on_terminal(self, duration: Duration) -> anyhow::Result<()> {
-        let tick = Ticker::new(self, duration);
-        for text in tick {
-            print!("\r{}", text?);
-            io::stdout().flush()?;
+    pub fn get_content(&self) -> &str {
+        &self.content[..self.content.len() - self.separator.len()]
+    }
+    pub fn offset(&self) -> (usize, usize) {
+        (self.i, self.full_content_char_len)
+    }
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+    pub fn watch(&mut self, waker: Waker) -> anyhow::Result<()> {
+        match self.source.kind() {
+            SourceKind::Push => self.source.watch(waker),
+            SourceKind::Static | SourceKind::Polled => Ok(()),
+        }
+    }
+    pub fn run_on_terminal(mut self, duration: Duration) -> anyhow::Result<()> {
+        if self.once {
+            if let Some(result) = self.advance() {
+                result?;
+                self.stdout().write(self.frame().into())?;
+            }
+            return Ok(());
         }
+        let events = EventLoop::from_options(duration, &self.loop_options)?;
+        self.watch(events.waker())?;
+        let _terminal = self.ansi.then(TerminalGuard::new).transpose()?;
+        let mut out = TerminalSink::new(self.stdout(), self.clear_line());
+        events.run(self, |text| out.write(text.into()))?;
+        out.finish()?;
+        Ok(())
+    }
+    /// Like [`RunningText::run_on_terminal`], with the cover of the current song before the text.
+    #[cfg(feature = "art")]
+    pub fn run_on_terminal_with_art(
+        mut self,
+        duration: Duration,
+        protocol: ImageProtocol,
+    ) -> anyhow::Result<()> {
+        let mut out = self.stdout();
+        let events = EventLoop::from_options(duration, &self.loop_options)?;
+        self.watch(events.waker())?;
+        let _terminal = self.ansi.then(TerminalGuard::new).transpose()?;
+        let clear = self.clear_line();
+        events.run(RunningTextWithArt::new(self, protocol), |(text, image)| {
+            Ok(out.write_frame(format_args!(
+                "{image}\r\x1b[{}C{text}{clear}",
+                ImageProtocol::WIDTH
+            ))?)
+        })?;
+        out.write_frame(format_args!("\n"))?;
         Ok(())
     }
     pub fn print_once(&mut self, mut i: usize, prev_content: &str) -> anyhow::Result<usize> {
         if prev_content != self.content {
             i = 0;
         }
-        self.i = i;
+        self.i = i.checked_rem(self.full_content_char_len).unwrap_or(0);
         self.byte_offset = self
             .content
             .char_indices()
-            .nth(self.i % self.full_content_char_len)
-            .unwrap()
-            .0;
-        println!("{}", self.next().unwrap()?);
+            .nth(self.i)
+            .map_or(0, |(i, _)| i);
+        if let Some(result) = self.advance() {
+            result?;
+            self.stdout().write(self.frame().into())?;
+        }
         Ok(self.i)
     }
     #[cfg(feature = "waybar")]
-    pub fn with_tooltip(self, tooltip: Tooltip) -> RunningTextWithTooltip {
-        RunningTextWithTooltip::new(self, tooltip)
+    pub fn with_tooltip(self, module: ModuleOptions) -> RunningTextWithTooltip {
+        RunningTextWithTooltip::new(self, module)
     }
     #[cfg(feature = "waybar")]
-    pub fn run_in_waybar(self, duration: Duration, tooltip: Option<Tooltip>) -> anyhow::Result<()> {
-        match tooltip {
-            Some(Tooltip::Simple(s)) => {
-                let tick = Ticker::new(self, duration);
-                for text in tick {
-                    println!("{{\"text\":\"{}\",\"tooltip\":\"{}\"}}", text?, s);
-                }
+    pub fn run_in_waybar(
+        mut self,
+        duration: Duration,
+        module: ModuleOptions,
+    ) -> anyhow::Result<()> {
+        let events = EventLoop::from_options(duration, &self.loop_options)?;
+        #[cfg(feature = "mpd")]
+        {
+            let src = self.get_source_mut();
+            module.formats().for_each(|format| src.track(format));
+            if let Some(Tooltip::History { count, .. }) = &module.tooltip {
+                src.track_history(*count);
             }
-            Some(t) => {
-                let tick = Ticker::new(self.with_tooltip(t), duration);
-                for (text, tt) in tick {
-                    println!("{{\"text\":\"{}\",\"tooltip\":\"{}\"}}", text?, tt);
-                }
+        }
+        self.watch(events.waker())?;
+        let mut out = WaybarOutput::new(&self);
+        match &module.tooltip {
+            Some(Tooltip::Simple(s)) if !module.has_formats() => {
+                let mut s = s.clone();
+                module.tooltip_options.apply(&mut s);
+                events.run(self, |text| {
+                    out.write(Frame {
+                        tooltip: Some(&s),
+                        ..text.into()
+                    })
+                })?;
             }
-            None => {
-                let tick = Ticker::new(self, duration);
-                for text in tick {
-                    println!("{{\"text\":\"{}\"}}", text?);
-                }
+            None if !module.has_formats() => {
+                events.run(self, |text| out.write(text.into()))?;
+            }
+            _ => {
+                events.run(
+                    self.with_tooltip(module),
+                    |(text, tooltip, class, fields)| {
+                        out.write(Frame {
+                            tooltip,
+                            class,
+                            fields,
+                            ..text.into()
+                        })
+                    },
+                )?;
             }
         };
-        io::stdout().flush()?;
         Ok(())
     }
-    pub fn get_source(&self) -> &TextSource {
-        &self.source
+    pub fn get_source(&self) -> &dyn TextSource {
+        self.source.as_ref()
+    }
+    pub fn get_source_mut(&mut self) -> &mut dyn TextSource {
+        self.source.as_mut()
     }
     fn does_content_fit(&self) -> bool {
-        !self.repeat && self.window_size >= self.content_char_len
+        self.layout.truncate_middle || !self.repeat && self.window_size >= self.content_char_len
     }
     fn get_new_content(&mut self) -> anyhow::Result<ContentChange> {
-        let changes =
-            self.source
-                .get_content(&mut self.content, &mut self.prefix, &mut self.suffix)?;
-        if !changes.contains(ContentChange::Running) {
-            return Ok(changes);
+        self.refreshed = match (self.refresh_interval, self.last_refresh) {
+            (Some(interval), Some(last)) => last.elapsed() >= interval,
+            _ => true,
+        };
+        if !self.refreshed {
+            return Ok(ContentChange::empty());
         }
-        // TODO: not always reset pos on content change
-        replace_newline(&mut self.content, &self.newline);
-        let content_len = self.content.len();
+        self.last_refresh = Some(Instant::now());
+        if self.source.kind() == SourceKind::Static {
+            // the contents were all there from the start
+            return Ok(ContentChange::empty());
+        }
+        self.source.set_stats(RunStats {
+            frame: self.frames,
+            uptime: self.started.elapsed(),
+            loops: self.loops,
+        });
+        log::trace!("Polling source");
+        let polled = self
+            .source
+            .poll(&mut self.source_content, &mut self.prefix, &mut self.suffix);
+        if let Some(recorder) = &mut self.recorder {
+            match &polled {
+                Ok(changes) if !changes.is_empty() => recorder.snapshot(
+                    Some(self.frames),
+                    *changes,
+                    [&self.source_content, &self.prefix, &self.suffix],
+                    self.source.separator(),
+                ),
+                Ok(_) => {}
+                Err(e) => recorder.error(self.frames, &format!("{e:#}")),
+            }
+        }
+        let changes = polled?;
+        let separator_changed = changes.contains(ContentChange::Separator);
+        if separator_changed {
+            if let Some(separator) = self.source.separator() {
+                separator.clone_into(&mut self.separator);
+                replace_newline(&mut self.separator, &self.newline);
+                self.separator_char_len = self.separator.chars().count();
+            }
+        }
+        if changes.contains(ContentChange::Running) {
+            replace_newline(&mut self.source_content, &self.newline);
+            self.layout_content();
+            let new_item = changes.contains(ContentChange::Identity);
+            if self.identity_changes && self.reset_on_change && new_item {
+                (self.i, self.byte_offset) = (0, 0);
+            }
+            self.hook_pending |= !self.identity_changes || new_item;
+        } else if separator_changed {
+            self.layout_content();
+        }
+        Ok(changes)
+    }
+    /// Lays `source_content` out into `content` and prepares it for scrolling.
+    fn layout_content(&mut self) {
+        let (layout, content) = (&self.layout, &mut self.content);
+        content.clone_from(&self.source_content);
+        #[cfg(feature = "wasm")]
+        for filter in &layout.filters {
+            // a broken filter shouldn't take the text down with it
+            if let Err(e) = filter.apply(content) {
+                log::warn!("Filter {} failed: {e:#}", filter.name());
+            }
+        }
+        if layout.strip_escapes {
+            strip_escapes(content);
+        }
+        if layout.strip_control {
+            content.retain(|c| !c.is_control());
+        }
+        if layout.nfc {
+            normalize_nfc(content);
+        }
+        if let Some(max_length) = layout.max_length {
+            truncate_graphemes(content, max_length, &layout.ellipsis);
+        }
+        if layout.truncate_middle {
+            truncate_graphemes_middle(content, self.window_size, &layout.ellipsis);
+        }
+        self.rtl = layout.bidi && reorder_bidi(content);
         self.content_char_len = self.content.chars().count();
+        self.append_separator();
+    }
+    fn append_separator(&mut self) {
         self.content.push_str(&self.separator);
-        self.full_content_char_len = self.content_char_len + self.separator.chars().count();
-        if self.reset_on_change {
+        self.full_content_char_len = self.content_char_len + self.separator_char_len;
+        // TODO: not always reset pos on content change
+        if self.reset_on_change && !self.identity_changes || self.full_content_char_len == 0 {
             (self.i, self.byte_offset) = (0, 0);
         } else {
             self.i %= self.full_content_char_len;
-            self.byte_offset = self.content.char_indices().nth(self.i).unwrap().0;
-        }
-        self.text = if self.does_content_fit() {
-            format!(
-                "{}{}{}",
-                self.prefix,
-                &self.content[..content_len],
-                self.suffix
-            )
-        } else {
-            String::new()
-        };
-        Ok(changes)
+            self.byte_offset = self
+                .content
+                .char_indices()
+                .nth(self.i)
+                .map_or(0, |(i, _)| i);
+        }
+        match self.does_content_fit() {
+            true => self.write_whole_text(),
+            false => self.text.clear(),
+        }
+    }
+    fn write_whole_text(&mut self) {
+        let content = &self.content[..self.content.len() - self.separator.len()];
+        self.text.clone_from(&self.prefix);
+        if self.rtl {
+            self.text.push(LRO);
+        }
+        let start = self.text.len();
+        self.text.push_str(content);
+        self.escape_markup_from(start);
+        if self.rtl {
+            self.text.push(PDF);
+        }
+        self.text.push_str(&self.suffix);
+    }
+    fn escape_markup_from(&mut self, start: usize) {
+        if self.escape_markup && self.text[start..].contains(['&', '<', '>']) {
+            let escaped = MarkupEscaped(&self.text[start..]).to_string();
+            self.text.replace_range(start.., &escaped);
+        }
+    }
+}
+
+impl Frames for RunningText {
+    type Frame<'a> = TextFrame<'a>;
+
+    fn advance(&mut self) -> Option<anyhow::Result<()>> {
+        if self.is_finished() {
+            return None;
+        }
+        let started = Instant::now();
+        let result = self.render();
+        self.render_time += started.elapsed();
+        if let Some(recorder) = self
+            .recorder
+            .as_mut()
+            .filter(|_| result.is_ok() && self.frame_changed)
+        {
+            recorder.frame(self.frames, &self.text);
+        }
+        self.frames += 1;
+        if result.is_ok() {
+            let frame = Frame::from(TextFrame {
+                text: &self.text,
+                changes: self.changes,
+                generation: self.generation,
+            });
+            for sink in &mut self.sinks {
+                let written = match self.frame_changed {
+                    true => sink.write(frame),
+                    // a frame held back by the rate limit goes out once it is allowed
+                    false => sink.flush(),
+                };
+                if let Err(e) = written {
+                    return Some(Err(e));
+                }
+            }
+        }
+        Some(result)
+    }
+    fn frame(&self) -> TextFrame<'_> {
+        TextFrame {
+            text: &self.text,
+            changes: self.changes,
+            generation: self.generation,
+        }
+    }
+    fn frame_changed(&self) -> bool {
+        self.frame_changed
+    }
+    fn force_refresh(&mut self) {
+        self.last_refresh = None;
+    }
+    fn scroll_once(&mut self) {
+        // there is nothing to scroll through when the text fits
+        self.scrolling = self.on_demand && !self.does_content_fit();
+    }
+    fn resize(&mut self) {
+        if self.columns.is_some() {
+            self.columns = terminal_width().or(self.columns);
+            self.fit_window();
+        }
+    }
+    fn is_finished(&self) -> bool {
+        // a static text that does not scroll has shown all it ever will
+        let shown = self.source.kind() == SourceKind::Static && self.does_content_fit();
+        self.once && self.frames > 0
+            || self
+                .max_loops
+                .is_some_and(|max| self.loops >= max || shown && self.loops > 0)
     }
 }
 
@@ -167,22 +985,184 @@ impl Iterator for RunningText {
     type Item = anyhow::Result<String>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        Some(self.advance()?.map(|()| self.text.clone()))
+    }
+}
+
+impl RunningText {
+    fn run_hook(&mut self) {
+        let Some(hook) = &self.on_change else {
+            return;
+        };
+        if !mem::take(&mut self.hook_pending) {
+            return;
+        }
+        hook.spawn(self.env());
+    }
+    /// Values passed to commands as `MERGNEH_*` variables.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = self.source.env();
+        env.push(("CONTENT", self.get_content().to_owned()));
+        env.push(("PREFIX", self.prefix.clone()));
+        env.push(("SUFFIX", self.suffix.clone()));
+        env
+    }
+    /// Whether the scroll trigger file was modified since the last check.
+    fn is_triggered(&mut self) -> bool {
+        let Some((path, last)) = &mut self.scroll_trigger else {
+            return false;
+        };
+        let current = modified(path);
+        current != mem::replace(last, current) && current.is_some()
+    }
+    /// Shows the error text, scrolled like contents if it does not fit. Returns whether the
+    /// frame changed by scrolling.
+    fn render_error(&mut self) -> bool {
+        self.text.clear();
+        let len = self.error_text.chars().count();
+        if len <= self.window_size {
+            self.text.push_str(&self.error_text);
+            self.escape_markup_from(0);
+            return false;
+        }
+        let cycle = len + self.separator_char_len;
+        self.text.extend(
+            self.error_text
+                .chars()
+                .chain(self.separator.chars())
+                .cycle()
+                .skip(self.error_offset)
+                .take(self.window_size),
+        );
+        self.error_offset = (self.error_offset + 1) % cycle;
+        self.escape_markup_from(0);
+        true
+    }
+    /// Lays the edge marks and the fade over the window that starts at `start` in `self.text`.
+    fn mark_edges(&mut self, start: usize) {
+        let lead = if self.i == 0 { "" } else { &self.lead_mark };
+        let (lead_len, trail_len) = (lead.chars().count(), self.trail_mark.chars().count());
+        let fade = usize::from(self.edge_fade);
+        // the marks have to leave some of the text to see
+        if lead_len + trail_len == 0 && fade == 0
+            || lead_len + trail_len > 0 && lead_len + trail_len >= self.window_size
+            || lead_len.max(fade) + trail_len.max(fade) > self.window_size
+        {
+            return;
+        }
+        let window = self.text.split_off(start);
+        let visible = window
+            .chars()
+            .skip(lead_len)
+            .take(self.window_size - lead_len - trail_len);
+        let mut marked: Vec<char> = lead
+            .chars()
+            .chain(visible)
+            .chain(self.trail_mark.chars())
+            .collect();
+        let Some(last) = marked.pop() else {
+            return;
+        };
+        let mut chars = marked.into_iter();
+        if self.edge_fade {
+            self.text.push_str("\x1b[2m");
+            self.text.extend(chars.next());
+            self.text.push_str("\x1b[22m");
+            self.text.extend(chars);
+            self.text.push_str("\x1b[2m");
+            self.text.push(last);
+            self.text.push_str("\x1b[22m");
+        } else {
+            self.text.extend(chars);
+            self.text.push(last);
+        }
+    }
+    /// Renders the next frame into `self.text`.
+    fn render(&mut self) -> anyhow::Result<()> {
+        let resting = self
+            .loop_options
+            .low_power
+            .as_ref()
+            .is_some_and(PowerMonitor::on_battery);
+        if resting != mem::replace(&mut self.resting, resting) {
+            // the text stands still at its start on battery
+            (self.i, self.byte_offset) = (0, 0);
+            self.pending_changes.insert(ContentChange::Running);
+        }
         let changes = match self.get_new_content() {
-            Ok(c) => c,
-            Err(e) => return Some(Err(e)),
+            Ok(c) => {
+                if c.intersects(ContentChange::Prefix | ContentChange::Suffix) {
+                    self.fit_window();
+                }
+                self.failing = false;
+                self.run_hook();
+                c | mem::replace(&mut self.pending_changes, ContentChange::empty())
+            }
+            Err(e) => {
+                self.changes = ContentChange::empty();
+                let error = format!("{e:#}");
+                // a source that keeps failing the same way (e.g. a command backing off) is
+                // reported once
+                let new_error = !self.failing || self.last_error.as_ref() != Some(&error);
+                self.failing = true;
+                self.source_errors += 1;
+                self.last_error = Some(error);
+                let Some(format) = &self.error_format else {
+                    return Err(e);
+                };
+                if new_error {
+                    log::error!("{e:#}");
+                    self.error_text = format
+                        .replace("{source}", self.source.name())
+                        .replace("{error}", self.last_error.as_deref().unwrap());
+                    replace_newline(&mut self.error_text, &self.newline);
+                    self.error_offset = 0;
+                }
+                // whatever comes after the error has to be rendered from scratch
+                self.pending_changes = ContentChange::all();
+                self.frame_changed = self.render_error() || new_error;
+                return Ok(());
+            }
         };
+        self.changes = changes;
+        if !changes.is_empty() {
+            self.generation += 1;
+        }
+        if self.hide_empty && self.content_char_len == 0 {
+            self.frame_changed = !changes.is_empty();
+            self.text.clear();
+            return Ok(());
+        }
         if self.content.is_empty() {
-            return None;
+            self.frame_changed = !changes.is_empty();
+            self.text.clone_from(&self.prefix);
+            self.text.push_str(&self.suffix);
+            return Ok(());
+        }
+        if self.on_demand && !self.scrolling && self.is_triggered() {
+            self.scroll_once();
         }
+        let still = self.on_demand && !self.scrolling || self.resting;
+        self.frame_changed = !self.does_content_fit() && !still || !changes.is_empty();
         if self.does_content_fit() {
+            // there is nothing to scroll, every frame shows the whole text and a loop is over
+            // whenever other contents come
+            if changes.contains(ContentChange::Running) {
+                self.loops += 1;
+            }
             if !changes.is_empty() {
-                self.text = self.prefix.clone()
-                    + (&self.content[..self.content.len() - self.separator.len()])
-                    + &self.suffix;
+                self.write_whole_text();
             }
-            return Some(Ok(self.text.to_owned()));
+            return Ok(());
+        }
+        if still && !self.frame_changed {
+            return Ok(());
         }
         self.text.clone_from(&self.prefix);
+        if self.rtl {
+            self.text.push(LRO);
+        }
+        let window_start = self.text.len();
         self.text.extend(
             self.content[self.byte_offset..]
                 .chars()
@@ -197,14 +1177,164 @@ impl Iterator for RunningText {
             remainder -= self.full_content_char_len;
         }
         self.text.extend(self.content.chars().take(remainder));
-        self.i = (self.i + 1) % self.full_content_char_len;
-        self.byte_offset += &self.content[self.byte_offset..]
-            .chars()
-            .next()
-            .map(char::len_utf8)
-            .unwrap_or_default();
-        self.byte_offset %= self.content.len();
+        self.mark_edges(window_start);
+        self.escape_markup_from(window_start);
+        if !still {
+            self.i = (self.i + 1) % self.full_content_char_len;
+            if self.i == 0 {
+                self.loops += 1;
+                // a single loop per trigger, then the start is shown again
+                if mem::take(&mut self.scrolling) {
+                    self.pending_changes.insert(ContentChange::Running);
+                }
+            }
+            self.byte_offset += &self.content[self.byte_offset..]
+                .chars()
+                .next()
+                .map(char::len_utf8)
+                .unwrap_or_default();
+            self.byte_offset %= self.content.len();
+        }
+        if self.rtl {
+            self.text.push(PDF);
+        }
         self.text.push_str(&self.suffix);
-        Some(Ok(self.text.clone()))
+        Ok(())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RunningText, LRO, PDF};
+    use crate::text_source::Content;
+
+    /// A static text, shown whole when it fits unless `repeat`.
+    fn text(content: &str, window: usize, repeat: bool) -> RunningText {
+        let content = Content::new(content.to_owned(), String::new(), String::new());
+        RunningText::new(
+            Box::new(content),
+            window,
+            String::new(),
+            String::new(),
+            repeat,
+            false,
+        )
+        .unwrap()
+    }
+
+    fn frames(text: &mut RunningText, count: usize) -> Vec<String> {
+        text.take(count).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn strip_control_bidi_test() {
+        let expected = format!("{LRO}abc םולש{PDF}");
+        let text = || text("abc\u{7} שלום", 20, false);
+        let mut stripped_first = text().with_strip_control(true).with_bidi(true);
+        assert_eq!(frames(&mut stripped_first, 1), [&*expected]);
+        // whatever order the options are given in, bidi reorders the stripped text once
+        let mut reordered_first = text().with_bidi(true).with_strip_control(true);
+        assert_eq!(frames(&mut reordered_first, 1), [&*expected]);
+    }
+
+    #[test]
+    fn max_loops_test() {
+        // a frame per character, twice
+        let scrolling = text("abc", 2, true).with_max_loops(Some(2));
+        assert_eq!(scrolling.count(), 6);
+        // not one loop per frame
+        let fitting = text("abc", 20, false).with_max_loops(Some(3));
+        assert_eq!(fitting.count(), 1);
+    }
+
+    #[test]
+    fn edge_marks_test() {
+        let mut marked = text("abcdef", 4, true).with_edge_marks("<".to_owned(), ">".to_owned());
+        // no leading mark before the start has scrolled out, the window wraps around after
+        assert_eq!(
+            frames(&mut marked, 7),
+            ["abc>", "<cd>", "<de>", "<ef>", "<fa>", "<ab>", "abc>"]
+        );
+        // marks that don't fit leave the window alone
+        let mut narrow = text("abcdef", 1, true).with_edge_marks("<".to_owned(), ">".to_owned());
+        assert_eq!(frames(&mut narrow, 2), ["a", "b"]);
+    }
+
+    #[test]
+    fn hide_empty_test() {
+        let affixed = || {
+            let content = Content::new(String::new(), "[".to_owned(), "]".to_owned());
+            RunningText::new(
+                Box::new(content),
+                4,
+                String::new(),
+                String::new(),
+                false,
+                false,
+            )
+            .unwrap()
+        };
+        assert_eq!(frames(&mut affixed(), 1), ["[]"]);
+        assert_eq!(frames(&mut affixed().with_hide_empty(true), 1), [""]);
+    }
+
+    #[test]
+    fn max_length_test() {
+        let mut truncated =
+            text("too long text", 20, false).with_max_length(Some(8), "…".to_owned());
+        assert_eq!(frames(&mut truncated, 1), ["too lon…"]);
+        // the truncated text scrolls and loops on its own
+        let mut scrolling = text("abcdef", 2, true)
+            .with_max_length(Some(3), "…".to_owned())
+            .with_max_loops(Some(1));
+        assert_eq!(frames(&mut scrolling, 4), ["ab", "b…", "…a"]);
+    }
+
+    #[test]
+    fn truncate_middle_test() {
+        // cut down to the window, whatever order the options come in
+        let mut truncated = text("abcdefghij", 5, false)
+            .with_truncate_middle(true)
+            .with_max_length(None, "…".to_owned());
+        assert_eq!(frames(&mut truncated, 1), ["ab…ij"]);
+        let mut fitting = text("abcde", 5, false)
+            .with_max_length(None, "…".to_owned())
+            .with_truncate_middle(true);
+        assert_eq!(frames(&mut fitting, 1), ["abcde"]);
+    }
+
+    #[test]
+    fn bidi_scroll_test() {
+        // reordered once, then scrolled left to right
+        let mut reordered = text("שלום", 2, true).with_bidi(true);
+        assert_eq!(
+            frames(&mut reordered, 2),
+            [format!("{LRO}םו{PDF}"), format!("{LRO}ול{PDF}")]
+        );
+    }
+
+    #[cfg(feature = "wasm")]
+    #[test]
+    fn filters_bidi_test() {
+        /// Gives the text back as it is.
+        const IDENTITY: &str = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "filter") (param $ptr i32) (param $len i32) (result i64)
+                (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                        (i64.extend_i32_u (local.get $len)))))"#;
+        let filter = || {
+            let wasm = wat::parse_str(IDENTITY).unwrap();
+            crate::wasm::WasmFilter::new("identity".to_owned(), &wasm).unwrap()
+        };
+        let mut text = text("abc שלום", 20, false)
+            .with_bidi(true)
+            .with_filters(vec![filter()]);
+        // filtered before the reordering, not reordered back by a second layout
+        assert_eq!(frames(&mut text, 1), [format!("{LRO}abc םולש{PDF}")]);
     }
 }
@@ -0,0 +1,360 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Stdout, Write},
+    mem,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{running_text::TextFrame, text_source::ContentChange};
+
+/// Where frames go, the counterpart of [`TextSource`](crate::TextSource): the built-in outputs
+/// and sinks of embedders are all driven the same way.
+pub trait Sink {
+    /// Takes a frame different from the previous one.
+    fn write(&mut self, frame: Frame) -> anyhow::Result<()>;
+    /// Called on renders without a new frame, for sinks holding frames back.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Whether a frame identical to the previous one is skipped.
+    fn set_dedup(&mut self, _dedup: bool) {}
+}
+
+/// A frame as sinks get it. The tooltip, class and fields are set only for outputs having them
+/// (waybar), the others show the text alone.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    pub text: &'a str,
+    pub changes: ContentChange,
+    pub generation: u64,
+    pub tooltip: Option<&'a str>,
+    pub class: Option<&'a str>,
+    pub fields: &'a [(String, String)],
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(text: &'a str) -> Self {
+        TextFrame {
+            text,
+            changes: ContentChange::empty(),
+            generation: 0,
+        }
+        .into()
+    }
+}
+
+impl<'a> From<TextFrame<'a>> for Frame<'a> {
+    fn from(frame: TextFrame<'a>) -> Self {
+        Frame {
+            text: frame.text,
+            changes: frame.changes,
+            generation: frame.generation,
+            tooltip: None,
+            class: None,
+            fields: &[],
+        }
+    }
+}
+
+/// Standard output written a whole frame at a time, so every frame goes out in a single write.
+/// As a [`Sink`] it writes the text followed by the terminator.
+#[derive(Debug)]
+pub struct StdoutSink {
+    out: Stdout,
+    frame: Vec<u8>,
+    last: Vec<u8>,
+    dedup: bool,
+    terminator: Terminator,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        StdoutSink {
+            out: io::stdout(),
+            frame: Vec::new(),
+            last: Vec::new(),
+            dedup: true,
+            terminator: Terminator::default(),
+        }
+    }
+    /// Whether a frame identical to the previous one is skipped.
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+    pub fn with_terminator(mut self, terminator: Terminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+    pub fn write_frame(&mut self, frame: fmt::Arguments) -> io::Result<()> {
+        self.frame.clear();
+        self.frame.write_fmt(frame)?;
+        if self.dedup && self.frame == self.last {
+            return Ok(());
+        }
+        let mut out = self.out.lock();
+        out.write_all(&self.frame)?;
+        out.flush()?;
+        mem::swap(&mut self.frame, &mut self.last);
+        Ok(())
+    }
+}
+
+impl Sink for StdoutSink {
+    fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let terminator = self.terminator;
+        Ok(self.write_frame(format_args!("{}{terminator}", frame.text))?)
+    }
+    fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Redraws every frame over the previous one on the current line of a terminal.
+#[derive(Debug)]
+pub struct TerminalSink {
+    out: StdoutSink,
+    /// Clears what is left of a longer previous frame.
+    clear: &'static str,
+}
+
+impl TerminalSink {
+    pub fn new(out: StdoutSink, clear: &'static str) -> Self {
+        TerminalSink { out, clear }
+    }
+    /// Moves to a new line, so the last frame stays on the screen.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.write_frame(format_args!("\n"))
+    }
+}
+
+impl Sink for TerminalSink {
+    fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let clear = self.clear;
+        Ok(self
+            .out
+            .write_frame(format_args!("\r{}{clear}", frame.text))?)
+    }
+    fn set_dedup(&mut self, dedup: bool) {
+        self.out.dedup = dedup;
+    }
+}
+
+/// Hides the cursor and turns off line wrapping while alive, so a frame is redrawn in place
+/// without a blinking cursor after it. The terminal is restored however the ticker exits,
+/// panics included.
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+    pub fn new() -> io::Result<Self> {
+        let mut out = io::stdout().lock();
+        out.write_all(b"\x1b[?25l\x1b[?7l")?;
+        out.flush()?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut out = io::stdout().lock();
+        let _ = out.write_all(b"\x1b[?7h\x1b[?25h");
+        let _ = out.flush();
+    }
+}
+
+/// What ends every plain text frame written to the standard output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Terminator {
+    #[default]
+    Newline,
+    /// Makes the next frame overwrite the current one in a terminal.
+    CarriageReturn,
+    Nul,
+    None,
+}
+
+impl Terminator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Terminator::Newline => "\n",
+            Terminator::CarriageReturn => "\r",
+            Terminator::Nul => "\0",
+            Terminator::None => "",
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Terminator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "newline" => Terminator::Newline,
+            "cr" => Terminator::CarriageReturn,
+            "nul" => Terminator::Nul,
+            "none" => Terminator::None,
+            _ => anyhow::bail!("unknown terminator '{s}', expected newline, cr, nul or none"),
+        })
+    }
+}
+
+/// Whether the error comes from the reader of the output going away (e.g. waybar exiting).
+pub fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// Frames written to a file (the latest one only) or a named pipe (a stream of lines), one sink
+/// per `--tee`.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    stream: Option<File>,
+    last: Option<String>,
+    dedup: bool,
+    min_interval: Option<Duration>,
+    last_write: Option<Instant>,
+    /// Latest frame that came too soon after the previous write.
+    pending: Option<String>,
+}
+
+impl FileSink {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        // regular files hold only the latest frame, anything else (pipes, ttys) gets a stream of lines
+        let stream = match fs::metadata(&path) {
+            Ok(m) if !m.is_file() => Some(OpenOptions::new().write(true).open(&path)?),
+            _ => None,
+        };
+        Ok(FileSink {
+            path,
+            stream,
+            last: None,
+            dedup: true,
+            min_interval: None,
+            last_write: None,
+            pending: None,
+        })
+    }
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+    /// Writes at most one frame per `min_interval`, the latest one held back is written by
+    /// [`FileSink::flush`] once the interval is over.
+    pub fn with_min_interval(mut self, min_interval: Option<Duration>) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+    fn is_limited(&self) -> bool {
+        match (self.min_interval, self.last_write) {
+            (Some(interval), Some(last)) => last.elapsed() < interval,
+            _ => false,
+        }
+    }
+    fn write_now(&mut self, frame: &str) -> io::Result<()> {
+        if self.dedup && self.last.as_deref() == Some(frame) {
+            return Ok(());
+        }
+        match &mut self.stream {
+            Some(stream) => writeln!(stream, "{frame}")?,
+            None => fs::write(&self.path, format!("{frame}\n"))?,
+        }
+        self.last_write = Some(Instant::now());
+        if self.dedup {
+            frame.clone_into(self.last.get_or_insert_with(String::new));
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        if self.is_limited() {
+            frame
+                .text
+                .clone_into(self.pending.get_or_insert_with(String::new));
+            return Ok(());
+        }
+        self.pending = None;
+        Ok(self.write_now(frame.text)?)
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.pending.is_none() || self.is_limited() {
+            return Ok(());
+        }
+        let frame = self.pending.take().unwrap();
+        Ok(self.write_now(&frame)?)
+    }
+    fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time::Duration};
+
+    use super::{FileSink, Frame, Sink, Terminator};
+
+    #[test]
+    fn file_sink_dedup_test() {
+        let path = std::env::temp_dir().join(format!("mergneh-sink-{}", std::process::id()));
+        let mut sink = FileSink::open(path.clone()).unwrap();
+        sink.write(Frame::new("frame")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "frame\n");
+        // a repeated frame is not written at all
+        fs::write(&path, "changed elsewhere").unwrap();
+        sink.write(Frame::new("frame")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "changed elsewhere");
+        sink.write(Frame::new("next")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "next\n");
+
+        let mut sink = FileSink::open(path.clone()).unwrap().with_dedup(false);
+        sink.write(Frame::new("next")).unwrap();
+        fs::write(&path, "changed elsewhere").unwrap();
+        sink.write(Frame::new("next")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "next\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn terminator_parse_test() {
+        assert_eq!("cr".parse::<Terminator>().unwrap().as_str(), "\r");
+        assert_eq!("nul".parse::<Terminator>().unwrap().to_string(), "\0");
+        assert_eq!("none".parse::<Terminator>().unwrap(), Terminator::None);
+        assert!("crlf".parse::<Terminator>().is_err());
+    }
+
+    #[test]
+    fn file_sink_min_interval_test() {
+        let path = std::env::temp_dir().join(format!("mergneh-limit-{}", std::process::id()));
+        let mut sink = FileSink::open(path.clone())
+            .unwrap()
+            .with_min_interval(Some(Duration::from_millis(50)));
+        sink.write(Frame::new("first")).unwrap();
+        sink.write(Frame::new("second")).unwrap();
+        sink.write(Frame::new("third")).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\n");
+        thread::sleep(Duration::from_millis(60));
+        sink.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    env,
+    os::unix::{
+        ffi::OsStrExt,
+        net::{SocketAddr, UnixDatagram},
+    },
+    process,
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+    ready: bool,
+}
+
+impl Notifier {
+    pub fn from_env() -> Option<Self> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => SocketAddr::from_abstract_name(name).ok()?,
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return None,
+            None => SocketAddr::from_pathname(&path).ok()?,
+        };
+        let watchdog_pid: Option<u32> = env::var("WATCHDOG_PID").ok().and_then(|p| p.parse().ok());
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| watchdog_pid.is_none_or(|pid| pid == process::id()))
+            .map(Duration::from_micros);
+        Some(Notifier {
+            socket: UnixDatagram::unbound().ok()?,
+            addr,
+            watchdog,
+            ready: false,
+        })
+    }
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        // systemd recommends petting twice as often as the timeout
+        self.watchdog.map(|d| d / 2)
+    }
+    pub fn ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            self.notify("READY=1");
+        }
+    }
+    pub fn pet_watchdog(&self) {
+        if self.watchdog.is_some() {
+            self.notify("WATCHDOG=1");
+        }
+    }
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+    fn notify(&self, state: &str) {
+        // nothing to do about a supervisor that went away
+        let _ = self.socket.send_to_addr(state.as_bytes(), &self.addr);
+    }
+}
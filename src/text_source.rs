@@ -1,16 +1,146 @@
+use anyhow::Context;
 use bitflags::bitflags;
 use clap::{ArgMatches, Id};
 
 use std::{
     ffi::{OsStr, OsString},
-    fs, io,
-    path::Path,
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::utils::Command;
+use crate::{
+    events::Waker,
+    lines::LinesSource,
+    timer::{TimerCommand, TimerSource},
+    utils::{Command, CommandError, Encoding},
+};
 
+#[cfg(feature = "art")]
+use crate::art::ArtCache;
 #[cfg(feature = "mpd")]
-use crate::mpd::{MpdFormatter, MpdSource, StatusIconsSet};
+use crate::mpd::{MpdFormatter, MpdSource, PlayerCommand, StateFormats, StatusIconsSet};
+#[cfg(feature = "plugin")]
+use crate::plugin::PluginSource;
+
+pub trait TextSource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content>;
+    fn poll(
+        &mut self,
+        content: &mut String,
+        prefix: &mut String,
+        suffix: &mut String,
+    ) -> anyhow::Result<ContentChange>;
+    /// Only [`SourceKind::Push`] sources are given a waker by [`TextSource::watch`].
+    fn kind(&self) -> SourceKind {
+        SourceKind::Polled
+    }
+    fn watch(&mut self, _waker: Waker) -> anyhow::Result<()> {
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        "custom".to_owned()
+    }
+    /// Kind of the source, shown by `{sourceName}`.
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+    fn is_connected(&self) -> Option<bool> {
+        None
+    }
+    /// Times the source connected to its server again, for sources that have one.
+    fn reconnects(&self) -> Option<u64> {
+        None
+    }
+    /// Values exposed to hooks as `MERGNEH_<NAME>` environment variables.
+    fn env(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+    /// Called before every poll, for sources showing how long the text has been running.
+    fn set_stats(&mut self, _stats: RunStats) {}
+    /// Separator rendered by the source, replaces `--separator` when it reports
+    /// [`ContentChange::Separator`].
+    fn separator(&self) -> Option<&str> {
+        None
+    }
+    /// Playback controls, for sources showing a player.
+    #[cfg(feature = "mpd")]
+    fn send(&mut self, _cmd: PlayerCommand) -> anyhow::Result<()> {
+        anyhow::bail!("the source is not --mpd")
+    }
+    /// Renders a format other than the source's own, see [`TextSource::track`].
+    #[cfg(feature = "mpd")]
+    fn format(&mut self, _format: &MpdFormatter, _f: &mut String) -> anyhow::Result<()> {
+        anyhow::bail!("MPD formats can only be used with --mpd")
+    }
+    /// Lists the next `count` songs in the queue, one per line.
+    #[cfg(all(feature = "mpd", feature = "waybar"))]
+    fn format_upcoming(
+        &mut self,
+        _format: &MpdFormatter,
+        _count: usize,
+        _f: &mut String,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("MPD formats can only be used with --mpd")
+    }
+    /// Lists the last `count` songs played before the current one, each after the time it
+    /// started.
+    #[cfg(feature = "mpd")]
+    fn format_history(
+        &mut self,
+        _format: &MpdFormatter,
+        _count: usize,
+        _f: &mut String,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("MPD formats can only be used with --mpd")
+    }
+    /// Starts fetching whatever data the format needs and the source's own formats don't.
+    #[cfg(feature = "mpd")]
+    fn track(&mut self, _format: &MpdFormatter) {}
+    /// Keeps at least `len` played songs around.
+    #[cfg(feature = "mpd")]
+    fn track_history(&mut self, _len: usize) {}
+    /// Starts loading album art even if no format shows it, false if the source has none.
+    #[cfg(feature = "art")]
+    fn track_art(&mut self) -> bool {
+        false
+    }
+    /// Cached cover of the current song, loaded only when art is tracked.
+    #[cfg(feature = "art")]
+    fn album_art(&self) -> Option<&Path> {
+        None
+    }
+    fn control_timer(&mut self, _cmd: TimerCommand) -> anyhow::Result<()> {
+        anyhow::bail!("the source is not a timer")
+    }
+}
+
+/// How a source learns about new contents, so it is polled only as often as it has to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// Contents known upfront, never polled again.
+    Static,
+    /// Contents read anew on every poll (every tick or `--refresh-interval`).
+    Polled,
+    /// Contents arrive on their own and wake the loop up right away, polling only picks them
+    /// up.
+    Push,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RunStats {
+    /// Frames rendered before the one being rendered.
+    pub frame: usize,
+    pub uptime: Duration,
+    /// Times the text scrolled through, see `--max-loops`.
+    pub loops: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct Content {
@@ -19,29 +149,117 @@ pub struct Content {
     pub suffix: String,
 }
 
+impl Content {
+    pub fn new(running: String, prefix: String, suffix: String) -> Self {
+        Self {
+            running,
+            prefix,
+            suffix,
+        }
+    }
+}
+
+impl TextSource for Content {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        Ok(self.clone())
+    }
+    fn kind(&self) -> SourceKind {
+        SourceKind::Static
+    }
+    fn poll(
+        &mut self,
+        _: &mut String,
+        _: &mut String,
+        _: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        Ok(ContentChange::empty())
+    }
+    fn describe(&self) -> String {
+        "text".to_owned()
+    }
+    fn name(&self) -> &'static str {
+        "text"
+    }
+}
+
 #[derive(Debug)]
 pub struct CmdSource {
     pub cmd: Command,
     pub prefix: String,
     pub suffix: String,
     last_output: String,
+    max_backoff: Duration,
+    backoff: Option<Backoff>,
 }
 
+/// Consecutive failures of a command, it isn't run again until `retry_at`.
+#[derive(Debug)]
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+    error: String,
+}
+
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 impl CmdSource {
     pub fn new<S: AsRef<OsStr>, I: IntoIterator<Item = S>>(
         args: I,
         prefix: String,
         suffix: String,
     ) -> Self {
+        Self::with_command(args.into_iter().collect(), prefix, suffix)
+    }
+    pub fn with_command(cmd: Command, prefix: String, suffix: String) -> Self {
         Self {
-            cmd: args.into_iter().collect(),
+            cmd,
             prefix,
             suffix,
             last_output: String::new(),
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            backoff: None,
         }
     }
+    /// Limits how long a failing command waits before it is retried, the wait doubles on
+    /// every failure starting from a second.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.cmd = self.cmd.with_timeout(timeout);
+        self
+    }
     pub fn get(&mut self, content: &mut String) -> anyhow::Result<ContentChange> {
-        let output = self.cmd.spawn_and_read_output()?;
+        if let Some(backoff) = self
+            .backoff
+            .as_ref()
+            .filter(|b| Instant::now() < b.retry_at)
+        {
+            anyhow::bail!("{}", backoff.error);
+        }
+        self.cmd.set_env([("CONTENT", &self.last_output)]);
+        let output = match self.cmd.spawn_and_read_output() {
+            Ok(output) => output,
+            Err(e @ CommandError::Timeout(_)) => {
+                log::warn!("{e}, keeping the previous output");
+                return Ok(ContentChange::empty());
+            }
+            Err(e) => {
+                let failures = self.backoff.as_ref().map_or(0, |b| b.failures) + 1;
+                let delay = backoff_delay(failures, self.max_backoff);
+                log::warn!("Command failed {failures} time(s) in a row, retrying in {delay:?}");
+                self.backoff = Some(Backoff {
+                    failures,
+                    retry_at: Instant::now() + delay,
+                    error: e.to_string(),
+                });
+                return Err(e.into());
+            }
+        };
+        if self.backoff.take().is_some() {
+            log::info!("Command succeeded again");
+        }
         if self.last_output == output {
             Ok(ContentChange::empty())
         } else {
@@ -53,125 +271,582 @@ impl CmdSource {
     }
 }
 
-bitflags! {
-    pub struct ContentChange: u8 {
-        const Running = 1;
-        const Prefix = 1 << 1;
-        const Suffix = 1 << 2;
+impl TextSource for CmdSource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        let mut output = String::new();
+        // the error is shown (or the command retried) once polling starts
+        if let Err(e) = self.get(&mut output) {
+            log::debug!("Initial command run failed: {e:#}");
+        }
+        Ok(Content {
+            running: output,
+            prefix: self.prefix.clone(),
+            suffix: self.suffix.clone(),
+        })
+    }
+    fn poll(
+        &mut self,
+        content: &mut String,
+        _: &mut String,
+        _: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        self.get(content)
+    }
+    fn describe(&self) -> String {
+        format!("command {}", self.cmd)
+    }
+    fn name(&self) -> &'static str {
+        "cmd"
     }
 }
 
+/// Shows the latest line read from stdin, e.g. `tail -f log | mg --stdin --follow`.
 #[derive(Debug)]
-pub enum TextSource {
-    String(Content),
-    Cmd(CmdSource),
-    #[cfg(feature = "mpd")]
-    Mpd(Box<MpdSource>),
+pub struct StdinSource {
+    lines: Receiver<io::Result<Vec<u8>>>,
+    encoding: Encoding,
+    waker: Arc<Mutex<Option<Waker>>>,
+    prefix: String,
+    suffix: String,
 }
 
-impl TextSource {
-    pub fn content(running: String, prefix: String, suffix: String) -> TextSource {
-        TextSource::String(Content {
-            running,
+impl StdinSource {
+    pub fn new(encoding: Encoding, prefix: String, suffix: String) -> Self {
+        Self::with_reader(BufReader::new(io::stdin()), encoding, prefix, suffix)
+    }
+    pub fn with_reader(
+        mut input: impl BufRead + Send + 'static,
+        encoding: Encoding,
+        prefix: String,
+        suffix: String,
+    ) -> Self {
+        let (sender, lines) = mpsc::channel();
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let shared = waker.clone();
+        thread::spawn(move || loop {
+            let mut line = Vec::new();
+            let read = input.read_until(b'\n', &mut line);
+            let eof = matches!(read, Ok(0));
+            if eof || sender.send(read.map(|_| line)).is_err() {
+                log::debug!("Stopped reading stdin");
+                break;
+            }
+            if let Some(waker) = &*shared.lock().unwrap() {
+                waker.wake();
+            }
+        });
+        Self {
+            lines,
+            encoding,
+            waker,
             prefix,
             suffix,
-        })
+        }
     }
-    pub fn get_initial_content(&mut self) -> anyhow::Result<Content> {
-        match self {
-            TextSource::String(c) => Ok(c.clone()),
-            TextSource::Cmd(s) => {
-                let mut output = String::new();
-                s.get(&mut output)?;
-                Ok(Content {
-                    running: output,
-                    prefix: s.prefix.clone(),
-                    suffix: s.suffix.clone(),
-                })
-            }
-            #[cfg(feature = "mpd")]
-            TextSource::Mpd(c) => {
-                let mut content = Content {
-                    running: String::new(),
-                    prefix: String::new(),
-                    suffix: String::new(),
-                };
-                c.running_format()
-                    .format_with_source(c, &mut content.running)?;
-                c.prefix_format()
-                    .format_with_source(c, &mut content.prefix)?;
-                c.suffix_format()
-                    .format_with_source(c, &mut content.suffix)?;
-                Ok(content)
+}
+
+impl TextSource for StdinSource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        // lines already waiting are shown by the first poll
+        Ok(Content::new(
+            String::new(),
+            self.prefix.clone(),
+            self.suffix.clone(),
+        ))
+    }
+    fn poll(
+        &mut self,
+        content: &mut String,
+        _: &mut String,
+        _: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        let mut last = None;
+        while let Ok(line) = self.lines.try_recv() {
+            last = Some(line.context("Reading stdin failed")?);
+        }
+        let Some(mut line) = last else {
+            return Ok(ContentChange::empty());
+        };
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
             }
         }
+        let line = self.encoding.decode(line)?;
+        if *content == line {
+            return Ok(ContentChange::empty());
+        }
+        *content = line;
+        Ok(ContentChange::Running)
+    }
+    fn kind(&self) -> SourceKind {
+        SourceKind::Push
+    }
+    fn watch(&mut self, waker: Waker) -> anyhow::Result<()> {
+        *self.waker.lock().unwrap() = Some(waker);
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        "stdin".to_owned()
+    }
+    fn name(&self) -> &'static str {
+        "stdin"
+    }
+}
+
+/// Puts the prefix and the suffix of a source into its running text, so they scroll along with
+/// it.
+pub struct ScrolledAffixes {
+    source: Box<dyn TextSource>,
+    /// Parts as the source last reported them.
+    parts: Content,
+}
+
+impl ScrolledAffixes {
+    pub fn new(source: Box<dyn TextSource>) -> Self {
+        Self {
+            source,
+            parts: Content::new(String::new(), String::new(), String::new()),
+        }
+    }
+    fn joined(&self) -> String {
+        let Content {
+            running,
+            prefix,
+            suffix,
+        } = &self.parts;
+        format!("{prefix}{running}{suffix}")
     }
-    pub fn get_content(
+}
+
+impl TextSource for ScrolledAffixes {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        self.parts = self.source.get_initial_content()?;
+        Ok(Content::new(self.joined(), String::new(), String::new()))
+    }
+    fn poll(
         &mut self,
         content: &mut String,
-        prefix: &mut String,
-        suffix: &mut String,
+        _: &mut String,
+        _: &mut String,
     ) -> anyhow::Result<ContentChange> {
-        match self {
-            TextSource::String(_) => Ok(ContentChange::empty()),
-            #[cfg(feature = "mpd")]
-            TextSource::Mpd(s) => s.get(content, prefix, suffix),
-            TextSource::Cmd(s) => s.get(content),
+        let Content {
+            running,
+            prefix,
+            suffix,
+        } = &mut self.parts;
+        let mut changes = self.source.poll(running, prefix, suffix)?;
+        let parts = ContentChange::Running | ContentChange::Prefix | ContentChange::Suffix;
+        if changes.intersects(parts) {
+            *content = self.joined();
+            changes.remove(parts);
+            changes.insert(ContentChange::Running);
         }
+        Ok(changes)
+    }
+    fn kind(&self) -> SourceKind {
+        self.source.kind()
+    }
+    fn watch(&mut self, waker: Waker) -> anyhow::Result<()> {
+        self.source.watch(waker)
+    }
+    fn describe(&self) -> String {
+        self.source.describe()
+    }
+    fn name(&self) -> &'static str {
+        self.source.name()
+    }
+    fn is_connected(&self) -> Option<bool> {
+        self.source.is_connected()
+    }
+    fn reconnects(&self) -> Option<u64> {
+        self.source.reconnects()
+    }
+    fn env(&self) -> Vec<(&'static str, String)> {
+        self.source.env()
+    }
+    fn set_stats(&mut self, stats: RunStats) {
+        self.source.set_stats(stats);
+    }
+    fn separator(&self) -> Option<&str> {
+        self.source.separator()
+    }
+    #[cfg(feature = "mpd")]
+    fn send(&mut self, cmd: PlayerCommand) -> anyhow::Result<()> {
+        self.source.send(cmd)
+    }
+    #[cfg(feature = "mpd")]
+    fn format(&mut self, format: &MpdFormatter, f: &mut String) -> anyhow::Result<()> {
+        self.source.format(format, f)
+    }
+    #[cfg(all(feature = "mpd", feature = "waybar"))]
+    fn format_upcoming(
+        &mut self,
+        format: &MpdFormatter,
+        count: usize,
+        f: &mut String,
+    ) -> anyhow::Result<()> {
+        self.source.format_upcoming(format, count, f)
+    }
+    #[cfg(feature = "mpd")]
+    fn format_history(
+        &mut self,
+        format: &MpdFormatter,
+        count: usize,
+        f: &mut String,
+    ) -> anyhow::Result<()> {
+        self.source.format_history(format, count, f)
+    }
+    #[cfg(feature = "mpd")]
+    fn track(&mut self, format: &MpdFormatter) {
+        self.source.track(format);
+    }
+    #[cfg(feature = "mpd")]
+    fn track_history(&mut self, len: usize) {
+        self.source.track_history(len);
+    }
+    #[cfg(feature = "art")]
+    fn track_art(&mut self) -> bool {
+        self.source.track_art()
+    }
+    #[cfg(feature = "art")]
+    fn album_art(&self) -> Option<&Path> {
+        self.source.album_art()
+    }
+    fn control_timer(&mut self, cmd: TimerCommand) -> anyhow::Result<()> {
+        self.source.control_timer(cmd)
     }
 }
 
-impl TryFrom<&mut ArgMatches> for TextSource {
-    type Error = anyhow::Error;
+pub(crate) fn backoff_delay(failures: u32, max: Duration) -> Duration {
+    Duration::from_secs(1)
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(max)
+}
 
-    fn try_from(value: &mut ArgMatches) -> anyhow::Result<Self, Self::Error> {
-        let kind = value.remove_one::<Id>("sources").unwrap();
-        let src = value.try_remove_one::<String>(kind.as_str());
-        let prefix = value.remove_one::<String>("prefix").unwrap();
-        let suffix = value.remove_one::<String>("suffix").unwrap();
-        return Ok(match kind.as_str() {
-            "SOURCE" => {
-                TextSource::content(from_file_or_string(&src.unwrap().unwrap())?, prefix, suffix)
-            }
-            "file" => {
-                TextSource::content(fs::read_to_string(src.unwrap().unwrap())?, prefix, suffix)
-            }
-            "string" => TextSource::content(src.unwrap().unwrap(), prefix, suffix),
-            "stdin" => TextSource::content(io::read_to_string(io::stdin())?, prefix, suffix),
-            "cmd" => TextSource::Cmd(CmdSource::new(
-                value.remove_many::<OsString>(kind.as_str()).unwrap(),
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ContentChange: u8 {
+        const Running = 1;
+        const Prefix = 1 << 1;
+        const Suffix = 1 << 2;
+        const Separator = 1 << 3;
+        /// The running text is about something else now (another song) rather than updated in
+        /// place, only reported by sources that can tell.
+        const Identity = 1 << 4;
+    }
+}
+
+impl ContentChange {
+    /// Names of the changed parts as they appear in JSON outputs.
+    pub fn names(self) -> impl Iterator<Item = &'static str> + Clone {
+        [
+            (Self::Prefix, "prefix"),
+            (Self::Running, "running"),
+            (Self::Suffix, "suffix"),
+            (Self::Separator, "separator"),
+        ]
+        .into_iter()
+        .filter(move |(part, _)| self.contains(*part))
+        .map(|(_, name)| name)
+    }
+}
+
+pub fn from_matches(value: &mut ArgMatches) -> anyhow::Result<Box<dyn TextSource>> {
+    let kind = value.remove_one::<Id>("sources").unwrap();
+    let src = value.try_remove_one::<String>(kind.as_str());
+    let prefix = value.remove_one::<String>("prefix").unwrap();
+    let suffix = value.remove_one::<String>("suffix").unwrap();
+    let encoding = value.remove_one::<Encoding>("encoding").unwrap();
+    Ok(match kind.as_str() {
+        "SOURCE" => Box::new(Content::new(
+            from_file_or_string(&src.unwrap().unwrap(), encoding)?,
+            prefix,
+            suffix,
+        )),
+        "file" => Box::new(Content::new(
+            encoding.decode(fs::read(src.unwrap().unwrap())?)?,
+            prefix,
+            suffix,
+        )),
+        "string" => Box::new(Content::new(src.unwrap().unwrap(), prefix, suffix)),
+        "stdin" if value.remove_one::<bool>("follow").unwrap() => {
+            Box::new(StdinSource::new(encoding, prefix, suffix))
+        }
+        "stdin" => Box::new(Content::new(
+            encoding.decode(read_stdin()?)?,
+            prefix,
+            suffix,
+        )),
+        "cmd" => Box::new(cmd_source(
+            value.remove_many::<OsString>(kind.as_str()).unwrap(),
+            value,
+            encoding,
+            prefix,
+            suffix,
+        )),
+        "lines" => Box::new(
+            LinesSource::open(
+                &value.remove_one::<PathBuf>(kind.as_str()).unwrap(),
+                encoding,
                 prefix,
                 suffix,
-            )),
-            #[cfg(feature = "mpd")]
-            "mpd" => TextSource::Mpd(Box::new(MpdSource::new(
-                value.try_remove_one(kind.as_str()).unwrap().unwrap(),
-                value.remove_one("format").unwrap(),
+            )?
+            .with_pace(value.remove_one("lines-every").unwrap())
+            .with_shuffle(value.remove_one("shuffle").unwrap())
+            .with_seed(value.remove_one("seed")),
+        ),
+        "timer" => Box::new(TimerSource::new(
+            value
+                .remove_one::<humantime::Duration>(kind.as_str())
+                .map(Into::into),
+            prefix,
+            suffix,
+        )),
+        #[cfg(feature = "plugin")]
+        "plugin" => Box::new(
+            PluginSource::new(
                 value
-                    .remove_one("prefix-format")
-                    .unwrap_or(MpdFormatter::only_string(prefix)),
+                    .remove_many::<OsString>(kind.as_str())
+                    .unwrap()
+                    .collect(),
+                prefix,
+                suffix,
+            )
+            .with_config(value.remove_many("plugin-config").into_iter().flatten())
+            .with_format(
+                value.remove_one("plugin-format"),
                 value
-                    .remove_one("suffix-format")
-                    .unwrap_or(MpdFormatter::only_string(suffix)),
+                    .try_get_one::<String>("default-placeholder")
+                    .ok()
+                    .flatten()
+                    .cloned()
+                    .unwrap_or_default(),
+            )
+            .with_max_backoff(
+                value
+                    .remove_one::<humantime::Duration>("cmd-max-backoff")
+                    .unwrap()
+                    .into(),
+            ),
+        ),
+        #[cfg(feature = "mpd")]
+        "mpd" => Box::new(
+            MpdSource::new(
+                value.try_remove_one(kind.as_str()).unwrap().unwrap(),
+                value.remove_one("format").unwrap(),
+                StateFormats::new(
+                    MpdFormatter::only_string(prefix),
+                    value.remove_many("prefix-format").into_iter().flatten(),
+                ),
+                StateFormats::new(
+                    MpdFormatter::only_string(suffix),
+                    value.remove_many("suffix-format").into_iter().flatten(),
+                ),
+                value.remove_many("separator-format").map(|formats| {
+                    let separator = value.get_one::<String>("separator").unwrap();
+                    StateFormats::new(MpdFormatter::only_string(separator.clone()), formats)
+                }),
                 StatusIconsSet::new(
                     value.remove_one("status-icons").unwrap(),
                     value.remove_one("consume-icons").unwrap(),
                     value.remove_one("random-icons").unwrap(),
                     value.remove_one("repeat-icons").unwrap(),
                     value.remove_one("single-icons").unwrap(),
+                    value.remove_one("output-icons").unwrap(),
+                    value.remove_one("error-icons").unwrap(),
                 ),
                 value.remove_one("default-placeholder").unwrap(),
-            )?)),
-            _ => unreachable!(),
-        });
-    }
+                #[cfg(feature = "lyrics")]
+                value.remove_one("music-dir"),
+                #[cfg(feature = "art")]
+                ArtCache::new(value.remove_one::<u64>("art-cache-size").unwrap() * 1024 * 1024),
+            )?
+            .with_instance(value.get_one::<String>("instance").cloned())
+            .with_ignored_changes(value.remove_many("ignore-changes").into_iter().flatten())
+            .with_volume_flash(
+                value
+                    .remove_one::<humantime::Duration>("volume-flash")
+                    .map(Into::into),
+                value.remove_one("volume-flash-format").unwrap(),
+            )
+            .with_idle_format(value.remove_one("idle-format"))
+            .with_crossfade_format(
+                value
+                    .remove_one::<bool>("crossfade")
+                    .unwrap()
+                    .then(|| value.remove_one("crossfade-format"))
+                    .flatten(),
+            )
+            .with_up_next(
+                value
+                    .remove_one::<humantime::Duration>("up-next")
+                    .map(Into::into),
+                value.remove_one("up-next-format").unwrap(),
+            )
+            .with_query_interval(
+                value
+                    .remove_one::<humantime::Duration>("mpd-query-interval")
+                    .map(Into::into),
+            )
+            .with_outputs_ttl(
+                value
+                    .remove_one::<humantime::Duration>("mpd-outputs-ttl")
+                    .map(Into::into),
+            )
+            .with_notify(
+                value
+                    .remove_one::<bool>("notify")
+                    .unwrap()
+                    .then(|| value.remove_one::<humantime::Duration>("notify-interval"))
+                    .flatten()
+                    .map(Into::into),
+            ),
+        ),
+        _ => unreachable!(),
+    })
+}
+
+/// Runs `args` the way `--cmd` does, with `--cmd-shell`, `--cmd-timeout` and
+/// `--cmd-max-backoff` of `value`.
+pub fn cmd_source<S: AsRef<OsStr>>(
+    args: impl IntoIterator<Item = S>,
+    value: &ArgMatches,
+    encoding: Encoding,
+    prefix: String,
+    suffix: String,
+) -> CmdSource {
+    let cmd: Command = match value.get_flag("cmd-shell") {
+        true => Command::shell(args),
+        false => args.into_iter().collect(),
+    };
+    CmdSource::with_command(cmd.with_encoding(encoding), prefix, suffix)
+        .with_max_backoff(
+            (*value
+                .get_one::<humantime::Duration>("cmd-max-backoff")
+                .unwrap())
+            .into(),
+        )
+        .with_timeout(
+            value
+                .get_one::<humantime::Duration>("cmd-timeout")
+                .map(|&d| d.into()),
+        )
 }
 
-fn from_file_or_string(arg: &str) -> io::Result<String> {
+fn read_stdin() -> io::Result<Vec<u8>> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+    Ok(input)
+}
+
+fn from_file_or_string(arg: &str, encoding: Encoding) -> anyhow::Result<String> {
     let path = Path::new(arg);
     Ok(if path.is_file() {
-        fs::read_to_string(path)?
+        encoding.decode(fs::read(path)?)?
     } else {
         arg.to_owned()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, BufReader, PipeWriter, Write},
+        sync::mpsc::{self, Receiver},
+        time::Duration,
+    };
+
+    use super::{
+        backoff_delay, Content, ContentChange, ScrolledAffixes, SourceKind, StdinSource, TextSource,
+    };
+    use crate::{
+        events::{Message, Waker},
+        timer::{TimerCommand, TimerSource},
+        utils::Encoding,
+    };
+
+    #[test]
+    fn backoff_delay_test() {
+        let max = Duration::from_secs(60);
+        assert_eq!(backoff_delay(1, max), Duration::from_secs(1));
+        assert_eq!(backoff_delay(2, max), Duration::from_secs(2));
+        assert_eq!(backoff_delay(5, max), Duration::from_secs(16));
+        assert_eq!(backoff_delay(7, max), max);
+        assert_eq!(backoff_delay(u32::MAX, max), max);
+    }
+
+    #[test]
+    fn scrolled_affixes_test() {
+        let content = Content::new("text".to_owned(), "<".to_owned(), ">".to_owned());
+        let mut source = ScrolledAffixes::new(Box::new(content));
+        let initial = source.get_initial_content().unwrap();
+        assert_eq!(
+            (
+                initial.running.as_str(),
+                initial.prefix.as_str(),
+                initial.suffix.as_str()
+            ),
+            ("<text>", "", "")
+        );
+
+        let timer = TimerSource::new(None, "[".to_owned(), "]".to_owned());
+        let mut source = ScrolledAffixes::new(Box::new(timer));
+        assert_eq!(source.get_initial_content().unwrap().running, "[00:00]");
+        source
+            .control_timer(TimerCommand::Set(Duration::from_secs(90)))
+            .unwrap();
+        let (mut running, mut prefix, mut suffix) = Default::default();
+        let changes = source.poll(&mut running, &mut prefix, &mut suffix).unwrap();
+        assert_eq!(changes, ContentChange::Running);
+        assert_eq!(running, "[01:30]");
+        assert!(prefix.is_empty() && suffix.is_empty());
+    }
+
+    /// A source reading from the returned pipe, its thread sends a refresh for every line read.
+    fn stdin_source(prefix: &str, suffix: &str) -> (StdinSource, PipeWriter, Receiver<Message>) {
+        let (reader, writer) = io::pipe().unwrap();
+        let mut source = StdinSource::with_reader(
+            BufReader::new(reader),
+            Encoding::Utf8,
+            prefix.to_owned(),
+            suffix.to_owned(),
+        );
+        let (sender, wakes) = mpsc::channel();
+        source.watch(Waker(sender)).unwrap();
+        (source, writer, wakes)
+    }
+
+    fn wait_for_lines(wakes: &Receiver<Message>, count: usize) {
+        for _ in 0..count {
+            assert!(matches!(
+                wakes.recv_timeout(Duration::from_secs(5)),
+                Ok(Message::Refresh)
+            ));
+        }
+    }
+
+    #[test]
+    fn stdin_source_test() {
+        let (mut source, mut input, wakes) = stdin_source("<", ">");
+        assert_eq!(source.kind(), SourceKind::Push);
+        assert_eq!(source.get_initial_content().unwrap().running, "");
+        input.write_all(b"first\r\nsecond\nlast").unwrap();
+        drop(input);
+        wait_for_lines(&wakes, 3);
+        let (mut running, mut prefix, mut suffix) = Default::default();
+        let changes = source.poll(&mut running, &mut prefix, &mut suffix).unwrap();
+        assert_eq!(changes, ContentChange::Running);
+        assert_eq!(running, "last");
+        let changes = source.poll(&mut running, &mut prefix, &mut suffix).unwrap();
+        assert!(changes.is_empty());
+
+        let (mut source, mut input, wakes) = stdin_source("", "");
+        input.write_all(b"only\r\n").unwrap();
+        wait_for_lines(&wakes, 1);
+        source.poll(&mut running, &mut prefix, &mut suffix).unwrap();
+        assert_eq!(running, "only");
+        assert_eq!(source.describe(), "stdin");
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    fmt::Write,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::text_source::{Content, ContentChange, TextSource};
+
+/// Commands the control socket forwards to a timer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimerCommand {
+    Start,
+    Stop,
+    Reset,
+    /// Starts a countdown over from the duration, or moves a stopwatch to it.
+    Set(Duration),
+}
+
+impl FromStr for TimerCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = s.split_once(' ').unwrap_or((s, ""));
+        Ok(match (name, arg.trim()) {
+            ("start", "") => TimerCommand::Start,
+            ("stop", "") => TimerCommand::Stop,
+            ("reset", "") => TimerCommand::Reset,
+            ("set", "") => anyhow::bail!("'set' requires a duration"),
+            ("set", duration) => TimerCommand::Set(humantime::parse_duration(duration)?),
+            ("start" | "stop" | "reset", _) => anyhow::bail!("'{name}' does not take arguments"),
+            _ => {
+                anyhow::bail!("unknown timer command '{name}' (expected start, stop, reset or set)")
+            }
+        })
+    }
+}
+
+/// A stopwatch, or a countdown when given a duration. It starts running right away.
+#[derive(Debug)]
+pub struct TimerSource {
+    countdown: Option<Duration>,
+    /// Time counted before the last start.
+    counted: Duration,
+    started: Option<Instant>,
+    prefix: String,
+    suffix: String,
+}
+
+impl TimerSource {
+    pub fn new(countdown: Option<Duration>, prefix: String, suffix: String) -> Self {
+        Self {
+            countdown,
+            counted: Duration::ZERO,
+            started: Some(Instant::now()),
+            prefix,
+            suffix,
+        }
+    }
+    pub fn control(&mut self, cmd: TimerCommand) {
+        match cmd {
+            TimerCommand::Start => {
+                self.started.get_or_insert_with(Instant::now);
+            }
+            TimerCommand::Stop => {
+                self.counted = self.elapsed();
+                self.started = None;
+            }
+            TimerCommand::Reset => self.restart(Duration::ZERO),
+            TimerCommand::Set(duration) if self.countdown.is_some() => {
+                self.countdown = Some(duration);
+                self.restart(Duration::ZERO);
+            }
+            TimerCommand::Set(duration) => self.restart(duration),
+        }
+    }
+    pub fn is_running(&self) -> bool {
+        self.started.is_some()
+    }
+    fn restart(&mut self, counted: Duration) {
+        self.counted = counted;
+        if let Some(started) = &mut self.started {
+            *started = Instant::now();
+        }
+    }
+    fn elapsed(&self) -> Duration {
+        self.counted + self.started.map_or(Duration::ZERO, |s| s.elapsed())
+    }
+    /// Remaining time of a countdown rounded up, so it shows 00:00 only when it is over.
+    fn shown(&self) -> Duration {
+        match self.countdown {
+            Some(total) => {
+                let remaining = total.saturating_sub(self.elapsed());
+                Duration::from_secs(remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0))
+            }
+            None => self.elapsed(),
+        }
+    }
+    fn render(&self, out: &mut String) {
+        let secs = self.shown().as_secs();
+        let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+        let _ = match hours {
+            0 => write!(out, "{minutes:02}:{secs:02}"),
+            _ => write!(out, "{hours}:{minutes:02}:{secs:02}"),
+        };
+    }
+}
+
+impl TextSource for TimerSource {
+    fn get_initial_content(&mut self) -> anyhow::Result<Content> {
+        let mut running = String::new();
+        self.render(&mut running);
+        Ok(Content::new(
+            running,
+            self.prefix.clone(),
+            self.suffix.clone(),
+        ))
+    }
+    fn poll(
+        &mut self,
+        content: &mut String,
+        _: &mut String,
+        _: &mut String,
+    ) -> anyhow::Result<ContentChange> {
+        let mut time = String::new();
+        self.render(&mut time);
+        if time == *content {
+            return Ok(ContentChange::empty());
+        }
+        *content = time;
+        Ok(ContentChange::Running)
+    }
+    fn describe(&self) -> String {
+        let state = if self.is_running() {
+            "running"
+        } else {
+            "stopped"
+        };
+        match self.countdown {
+            Some(total) => format!(
+                "countdown from {} ({state})",
+                humantime::format_duration(total)
+            ),
+            None => format!("stopwatch ({state})"),
+        }
+    }
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut time = String::new();
+        self.render(&mut time);
+        vec![
+            ("TIMER", time),
+            ("TIMER_RUNNING", self.is_running().to_string()),
+        ]
+    }
+    fn control_timer(&mut self, cmd: TimerCommand) -> anyhow::Result<()> {
+        self.control(cmd);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{TimerCommand, TimerSource};
+
+    fn shown(timer: &TimerSource) -> String {
+        let mut s = String::new();
+        timer.render(&mut s);
+        s
+    }
+
+    #[test]
+    fn command_parse_test() {
+        assert_eq!(
+            "start".parse::<TimerCommand>().unwrap(),
+            TimerCommand::Start
+        );
+        assert_eq!(
+            "set 25m".parse::<TimerCommand>().unwrap(),
+            TimerCommand::Set(Duration::from_secs(25 * 60))
+        );
+        assert!("set".parse::<TimerCommand>().is_err());
+        assert!("set soon".parse::<TimerCommand>().is_err());
+        assert!("stop now".parse::<TimerCommand>().is_err());
+        assert!("lap".parse::<TimerCommand>().is_err());
+    }
+
+    #[test]
+    fn countdown_test() {
+        let mut timer = TimerSource::new(
+            Some(Duration::from_secs(25 * 60)),
+            String::new(),
+            String::new(),
+        );
+        timer.control(TimerCommand::Stop);
+        assert_eq!(shown(&timer), "25:00");
+        timer.counted = Duration::from_millis(1500);
+        assert_eq!(shown(&timer), "24:59");
+        timer.counted = Duration::from_secs(3600);
+        assert_eq!(shown(&timer), "00:00");
+
+        timer.control(TimerCommand::Set(Duration::from_secs(2 * 3600 + 5)));
+        assert_eq!(shown(&timer), "2:00:05");
+        assert!(!timer.is_running());
+    }
+
+    #[test]
+    fn stopwatch_test() {
+        let mut timer = TimerSource::new(None, String::new(), String::new());
+        timer.control(TimerCommand::Stop);
+        timer.control(TimerCommand::Set(Duration::from_secs(90)));
+        assert_eq!(shown(&timer), "01:30");
+        timer.control(TimerCommand::Start);
+        assert!(timer.is_running());
+        timer.control(TimerCommand::Stop);
+        assert!(timer.elapsed() >= Duration::from_secs(90));
+        timer.control(TimerCommand::Reset);
+        assert_eq!(shown(&timer), "00:00");
+    }
+}
@@ -1,13 +1,135 @@
 use std::{
+    env,
     error::Error,
-    ffi::OsStr,
-    fmt::Display,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display, Write},
     io,
-    iter::repeat,
-    process::{self, Child, Stdio},
+    iter::repeat_n,
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{self, Child, ExitStatus, Output, Stdio},
+    str::FromStr,
     string::FromUtf8Error,
+    sync::mpsc,
+    thread,
+    time::Duration,
 };
 
+use unicode_bidi::BidiInfo;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+use unicode_segmentation::UnicodeSegmentation;
+
+pub fn truncate_graphemes(text: &mut String, max_len: usize, ellipsis: &str) {
+    let Some((cut, _)) = text.grapheme_indices(true).nth(max_len) else {
+        return;
+    };
+    let ellipsis_len = ellipsis.graphemes(true).count();
+    if ellipsis_len >= max_len {
+        text.truncate(cut);
+        return;
+    }
+    let cut = text
+        .grapheme_indices(true)
+        .nth(max_len - ellipsis_len)
+        .map_or(cut, |(i, _)| i);
+    text.truncate(cut);
+    text.push_str(ellipsis);
+}
+
+/// Like [`truncate_graphemes`], but keeps both ends and puts the ellipsis in the middle.
+pub fn truncate_graphemes_middle(text: &mut String, max_len: usize, ellipsis: &str) {
+    let len = text.graphemes(true).count();
+    let ellipsis_len = ellipsis.graphemes(true).count();
+    if len <= max_len || ellipsis_len >= max_len {
+        return truncate_graphemes(text, max_len, ellipsis);
+    }
+    let kept = max_len - ellipsis_len;
+    // the start usually says more, it gets the odd grapheme
+    let (head, tail) = (kept - kept / 2, kept / 2);
+    let mut graphemes = text.grapheme_indices(true).map(|(i, _)| i);
+    let start = graphemes.nth(head).unwrap();
+    let end = graphemes.nth(len - tail - head - 1).unwrap_or(text.len());
+    text.replace_range(start..end, ellipsis);
+}
+
+/// Width of the terminal on the standard output, `None` when it is not a terminal.
+pub fn terminal_width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_col > 0).then_some(size.ws_col as usize)
+}
+
+pub fn runtime_dir() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR").map_or_else(env::temp_dir, PathBuf::from)
+}
+
+pub fn cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("mergneh")
+}
+
+/// Reorders text from logical to visual order, returns whether there were any RTL characters.
+pub fn reorder_bidi(text: &mut String) -> bool {
+    let info = BidiInfo::new(text, None);
+    if !info.has_rtl() {
+        return false;
+    }
+    let visual = info
+        .paragraphs
+        .iter()
+        .map(|para| info.reorder_line(para, para.range.clone()))
+        .collect::<String>();
+    *text = visual;
+    true
+}
+
+/// Composes characters where possible (NFC), so a letter and its accent stay one character.
+pub fn normalize_nfc(text: &mut String) {
+    // most text is composed already
+    if is_nfc_quick(text.chars()) == IsNormalized::Yes {
+        return;
+    }
+    *text = text.nfc().collect();
+}
+
+/// Removes terminal escape sequences (colors, cursor movement, OSC titles and links).
+pub fn strip_escapes(text: &mut String) {
+    if !text.contains('\x1b') {
+        return;
+    }
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters up to a final byte
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: up to BEL or ST
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || c == '\x1b' && chars.next() == Some('\\') {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    *text = stripped;
+}
+
 pub fn replace_newline(text: &mut String, replacement: &str) {
     text.retain(|c| c != '\r');
     if replacement.is_empty() {
@@ -17,7 +139,7 @@ pub fn replace_newline(text: &mut String, replacement: &str) {
     let newline_count = text.chars().filter(|&c| c == '\n').count();
     let additional_len = (replacement.len() - 1) * newline_count;
     text.reserve(additional_len);
-    text.extend(repeat('\0').take(additional_len));
+    text.extend(repeat_n('\0', additional_len));
 
     let mut dest = text.len();
     let mut src = text.len() - additional_len;
@@ -38,13 +160,133 @@ pub fn replace_newline(text: &mut String, replacement: &str) {
     }
 }
 
+/// How bytes read from commands and files are turned into text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8, or UTF-16 with a byte order mark, anything else is taken for Latin-1.
+    #[default]
+    Auto,
+    Utf8,
+    /// UTF-8 with invalid sequences replaced by U+FFFD.
+    Utf8Lossy,
+    Latin1,
+}
+
+impl Encoding {
+    pub fn decode(self, bytes: Vec<u8>) -> Result<String, FromUtf8Error> {
+        match self {
+            Encoding::Auto => Ok(match bytes.as_slice() {
+                [0xef, 0xbb, 0xbf, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+                [0xff, 0xfe, rest @ ..] => decode_utf16(rest, u16::from_le_bytes),
+                [0xfe, 0xff, rest @ ..] => decode_utf16(rest, u16::from_be_bytes),
+                _ => String::from_utf8(bytes).unwrap_or_else(|e| {
+                    log::debug!("Decoding invalid UTF-8 as Latin-1: {e}");
+                    latin1(e.as_bytes())
+                }),
+            }),
+            Encoding::Utf8 => String::from_utf8(bytes),
+            Encoding::Utf8Lossy => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            Encoding::Latin1 => Ok(latin1(&bytes)),
+        }
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    char::decode_utf16(bytes.chunks_exact(2).map(|c| from_bytes([c[0], c[1]])))
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "auto" => Encoding::Auto,
+            "utf-8" | "utf8" => Encoding::Utf8,
+            "utf-8-lossy" | "lossy" => Encoding::Utf8Lossy,
+            "latin1" | "latin-1" | "iso-8859-1" => Encoding::Latin1,
+            _ => {
+                anyhow::bail!("unknown encoding '{s}', expected auto, utf-8, utf-8-lossy or latin1")
+            }
+        })
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Auto => "auto",
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf8Lossy => "utf-8-lossy",
+            Encoding::Latin1 => "latin1",
+        })
+    }
+}
+
+/// Whether escape sequences coming from sources reach the output, `--color`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Only to a terminal, and not when `NO_COLOR` is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Resolves the mode for an output, `terminal` telling whether it can show colors at all.
+    pub fn enabled(self, terminal: bool) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            // https://no-color.org
+            ColorMode::Auto => terminal && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+        }
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => ColorMode::Auto,
+            "always" => ColorMode::Always,
+            "never" => ColorMode::Never,
+            _ => anyhow::bail!("unknown color mode '{s}', expected auto, always or never"),
+        })
+    }
+}
+
+impl Display for ColorMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorMode::Auto => "auto",
+            ColorMode::Always => "always",
+            ColorMode::Never => "never",
+        })
+    }
+}
+
 #[derive(Debug)]
-pub struct Command(process::Command);
+pub struct Command {
+    cmd: process::Command,
+    timeout: Option<Duration>,
+    shell: bool,
+    encoding: Encoding,
+}
 
 #[derive(Debug)]
 pub enum CommandError {
     Io(io::Error),
     UTF8(FromUtf8Error),
+    Status(ExitStatus),
+    Timeout(Duration),
 }
 
 impl Error for CommandError {}
@@ -54,20 +296,145 @@ impl Display for CommandError {
         match self {
             CommandError::Io(e) => write!(f, "Io error while executing command: {}", e),
             CommandError::UTF8(e) => write!(f, "Child process has outputed invalid UTF-8: {}", e),
+            CommandError::Status(status) => write!(f, "Command failed: {}", status),
+            CommandError::Timeout(timeout) => write!(f, "Command timed out after {:?}", timeout),
         }
     }
 }
 
 impl Command {
+    /// Runs the arguments joined with spaces as a `sh -c` script, so pipes and quoting work.
+    pub fn shell<S: AsRef<OsStr>>(args: impl IntoIterator<Item = S>) -> Self {
+        let mut script = OsString::new();
+        for (i, arg) in args.into_iter().enumerate() {
+            if i > 0 {
+                script.push(" ");
+            }
+            script.push(arg);
+        }
+        let mut cmd = Command::from_iter([OsStr::new("sh"), OsStr::new("-c"), &script]);
+        cmd.shell = true;
+        cmd
+    }
+    /// Kills the command (with everything it has spawned) if it runs for longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        if timeout.is_some() {
+            self.cmd.process_group(0);
+        }
+        self.timeout = timeout;
+        self
+    }
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+    /// Replaces the `MERGNEH_<NAME>` environment variables of the command.
+    pub fn set_env<K: AsRef<str>, V: AsRef<OsStr>>(
+        &mut self,
+        env: impl IntoIterator<Item = (K, V)>,
+    ) {
+        let previous = self
+            .cmd
+            .get_envs()
+            .filter(|(key, value)| value.is_some() && key.to_string_lossy().starts_with("MERGNEH_"))
+            .map(|(key, _)| key.to_owned())
+            .collect::<Vec<_>>();
+        for key in previous {
+            self.cmd.env_remove(key);
+        }
+        for (key, value) in env {
+            self.cmd.env(format!("MERGNEH_{}", key.as_ref()), value);
+        }
+    }
     pub fn spawn_and_read_output(&mut self) -> Result<String, CommandError> {
-        String::from_utf8(
-            self.0
+        let output = match self.timeout {
+            Some(timeout) => self.output_with_timeout(timeout)?,
+            None => self
+                .cmd
                 .spawn()
                 .and_then(Child::wait_with_output)
-                .map_err(CommandError::Io)?
-                .stdout,
+                .map_err(CommandError::Io)?,
+        };
+        if !output.status.success() {
+            return Err(CommandError::Status(output.status));
+        }
+        self.encoding
+            .decode(output.stdout)
+            .map_err(CommandError::UTF8)
+    }
+    fn output_with_timeout(&mut self, timeout: Duration) -> Result<Output, CommandError> {
+        let child = self.cmd.spawn().map_err(CommandError::Io)?;
+        let group = child.id();
+        let (sender, receiver) = mpsc::channel();
+        // the output may be closed before the command exits, both are waited for off the
+        // render thread
+        thread::spawn(move || {
+            let _ = sender.send(child.wait_with_output());
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(output) => output.map_err(CommandError::Io),
+            Err(_) => {
+                // the waiting thread reaps the command once it is killed
+                kill_group(group);
+                Err(CommandError::Timeout(timeout))
+            }
+        }
+    }
+}
+
+/// Kills a process group, the command spawned in it along with everything it has spawned.
+fn kill_group(group: u32) {
+    log::debug!("Killing process group {group}");
+    if unsafe { libc::kill(-(group as libc::pid_t), libc::SIGKILL) } != 0 {
+        log::debug!(
+            "Failed killing process group: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.cmd.get_program().to_string_lossy())?;
+        for arg in self.cmd.get_args() {
+            write!(f, " {}", arg.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Command {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.shell {
+            let script = self.cmd.get_args().nth(1).unwrap_or_default();
+            return serializer.collect_str(&script.to_string_lossy());
+        }
+        serializer.collect_seq(
+            std::iter::once(self.cmd.get_program())
+                .chain(self.cmd.get_args())
+                .map(OsStr::to_string_lossy),
         )
-        .map_err(CommandError::UTF8)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Command {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// A string is a shell script, an array is executed as is.
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Shell(String),
+            Exec(Vec<String>),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Shell(script) => Ok(Command::shell([script])),
+            Repr::Exec(args) if args.is_empty() => {
+                Err(serde::de::Error::invalid_length(0, &"at least one element"))
+            }
+            Repr::Exec(args) => Ok(args.into_iter().collect()),
+        }
     }
 }
 
@@ -79,18 +446,322 @@ impl<S: AsRef<OsStr>> FromIterator<S> for Command {
                 .expect("Iterator for Command must have at least one element"),
         );
         cmd.stdout(Stdio::piped()).args(iter);
-        Command(cmd)
+        cmd.into()
     }
 }
 
 impl From<Command> for process::Command {
     fn from(val: Command) -> Self {
-        val.0
+        val.cmd
     }
 }
 
 impl From<process::Command> for Command {
     fn from(value: process::Command) -> Self {
-        Command(value)
+        Command {
+            cmd: value,
+            timeout: None,
+            shell: false,
+            encoding: Encoding::default(),
+        }
+    }
+}
+
+/// Escapes text for a JSON string literal.
+pub struct JsonEscaped<'a>(pub &'a str);
+
+impl Display for JsonEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A string with the characters Pango markup gives a meaning to written as entities.
+pub struct MarkupEscaped<'a>(pub &'a str);
+
+impl Display for MarkupEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Strings written as a JSON array.
+pub struct JsonArray<I>(pub I);
+
+impl<I> Display for JsonArray<I>
+where
+    I: Iterator + Clone,
+    I::Item: AsRef<str>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, item) in self.0.clone().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "\"{}\"", JsonEscaped(item.as_ref()))?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Standard base64 with padding, as terminal image protocols want it.
+pub struct Base64<'a>(pub &'a [u8]);
+
+impl Display for Base64<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        for chunk in self.0.chunks(3) {
+            let bytes = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            for i in 0..4 {
+                match i <= chunk.len() {
+                    true => f.write_char(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char)?,
+                    false => f.write_char('=')?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "serde", feature = "mpd"))]
+macro_rules! serde_with_str {
+    ($($type:ty),+) => {$(
+        impl serde::Serialize for $type {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $type {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    )+};
+}
+#[cfg(all(feature = "serde", feature = "mpd"))]
+pub(crate) use serde_with_str;
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        normalize_nfc, strip_escapes, truncate_graphemes, truncate_graphemes_middle, Base64,
+        Command, CommandError, Encoding, JsonArray, JsonEscaped, MarkupEscaped,
+    };
+
+    #[test]
+    fn truncate_graphemes_test() {
+        macro_rules! assert_truncated {
+            ($str:literal, $max:literal, $ellipsis:literal => $expected:literal) => {
+                let mut text = $str.to_owned();
+                truncate_graphemes(&mut text, $max, $ellipsis);
+                assert_eq!(text, $expected);
+            };
+        }
+        assert_truncated!("short", 10, "…" => "short");
+        assert_truncated!("exactly", 7, "…" => "exactly");
+        assert_truncated!("too long text", 8, "…" => "too lon…");
+        assert_truncated!("too long text", 8, "..." => "too l...");
+        assert_truncated!("too long text", 2, "..." => "to");
+        assert_truncated!("e\u{301}e\u{301}e\u{301}e\u{301}", 3, "…" => "e\u{301}e\u{301}…");
+        assert_truncated!("🇷🇺🇺🇦🇰🇿", 2, "" => "🇷🇺🇺🇦");
+    }
+
+    #[test]
+    fn normalize_nfc_test() {
+        let mut text = "Bjo\u{308}rk – Jo\u{301}ga".to_owned();
+        normalize_nfc(&mut text);
+        assert_eq!(text, "Björk – Jóga");
+        // no precomposed form, the combining mark stays
+        let mut text = "q\u{301}".to_owned();
+        normalize_nfc(&mut text);
+        assert_eq!(text, "q\u{301}");
+    }
+
+    #[test]
+    fn strip_escapes_test() {
+        let mut text =
+            "\x1b[1;31mred\x1b[0m and \x1b]8;;https://x.org\x1b\\link\x1b]8;;\x07".to_owned();
+        strip_escapes(&mut text);
+        assert_eq!(text, "red and link");
+        let mut text = "plain [text]".to_owned();
+        strip_escapes(&mut text);
+        assert_eq!(text, "plain [text]");
+    }
+
+    #[test]
+    fn truncate_graphemes_middle_test() {
+        macro_rules! assert_truncated {
+            ($str:literal, $max:literal, $ellipsis:literal => $expected:literal) => {
+                let mut text = $str.to_owned();
+                truncate_graphemes_middle(&mut text, $max, $ellipsis);
+                assert_eq!(text, $expected);
+            };
+        }
+        assert_truncated!("short", 10, "…" => "short");
+        assert_truncated!("Some long album title.flac", 19, "…" => "Some long…itle.flac");
+        assert_truncated!("abcdefgh", 5, "…" => "ab…gh");
+        assert_truncated!("abcdefgh", 6, "..." => "ab...h");
+        assert_truncated!("abcdefgh", 4, "" => "abgh");
+        assert_truncated!("abcdefgh", 2, "..." => "ab");
+        assert_truncated!("e\u{301}xyze\u{301}", 3, "…" => "e\u{301}…e\u{301}");
+    }
+
+    #[test]
+    fn json_escape_test() {
+        macro_rules! assert_escaped {
+            ($str:literal => $escaped:literal) => {
+                assert_eq!(JsonEscaped($str).to_string(), $escaped)
+            };
+        }
+        assert_escaped!("plain text" => "plain text");
+        assert_escaped!("Artist - \"Title\"" => "Artist - \\\"Title\\\"");
+        assert_escaped!("first\nsecond" => "first\\nsecond");
+        assert_escaped!("C:\\music\t\r" => "C:\\\\music\\t\\r");
+        assert_escaped!("bell\u{7}" => "bell\\u0007");
+        assert_escaped!("юникод ♫" => "юникод ♫");
+        assert_eq!(
+            JsonArray(["a", "\"b\""].iter()).to_string(),
+            r#"["a","\"b\""]"#
+        );
+        assert_eq!(JsonArray("".split_whitespace()).to_string(), "[]");
+    }
+
+    #[test]
+    fn markup_escape_test() {
+        assert_eq!(
+            MarkupEscaped("Simon & Garfunkel <live>").to_string(),
+            "Simon &amp; Garfunkel &lt;live&gt;"
+        );
+        assert_eq!(MarkupEscaped("plain").to_string(), "plain");
+    }
+
+    #[test]
+    fn base64_test() {
+        assert_eq!(Base64(b"").to_string(), "");
+        assert_eq!(Base64(b"f").to_string(), "Zg==");
+        assert_eq!(Base64(b"fo").to_string(), "Zm8=");
+        assert_eq!(Base64(b"foo").to_string(), "Zm9v");
+        assert_eq!(Base64(b"foobar").to_string(), "Zm9vYmFy");
+        assert_eq!(Base64(&[0xff, 0xfe, 0x00]).to_string(), "//4A");
+    }
+
+    #[test]
+    fn command_status_test() {
+        let mut cmd: Command = ["sh", "-c", "echo output"].into_iter().collect();
+        assert_eq!(cmd.spawn_and_read_output().unwrap(), "output\n");
+        let mut cmd: Command = ["sh", "-c", "echo partial; exit 3"].into_iter().collect();
+        assert!(matches!(
+            cmd.spawn_and_read_output(),
+            Err(CommandError::Status(status)) if status.code() == Some(3)
+        ));
+    }
+
+    #[test]
+    fn encoding_test() {
+        let decode = |encoding: &str, bytes: &[u8]| {
+            encoding.parse::<Encoding>().unwrap().decode(bytes.to_vec())
+        };
+        assert_eq!(decode("auto", "Sigur Rós".as_bytes()).unwrap(), "Sigur Rós");
+        assert_eq!(decode("auto", b"Sigur R\xf3s").unwrap(), "Sigur Rós");
+        assert_eq!(decode("auto", b"\xef\xbb\xbfBOM").unwrap(), "BOM");
+        assert_eq!(decode("auto", b"\xff\xfeR\x00\xf3\x00").unwrap(), "Ró");
+        assert_eq!(decode("auto", b"\xfe\xff\x00R\x00\xf3").unwrap(), "Ró");
+        assert_eq!(decode("latin1", "ó".as_bytes()).unwrap(), "Ã³");
+        assert_eq!(decode("utf-8-lossy", b"R\xf3s").unwrap(), "R\u{fffd}s");
+        assert!(decode("utf-8", b"R\xf3s").is_err());
+        assert!("koi8-r".parse::<Encoding>().is_err());
+    }
+
+    #[test]
+    fn command_env_test() {
+        let mut cmd: Command = ["sh", "-c", "echo \"$MERGNEH_ARTIST - $MERGNEH_TITLE\""]
+            .into_iter()
+            .collect();
+        cmd.set_env([("ARTIST", "Artist"), ("TITLE", "Title")]);
+        assert_eq!(cmd.spawn_and_read_output().unwrap(), "Artist - Title\n");
+        // values missing from the next song are not left over
+        cmd.set_env([("ARTIST", "Other")]);
+        assert_eq!(cmd.spawn_and_read_output().unwrap(), "Other - \n");
+    }
+
+    #[test]
+    fn command_shell_test() {
+        let mut cmd = Command::shell(["echo one two", "|", "tr ' ' -"]);
+        assert_eq!(cmd.spawn_and_read_output().unwrap(), "one-two\n");
+        // without a shell the pipe is just another argument
+        let mut cmd: Command = ["echo", "one", "|", "tr"].into_iter().collect();
+        assert_eq!(cmd.spawn_and_read_output().unwrap(), "one | tr\n");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn command_serde_test() {
+        for json in [r#""echo \"$HOME\" | wc -c""#, r#"["echo","$HOME"]"#] {
+            let cmd: Command = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&cmd).unwrap(), json);
+        }
+        let cmd: Command = serde_json::from_str(r#""echo a | wc -l""#).unwrap();
+        assert_eq!(cmd.to_string(), "sh -c echo a | wc -l");
+        assert!(serde_json::from_str::<Command>("[]").is_err());
+    }
+
+    #[test]
+    fn command_timeout_test() {
+        let mut cmd = ["sh", "-c", "sleep 10 & sleep 10"]
+            .into_iter()
+            .collect::<Command>()
+            .with_timeout(Some(Duration::from_millis(200)));
+        let start = Instant::now();
+        assert!(matches!(
+            cmd.spawn_and_read_output(),
+            Err(CommandError::Timeout(_))
+        ));
+        // the background sleep holding the output open is killed too
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let mut cmd = ["echo", "fast"]
+            .into_iter()
+            .collect::<Command>()
+            .with_timeout(Some(Duration::from_secs(5)));
+        assert_eq!(cmd.spawn_and_read_output().unwrap(), "fast\n");
+        // the status comes after the output is closed
+        let mut cmd = ["sh", "-c", "exec >&-; sleep 0.2; exit 3"]
+            .into_iter()
+            .collect::<Command>()
+            .with_timeout(Some(Duration::from_secs(5)));
+        assert!(matches!(
+            cmd.spawn_and_read_output(),
+            Err(CommandError::Status(status)) if status.code() == Some(3)
+        ));
     }
 }
@@ -0,0 +1,124 @@
+use std::{fs, path::Path};
+
+use wasmi::{Config, Engine, Linker, Module, Store};
+
+/// Instructions a filter may execute on one text, so a looping module can't hang the ticker.
+const FUEL: u64 = 10_000_000;
+
+/// A WASM module transforming the contents before they are laid out.
+///
+/// The module exports its `memory`, `alloc(len: i32) -> i32` returning where the host writes
+/// `len` bytes of UTF-8 input and `filter(ptr: i32, len: i32) -> i64` returning the output as
+/// `ptr << 32 | len`. Every text gets a fresh instance, so allocations never have to be freed.
+pub struct WasmFilter {
+    name: String,
+    engine: Engine,
+    module: Module,
+}
+
+impl WasmFilter {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let wasm = fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Failed reading {}: {e}", path.display()))?;
+        Self::new(path.display().to_string(), &wasm)
+    }
+    pub fn new(name: String, wasm: &[u8]) -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)
+            .map_err(|e| anyhow::anyhow!("Invalid WASM module {name}: {e}"))?;
+        Ok(Self {
+            name,
+            engine,
+            module,
+        })
+    }
+    pub fn apply(&self, text: &mut String) -> anyhow::Result<()> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL).map_err(|e| anyhow::anyhow!("{e}"))?;
+        let instance = Linker::new(&self.engine)
+            .instantiate(&mut store, &self.module)?
+            .start(&mut store)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("{} does not export memory", self.name))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+        let filter = instance.get_typed_func::<(i32, i32), i64>(&store, "filter")?;
+
+        let len = i32::try_from(text.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, text.as_bytes())
+            .map_err(|e| anyhow::anyhow!("{} allocated out of its memory: {e}", self.name))?;
+        let output = filter.call(&mut store, (ptr, len))? as u64;
+        let (ptr, len) = ((output >> 32) as usize, output as u32 as usize);
+        let bytes = memory
+            .data(&store)
+            .get(ptr..ptr + len)
+            .ok_or_else(|| anyhow::anyhow!("{} returned output out of its memory", self.name))?;
+        let output = std::str::from_utf8(bytes)
+            .map_err(|e| anyhow::anyhow!("{} returned invalid UTF-8: {e}", self.name))?;
+        text.clear();
+        text.push_str(output);
+        Ok(())
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WasmFilter;
+
+    /// Uppercases ASCII letters in place.
+    const UPPERCASE: &str = r#"(module
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (func (export "alloc") (param $len i32) (result i32)
+            (global.get $next)
+            (global.set $next (i32.add (global.get $next) (local.get $len))))
+        (func (export "filter") (param $ptr i32) (param $len i32) (result i64)
+            (local $i i32) (local $c i32)
+            (block $done
+                (loop $next
+                    (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                    (local.set $c (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                    (if (i32.and (i32.ge_u (local.get $c) (i32.const 97))
+                                 (i32.le_u (local.get $c) (i32.const 122)))
+                        (then (i32.store8 (i32.add (local.get $ptr) (local.get $i))
+                                          (i32.sub (local.get $c) (i32.const 32)))))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br $next)))
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len)))))"#;
+
+    const LOOP: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) (i32.const 0))
+        (func (export "filter") (param i32 i32) (result i64) (loop (br 0)) (i64.const 0)))"#;
+
+    fn filter(wat: &str) -> WasmFilter {
+        WasmFilter::new("test".to_owned(), &wat::parse_str(wat).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn apply_test() {
+        let filter = filter(UPPERCASE);
+        let mut text = "Sigur Rós - Hoppípolla".to_owned();
+        filter.apply(&mut text).unwrap();
+        assert_eq!(text, "SIGUR RóS - HOPPíPOLLA");
+        // every text gets a fresh instance with an empty heap
+        filter.apply(&mut text).unwrap();
+        assert_eq!(text, "SIGUR RóS - HOPPíPOLLA");
+    }
+
+    #[test]
+    fn fuel_test() {
+        let mut text = "text".to_owned();
+        assert!(filter(LOOP).apply(&mut text).is_err());
+        assert_eq!(text, "text");
+        assert!(WasmFilter::new("invalid".to_owned(), b"\0asm").is_err());
+    }
+}
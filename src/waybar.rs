@@ -1,61 +1,506 @@
-use crate::utils::Command;
-
-#[cfg(feature = "mpd")]
-use crate::mpd::MpdFormatter;
 #[cfg(feature = "mpd")]
-use crate::text_source::TextSource;
+use std::str::FromStr;
+use std::{
+    fmt::{self, Display},
+    fs, mem,
+    path::PathBuf,
+};
 
-use super::RunningText;
+use crate::utils::{truncate_graphemes, Command, CommandError, JsonArray, JsonEscaped};
+
+#[cfg(feature = "mpd")]
+use crate::mpd::{MpdFormatter, ParseOptions};
+use crate::{
+    events::Frames,
+    running_text::{RunningText, TextFrame},
+    sink::{Frame, Sink, StdoutSink},
+    text_source::ContentChange,
+};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum Tooltip {
     Simple(String),
     Cmd(Command),
+    File(PathBuf),
     #[cfg(feature = "mpd")]
     Mpd(MpdFormatter),
+    #[cfg(feature = "mpd")]
+    Queue {
+        format: MpdFormatter,
+        count: usize,
+    },
+    #[cfg(feature = "mpd")]
+    History {
+        format: MpdFormatter,
+        count: usize,
+    },
+}
+#[derive(Debug, Default)]
+pub struct TooltipOptions {
+    pub strip_newlines: bool,
+    pub max_length: Option<usize>,
+    pub ellipsis: String,
+}
+
+impl TooltipOptions {
+    pub fn apply(&self, tooltip: &mut String) {
+        if self.strip_newlines {
+            tooltip.retain(|c| c != '\n');
+        }
+        if let Some(max_length) = self.max_length {
+            truncate_graphemes(tooltip, max_length, &self.ellipsis);
+        }
+    }
+}
+
+/// An extra key of the JSON output rendered with its own format, given as `KEY=FORMAT`.
+#[cfg(feature = "mpd")]
+#[derive(Debug, Clone)]
+pub struct JsonField {
+    pub key: String,
+    pub format: MpdFormatter,
 }
+
+#[cfg(feature = "mpd")]
+impl FromStr for JsonField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(s, ParseOptions::default())
+    }
+}
+
+#[cfg(feature = "mpd")]
+impl JsonField {
+    pub fn parse_with(s: &str, options: ParseOptions) -> anyhow::Result<Self> {
+        let (key, format) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected KEY=FORMAT"))?;
+        anyhow::ensure!(!key.is_empty(), "empty key in '{s}'");
+        anyhow::ensure!(
+            !FrameJson::KEYS.contains(&key),
+            "'{key}' is already a key of the output"
+        );
+        Ok(JsonField {
+            key: key.to_owned(),
+            format: MpdFormatter::parse_with(format, options)?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ModuleOptions {
+    pub tooltip: Option<Tooltip>,
+    pub tooltip_options: TooltipOptions,
+    #[cfg(feature = "mpd")]
+    pub class: Option<MpdFormatter>,
+    #[cfg(feature = "mpd")]
+    pub fields: Vec<JsonField>,
+}
+
+impl ModuleOptions {
+    /// Whether a class or extra fields are rendered on every refresh.
+    pub fn has_formats(&self) -> bool {
+        #[cfg(feature = "mpd")]
+        return self.class.is_some() || !self.fields.is_empty();
+        #[cfg(not(feature = "mpd"))]
+        false
+    }
+    /// MPD formats rendered besides the source's own ones.
+    #[cfg(feature = "mpd")]
+    pub fn formats(&self) -> impl Iterator<Item = &MpdFormatter> {
+        let tooltip = match &self.tooltip {
+            Some(
+                Tooltip::Mpd(format)
+                | Tooltip::Queue { format, .. }
+                | Tooltip::History { format, .. },
+            ) => Some(format),
+            _ => None,
+        };
+        tooltip
+            .into_iter()
+            .chain(&self.class)
+            .chain(self.fields.iter().map(|field| &field.format))
+    }
+}
+
 pub struct RunningTextWithTooltip {
     text: RunningText,
-    tooltip: Tooltip,
+    tooltip: Option<Tooltip>,
+    options: TooltipOptions,
+    #[cfg(feature = "mpd")]
+    class_format: Option<MpdFormatter>,
+    #[cfg(feature = "mpd")]
+    field_formats: Vec<JsonField>,
     buffer: String,
+    previous: String,
+    class: Option<String>,
+    #[cfg(feature = "mpd")]
+    previous_class: Option<String>,
+    /// Keys and values of the extra fields.
+    fields: Vec<(String, String)>,
+    changed: bool,
 }
 
 impl RunningTextWithTooltip {
-    pub fn new(text: RunningText, tooltip: Tooltip) -> RunningTextWithTooltip {
+    pub fn new(text: RunningText, module: ModuleOptions) -> RunningTextWithTooltip {
         RunningTextWithTooltip {
             text,
-            tooltip,
+            tooltip: module.tooltip,
+            options: module.tooltip_options,
+            #[cfg(feature = "mpd")]
+            class_format: module.class,
+            #[cfg(feature = "mpd")]
+            field_formats: module.fields,
             buffer: String::new(),
+            previous: String::new(),
+            class: None,
+            #[cfg(feature = "mpd")]
+            previous_class: None,
+            fields: Vec::new(),
+            changed: true,
         }
     }
 }
 
+/// Prints frames as JSON lines, tagged with the source and instance they come from.
+pub struct WaybarOutput {
+    out: StdoutSink,
+    source: &'static str,
+    instance: Option<String>,
+}
+
+impl WaybarOutput {
+    pub fn new(text: &RunningText) -> Self {
+        Self {
+            out: text.stdout(),
+            source: text.get_source().name(),
+            instance: text.instance().map(str::to_owned),
+        }
+    }
+}
+
+impl Sink for WaybarOutput {
+    fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let frame = FrameJson {
+            text: frame.text,
+            tooltip: frame.tooltip,
+            class: frame.class,
+            fields: frame.fields,
+            changes: frame.changes,
+            generation: frame.generation,
+            source: self.source,
+            instance: self.instance.as_deref(),
+        };
+        Ok(self.out.write_frame(format_args!("{frame}\n"))?)
+    }
+    fn set_dedup(&mut self, dedup: bool) {
+        self.out.set_dedup(dedup);
+    }
+}
+
+/// A line of waybar's custom module JSON, the class is split on whitespace. Waybar ignores the
+/// extra fields, changes, source and instance, they are there for scripts reading the output.
+struct FrameJson<'a> {
+    text: &'a str,
+    tooltip: Option<&'a str>,
+    class: Option<&'a str>,
+    fields: &'a [(String, String)],
+    changes: ContentChange,
+    generation: u64,
+    source: &'a str,
+    instance: Option<&'a str>,
+}
+
+impl FrameJson<'_> {
+    /// Keys extra fields can't take, including the ones waybar reads but mergneh doesn't set.
+    #[cfg(feature = "mpd")]
+    const KEYS: [&'static str; 9] = [
+        "text",
+        "tooltip",
+        "class",
+        "alt",
+        "percentage",
+        "changed",
+        "generation",
+        "source",
+        "instance",
+    ];
+}
+
+impl Display for FrameJson<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let FrameJson {
+            text,
+            tooltip,
+            class,
+            fields,
+            changes,
+            generation,
+            source,
+            instance,
+        } = self;
+        write!(f, "{{\"text\":\"{}\"", JsonEscaped(text))?;
+        if let Some(tooltip) = tooltip {
+            write!(f, ",\"tooltip\":\"{}\"", JsonEscaped(tooltip))?;
+        }
+        if let Some(class) = class {
+            write!(f, ",\"class\":{}", JsonArray(class.split_whitespace()))?;
+        }
+        for (key, value) in fields.iter() {
+            write!(f, ",\"{}\":\"{}\"", JsonEscaped(key), JsonEscaped(value))?;
+        }
+        write!(
+            f,
+            ",\"changed\":{},\"generation\":{generation}",
+            JsonArray(changes.names())
+        )?;
+        write!(f, ",\"source\":\"{}\"", JsonEscaped(source))?;
+        if let Some(instance) = instance {
+            write!(f, ",\"instance\":\"{}\"", JsonEscaped(instance))?;
+        }
+        f.write_str("}")
+    }
+}
+
+impl RunningTextWithTooltip {
+    fn update_tooltip(&mut self) -> anyhow::Result<()> {
+        if let Some(Tooltip::Cmd(cmd)) = &mut self.tooltip {
+            cmd.set_env(self.text.env());
+        }
+        let Some(tooltip) = &mut self.tooltip else {
+            return Ok(());
+        };
+        #[cfg(feature = "mpd")]
+        let src = self.text.get_source_mut();
+        match tooltip {
+            Tooltip::Simple(s) => s.clone_into(&mut self.buffer),
+            Tooltip::Cmd(cmd) => match cmd.spawn_and_read_output() {
+                Ok(output) => output.clone_into(&mut self.buffer),
+                Err(e @ CommandError::Timeout(_)) => {
+                    log::warn!("Tooltip {e}, keeping the previous one");
+                    self.buffer.clone_from(&self.previous);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            },
+            Tooltip::File(path) => self.buffer = fs::read_to_string(path)?,
+            #[cfg(feature = "mpd")]
+            Tooltip::Mpd(f) => {
+                self.buffer.clear();
+                src.format(f, &mut self.buffer)?;
+            }
+            #[cfg(feature = "mpd")]
+            Tooltip::Queue { format, count } => {
+                self.buffer.clear();
+                src.format_upcoming(format, *count, &mut self.buffer)?;
+            }
+            #[cfg(feature = "mpd")]
+            Tooltip::History { format, count } => {
+                self.buffer.clear();
+                src.format_history(format, *count, &mut self.buffer)?;
+            }
+        };
+        self.options.apply(&mut self.buffer);
+        Ok(())
+    }
+    #[cfg(feature = "mpd")]
+    fn update_class(&mut self) -> anyhow::Result<()> {
+        let Some(format) = &self.class_format else {
+            return Ok(());
+        };
+        let class = self.class.get_or_insert_with(String::new);
+        class.clear();
+        self.text.get_source_mut().format(format, class)
+    }
+    #[cfg(feature = "mpd")]
+    fn update_fields(&mut self) -> anyhow::Result<()> {
+        if self.field_formats.is_empty() {
+            return Ok(());
+        }
+        let src = self.text.get_source_mut();
+        let mut changed = false;
+        self.fields
+            .resize_with(self.field_formats.len(), Default::default);
+        for (field, (key, value)) in self.field_formats.iter().zip(&mut self.fields) {
+            let mut new = String::new();
+            src.format(&field.format, &mut new)?;
+            changed |= *value != new;
+            field.key.clone_into(key);
+            *value = new;
+        }
+        self.changed |= changed;
+        Ok(())
+    }
+}
+
 impl Iterator for RunningTextWithTooltip {
-    type Item = (anyhow::Result<String>, String);
+    type Item = (anyhow::Result<String>, Option<String>, Option<String>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let iteration = self.text.next().unwrap();
-        let src = self.text.get_source();
-        let tooltip = match (&mut self.tooltip, src) {
-            (Tooltip::Simple(s), _) => s,
-            (Tooltip::Cmd(cmd), _) => {
-                cmd.spawn_and_read_output()
-                    .expect("Child error")
-                    .clone_into(&mut self.buffer);
-                self.buffer.retain(|c| c != '\n');
-                &self.buffer
+        let result = self.advance()?;
+        let (text, tooltip, class, _) = self.frame();
+        Some((
+            result.map(|()| text.text.to_owned()),
+            tooltip.map(str::to_owned),
+            class.map(str::to_owned),
+        ))
+    }
+}
+
+impl Frames for RunningTextWithTooltip {
+    type Frame<'a> = (
+        TextFrame<'a>,
+        Option<&'a str>,
+        Option<&'a str>,
+        &'a [(String, String)],
+    );
+
+    fn advance(&mut self) -> Option<anyhow::Result<()>> {
+        let result = self.text.advance()?;
+        self.changed = false;
+        if self.text.is_refreshed() {
+            // the buffers of the last two updates take turns
+            mem::swap(&mut self.buffer, &mut self.previous);
+            if let Err(e) = self.update_tooltip() {
+                log::warn!("Failed updating tooltip: {e:#}");
+                self.buffer = format!("tooltip error: {e:#}");
+                // an error chain can be longer than any tooltip
+                self.options.apply(&mut self.buffer);
             }
+            self.changed = self.previous != self.buffer;
             #[cfg(feature = "mpd")]
-            (Tooltip::Mpd(f), TextSource::Mpd(s)) => {
-                self.buffer.clear();
-                f.format_with_source(s, &mut self.buffer)
-                    .expect("MPD format error");
-                self.buffer.retain(|c| c != '\n');
-                &self.buffer
+            {
+                mem::swap(&mut self.class, &mut self.previous_class);
+                if let Err(e) = self.update_class() {
+                    log::warn!("Failed updating class: {e:#}");
+                    self.class = None;
+                }
+                self.changed |= self.previous_class != self.class;
+                if let Err(e) = self.update_fields() {
+                    log::warn!("Failed updating fields: {e:#}");
+                    self.fields.clear();
+                }
             }
+        }
+        Some(result)
+    }
+    fn frame(&self) -> Self::Frame<'_> {
+        (
+            self.text.frame(),
+            self.tooltip.as_ref().map(|_| self.buffer.as_str()),
+            self.class.as_deref(),
+            &self.fields,
+        )
+    }
+    fn frame_changed(&self) -> bool {
+        self.changed || self.text.frame_changed()
+    }
+    fn force_refresh(&mut self) {
+        self.text.force_refresh();
+    }
+    fn scroll_once(&mut self) {
+        self.text.scroll_once();
+    }
+    fn is_finished(&self) -> bool {
+        self.text.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameJson, ModuleOptions, RunningTextWithTooltip, Tooltip, TooltipOptions};
+    use crate::{
+        events::Frames,
+        text_source::{Content, ContentChange},
+        RunningText,
+    };
+
+    fn frame_json(text: &str, tooltip: Option<&str>, class: Option<&str>) -> String {
+        FrameJson {
+            text,
+            tooltip,
+            class,
+            fields: &[],
+            changes: ContentChange::empty(),
+            generation: 1,
+            source: "mpd",
+            instance: None,
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn frame_json_test() {
+        assert_eq!(
+            frame_json("text", None, None),
+            r#"{"text":"text","changed":[],"generation":1,"source":"mpd"}"#
+        );
+        assert_eq!(
+            frame_json("text", Some("tip"), Some(" muted  long-track ")),
+            r#"{"text":"text","tooltip":"tip","class":["muted","long-track"],"changed":[],"generation":1,"source":"mpd"}"#
+        );
+        assert_eq!(
+            frame_json("text", None, Some("")),
+            r#"{"text":"text","class":[],"changed":[],"generation":1,"source":"mpd"}"#
+        );
+        let frame = FrameJson {
+            text: "text",
+            tooltip: None,
+            class: None,
+            fields: &[("artist".to_owned(), "Sigur Rós".to_owned())],
+            changes: ContentChange::Running | ContentChange::Suffix,
+            generation: 7,
+            source: "cmd",
+            instance: Some("top \"bar\""),
+        };
+        assert_eq!(
+            frame.to_string(),
+            r#"{"text":"text","artist":"Sigur Rós","changed":["running","suffix"],"generation":7,"source":"cmd","instance":"top \"bar\""}"#
+        );
+    }
+
+    #[cfg(feature = "mpd")]
+    #[test]
+    fn json_field_test() {
+        let field: super::JsonField = "elapsed={elapsedTime}".parse().unwrap();
+        assert_eq!(field.key, "elapsed");
+        assert_eq!(field.format.to_string(), "{elapsedTime}");
+        assert!("text={title}".parse::<super::JsonField>().is_err());
+        assert!("={title}".parse::<super::JsonField>().is_err());
+        assert!("title".parse::<super::JsonField>().is_err());
+        assert!("title={nope}".parse::<super::JsonField>().is_err());
+    }
+
+    #[test]
+    fn tooltip_error_test() {
+        let content = Content::new("text".to_owned(), String::new(), String::new());
+        let text = RunningText::new(
+            Box::new(content),
+            20,
+            String::new(),
+            String::new(),
+            false,
+            false,
+        )
+        .unwrap();
+        let module = ModuleOptions {
+            tooltip: Some(Tooltip::Cmd(["sh", "-c", "exit 3"].into_iter().collect())),
+            tooltip_options: TooltipOptions {
+                strip_newlines: true,
+                max_length: Some(12),
+                ellipsis: "…".to_owned(),
+            },
+            #[cfg(feature = "mpd")]
+            class: None,
             #[cfg(feature = "mpd")]
-            (Tooltip::Mpd(_), _) => panic!("MPD format for tooltip can only be used with --mpd"),
+            fields: Vec::new(),
         };
-        Some((iteration, tooltip.to_owned()))
+        let mut text = RunningTextWithTooltip::new(text, module);
+        text.advance().unwrap().unwrap();
+        // the fallback is cut down like any other tooltip
+        assert_eq!(text.frame().1, Some("tooltip err…"));
     }
 }